@@ -0,0 +1,146 @@
+use anchor_lang::prelude::*;
+use anchor_lang::{Discriminator, Event};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use defi_trust_fund::defi_trust_fund::*;
+use defi_trust_fund::ID;
+
+// Decoding of the events the program emits. Anchor writes each one as a "Program data:"
+// log line holding the base64 of its discriminator followed by the Borsh body.
+
+macro_rules! program_events {
+    ($($event:ident),* $(,)?) => {
+        // Every event the program emits, named after its struct
+        pub enum ProgramEvent {
+            $($event($event),)*
+        }
+
+        impl ProgramEvent {
+            // Decode one event from its discriminator and body; None for anything else
+            pub fn decode(data: &[u8]) -> Option<Self> {
+                if data.len() < 8 {
+                    return None;
+                }
+                let (discriminator, body) = data.split_at(8);
+                $(
+                    if discriminator == $event::DISCRIMINATOR {
+                        return $event::try_from_slice(body).ok().map(Self::$event);
+                    }
+                )*
+                None
+            }
+
+            // Schema version the event was emitted with
+            pub fn version(&self) -> u8 {
+                match self {
+                    $(Self::$event(event) => event.version,)*
+                }
+            }
+
+            // The event as the program logs it: discriminator, then the Borsh body
+            pub fn encode(&self) -> Vec<u8> {
+                match self {
+                    $(Self::$event(event) => event.data(),)*
+                }
+            }
+        }
+    };
+}
+
+program_events! {
+    PoolInitializedEvent,
+    AccountMigratedEvent,
+    StakeEvent,
+    UnstakeEvent,
+    UnstakeRequestedEvent,
+    UnstakeRequestCancelledEvent,
+    ReferralAttributedEvent,
+    ReferralRewardsClaimedEvent,
+    CommitmentExtendedEvent,
+    CommitmentPolicyUpdateEvent,
+    CommitmentMigratedEvent,
+    CommitmentRenewedEvent,
+    AutoRenewUpdateEvent,
+    RolloverEvent,
+    RecoveryPolicyUpdateEvent,
+    RecoveryModeActivatedEvent,
+    RecoveryAdminRotatedEvent,
+    CompoundEvent,
+    EmergencyWithdrawEvent,
+    StakeTransferredEvent,
+    UserStakeClosedEvent,
+    SplStakeEvent,
+    SwapAndStakeEvent,
+    SplUnstakeEvent,
+    SplEmergencyWithdrawEvent,
+    RewardsFundedEvent,
+    RewardsDistributedEvent,
+    AccessChangedEvent,
+    EmergencyPauseEvent,
+    GuardianPauseEvent,
+    GuardianUpdateEvent,
+    TreasuryUpdateEvent,
+    EmergencyUnpauseEvent,
+    PoolSunsetEvent,
+    PoolClosedEvent,
+    ApyTiersUpdateEvent,
+    FeeTiersUpdateEvent,
+    LoyaltyTiersUpdateEvent,
+    YieldsClaimedEvent,
+    RewardTokensPaidEvent,
+    TokenRewardsFundedEvent,
+    YieldsCreditedEvent,
+    CreditedYieldsWithdrawnEvent,
+    NftBoostUpdateEvent,
+    ProposalCreatedEvent,
+    VoteCastEvent,
+    ProposalFinalizedEvent,
+    InsuranceFundedEvent,
+    InsurancePayoutEvent,
+    RebalanceEvent,
+    SnapshotTakenEvent,
+    SolvencyEvent,
+    SolvencyCheckEvent,
+    ReserveRunwayPauseEvent,
+    ParameterUpdateEvent,
+    FeesWithdrawnEvent,
+    PenaltiesWithdrawnEvent,
+    MarinadeDeployedEvent,
+    MarinadeRecalledEvent,
+    MarinadeUnstakeOrderedEvent,
+    MarinadeTicketClaimedEvent,
+}
+
+// Events emitted by this program in a transaction's logs, in order. Invocations are
+// tracked so data logged by other programs, including ones this program calls, is
+// skipped.
+pub fn parse_logs<'a>(logs: impl IntoIterator<Item = &'a str>) -> Vec<ProgramEvent> {
+    let program_id = ID.to_string();
+    let mut invocations: Vec<&str> = Vec::new();
+    let mut events = Vec::new();
+
+    for log in logs {
+        let Some(rest) = log.strip_prefix("Program ") else {
+            continue;
+        };
+        if let Some(data) = rest.strip_prefix("data: ") {
+            if invocations.last() == Some(&program_id.as_str()) {
+                if let Some(event) = STANDARD.decode(data).ok().and_then(|data| ProgramEvent::decode(&data)) {
+                    events.push(event);
+                }
+            }
+            continue;
+        }
+
+        let mut words = rest.split(' ');
+        match (words.next(), words.next()) {
+            (Some(program), Some("invoke")) => invocations.push(program),
+            (Some(_), Some("success")) | (Some(_), Some("failed:")) => {
+                invocations.pop();
+            }
+            _ => {}
+        }
+    }
+
+    events
+}
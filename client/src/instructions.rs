@@ -0,0 +1,175 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::{system_program, sysvar};
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anchor_spl::associated_token::{self, get_associated_token_address};
+use anchor_spl::token;
+use defi_trust_fund::{accounts, instruction, ID};
+
+use crate::{
+    access_entry_address, fee_vault_address, pool_address, pool_vault_address, receipt_mint_address,
+    referral_address, reward_vault_address, user_history_address, user_stake_address,
+};
+
+// Builders for the instructions bots send most. Account lists come from the structs
+// Anchor generates for each on-chain context, so their order always matches the program.
+// has_user_history passes the user's opt-in history account so the operation is recorded.
+
+// Referral accounts a stake must carry: a first stake may record a referrer, and every
+// later top-up of a referred position must pass that referrer's account
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StakeReferral {
+    None,
+    New(Pubkey),
+    Existing(Pubkey),
+}
+
+// Stake SOL into a pool. The user's access entry must be passed whenever the pool is
+// in whitelist or blacklist mode, whether or not the admin has created it.
+#[allow(clippy::too_many_arguments)]
+pub fn build_stake_ix(
+    pool_id: u64,
+    user: Pubkey,
+    amount: u64,
+    committed_days: u64,
+    min_expected_amount: u64,
+    referral: StakeReferral,
+    has_access_entry: bool,
+    has_user_history: bool,
+) -> Instruction {
+    let receipt_mint = receipt_mint_address(pool_id);
+    let (referrer, referral_account) = match referral {
+        StakeReferral::None => (None, None),
+        StakeReferral::New(referrer) => (Some(referrer), Some(referral_address(pool_id, &referrer))),
+        StakeReferral::Existing(referrer) => (None, Some(referral_address(pool_id, &referrer))),
+    };
+
+    Instruction {
+        program_id: ID,
+        accounts: accounts::Stake {
+            user,
+            pool: pool_address(pool_id),
+            pool_vault: pool_vault_address(pool_id),
+            fee_vault: fee_vault_address(pool_id),
+            user_stake: user_stake_address(pool_id, &user),
+            receipt_mint,
+            user_receipt_account: get_associated_token_address(&user, &receipt_mint),
+            referral_account,
+            access_entry: has_access_entry.then(|| access_entry_address(pool_id, &user)),
+            user_history: has_user_history.then(|| user_history_address(&user)),
+            token_program: token::ID,
+            associated_token_program: associated_token::ID,
+            system_program: system_program::ID,
+            rent: sysvar::rent::ID,
+        }
+        .to_account_metas(None),
+        data: instruction::Stake { amount, committed_days, min_expected_amount, referrer }.data(),
+    }
+}
+
+// Claim accrued yields to the owner's wallet. NFT-boosted claims need the holder's NFT
+// accounts appended by the caller.
+pub fn build_claim_yields_ix(
+    pool_id: u64,
+    user: Pubkey,
+    deadline: i64,
+    min_yields: u64,
+    has_user_history: bool,
+) -> Instruction {
+    Instruction {
+        program_id: ID,
+        accounts: accounts::ClaimYields {
+            user,
+            pool: pool_address(pool_id),
+            reward_vault: reward_vault_address(pool_id),
+            user_stake: user_stake_address(pool_id, &user),
+            user_history: has_user_history.then(|| user_history_address(&user)),
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: instruction::ClaimYields { deadline, min_yields }.data(),
+    }
+}
+
+// Instant unstake back to the owner's wallet
+pub fn build_unstake_ix(
+    pool_id: u64,
+    user: Pubkey,
+    amount: u64,
+    deadline: i64,
+    min_return_amount: u64,
+    has_user_history: bool,
+) -> Instruction {
+    let receipt_mint = receipt_mint_address(pool_id);
+
+    Instruction {
+        program_id: ID,
+        accounts: accounts::Unstake {
+            user,
+            pool: pool_address(pool_id),
+            pool_vault: pool_vault_address(pool_id),
+            user_stake: user_stake_address(pool_id, &user),
+            receipt_mint,
+            user_receipt_account: get_associated_token_address(&user, &receipt_mint),
+            user_history: has_user_history.then(|| user_history_address(&user)),
+            token_program: token::ID,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: instruction::Unstake { amount, deadline, min_return_amount }.data(),
+    }
+}
+
+// Read-only preview of a stake, for simulation: the OperationPreview comes back as the
+// transaction's return data. has_position passes the user's position account, which
+// only exists once they have staked; a referred position must pass its referrer.
+// Like a stake, a gated pool needs the access entry.
+pub fn build_preview_stake_ix(
+    pool_id: u64,
+    user: Pubkey,
+    amount: u64,
+    committed_days: u64,
+    has_position: bool,
+    referrer: Option<Pubkey>,
+    has_access_entry: bool,
+) -> Instruction {
+    Instruction {
+        program_id: ID,
+        accounts: accounts::PreviewStake {
+            user,
+            pool: pool_address(pool_id),
+            user_stake: has_position.then(|| user_stake_address(pool_id, &user)),
+            referral_account: referrer.map(|referrer| referral_address(pool_id, &referrer)),
+            access_entry: has_access_entry.then(|| access_entry_address(pool_id, &user)),
+        }
+        .to_account_metas(None),
+        data: instruction::PreviewStake { amount, committed_days }.data(),
+    }
+}
+
+// Read-only preview of an instant unstake of the user's whole position
+pub fn build_preview_unstake_ix(pool_id: u64, user: Pubkey) -> Instruction {
+    Instruction {
+        program_id: ID,
+        accounts: accounts::PreviewUnstake {
+            pool: pool_address(pool_id),
+            pool_vault: pool_vault_address(pool_id),
+            user_stake: user_stake_address(pool_id, &user),
+        }
+        .to_account_metas(None),
+        data: instruction::PreviewUnstake {}.data(),
+    }
+}
+
+// Keeper crank crediting accrued yields to the given owners' positions; the program
+// skips any position it cannot credit, so stale lists are harmless
+pub fn build_batch_credit_yields_ix(pool_id: u64, keeper: Pubkey, owners: &[Pubkey]) -> Instruction {
+    let mut metas = accounts::BatchCreditYields { keeper, pool: pool_address(pool_id) }.to_account_metas(None);
+    metas.extend(owners.iter().map(|owner| AccountMeta::new(user_stake_address(pool_id, owner), false)));
+
+    Instruction {
+        program_id: ID,
+        accounts: metas,
+        data: instruction::BatchCreditYields {}.data(),
+    }
+}
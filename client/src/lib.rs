@@ -0,0 +1,99 @@
+use anchor_lang::prelude::*;
+use anchor_lang::AccountDeserialize;
+
+// Off-chain helpers for keepers, bots and indexers written in Rust: PDAs, instruction
+// builders, account decoders and event parsing. Nothing here talks to a cluster; the
+// caller brings its own RPC client and hands account data or logs in.
+
+pub mod events;
+pub mod instructions;
+
+pub use defi_trust_fund::math;
+pub use defi_trust_fund::{
+    pending_yields, pool_stats, position_value, stake_preview, unstake_preview, Incident, IncidentLog,
+    OperationPreview, PendingYields, Pool, PoolParameter, PoolRegistry, PoolStats, PositionValue, ReferralAccount,
+    UserHistory, UserStake, ID,
+};
+
+pub fn pool_registry_address() -> Pubkey {
+    Pubkey::find_program_address(&[b"pool_registry"], &ID).0
+}
+
+pub fn pool_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"pool", pool_id)
+}
+
+pub fn pool_vault_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"pool_vault", pool_id)
+}
+
+pub fn fee_vault_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"fee_vault", pool_id)
+}
+
+pub fn reward_vault_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"reward_vault", pool_id)
+}
+
+pub fn insurance_vault_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"insurance_vault", pool_id)
+}
+
+pub fn receipt_mint_address(pool_id: u64) -> Pubkey {
+    pool_scoped_address(b"receipt_mint", pool_id)
+}
+
+pub fn user_stake_address(pool_id: u64, user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"user_stake", &pool_id.to_le_bytes(), user.as_ref()], &ID).0
+}
+
+pub fn referral_address(pool_id: u64, referrer: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"referral", &pool_id.to_le_bytes(), referrer.as_ref()], &ID).0
+}
+
+pub fn access_entry_address(pool_id: u64, user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"access", &pool_id.to_le_bytes(), user.as_ref()], &ID).0
+}
+
+pub fn user_history_address(user: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"history", user.as_ref()], &ID).0
+}
+
+pub fn incident_log_address(pool_id: u64) -> Pubkey {
+    Pubkey::find_program_address(&[b"incidents", pool_address(pool_id).as_ref()], &ID).0
+}
+
+pub fn reward_token_vault_address(pool_id: u64, reward_mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"reward_token_vault", &pool_id.to_le_bytes(), reward_mint.as_ref()], &ID).0
+}
+
+fn pool_scoped_address(prefix: &[u8], pool_id: u64) -> Pubkey {
+    Pubkey::find_program_address(&[prefix, &pool_id.to_le_bytes()], &ID).0
+}
+
+// Decode any program account from its raw data, discriminator included. Accounts still
+// on an older layout version fail here until they are migrated.
+pub fn decode_account<T: AccountDeserialize>(data: &[u8]) -> Result<T> {
+    T::try_deserialize(&mut &data[..])
+}
+
+// Fetch and decode an account with whatever the caller uses to read account data;
+// Ok(None) when the account does not exist
+pub fn fetch_account<T: AccountDeserialize>(
+    address: &Pubkey,
+    get_account_data: impl FnOnce(&Pubkey) -> Option<Vec<u8>>,
+) -> Result<Option<T>> {
+    get_account_data(address).map(|data| decode_account(&data)).transpose()
+}
+
+pub fn fetch_pool(pool_id: u64, get_account_data: impl FnOnce(&Pubkey) -> Option<Vec<u8>>) -> Result<Option<Pool>> {
+    fetch_account(&pool_address(pool_id), get_account_data)
+}
+
+pub fn fetch_user_stake(
+    pool_id: u64,
+    user: &Pubkey,
+    get_account_data: impl FnOnce(&Pubkey) -> Option<Vec<u8>>,
+) -> Result<Option<UserStake>> {
+    fetch_account(&user_stake_address(pool_id, user), get_account_data)
+}
@@ -0,0 +1,364 @@
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::{system_program, sysvar};
+use anchor_lang::{AccountSerialize, Discriminator};
+use anchor_spl::associated_token::{self, get_associated_token_address};
+use anchor_spl::token;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use defi_trust_fund::defi_trust_fund::*;
+use defi_trust_fund::{PoolParameter, EVENT_VERSION};
+use defi_trust_fund::instruction;
+use defi_trust_fund_client::events::{parse_logs, ProgramEvent};
+use defi_trust_fund_client::instructions::{
+    build_batch_credit_yields_ix, build_claim_yields_ix, build_preview_stake_ix, build_preview_unstake_ix,
+    build_stake_ix, build_unstake_ix, StakeReferral,
+};
+use defi_trust_fund_client::*;
+
+const AMOUNT: u64 = 1_000_000_000;
+const DAY: i64 = 86400;
+const POOL_ID: u64 = 3;
+
+fn metas(ix: &anchor_lang::solana_program::instruction::Instruction) -> Vec<(Pubkey, bool, bool)> {
+    ix.accounts.iter().map(|meta| (meta.pubkey, meta.is_signer, meta.is_writable)).collect()
+}
+
+#[test]
+fn stake_accounts_follow_the_stake_context() {
+    let user = Pubkey::new_unique();
+    let referrer = Pubkey::new_unique();
+    let receipt_mint = receipt_mint_address(POOL_ID);
+    let ix = build_stake_ix(POOL_ID, user, AMOUNT, 30, AMOUNT - 5_000_000, StakeReferral::New(referrer), false, false);
+
+    assert_eq!(ix.program_id, ID);
+    assert_eq!(
+        metas(&ix),
+        vec![
+            (user, true, true),
+            (pool_address(POOL_ID), false, true),
+            (pool_vault_address(POOL_ID), false, true),
+            (fee_vault_address(POOL_ID), false, true),
+            (user_stake_address(POOL_ID, &user), false, true),
+            (receipt_mint, false, true),
+            (get_associated_token_address(&user, &receipt_mint), false, true),
+            (referral_address(POOL_ID, &referrer), false, true),
+            (ID, false, false), // No access entry
+            (ID, false, false), // No history
+            (token::ID, false, false),
+            (associated_token::ID, false, false),
+            (system_program::ID, false, false),
+            (sysvar::rent::ID, false, false),
+        ]
+    );
+
+    assert_eq!(ix.data[..8], instruction::Stake::DISCRIMINATOR);
+    let args = instruction::Stake::try_from_slice(&ix.data[8..]).unwrap();
+    assert_eq!(args.amount, AMOUNT);
+    assert_eq!(args.committed_days, 30);
+    assert_eq!(args.min_expected_amount, AMOUNT - 5_000_000);
+    assert_eq!(args.referrer, Some(referrer));
+}
+
+#[test]
+fn top_ups_of_referred_positions_pass_the_account_but_not_the_referrer() {
+    let user = Pubkey::new_unique();
+    let referrer = Pubkey::new_unique();
+    let ix = build_stake_ix(POOL_ID, user, AMOUNT, 30, 0, StakeReferral::Existing(referrer), true, true);
+
+    assert_eq!(ix.accounts[7].pubkey, referral_address(POOL_ID, &referrer));
+    assert_eq!(ix.accounts[8].pubkey, access_entry_address(POOL_ID, &user));
+    assert_eq!(ix.accounts[9].pubkey, user_history_address(&user));
+    assert!(ix.accounts[9].is_writable);
+    assert_eq!(instruction::Stake::try_from_slice(&ix.data[8..]).unwrap().referrer, None);
+}
+
+#[test]
+fn claim_and_unstake_accounts_follow_their_contexts() {
+    let user = Pubkey::new_unique();
+    let claim = build_claim_yields_ix(POOL_ID, user, i64::MAX, 7, true);
+    assert_eq!(
+        metas(&claim),
+        vec![
+            (user, true, true),
+            (pool_address(POOL_ID), false, true),
+            (reward_vault_address(POOL_ID), false, true),
+            (user_stake_address(POOL_ID, &user), false, true),
+            (user_history_address(&user), false, true),
+            (system_program::ID, false, false),
+        ]
+    );
+    let args = instruction::ClaimYields::try_from_slice(&claim.data[8..]).unwrap();
+    assert_eq!((args.deadline, args.min_yields), (i64::MAX, 7));
+
+    let receipt_mint = receipt_mint_address(POOL_ID);
+    let unstake = build_unstake_ix(POOL_ID, user, AMOUNT, 1_000, AMOUNT / 2, false);
+    assert_eq!(
+        metas(&unstake),
+        vec![
+            (user, true, true),
+            (pool_address(POOL_ID), false, true),
+            (pool_vault_address(POOL_ID), false, true),
+            (user_stake_address(POOL_ID, &user), false, true),
+            (receipt_mint, false, true),
+            (get_associated_token_address(&user, &receipt_mint), false, true),
+            (ID, false, false), // No history
+            (token::ID, false, false),
+            (system_program::ID, false, false),
+        ]
+    );
+    assert_eq!(unstake.data[..8], instruction::Unstake::DISCRIMINATOR);
+}
+
+#[test]
+fn previews_are_read_only_and_need_no_signature() {
+    let user = Pubkey::new_unique();
+    let first_stake = build_preview_stake_ix(POOL_ID, user, AMOUNT, 30, false, None, false);
+    assert_eq!(
+        metas(&first_stake),
+        vec![
+            (user, false, false),
+            (pool_address(POOL_ID), false, false),
+            (ID, false, false), // No position yet
+            (ID, false, false), // No referrer
+            (ID, false, false), // No access entry
+        ]
+    );
+    let args = instruction::PreviewStake::try_from_slice(&first_stake.data[8..]).unwrap();
+    assert_eq!((args.amount, args.committed_days), (AMOUNT, 30));
+
+    let referrer = Pubkey::new_unique();
+    let top_up = build_preview_stake_ix(POOL_ID, user, AMOUNT, 30, true, Some(referrer), true);
+    assert_eq!(top_up.accounts[2].pubkey, user_stake_address(POOL_ID, &user));
+    assert_eq!(top_up.accounts[3].pubkey, referral_address(POOL_ID, &referrer));
+    assert_eq!(top_up.accounts[4].pubkey, access_entry_address(POOL_ID, &user));
+
+    let unstake = build_preview_unstake_ix(POOL_ID, user);
+    assert_eq!(
+        metas(&unstake),
+        vec![
+            (pool_address(POOL_ID), false, false),
+            (pool_vault_address(POOL_ID), false, false),
+            (user_stake_address(POOL_ID, &user), false, false),
+        ]
+    );
+    assert_eq!(unstake.data[..8], instruction::PreviewUnstake::DISCRIMINATOR);
+}
+
+#[test]
+fn batch_credit_appends_each_position_as_writable() {
+    let keeper = Pubkey::new_unique();
+    let owners = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let ix = build_batch_credit_yields_ix(POOL_ID, keeper, &owners);
+
+    assert_eq!(
+        metas(&ix),
+        vec![
+            (keeper, true, false),
+            (pool_address(POOL_ID), false, true),
+            (user_stake_address(POOL_ID, &owners[0]), false, true),
+            (user_stake_address(POOL_ID, &owners[1]), false, true),
+        ]
+    );
+}
+
+#[test]
+fn fetched_accounts_decode_and_quote_like_the_program() {
+    let mut pool = Pool::deserialize(&mut &[0u8; 2048][..]).unwrap();
+    pool.pool_id = POOL_ID;
+    pool.max_apy = 2000;
+    pool.is_active = true;
+    let mut user_stake = UserStake::deserialize(&mut &[0u8; 1024][..]).unwrap();
+    user_stake.user = Pubkey::new_unique();
+    user_stake.amount = 365 * AMOUNT;
+    user_stake.locked_apy_bps = 1000;
+    user_stake.accrual_rate_bps = 1000;
+    user_stake.committed_days = 30;
+
+    let mut pool_data = Vec::new();
+    pool.try_serialize(&mut pool_data).unwrap();
+    let mut stake_data = Vec::new();
+    user_stake.try_serialize(&mut stake_data).unwrap();
+
+    let fetched_pool = fetch_pool(POOL_ID, |address| {
+        assert_eq!(*address, pool_address(POOL_ID));
+        Some(pool_data)
+    })
+    .unwrap()
+    .unwrap();
+    let fetched_stake = fetch_user_stake(POOL_ID, &user_stake.user, |address| {
+        assert_eq!(*address, user_stake_address(POOL_ID, &user_stake.user));
+        Some(stake_data)
+    })
+    .unwrap()
+    .unwrap();
+    assert_eq!(fetched_pool.max_apy, 2000);
+    assert_eq!(fetched_stake.amount, 365 * AMOUNT);
+    assert!(fetch_pool(POOL_ID + 1, |_| None).unwrap().is_none());
+
+    // 10% on 365 SOL is 0.1 SOL a day
+    let quote = pending_yields(&fetched_pool, &fetched_stake, 10 * DAY).unwrap();
+    assert_eq!(quote.yields, AMOUNT);
+    assert_eq!(quote.yields, math::compute_yields(365 * AMOUNT, 1000, 10 * DAY).unwrap());
+    assert_eq!(quote.days_until_commitment_met, 20);
+}
+
+#[test]
+fn only_this_programs_events_are_parsed() {
+    let event_data = |discriminator: [u8; 8], body: Vec<u8>| {
+        let mut data = discriminator.to_vec();
+        data.extend(body);
+        format!("Program data: {}", STANDARD.encode(data))
+    };
+    let user = Pubkey::new_unique();
+    let stake = event_data(
+        StakeEvent::DISCRIMINATOR,
+        StakeEvent {
+            version: EVENT_VERSION,
+            user,
+            pool: pool_address(POOL_ID),
+            payer: user,
+            amount: AMOUNT,
+            fee_bps: 25,
+            committed_days: 180,
+            user_total_after: AMOUNT,
+            apy_bps_applied: 1000,
+            timestamp: 1,
+        }
+        .try_to_vec()
+        .unwrap(),
+    );
+    let parameter = event_data(
+        ParameterUpdateEvent::DISCRIMINATOR,
+        ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: user,
+            parameter: PoolParameter::PenaltyGraceBps,
+            old_value: 10000,
+            new_value: 9500,
+            timestamp: 2,
+        }
+        .try_to_vec()
+        .unwrap(),
+    );
+    let other_program = Pubkey::new_unique().to_string();
+    let program = ID.to_string();
+
+    let invoke = |program: &str| format!("Program {program} invoke [1]");
+    let success = |program: &str| format!("Program {program} success");
+    let logs = [
+        invoke(&program),
+        "Program log: Instruction: Stake".to_string(),
+        stake.clone(),
+        invoke(&other_program),
+        stake, // Logged by the inner program, so not ours
+        success(&other_program),
+        parameter,
+        success(&program),
+        "Program data: not base64".to_string(),
+    ];
+
+    let events = parse_logs(logs.iter().map(String::as_str));
+    assert_eq!(events.len(), 2);
+    match &events[0] {
+        ProgramEvent::StakeEvent(event) => {
+            assert_eq!(event.fee_bps, 25);
+            assert_eq!(event.committed_days, 180);
+            assert_eq!(event.pool, pool_address(POOL_ID));
+            assert_eq!(event.user_total_after, AMOUNT);
+        }
+        _ => panic!("expected a stake event"),
+    }
+    match &events[1] {
+        ProgramEvent::ParameterUpdateEvent(event) => assert_eq!(event.new_value, 9500),
+        _ => panic!("expected a parameter update"),
+    }
+}
+
+// Every event the program emits, as it would appear in a transaction's logs
+macro_rules! every_event {
+    ($($event:ident),* $(,)?) => {
+        vec![$({
+            let mut event = $event::deserialize(&mut &[0u8; 1024][..]).unwrap();
+            event.version = EVENT_VERSION;
+            ProgramEvent::$event(event)
+        }),*]
+    };
+}
+
+#[test]
+fn every_event_round_trips_through_the_logs() {
+    let emitted = every_event![
+        PoolInitializedEvent,
+        AccountMigratedEvent,
+        StakeEvent,
+        UnstakeEvent,
+        UnstakeRequestedEvent,
+        UnstakeRequestCancelledEvent,
+        ReferralAttributedEvent,
+        ReferralRewardsClaimedEvent,
+        CommitmentExtendedEvent,
+        CommitmentPolicyUpdateEvent,
+        CommitmentMigratedEvent,
+        CommitmentRenewedEvent,
+        AutoRenewUpdateEvent,
+        RolloverEvent,
+        RecoveryPolicyUpdateEvent,
+        RecoveryModeActivatedEvent,
+        RecoveryAdminRotatedEvent,
+        CompoundEvent,
+        EmergencyWithdrawEvent,
+        StakeTransferredEvent,
+        UserStakeClosedEvent,
+        SplStakeEvent,
+        SwapAndStakeEvent,
+        SplUnstakeEvent,
+        SplEmergencyWithdrawEvent,
+        RewardsFundedEvent,
+        RewardsDistributedEvent,
+        AccessChangedEvent,
+        EmergencyPauseEvent,
+        GuardianPauseEvent,
+        GuardianUpdateEvent,
+        TreasuryUpdateEvent,
+        EmergencyUnpauseEvent,
+        PoolSunsetEvent,
+        PoolClosedEvent,
+        ApyTiersUpdateEvent,
+        FeeTiersUpdateEvent,
+        LoyaltyTiersUpdateEvent,
+        YieldsClaimedEvent,
+        RewardTokensPaidEvent,
+        TokenRewardsFundedEvent,
+        YieldsCreditedEvent,
+        CreditedYieldsWithdrawnEvent,
+        NftBoostUpdateEvent,
+        ProposalCreatedEvent,
+        VoteCastEvent,
+        ProposalFinalizedEvent,
+        InsuranceFundedEvent,
+        InsurancePayoutEvent,
+        RebalanceEvent,
+        SnapshotTakenEvent,
+        SolvencyEvent,
+        SolvencyCheckEvent,
+        ReserveRunwayPauseEvent,
+        ParameterUpdateEvent,
+        FeesWithdrawnEvent,
+        PenaltiesWithdrawnEvent,
+        MarinadeDeployedEvent,
+        MarinadeRecalledEvent,
+        MarinadeUnstakeOrderedEvent,
+        MarinadeTicketClaimedEvent,
+    ];
+    let program = ID.to_string();
+    let mut logs = vec![format!("Program {program} invoke [1]")];
+    logs.extend(emitted.iter().map(|event| format!("Program data: {}", STANDARD.encode(event.encode()))));
+    logs.push(format!("Program {program} success"));
+
+    let parsed = parse_logs(logs.iter().map(String::as_str));
+    assert_eq!(parsed.len(), emitted.len());
+    for (parsed, emitted) in parsed.iter().zip(&emitted) {
+        assert_eq!(parsed.version(), EVENT_VERSION);
+        assert_eq!(parsed.encode(), emitted.encode());
+    }
+}
@@ -0,0 +1,60 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use defi_trust_fund::math::{compute_penalty, compute_yields};
+use defi_trust_fund::{MAX_APY_BPS, MAX_COMMITMENT_DAYS};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+struct YieldInput {
+    amount: u64,
+    apy_bps: u64,
+    stake_timestamp: i64,
+    current_time: i64,
+    committed_days: u64,
+    penalty_bps: u64,
+    sliding_scale: bool,
+    grace_bps: u64,
+}
+
+fuzz_target!(|data: YieldInput| {
+    let elapsed_seconds = data.current_time.saturating_sub(data.stake_timestamp);
+
+    // The program's own math must never panic, only return an error
+    let yields = compute_yields(data.amount, data.apy_bps, elapsed_seconds);
+    let penalty = compute_penalty(
+        data.amount,
+        data.penalty_bps,
+        elapsed_seconds,
+        data.committed_days,
+        data.sliding_scale,
+        data.grace_bps,
+    );
+
+    // Inside the legal parameter ranges nothing errors: a year at the highest APY fits
+    // any amount, and amounts below a tenth of u64::MAX fit up to ten years
+    const YEAR: i64 = 365 * 86_400;
+    let elapsed = elapsed_seconds.max(0);
+    if data.apy_bps <= MAX_APY_BPS && (elapsed <= YEAR || (elapsed <= 10 * YEAR && data.amount <= u64::MAX / 10)) {
+        assert!(yields.is_ok());
+    }
+    if data.penalty_bps <= 10_000 && data.committed_days <= MAX_COMMITMENT_DAYS {
+        assert!(penalty.is_ok());
+    }
+
+    // Bound: at an APY of at most 100%, a position earns no more than its principal
+    // over each 365 days staked, pro rata to the second
+    if let Ok(yields) = yields {
+        if data.apy_bps <= 10_000 {
+            assert!(yields as u128 <= data.amount as u128 * elapsed as u128 / YEAR as u128);
+        }
+    }
+
+    // The early-exit penalty never exceeds the principal and the payout round-trips
+    if let Ok(penalty) = penalty {
+        if data.penalty_bps <= 10_000 {
+            assert!(penalty <= data.amount);
+            let return_amount = data.amount.checked_sub(penalty).unwrap();
+            assert_eq!(return_amount.checked_add(penalty), Some(data.amount));
+        }
+    }
+});
@@ -0,0 +1,39 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+
+// What another program needs to CPI into the read-only views and decode their answers,
+// without depending on the program crate or Anchor. The program's tests check these
+// against its own definitions, so the two cannot drift apart.
+
+// Program address, as raw bytes so callers can build whichever Pubkey type they use
+pub const PROGRAM_ID: [u8; 32] = [
+    218, 7, 92, 178, 255, 94, 198, 129, 118, 19, 222, 83, 11, 105, 42, 135, 53, 71, 119, 105, 218, 71, 67, 12, 189,
+    129, 84, 51, 92, 74, 131, 39,
+];
+
+// Instruction data for get_position_value; accounts: [pool, user_stake]
+pub const GET_POSITION_VALUE_DISCRIMINATOR: [u8; 8] = [176, 101, 36, 67, 215, 72, 215, 247];
+
+// Instruction data for get_pool_stats; accounts: [pool]
+pub const GET_POOL_STATS_DISCRIMINATOR: [u8; 8] = [119, 221, 164, 30, 10, 6, 255, 128];
+
+// Return data of get_position_value
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PositionValue {
+    // Principal in lamports
+    pub amount: u64,
+    // Yields a claim would pay now plus yields already credited to the position
+    pub accrued_yields: u64,
+    // Unix timestamp at which the commitment is met
+    pub unlock_ts: i64,
+    // Rate the position accrues at, loyalty boost included
+    pub apy_bps: u64,
+}
+
+// Return data of get_pool_stats
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub total_staked: u64,
+    pub total_users: u64,
+    // Projected yield as bps of what the reward reserve can back
+    pub utilization_bps: u64,
+}
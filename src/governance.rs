@@ -0,0 +1,128 @@
+use anchor_lang::prelude::*;
+
+use crate::{ErrorCode, Pool};
+
+// Stake-weighted parameter governance. The instructions live in the program module;
+// the rules they share are kept here so they can be tested without a validator.
+
+// Parameters a proposal may change
+pub const PARAM_DEPOSIT_FEE: u8 = 0;
+pub const PARAM_LEVERAGE_RATIO: u8 = 1;
+pub const PARAM_REINVESTMENT_RATE: u8 = 2;
+pub const PARAM_MIN_SLOTS_BEFORE_EXIT: u8 = 3;
+
+pub const PROPOSAL_STATUS_ACTIVE: u8 = 0;
+pub const PROPOSAL_STATUS_EXECUTED: u8 = 1;
+pub const PROPOSAL_STATUS_REJECTED: u8 = 2;
+
+pub const MIN_VOTING_PERIOD: i64 = 86400;
+pub const MAX_VOTING_PERIOD: i64 = 14 * 86400;
+// Time between the end of voting and the change taking effect, so stakers who
+// disagree can leave first
+pub const EXECUTION_DELAY: i64 = 7 * 86400;
+
+// Allowed range of each governed parameter. The admin setters for the other parameters
+// enforce the same bounds; update_deposit_fee allows 0 to 10% so the admin can keep the
+// 0.5% launch default, which governance cannot propose.
+pub fn parameter_range(param_key: u8) -> Option<std::ops::RangeInclusive<u64>> {
+    match param_key {
+        PARAM_DEPOSIT_FEE => Some(100..=1000),        // 1% to 10%
+        PARAM_LEVERAGE_RATIO => Some(2000..=6000),    // 2x to 6x
+        PARAM_REINVESTMENT_RATE => Some(1000..=3000), // 10% to 30%
+        PARAM_MIN_SLOTS_BEFORE_EXIT => Some(1..=150), // Up to about a minute
+        _ => None,
+    }
+}
+
+// Governance may only move a parameter within its allowed range
+pub fn validate_proposal_value(param_key: u8, new_value: u64) -> Result<()> {
+    let range = parameter_range(param_key).ok_or(ErrorCode::InvalidProposal)?;
+    require!(range.contains(&new_value), ErrorCode::InvalidProposal);
+
+    Ok(())
+}
+
+// Votes needed for a proposal to count: quorum_bps of the stake at creation time
+pub fn quorum_votes(total_staked: u64, quorum_bps: u64) -> u64 {
+    (total_staked as u128 * quorum_bps as u128 / 10000) as u64
+}
+
+// Passes with quorum reached and strictly more stake for than against
+pub fn proposal_passed(votes_for: u64, votes_against: u64, quorum_votes: u64) -> bool {
+    let total_votes = votes_for as u128 + votes_against as u128;
+    total_votes >= quorum_votes as u128 && votes_for > votes_against
+}
+
+// Write an approved value into the pool, returning the value it replaced
+pub fn apply_proposal(pool: &mut Pool, param_key: u8, new_value: u64) -> Result<u64> {
+    validate_proposal_value(param_key, new_value)?;
+
+    let old_value = match param_key {
+        PARAM_DEPOSIT_FEE => std::mem::replace(&mut pool.deposit_fee_bps, new_value),
+        PARAM_LEVERAGE_RATIO => std::mem::replace(&mut pool.leverage_ratio, new_value),
+        PARAM_REINVESTMENT_RATE => std::mem::replace(&mut pool.reinvestment_rate, new_value),
+        PARAM_MIN_SLOTS_BEFORE_EXIT => std::mem::replace(&mut pool.min_slots_before_exit, new_value),
+        _ => return err!(ErrorCode::InvalidProposal),
+    };
+
+    Ok(old_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposit_fee_proposals_match_the_governance_range() {
+        assert!(validate_proposal_value(PARAM_DEPOSIT_FEE, 99).is_err());
+        assert!(validate_proposal_value(PARAM_DEPOSIT_FEE, 100).is_ok());
+        assert!(validate_proposal_value(PARAM_DEPOSIT_FEE, 1000).is_ok());
+        assert!(validate_proposal_value(PARAM_DEPOSIT_FEE, 1001).is_err());
+    }
+
+    #[test]
+    fn leverage_ratio_bounds_match_the_governance_range() {
+        assert!(validate_proposal_value(PARAM_LEVERAGE_RATIO, 1999).is_err());
+        assert!(validate_proposal_value(PARAM_LEVERAGE_RATIO, 2000).is_ok());
+        assert!(validate_proposal_value(PARAM_LEVERAGE_RATIO, 6000).is_ok());
+        assert!(validate_proposal_value(PARAM_LEVERAGE_RATIO, 6001).is_err());
+    }
+
+    #[test]
+    fn reinvestment_rate_bounds_match_the_governance_range() {
+        assert!(validate_proposal_value(PARAM_REINVESTMENT_RATE, 999).is_err());
+        assert!(validate_proposal_value(PARAM_REINVESTMENT_RATE, 1000).is_ok());
+        assert!(validate_proposal_value(PARAM_REINVESTMENT_RATE, 3000).is_ok());
+        assert!(validate_proposal_value(PARAM_REINVESTMENT_RATE, 3001).is_err());
+    }
+
+    #[test]
+    fn exit_window_bounds_match_the_governance_range() {
+        assert!(validate_proposal_value(PARAM_MIN_SLOTS_BEFORE_EXIT, 0).is_err());
+        assert!(validate_proposal_value(PARAM_MIN_SLOTS_BEFORE_EXIT, 1).is_ok());
+        assert!(validate_proposal_value(PARAM_MIN_SLOTS_BEFORE_EXIT, 150).is_ok());
+        assert!(validate_proposal_value(PARAM_MIN_SLOTS_BEFORE_EXIT, 151).is_err());
+    }
+
+    #[test]
+    fn unknown_parameters_are_rejected() {
+        assert!(validate_proposal_value(u8::MAX, 500).is_err());
+    }
+
+    #[test]
+    fn proposals_need_quorum_and_a_strict_majority() {
+        let quorum = quorum_votes(1_000, 1000);
+        assert_eq!(quorum, 100);
+
+        assert!(proposal_passed(60, 40, quorum));
+        assert!(!proposal_passed(50, 49, quorum)); // 99 votes, below quorum
+        assert!(!proposal_passed(50, 50, quorum)); // tie
+        assert!(!proposal_passed(40, 60, quorum));
+    }
+
+    #[test]
+    fn quorum_does_not_overflow_on_large_pools() {
+        assert_eq!(quorum_votes(u64::MAX, 10000), u64::MAX);
+        assert!(proposal_passed(u64::MAX, u64::MAX - 1, u64::MAX));
+    }
+}
@@ -1,7 +1,57 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::metadata::mpl_token_metadata::accounts::Metadata as MetadataAccount;
+use anchor_spl::metadata::mpl_token_metadata::types::DataV2;
+use anchor_spl::metadata::mpl_token_metadata::{MAX_NAME_LENGTH, MAX_SYMBOL_LENGTH, MAX_URI_LENGTH};
+use anchor_spl::metadata::{self, CreateMetadataAccountsV3, Metadata, UpdateMetadataAccountsV2};
+use anchor_spl::token::spl_token::native_mint;
+use anchor_spl::token::{self, Approve, Burn, CloseAccount, Mint, MintTo, SyncNative, Token, TokenAccount, Transfer};
+
+pub mod governance;
+pub mod math;
+
+use governance::{
+    apply_proposal, proposal_passed, quorum_votes, validate_proposal_value, EXECUTION_DELAY, MAX_VOTING_PERIOD,
+    MIN_VOTING_PERIOD, PARAM_LEVERAGE_RATIO, PARAM_MIN_SLOTS_BEFORE_EXIT, PARAM_REINVESTMENT_RATE,
+    PROPOSAL_STATUS_ACTIVE, PROPOSAL_STATUS_EXECUTED, PROPOSAL_STATUS_REJECTED,
+};
+use math::{
+    apply_apy_boost, apply_commitment_discount, books_balance, calculate_solvency_ratio_bps, compute_fee,
+    compute_penalty, compute_yields, crossed_solvency_alert, fee_within_quote_tolerance, gross_for_net,
+    lamports_to_reward_tokens, marinade_rate_floor, msol_for_lamports, msol_value, outflow_headroom,
+    outflow_within_cap, projected_apy_bps, recalled_principal,
+    reconciliation_delta, renewal_cycles, reserve_runway_days, reward_per_token_increment, rewards_earned,
+    split_marinade_return, within_leverage_cap, within_yield_capacity, yield_capacity_utilization_bps,
+};
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
+// Logs the compute units left at a named point of a hot path. Only built with the
+// cu-profile feature, so release builds pay nothing for the markers.
+macro_rules! cu_checkpoint {
+    ($label:literal) => {
+        #[cfg(feature = "cu-profile")]
+        {
+            msg!($label);
+            anchor_lang::solana_program::log::sol_log_compute_units();
+        }
+    };
+}
+
+// require! that first logs the values behind the check, so a client can show the user
+// the limit they ran into without fetching the pool and re-deriving it
+macro_rules! require_with_context {
+    ($invariant:expr, $error:expr, $($context:tt)+) => {
+        if !($invariant) {
+            msg!($($context)+);
+            return Err(error!($error));
+        }
+    };
+}
+
 #[program]
 pub mod defi_trust_fund {
     use super::*;
@@ -9,570 +59,11461 @@ pub mod defi_trust_fund {
     // Events
     #[event]
     pub struct PoolInitializedEvent {
+        pub version: u8,
         pub admin: Pubkey,
         pub pool: Pubkey,
+        pub pool_id: u64,
         pub max_apy: u64,
         pub min_commitment_days: u64,
         pub max_commitment_days: u64,
         pub timestamp: i64,
     }
 
+    #[event]
+    pub struct AccountMigratedEvent {
+        pub version: u8,
+        pub account: Pubkey,
+        pub layout_version: u8,
+        pub timestamp: i64,
+    }
+
     #[event]
     pub struct StakeEvent {
+        pub version: u8,
         pub user: Pubkey,
+        pub pool: Pubkey,
+        pub payer: Pubkey,
         pub amount: u64,
+        pub fee_bps: u64,
         pub committed_days: u64,
+        pub user_total_after: u64,
+        pub apy_bps_applied: u64,
         pub timestamp: i64,
     }
 
     #[event]
     pub struct UnstakeEvent {
+        pub version: u8,
         pub user: Pubkey,
+        pub pool: Pubkey,
         pub amount: u64,
         pub penalty: u64,
+        pub return_amount: u64,
+        pub remaining_amount: u64,
+        // Time since the position was opened, against which the commitment is checked
+        pub elapsed_seconds: i64,
         pub timestamp: i64,
     }
 
     #[event]
-    pub struct EmergencyPauseEvent {
-        pub admin: Pubkey,
-        pub reason: String,
+    pub struct UnstakeRequestedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub amount: u64,
+        pub executable_at: i64,
         pub timestamp: i64,
     }
 
     #[event]
-    pub struct EmergencyUnpauseEvent {
-        pub admin: Pubkey,
+    pub struct UnstakeRequestCancelledEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub amount: u64,
         pub timestamp: i64,
     }
 
     #[event]
-    pub struct ParameterUpdateEvent {
-        pub admin: Pubkey,
-        pub parameter: String,
-        pub old_value: u64,
-        pub new_value: u64,
+    pub struct ReferralAttributedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub referrer: Pubkey,
         pub timestamp: i64,
     }
 
-    // Initialize the pool
-    pub fn initialize_pool(
-        ctx: Context<InitializePool>,
-        max_apy: u64,
-        min_commitment_days: u64,
-        max_commitment_days: u64,
-    ) -> Result<()> {
-        // Validate parameters
-        require!(max_apy > 0 && max_apy <= 10000, ErrorCode::InvalidApy); // Max 100% APY
-        require!(min_commitment_days > 0, ErrorCode::InvalidCommitmentDays);
-        require!(max_commitment_days >= min_commitment_days, ErrorCode::InvalidCommitmentDays);
-        require!(max_commitment_days <= 365, ErrorCode::InvalidCommitmentDays);
+    #[event]
+    pub struct ReferralRewardsClaimedEvent {
+        pub version: u8,
+        pub referrer: Pubkey,
+        pub amount: u64,
+        pub timestamp: i64,
+    }
 
-        let pool = &mut ctx.accounts.pool;
-        let clock = Clock::get()?;
+    #[event]
+    pub struct CommitmentExtendedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub old_days: u64,
+        pub new_days: u64,
+        pub timestamp: i64,
+    }
 
-        // Initialize pool state
-        pool.admin = ctx.accounts.admin.key();
-        pool.max_apy = max_apy;
-        pool.min_commitment_days = min_commitment_days;
-        pool.max_commitment_days = max_commitment_days;
-        pool.min_stake_amount = 100_000_000; // 0.1 SOL minimum
-        pool.max_stake_amount = 1_000_000_000_000; // 1000 SOL maximum
-        pool.total_staked = 0;
-        pool.total_users = 0;
-        pool.total_fees_collected = 0;
-        pool.deposit_fee_bps = 50; // 0.5% fee
-        pool.is_paused = false;
-        pool.created_at = clock.unix_timestamp;
-        pool.last_update = clock.unix_timestamp;
+    // Positions stamped with a policy_version below this one keep the commitment they
+    // were opened under
+    #[event]
+    pub struct CommitmentPolicyUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub pool: Pubkey,
+        pub old_min_days: u64,
+        pub new_min_days: u64,
+        pub policy_version: u64,
+        pub timestamp: i64,
+    }
 
-        emit!(PoolInitializedEvent {
-            admin: ctx.accounts.admin.key(),
-            pool: ctx.accounts.pool.key(),
-            max_apy,
-            min_commitment_days,
-            max_commitment_days,
-            timestamp: clock.unix_timestamp,
-        });
+    #[event]
+    pub struct CommitmentMigratedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub old_days: u64,
+        pub new_days: u64,
+        pub bonus: u64,
+        pub policy_version: u64,
+        pub timestamp: i64,
+    }
 
-        Ok(())
+    // A rolling position entered `cycles` fresh commitments, the last starting at
+    // stake_timestamp; emitted by whichever instruction settled it
+    #[event]
+    pub struct CommitmentRenewedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool_id: u64,
+        pub cycles: u64,
+        pub committed_days: u64,
+        pub stake_timestamp: i64,
+        pub timestamp: i64,
     }
 
-    // Stake function
-    pub fn stake(ctx: Context<Stake>, amount: u64, committed_days: u64) -> Result<()> {
-        // Security checks
-        require!(!ctx.accounts.pool.is_paused, ErrorCode::PoolPaused);
-        require!(amount >= ctx.accounts.pool.min_stake_amount, ErrorCode::AmountTooSmall);
-        require!(amount <= ctx.accounts.pool.max_stake_amount, ErrorCode::AmountTooLarge);
-        require!(committed_days >= ctx.accounts.pool.min_commitment_days, ErrorCode::InvalidCommitmentDays);
-        require!(committed_days <= ctx.accounts.pool.max_commitment_days, ErrorCode::InvalidCommitmentDays);
+    #[event]
+    pub struct AutoRenewUpdateEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub auto_renew: bool,
+        pub stake_timestamp: i64,
+        pub timestamp: i64,
+    }
 
-        let pool = &mut ctx.accounts.pool;
-        let user_stake = &mut ctx.accounts.user_stake;
-        let clock = Clock::get()?;
+    #[event]
+    pub struct RecoveryPolicyUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub old_recovery_authority: Pubkey,
+        pub new_recovery_authority: Pubkey,
+        pub admin_inactivity_timeout: i64,
+        pub timestamp: i64,
+    }
 
-        // Calculate fee
-        let fee_amount = amount.checked_mul(pool.deposit_fee_bps).unwrap().checked_div(10000).unwrap();
-        let net_amount = amount.checked_sub(fee_amount).unwrap();
+    #[event]
+    pub struct RecoveryModeActivatedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub activated_by: Pubkey,
+        pub last_admin_activity: i64,
+        pub timestamp: i64,
+    }
 
-        // Transfer SOL from user to pool vault
-        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
-            &ctx.accounts.user.key(),
-            &ctx.accounts.pool_vault.key(),
-            amount,
-        );
+    #[event]
+    pub struct RecoveryAdminRotatedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub recovery_authority: Pubkey,
+        pub old_admin: Pubkey,
+        pub new_admin: Pubkey,
+        pub timestamp: i64,
+    }
 
-        anchor_lang::solana_program::program::invoke(
-            &transfer_instruction,
-            &[
-                ctx.accounts.user.to_account_info(),
-                ctx.accounts.pool_vault.to_account_info(),
-            ],
-        )?;
+    #[event]
+    pub struct RolloverEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub principal: u64,
+        pub yields_handled: u64,
+        pub yields_compounded: u64,
+        pub fee: u64,
+        pub new_committed_days: u64,
+        pub apy_bps: u64,
+        pub timestamp: i64,
+    }
 
-        // Update user stake
-        user_stake.user = ctx.accounts.user.key();
-        user_stake.amount = net_amount;
-        user_stake.committed_days = committed_days;
-        user_stake.stake_timestamp = clock.unix_timestamp;
-        user_stake.last_claim_timestamp = clock.unix_timestamp;
-        user_stake.total_claimed = 0;
+    #[event]
+    pub struct CompoundEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub yields_compounded: u64,
+        pub yields_paid_out: u64,
+        pub new_amount: u64,
+        pub timestamp: i64,
+    }
 
-        // Update pool state
-        pool.total_staked = pool.total_staked.checked_add(net_amount).unwrap();
-        pool.total_users = pool.total_users.checked_add(1).unwrap();
-        pool.total_fees_collected = pool.total_fees_collected.checked_add(fee_amount).unwrap();
-        pool.last_update = clock.unix_timestamp;
+    #[event]
+    pub struct EmergencyWithdrawEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub amount: u64,
+        pub timestamp: i64,
+    }
 
-        emit!(StakeEvent {
-            user: ctx.accounts.user.key(),
-            amount: net_amount,
-            committed_days,
-            timestamp: clock.unix_timestamp,
-        });
+    #[event]
+    pub struct StakeTransferredEvent {
+        pub version: u8,
+        pub from: Pubkey,
+        pub to: Pubkey,
+        pub amount: u64,
+        pub timestamp: i64,
+    }
 
-        Ok(())
+    #[event]
+    pub struct UserStakeClosedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub total_staked_lifetime: u64,
+        pub total_yields_claimed: u64,
+        pub timestamp: i64,
     }
 
-    // Claim yields
-    pub fn claim_yields(ctx: Context<ClaimYields>) -> Result<()> {
-        require!(!ctx.accounts.pool.is_paused, ErrorCode::PoolPaused);
-        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+    #[event]
+    pub struct SplStakeEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub mint: Pubkey,
+        pub amount: u64,
+        pub committed_days: u64,
+        pub timestamp: i64,
+    }
 
-        let pool = &mut ctx.accounts.pool;
-        let user_stake = &mut ctx.accounts.user_stake;
-        let clock = Clock::get()?;
+    #[event]
+    pub struct SwapAndStakeEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub input_mint: Pubkey,
+        pub input_amount: u64,
+        pub sol_received: u64,
+        pub timestamp: i64,
+    }
 
-        // Calculate time since last claim
-        let time_since_last_claim = clock.unix_timestamp.checked_sub(user_stake.last_claim_timestamp).unwrap();
-        require!(time_since_last_claim > 0, ErrorCode::NoYieldToClaim);
+    #[event]
+    pub struct SplUnstakeEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub mint: Pubkey,
+        pub amount: u64,
+        pub penalty: u64,
+        pub return_amount: u64,
+        pub remaining_amount: u64,
+        pub timestamp: i64,
+    }
 
-        // Calculate yield (simplified calculation)
-        let days_staked = time_since_last_claim.checked_div(86400).unwrap(); // Convert seconds to days
-        let apy_rate = pool.max_apy.checked_div(10000).unwrap(); // Convert basis points to decimal
-        let daily_rate = apy_rate.checked_div(365).unwrap();
-        
-        let yield_amount = user_stake.amount
-            .checked_mul(daily_rate).unwrap()
-            .checked_mul(days_staked.try_into().unwrap()).unwrap()
-            .checked_div(10000).unwrap();
+    #[event]
+    pub struct SplEmergencyWithdrawEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub mint: Pubkey,
+        pub amount: u64,
+        pub timestamp: i64,
+    }
 
-        require!(yield_amount > 0, ErrorCode::NoYieldToClaim);
+    #[event]
+    pub struct RewardsFundedEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub amount: u64,
+        pub reward_reserve: u64,
+        pub timestamp: i64,
+    }
 
-        // Check if pool has sufficient funds
-        let pool_balance = ctx.accounts.pool_vault.lamports();
-        require!(pool_balance >= yield_amount, ErrorCode::InsufficientFunds);
+    #[event]
+    pub struct RewardsDistributedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub admin: Pubkey,
+        pub fee_amount: u64,
+        pub penalty_amount: u64,
+        pub reward_per_token_stored: u128,
+        pub reward_reserve: u64,
+        pub timestamp: i64,
+    }
 
-        // Transfer yield to user
-        **ctx.accounts.pool_vault.try_borrow_mut_lamports()? -= yield_amount;
-        **ctx.accounts.user.try_borrow_mut_lamports()? += yield_amount;
+    #[event]
+    pub struct AccessChangedEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub user: Pubkey,
+        pub whitelisted: bool,
+        pub blacklisted: bool,
+        pub timestamp: i64,
+    }
 
-        // Update user stake
-        user_stake.last_claim_timestamp = clock.unix_timestamp;
-        user_stake.total_claimed = user_stake.total_claimed.checked_add(yield_amount).unwrap();
+    #[event]
+    pub struct EmergencyPauseEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub reason: String,
+        pub pause_flags: u8,
+        pub timestamp: i64,
+    }
 
-        // Update pool state
-        pool.total_staked = pool.total_staked.checked_sub(yield_amount).unwrap();
-        pool.last_update = clock.unix_timestamp;
+    #[event]
+    pub struct GuardianPauseEvent {
+        pub version: u8,
+        pub guardian: Pubkey,
+        pub reason: String,
+        pub expires_at: i64,
+        pub timestamp: i64,
+    }
 
-        Ok(())
+    #[event]
+    pub struct GuardianUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub old_guardian: Pubkey,
+        pub new_guardian: Pubkey,
+        pub pause_max_duration: i64,
+        pub timestamp: i64,
     }
 
-    // Unstake function
-    pub fn unstake(ctx: Context<Unstake>) -> Result<()> {
-        require!(!ctx.accounts.pool.is_paused, ErrorCode::PoolPaused);
-        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+    #[event]
+    pub struct TreasuryUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub old_treasury: Pubkey,
+        pub new_treasury: Pubkey,
+        pub timestamp: i64,
+    }
 
-        let pool = &mut ctx.accounts.pool;
-        let user_stake = &mut ctx.accounts.user_stake;
-        let clock = Clock::get()?;
+    #[event]
+    pub struct EmergencyUnpauseEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub timestamp: i64,
+    }
 
-        // Calculate time staked
-        let time_staked = clock.unix_timestamp.checked_sub(user_stake.stake_timestamp).unwrap();
-        let days_staked = time_staked.checked_div(86400).unwrap(); // Convert seconds to days
+    #[event]
+    pub struct PoolSunsetEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub sunset_at: i64,
+        pub timestamp: i64,
+    }
 
-        let unstake_amount = user_stake.amount;
-        let mut penalty_amount = 0;
+    #[event]
+    pub struct PoolClosedEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub pool_id: u64,
+        pub timestamp: i64,
+    }
 
-        // Apply penalty for early exit (5% if commitment not met)
-        if days_staked < user_stake.committed_days.try_into().unwrap() {
-            penalty_amount = unstake_amount.checked_mul(5).unwrap().checked_div(100).unwrap();
-        }
+    #[event]
+    pub struct ApyTiersUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub tiers: Vec<ApyTier>,
+        pub timestamp: i64,
+    }
 
-        let final_amount = unstake_amount.checked_sub(penalty_amount).unwrap();
+    #[event]
+    pub struct FeeTiersUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub tiers: Vec<FeeTier>,
+        pub timestamp: i64,
+    }
 
-        // Transfer funds back to user
-        **ctx.accounts.pool_vault.try_borrow_mut_lamports()? -= final_amount;
-        **ctx.accounts.user.try_borrow_mut_lamports()? += final_amount;
+    #[event]
+    pub struct LoyaltyTiersUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub tiers: Vec<LoyaltyTier>,
+        pub timestamp: i64,
+    }
 
-        // Update pool state
-        pool.total_staked = pool.total_staked.checked_sub(unstake_amount).unwrap();
-        pool.total_users = pool.total_users.checked_sub(1).unwrap();
-        pool.last_update = clock.unix_timestamp;
+    #[event]
+    pub struct YieldsClaimedEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub amount: u64,
+        pub apy_bps: u64,
+        pub loyalty_boost_bps: u64,
+        pub nft_boost_bps: u64,
+        pub accrual_seconds: u64,
+        pub timestamp: i64,
+    }
 
-        // Reset user stake
-        user_stake.amount = 0;
-        user_stake.committed_days = 0;
-        user_stake.stake_timestamp = 0;
-        user_stake.last_claim_timestamp = 0;
-        user_stake.total_claimed = 0;
+    #[event]
+    pub struct RewardTokensPaidEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub pool: Pubkey,
+        pub reward_mint: Pubkey,
+        pub yield_lamports: u64,
+        pub reward_tokens: u64,
+        pub reward_tokens_per_sol: u64,
+        pub timestamp: i64,
+    }
 
-        emit!(UnstakeEvent {
-            user: ctx.accounts.user.key(),
-            amount: final_amount,
-            penalty: penalty_amount,
-            timestamp: clock.unix_timestamp,
-        });
+    #[event]
+    pub struct TokenRewardsFundedEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub reward_mint: Pubkey,
+        pub amount: u64,
+        pub vault_balance: u64,
+        pub timestamp: i64,
+    }
 
-        Ok(())
+    #[event]
+    pub struct YieldsCreditedEvent {
+        pub version: u8,
+        pub keeper: Pubkey,
+        pub positions_credited: u64,
+        pub amount: u64,
+        pub timestamp: i64,
     }
 
-    // Emergency pause (admin only)
-    pub fn emergency_pause(ctx: Context<AdminOnly>, reason: String) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
-        require!(reason.len() <= 200, ErrorCode::InvalidReason);
+    #[event]
+    pub struct CreditedYieldsWithdrawnEvent {
+        pub version: u8,
+        pub user: Pubkey,
+        pub amount: u64,
+        pub timestamp: i64,
+    }
 
-        let pool = &mut ctx.accounts.pool;
-        let clock = Clock::get()?;
+    #[event]
+    pub struct NftBoostUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub boost_collection: Option<Pubkey>,
+        pub nft_boost_bps: u64,
+        pub timestamp: i64,
+    }
 
-        pool.is_paused = true;
-        pool.last_update = clock.unix_timestamp;
+    #[event]
+    pub struct ProposalCreatedEvent {
+        pub version: u8,
+        pub proposal_id: u64,
+        pub proposer: Pubkey,
+        pub param_key: u8,
+        pub new_value: u64,
+        pub voting_ends_at: i64,
+        pub timestamp: i64,
+    }
 
-        emit!(EmergencyPauseEvent {
-            admin: ctx.accounts.admin.key(),
-            reason,
-            timestamp: clock.unix_timestamp,
-        });
+    #[event]
+    pub struct VoteCastEvent {
+        pub version: u8,
+        pub proposal_id: u64,
+        pub voter: Pubkey,
+        pub support: bool,
+        pub weight: u64,
+        pub timestamp: i64,
+    }
 
-        Ok(())
+    #[event]
+    pub struct ProposalFinalizedEvent {
+        pub version: u8,
+        pub proposal_id: u64,
+        pub passed: bool,
+        pub param_key: u8,
+        pub old_value: u64,
+        pub new_value: u64,
+        pub votes_for: u64,
+        pub votes_against: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct InsuranceFundedEvent {
+        pub version: u8,
+        pub funder: Pubkey,
+        pub amount: u64,
+        pub insurance_balance: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct InsurancePayoutEvent {
+        pub version: u8,
+        pub recipient: Pubkey,
+        pub amount: u64,
+        pub reason_hash: [u8; 32],
+        pub insurance_balance: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct RebalanceEvent {
+        pub version: u8,
+        pub fees_reinvested: u64,
+        pub insurance_swept: u64,
+        pub reward_reserve: u64,
+        pub projected_apy: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct SnapshotTakenEvent {
+        pub version: u8,
+        pub epoch_index: u64,
+        pub total_staked: u64,
+        pub reward_reserve: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct SolvencyEvent {
+        pub version: u8,
+        pub projected_liability: u64,
+        pub reward_reserve: u64,
+        pub solvency_factor_bps: u64,
+        pub utilization_bps: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct SolvencyCheckEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub expected: u64,
+        pub actual: u64,
+        pub delta: i64,
+        pub ok: bool,
+        pub deposits_paused: bool,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct ReserveRunwayPauseEvent {
+        pub version: u8,
+        pub runway_days: u64,
+        pub min_runway_days: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct ParameterUpdateEvent {
+        pub version: u8,
+        pub admin: Pubkey,
+        pub parameter: PoolParameter,
+        pub old_value: u64,
+        pub new_value: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct FeesWithdrawnEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub admin: Pubkey,
+        pub treasury: Pubkey,
+        pub amount: u64,
+        pub fees_remaining: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct PenaltiesWithdrawnEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub admin: Pubkey,
+        pub treasury: Pubkey,
+        pub amount: u64,
+        pub penalties_remaining: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct MarinadeDeployedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub amount: u64,
+        pub msol_received: u64,
+        pub deployed_total: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct MarinadeRecalledEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub msol_amount: u64,
+        pub sol_received: u64,
+        pub cost_released: u64,
+        pub yield_to_reserve: u64,
+        pub loss_covered: u64,
+        pub deployed_total: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct MarinadeUnstakeOrderedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub ticket: Pubkey,
+        pub msol_amount: u64,
+        pub ticket_lamports: u64,
+        pub cost_released: u64,
+        pub deployed_total: u64,
+        pub timestamp: i64,
+    }
+
+    #[event]
+    pub struct MarinadeTicketClaimedEvent {
+        pub version: u8,
+        pub pool: Pubkey,
+        pub ticket: Pubkey,
+        pub sol_received: u64,
+        pub cost_released: u64,
+        pub yield_to_reserve: u64,
+        pub loss_covered: u64,
+        pub ticket_principal_total: u64,
+        pub timestamp: i64,
     }
 
-    // Emergency unpause (admin only)
-    pub fn emergency_unpause(ctx: Context<AdminOnly>) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
+    // Initialize the pool
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_pool(
+        ctx: Context<InitializePool>,
+        pool_id: u64,
+        max_apy: u64,
+        min_commitment_days: u64,
+        max_commitment_days: u64,
+        stake_mint: Option<Pubkey>,
+        apy_tiers: Vec<ApyTier>,
+        fee_tiers: Vec<FeeTier>,
+    ) -> Result<()> {
+        // Validate parameters
+        require!(max_apy > 0 && max_apy <= MAX_APY_BPS, ErrorCode::InvalidApy);
+        require!(min_commitment_days > 0, ErrorCode::InvalidCommitmentDays);
+        require!(max_commitment_days >= min_commitment_days, ErrorCode::InvalidCommitmentDays);
+        require!(max_commitment_days <= MAX_COMMITMENT_DAYS, ErrorCode::InvalidCommitmentDays);
+        validate_apy_tiers(&apy_tiers)?;
+        validate_fee_tiers(&fee_tiers)?;
+        // Pool ids are handed out sequentially by the registry
+        require!(pool_id == ctx.accounts.pool_registry.next_pool_id, ErrorCode::InvalidPoolId);
+
+        let pool_registry = &mut ctx.accounts.pool_registry;
+        pool_registry.next_pool_id = pool_registry.next_pool_id.checked_add(1).unwrap();
+        pool_registry.total_pools = pool_registry.total_pools.checked_add(1).unwrap();
 
         let pool = &mut ctx.accounts.pool;
         let clock = Clock::get()?;
 
-        pool.is_paused = false;
+        // Initialize pool state
+        pool.version = POOL_VERSION;
+        pool.pool_id = pool_id;
+        pool.admin = ctx.accounts.admin.key();
+        pool.max_apy = max_apy;
+        pool.apy_tiers = apy_tiers;
+        pool.loyalty_tiers = Vec::new();
+        pool.boost_collection = None;
+        pool.nft_boost_bps = 0;
+        pool.proposal_threshold = 10_000_000_000; // 10 SOL staked to propose
+        pool.governance_quorum_bps = 1000; // 10% of total stake must vote
+        pool.next_proposal_id = 0;
+        pool.leverage_ratio = DEFAULT_LEVERAGE_RATIO;
+        pool.reinvestment_rate = DEFAULT_REINVESTMENT_RATE;
+        pool.fees_awaiting_rebalance = 0;
+        pool.rebalance_interval = 86400; // Once a day
+        pool.last_rebalance_timestamp = clock.unix_timestamp;
+        pool.projected_apy = highest_apy_bps(pool);
+        pool.min_runway_days = 30;
+        pool.total_yields_paid = 0;
+        pool.snapshot_count = 0;
+        pool.snapshot_interval = 86400; // Daily
+        pool.last_snapshot_timestamp = 0;
+        pool.snapshot_retention_epochs = 365;
+        pool.large_operation_threshold = 10_000_000_000; // 10 SOL per slot
+        pool.min_block_delay = 2; // Slots
+        pool.current_slot = 0;
+        pool.current_slot_volume = 0;
+        pool.slot_delay_until = 0;
+        pool.max_slippage_bps = 100; // Fee may differ from the quote by 1% of the deposit
+        pool.min_slots_before_exit = 1; // No exit in the staking slot
+        pool.fee_tiers = fee_tiers;
+        pool.total_yields_credited = 0;
+        pool.insurance_share_bps = 0; // No insurance carve-out until the admin sets one
+        pool.insurance_pending = 0;
+        pool.insurance_balance = 0;
+        pool.is_active = true;
+        pool.sunset_at = 0;
+        // Canonical bumps, so later instructions check the addresses without re-deriving them
+        pool.bump = ctx.bumps.pool;
+        pool.pool_vault_bump = ctx.bumps.pool_vault;
+        pool.fee_vault_bump = ctx.bumps.fee_vault;
+        pool.reward_vault_bump = ctx.bumps.reward_vault;
+        pool.long_commit_threshold_days = 180;
+        pool.long_commit_fee_discount_bps = 5000; // Half the deposit fee from 180 days
+        pool.penalty_grace_bps = 9500; // No early-exit penalty once 95% of the commitment is served
+        pool.max_yield_per_claim = 0; // Payouts are uncapped until the admin sets the caps
+        pool.max_yield_per_user_per_year = 0;
+        pool.treasury = ctx.accounts.admin.key(); // Until the admin names a separate treasury
+        pool.total_projected_liability = 0;
+        pool.solvency_factor_bps = 10000; // Committed yield must fit within the reserve
+        pool.max_reconciliation_delta = 0; // Any shortfall against the books pauses deposits
+        pool.policy_version = 0;
+        pool.migration_bonus_bps = 0; // No bonus for adopting a raised minimum until the admin sets one
+        pool.min_claim_amount = 0; // Any yield may be claimed until the admin sets a minimum
+        pool.renewal_grace_seconds = 0; // Commitments do not roll over until the admin turns renewal on
+        pool.idle_apy_bps = 0;
+        pool.recovery_authority = Pubkey::default(); // No recovery until the admin names an authority
+        pool.admin_inactivity_timeout = DEFAULT_ADMIN_INACTIVITY_TIMEOUT;
+        pool.last_admin_activity = clock.unix_timestamp;
+        pool.recovery_mode = false;
+        pool.rollover_fee_bps = 0; // Rollovers are free until the admin sets a fee
+        pool.reward_mint = None; // Yields are paid in SOL
+        pool.reward_token_vault_bump = 0;
+        pool.reward_tokens_per_sol = 0;
+        pool.min_token_stake_amount = 1; // Token limits are in the stake mint's base units;
+        pool.max_token_stake_amount = u64::MAX; // none apply until the admin sets them
+        pool.min_commitment_days = min_commitment_days;
+        pool.max_commitment_days = max_commitment_days;
+        pool.min_stake_amount = 100_000_000; // 0.1 SOL minimum
+        pool.max_stake_amount = DEFAULT_MAX_STAKE_AMOUNT;
+        pool.total_staked = 0;
+        pool.total_users = 0;
+        pool.total_fees_collected = 0;
+        pool.total_penalties_collected = 0;
+        pool.reward_reserve = 0;
+        pool.total_referral_rewards_pending = 0;
+        pool.deposit_fee_bps = 50; // 0.5% fee
+        pool.referral_share_bps = 0; // Referrals earn nothing until enabled
+        pool.access_mode = ACCESS_MODE_OPEN;
+        pool.early_exit_penalty_bps = 500; // 5% early-exit penalty
+        pool.sliding_penalty_enabled = false;
+        pool.withdrawal_cooldown_seconds = 86400; // Queued withdrawals wait 24 hours
+        pool.instant_exit_enabled = true;
+        pool.instant_exit_penalty_bps = 0;
+        pool.max_daily_outflow_bps = 10000; // No outflow cap until the admin sets one
+        pool.outflow_window_start = 0;
+        pool.outflow_in_window = 0;
+        pool.pause_flags = 0;
+        pool.guardian = Pubkey::default(); // No guardian until the admin appoints one
+        pool.guardian_pause_max_duration = 72 * 3600; // Guardian pauses lapse after 72 hours
+        pool.paused_at = 0;
+        pool.paused_by = Pubkey::default();
+        pool.pause_expires_at = 0;
+        pool.emergency_withdraw_delay = 7 * 86400; // Principal can be pulled after 7 days paused
+        pool.stake_mint = stake_mint;
+        pool.total_token_staked = 0;
+        pool.total_token_fees_collected = 0;
+        pool.total_token_penalties_collected = 0;
+        pool.created_at = clock.unix_timestamp;
         pool.last_update = clock.unix_timestamp;
 
-        emit!(EmergencyUnpauseEvent {
+        emit!(PoolInitializedEvent {
+            version: EVENT_VERSION,
             admin: ctx.accounts.admin.key(),
+            pool: ctx.accounts.pool.key(),
+            pool_id,
+            max_apy,
+            min_commitment_days,
+            max_commitment_days,
             timestamp: clock.unix_timestamp,
         });
 
         Ok(())
     }
 
-    // Update APY (admin only)
-    pub fn update_apy(ctx: Context<AdminOnly>, new_apy: u64) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
-        require!(new_apy > 0 && new_apy <= 10000, ErrorCode::InvalidApy);
+    // Stake function
+    pub fn stake(
+        ctx: Context<Stake>,
+        amount: u64,
+        committed_days: u64,
+        min_expected_amount: u64,
+        referrer: Option<Pubkey>,
+    ) -> Result<()> {
+        cu_checkpoint!("stake: accounts loaded");
+        stake_lamports(ctx.accounts, ctx.bumps.user_stake, amount, committed_days, min_expected_amount, referrer)
+    }
 
-        let pool = &mut ctx.accounts.pool;
+    // Stake so that exactly net_amount lands in the position: the program sizes the
+    // deposit to cover the fee and refuses if that is more than max_gross_amount
+    pub fn stake_exact_net(
+        ctx: Context<Stake>,
+        net_amount: u64,
+        committed_days: u64,
+        max_gross_amount: u64,
+        deadline: i64,
+    ) -> Result<()> {
+        check_transaction_deadline(deadline, Clock::get()?.unix_timestamp)?;
+        require!(net_amount > 0, ErrorCode::InvalidAmount);
+
+        let gross_amount = exact_net_deposit(&ctx.accounts.pool, net_amount, committed_days)?;
+        require_with_context!(
+            gross_amount <= max_gross_amount,
+            ErrorCode::SlippageExceeded,
+            "max_gross={} gross={}",
+            max_gross_amount,
+            gross_amount
+        );
+
+        // The net is the quote, so the fee charged must be exactly the one sized for
+        stake_lamports(ctx.accounts, ctx.bumps.user_stake, gross_amount, committed_days, net_amount, None)
+    }
+
+    // Stake from a wSOL token account; the amount is unwrapped into the pool vault through
+    // a temporary token account, then accounted exactly like a native stake
+    pub fn stake_wsol(
+        ctx: Context<StakeWsol>,
+        amount: u64,
+        committed_days: u64,
+        min_expected_amount: u64,
+        referrer: Option<Pubkey>,
+    ) -> Result<()> {
+        let (fee_bps, fee_amount) = price_stake(&mut ctx.accounts.stake, amount, committed_days, min_expected_amount)?;
+
+        let unwrap_rent = ctx.accounts.unwrap_account.to_account_info().lamports();
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.stake.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.user_wsol_account.to_account_info(),
+                    to: ctx.accounts.unwrap_account.to_account_info(),
+                    authority: ctx.accounts.stake.user.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        unwrap_into_pool_vault(&ctx.accounts.stake, &ctx.accounts.unwrap_account, unwrap_rent, fee_amount)?;
+
+        open_stake(&mut ctx.accounts.stake, ctx.bumps.stake.user_stake, amount, fee_bps, fee_amount, committed_days, referrer)
+    }
+
+    // Stake any token the swap router can sell for SOL. The route's accounts come in as
+    // remaining accounts and its output must land in a pool-owned wSOL account, which is
+    // then unwrapped and staked like stake_wsol, so the swap and the stake are atomic.
+    pub fn stake_with_swap(
+        ctx: Context<StakeWithSwap>,
+        input_amount: u64,
+        min_sol_out: u64,
+        route_data: Vec<u8>,
+        committed_days: u64,
+        min_expected_amount: u64,
+        referrer: Option<Pubkey>,
+    ) -> Result<()> {
+        require!(input_amount > 0, ErrorCode::InvalidAmount);
+        // A zero floor would let the route pay out anything at all
+        require!(min_sol_out > 0, ErrorCode::SwapSlippageExceeded);
+
+        let input_before = ctx.accounts.user_input_account.amount;
+        let output_before = ctx.accounts.swap_output_account.amount;
+        let unwrap_rent = ctx.accounts.swap_output_account.to_account_info().lamports();
+
+        // Plain invoke: the user's signature carries through to the route, the pool's never does
+        let route = Instruction {
+            program_id: ctx.accounts.swap_program.key(),
+            accounts: ctx.remaining_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: account.key(),
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: route_data,
+        };
+        anchor_lang::solana_program::program::invoke(&route, ctx.remaining_accounts)?;
+
+        ctx.accounts.user_input_account.reload()?;
+        ctx.accounts.swap_output_account.reload()?;
+        let input_spent = input_before.saturating_sub(ctx.accounts.user_input_account.amount);
+        let sol_received = ctx.accounts.swap_output_account.amount.checked_sub(output_before).unwrap();
+        require!(input_spent <= input_amount, ErrorCode::InvalidSwapRoute);
+        require!(sol_received >= min_sol_out, ErrorCode::SwapSlippageExceeded);
+
+        let (fee_bps, fee_amount) =
+            price_stake(&mut ctx.accounts.stake, sol_received, committed_days, min_expected_amount)?;
+        unwrap_into_pool_vault(&ctx.accounts.stake, &ctx.accounts.swap_output_account, unwrap_rent, fee_amount)?;
+
+        emit!(SwapAndStakeEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.stake.user.key(),
+            pool: ctx.accounts.stake.pool.key(),
+            input_mint: ctx.accounts.user_input_account.mint,
+            input_amount: input_spent,
+            sol_received,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        open_stake(
+            &mut ctx.accounts.stake,
+            ctx.bumps.stake.user_stake,
+            sol_received,
+            fee_bps,
+            fee_amount,
+            committed_days,
+            referrer,
+        )
+    }
+
+    // Stake on behalf of a beneficiary: the payer funds the deposit, while the position,
+    // its receipts and every future claim belong to the beneficiary, who need not sign
+    pub fn stake_for(
+        ctx: Context<StakeFor>,
+        amount: u64,
+        committed_days: u64,
+        min_expected_amount: u64,
+        referrer: Option<Pubkey>,
+    ) -> Result<()> {
         let clock = Clock::get()?;
-        let old_apy = pool.max_apy;
+        let beneficiary = ctx.accounts.beneficiary.key();
+        let payer = ctx.accounts.payer.key();
 
-        pool.max_apy = new_apy;
-        pool.last_update = clock.unix_timestamp;
+        let access_entry = load_access_entry(&ctx.accounts.pool, ctx.accounts.access_entry.as_ref())?;
+        let (fee_bps, fee_amount) = quote_stake(
+            &mut ctx.accounts.pool,
+            access_entry.as_ref(),
+            amount,
+            committed_days,
+            &clock,
+        )?;
+        let net_amount = amount.checked_sub(fee_amount).unwrap();
+        check_quote(amount, fee_amount, min_expected_amount, ctx.accounts.pool.max_slippage_bps)?;
 
-        emit!(ParameterUpdateEvent {
-            admin: ctx.accounts.admin.key(),
-            parameter: "max_apy".to_string(),
-            old_value: old_apy,
-            new_value: new_apy,
+        deposit_lamports(
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.pool_vault,
+            &ctx.accounts.fee_vault,
+            net_amount,
+            fee_amount,
+        )?;
+
+        // Receipts go to the beneficiary, so only they can ever unstake
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        token::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                MintTo {
+                    mint: ctx.accounts.receipt_mint.to_account_info(),
+                    to: ctx.accounts.beneficiary_receipt_account.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            net_amount,
+        )?;
+
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        if let Some(referrer) = referrer {
+            require!(referrer != payer, ErrorCode::SelfReferral);
+            attribute_referrer(user_stake, beneficiary, referrer)?;
+
+            emit!(ReferralAttributedEvent {
+                version: EVENT_VERSION,
+                user: beneficiary,
+                referrer,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        record_stake(
+            &mut ctx.accounts.pool,
+            user_stake,
+            ctx.accounts.referral_account.as_deref_mut(),
+            beneficiary,
+            amount,
+            fee_amount,
+            committed_days,
+            &clock,
+        )?;
+        user_stake.funded_by = Some(payer);
+        user_stake.bump = ctx.bumps.user_stake;
+
+        emit!(StakeEvent {
+            version: EVENT_VERSION,
+            user: beneficiary,
+            pool: ctx.accounts.pool.key(),
+            payer,
+            amount: net_amount,
+            fee_bps,
+            committed_days,
+            user_total_after: user_stake.amount,
+            apy_bps_applied: user_stake.locked_apy_bps,
             timestamp: clock.unix_timestamp,
         });
 
         Ok(())
     }
 
-    // Update deposit fee (admin only)
-    pub fn update_deposit_fee(ctx: Context<AdminOnly>, new_fee_bps: u64) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
-        require!(new_fee_bps <= 1000, ErrorCode::InvalidFee); // Max 10%
+    // Create the referral account a referrer accumulates rewards in
+    pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+        let referral_account = &mut ctx.accounts.referral_account;
+
+        referral_account.referrer = ctx.accounts.referrer.key();
+        referral_account.pending_rewards = 0;
+        referral_account.total_referred_volume = 0;
+        referral_account.total_rewards_claimed = 0;
+
+        Ok(())
+    }
+
+    // Pay out a referrer's accumulated share of deposit fees
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        let amount = ctx.accounts.referral_account.pending_rewards;
+        require!(amount > 0, ErrorCode::NoYieldToClaim);
 
-        let pool = &mut ctx.accounts.pool;
         let clock = Clock::get()?;
-        let old_fee = pool.deposit_fee_bps;
 
-        pool.deposit_fee_bps = new_fee_bps;
+        // Referral rewards are a slice of the fees already sitting in the fee vault
+        transfer_from_vault(
+            &ctx.accounts.fee_vault,
+            &ctx.accounts.referrer.to_account_info(),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let referral_account = &mut ctx.accounts.referral_account;
+
+        referral_account.pending_rewards = 0;
+        referral_account.total_rewards_claimed = referral_account.total_rewards_claimed.checked_add(amount).unwrap();
+
+        pool.total_fees_collected = pool.total_fees_collected.checked_sub(amount).unwrap();
+        pool.total_referral_rewards_pending = pool.total_referral_rewards_pending.checked_sub(amount).unwrap();
         pool.last_update = clock.unix_timestamp;
 
-        emit!(ParameterUpdateEvent {
-            admin: ctx.accounts.admin.key(),
-            parameter: "deposit_fee_bps".to_string(),
-            old_value: old_fee,
-            new_value: new_fee_bps,
+        emit!(ReferralRewardsClaimedEvent {
+            version: EVENT_VERSION,
+            referrer: ctx.accounts.referrer.key(),
+            amount,
             timestamp: clock.unix_timestamp,
         });
 
         Ok(())
     }
 
-    // Update pool limits (admin only)
-    pub fn update_pool_limits(
-        ctx: Context<AdminOnly>,
-        new_min_stake: u64,
-        new_max_stake: u64,
-    ) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
-        require!(new_min_stake > 0, ErrorCode::InvalidAmount);
-        require!(new_max_stake > new_min_stake, ErrorCode::InvalidAmount);
-
-        let pool = &mut ctx.accounts.pool;
+    // Claim yields
+    // Holders of the pool's boost collection NFT may pass [token account, metadata]
+    // as remaining accounts to claim with the NFT boost
+    pub fn claim_yields(ctx: Context<ClaimYields>, deadline: i64, min_yields: u64) -> Result<()> {
+        cu_checkpoint!("claim_yields: accounts loaded");
         let clock = Clock::get()?;
+        check_transaction_deadline(deadline, clock.unix_timestamp)?;
+        require!(!ctx.accounts.pool.claims_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+        require!(has_yield_source(&ctx.accounts.user_stake), ErrorCode::NoStake);
+        check_exit_slot(&ctx.accounts.pool, &ctx.accounts.user_stake, clock.slot)?;
 
-        pool.min_stake_amount = new_min_stake;
-        pool.max_stake_amount = new_max_stake;
-        pool.last_update = clock.unix_timestamp;
+        let nft_boost_bps = resolve_nft_boost_bps(
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake.user,
+            ctx.remaining_accounts,
+        )?;
+        cu_checkpoint!("claim_yields: boost resolved");
+
+        let paid = pay_accrued_yield(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.user.to_account_info(),
+            nft_boost_bps,
+            min_yields,
+        )?;
+        record_history(&mut ctx.accounts.user_history, HISTORY_OP_CLAIM, paid, clock.unix_timestamp);
+        cu_checkpoint!("claim_yields: paid");
 
         Ok(())
     }
 
-    // Withdraw fees (admin only)
-    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
-        require!(ctx.accounts.admin.key() == ctx.accounts.pool.admin, ErrorCode::Unauthorized);
-        require!(amount > 0, ErrorCode::InvalidAmount);
-
-        let pool = &mut ctx.accounts.pool;
+    // Claim yields on behalf of the owner; the payout always goes to the owner
+    pub fn claim_yields_delegated(ctx: Context<ClaimYieldsDelegated>) -> Result<()> {
         let clock = Clock::get()?;
+        require!(!ctx.accounts.pool.claims_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+        require!(has_yield_source(&ctx.accounts.user_stake), ErrorCode::NoStake);
+        check_exit_slot(&ctx.accounts.pool, &ctx.accounts.user_stake, clock.slot)?;
 
-        // Check if pool has sufficient fees
-        require!(pool.total_fees_collected >= amount, ErrorCode::InsufficientFunds);
-
-        // Transfer fees to admin
-        **ctx.accounts.pool_vault.try_borrow_mut_lamports()? -= amount;
-        **ctx.accounts.admin.try_borrow_mut_lamports()? += amount;
+        // The NFT must still be held by the owner, not the delegate
+        let nft_boost_bps = resolve_nft_boost_bps(
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake.user,
+            ctx.remaining_accounts,
+        )?;
 
-        pool.total_fees_collected = pool.total_fees_collected.checked_sub(amount).unwrap();
-        pool.last_update = clock.unix_timestamp;
+        pay_accrued_yield(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.user.to_account_info(),
+            nft_boost_bps,
+            NO_MIN_PAYOUT,
+        )?;
 
         Ok(())
     }
+
+    // Claim yields on a pool that pays them in its reward token. The yield accrues in
+    // lamports as usual and is converted at the admin-set reward_tokens_per_sol; the
+    // owner's token account for the reward mint is created if it does not exist yet.
+    pub fn claim_token_yields(ctx: Context<ClaimTokenYields>, deadline: i64, min_reward_tokens: u64) -> Result<()> {
+        let clock = Clock::get()?;
+        check_transaction_deadline(deadline, clock.unix_timestamp)?;
+        require!(!ctx.accounts.pool.claims_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+        require!(has_yield_source(&ctx.accounts.user_stake), ErrorCode::NoStake);
+        check_exit_slot(&ctx.accounts.pool, &ctx.accounts.user_stake, clock.slot)?;
+
+        let nft_boost_bps = resolve_nft_boost_bps(
+            &ctx.accounts.pool,
+            &ctx.accounts.user_stake.user,
+            ctx.remaining_accounts,
+        )?;
+
+        let (yield_amount, apy_bps, seconds_accrued) = take_claimable_yield(
+            &ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            nft_boost_bps,
+            clock.unix_timestamp,
+        )?;
+        require!(ctx.accounts.pool.reward_tokens_per_sol > 0, ErrorCode::InvalidRewardTokenPrice);
+        let reward_tokens = lamports_to_reward_tokens(yield_amount, ctx.accounts.pool.reward_tokens_per_sol)?;
+        require!(reward_tokens > 0, ErrorCode::NoYieldToClaim);
+        check_min_payout(reward_tokens, min_reward_tokens)?;
+        // An empty token reserve fails the claim; it never falls back to paying SOL
+        require_with_context!(
+            ctx.accounts.reward_token_vault.amount >= reward_tokens,
+            ErrorCode::InsufficientRewardReserve,
+            "reward_token_vault={} got={}",
+            ctx.accounts.reward_token_vault.amount,
+            reward_tokens
+        );
+
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.reward_token_vault.to_account_info(),
+                    to: ctx.accounts.user_reward_token_account.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            reward_tokens,
+        )?;
+
+        book_yield_claim(&mut ctx.accounts.pool, &mut ctx.accounts.user_stake, yield_amount, clock.unix_timestamp);
+
+        let pool = &ctx.accounts.pool;
+        let user_stake = &ctx.accounts.user_stake;
+        emit!(YieldsClaimedEvent {
+            version: EVENT_VERSION,
+            user: user_stake.user,
+            pool: pool.key(),
+            amount: yield_amount,
+            apy_bps,
+            loyalty_boost_bps: resolve_loyalty_boost_bps(&pool.loyalty_tiers, user_stake.total_staked_lifetime),
+            nft_boost_bps,
+            accrual_seconds: seconds_accrued as u64,
+            timestamp: clock.unix_timestamp,
+        });
+        emit!(RewardTokensPaidEvent {
+            version: EVENT_VERSION,
+            user: user_stake.user,
+            pool: pool.key(),
+            reward_mint: ctx.accounts.reward_mint.key(),
+            yield_lamports: yield_amount,
+            reward_tokens,
+            reward_tokens_per_sol: pool.reward_tokens_per_sol,
+            timestamp: clock.unix_timestamp,
+        });
+        record_history(&mut ctx.accounts.user_history, HISTORY_OP_CLAIM, yield_amount, clock.unix_timestamp);
+
+        Ok(())
+    }
+
+    // Credit accrued yield to up to MAX_CREDIT_BATCH positions passed as
+    // remaining accounts (permissionless). Nothing is paid out: the yield moves from the
+    // reward reserve into each position's pending_yields for its owner to withdraw.
+    // Accounts that are not positions of this pool, or have nothing to credit, are skipped.
+    pub fn batch_credit_yields(ctx: Context<BatchCreditYields>) -> Result<()> {
+        require!(ctx.remaining_accounts.len() <= MAX_CREDIT_BATCH, ErrorCode::BatchTooLarge);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let pool_id_bytes = pool.pool_id.to_le_bytes();
+        let mut positions_credited: u64 = 0;
+        let mut total_credited: u64 = 0;
+
+        // The NFT boost needs each holder's accounts, so a keeper cannot credit boosted
+        // pools without cutting the holders' rate; credited yield is paid in SOL, so pools
+        // paying in a reward token are skipped too
+        if pool.boost_collection.is_none() && pool.reward_mint.is_none() {
+            for account in ctx.remaining_accounts {
+                if account.owner != &crate::ID || !account.is_writable {
+                    continue;
+                }
+                let mut user_stake = match UserStake::try_deserialize(&mut &account.try_borrow_data()?[..]) {
+                    Ok(user_stake) if user_stake.version == USER_STAKE_VERSION => user_stake,
+                    _ => continue,
+                };
+                let expected_key = Pubkey::create_program_address(
+                    &[b"user_stake", &pool_id_bytes, user_stake.user.as_ref(), &[user_stake.bump]],
+                    &crate::ID,
+                );
+                if expected_key != Ok(account.key()) {
+                    continue;
+                }
+
+                let credited = credit_accrued_yield(pool, &mut user_stake, clock.unix_timestamp)?;
+                if credited == 0 {
+                    continue;
+                }
+                user_stake.try_serialize(&mut &mut account.try_borrow_mut_data()?[..])?;
+
+                positions_credited += 1;
+                total_credited = total_credited.checked_add(credited).unwrap();
+            }
+        }
+
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(YieldsCreditedEvent {
+            version: EVENT_VERSION,
+            keeper: ctx.accounts.keeper.key(),
+            positions_credited,
+            amount: total_credited,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Pay out yields a keeper has credited to the position
+    pub fn withdraw_credited_yields(ctx: Context<WithdrawCreditedYields>) -> Result<()> {
+        let credited = ctx.accounts.user_stake.pending_yields;
+        require!(credited > 0, ErrorCode::NoYieldToClaim);
+
+        let clock = Clock::get()?;
+        let amount = credited_payout(&mut ctx.accounts.pool, &mut ctx.accounts.user_stake, clock.unix_timestamp)?;
+
+        // Credited yields were taken out of the reward reserve but left in the reward vault
+        transfer_from_vault(
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.user.to_account_info(),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        user_stake.pending_yields = credited.checked_sub(amount).unwrap();
+        user_stake.total_claimed = user_stake.total_claimed.checked_add(amount).unwrap();
+        user_stake.total_yields_claimed = user_stake.total_yields_claimed.checked_add(amount).unwrap();
+
+        pool.total_yields_credited = pool.total_yields_credited.checked_sub(amount).unwrap();
+        pool.total_yields_paid = pool.total_yields_paid.checked_add(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(CreditedYieldsWithdrawnEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Let another wallet trigger claims for this position (owner only)
+    pub fn set_claim_delegate(ctx: Context<SetClaimDelegate>, delegate: Pubkey) -> Result<()> {
+        ctx.accounts.user_stake.claim_delegate = Some(delegate);
+
+        Ok(())
+    }
+
+    // Remove the claim delegate (owner only)
+    pub fn revoke_claim_delegate(ctx: Context<SetClaimDelegate>) -> Result<()> {
+        ctx.accounts.user_stake.claim_delegate = None;
+
+        Ok(())
+    }
+
+    // Lengthen the commitment of an existing position
+    pub fn extend_commitment(ctx: Context<ExtendCommitment>, new_committed_days: u64) -> Result<()> {
+        require!(!pool_is_paused(&ctx.accounts.pool, Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        require!(new_committed_days > ctx.accounts.user_stake.committed_days, ErrorCode::InvalidCommitmentDays);
+        require!(new_committed_days <= ctx.accounts.pool.max_commitment_days, ErrorCode::InvalidCommitmentDays);
+
+        let pool = &ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        // Days so far settle at the old rate so a better tier never applies retroactively
+        update_rewards(pool, user_stake, 0, clock.unix_timestamp)?;
+
+        // The lock still runs from the original stake time; only its length grows
+        let old_days = user_stake.committed_days;
+        user_stake.committed_days = new_committed_days;
+        let apy_bps = resolve_apy_bps(&pool.apy_tiers, pool.max_apy, new_committed_days);
+        user_stake.locked_apy_bps = user_stake.locked_apy_bps.max(apy_bps);
+        user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+
+        emit!(CommitmentExtendedEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            old_days,
+            new_days: new_committed_days,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Opt a position out of rolling commitments, or back in. Settles first, so time already
+    // passed accrues under the setting it passed under; opting back in once the
+    // commitment has ended starts a fresh one of the same length now.
+    pub fn set_auto_renew(ctx: Context<ExtendCommitment>, auto_renew: bool) -> Result<()> {
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+
+        let pool = &ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        update_rewards(pool, user_stake, 0, clock.unix_timestamp)?;
+        let rejoins_ended_commitment = auto_renew
+            && user_stake.auto_renew_opted_out
+            && pool.renewal_grace_seconds > 0
+            && clock.unix_timestamp >= commitment_end(user_stake);
+        if rejoins_ended_commitment {
+            user_stake.stake_timestamp = clock.unix_timestamp;
+        }
+        user_stake.auto_renew_opted_out = !auto_renew;
+
+        emit!(AutoRenewUpdateEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            pool: pool.key(),
+            auto_renew,
+            stake_timestamp: user_stake.stake_timestamp,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Opt a grandfathered position into the pool's current minimum commitment in return
+    // for the one-time migration bonus, credited to the yield it has accrued
+    pub fn migrate_commitment(ctx: Context<ExtendCommitment>) -> Result<()> {
+        require!(!pool_is_paused(&ctx.accounts.pool, Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+
+        let pool = &ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        let old_days = user_stake.committed_days;
+        let bonus = adopt_commitment_policy(pool, user_stake, clock.unix_timestamp)?;
+
+        emit!(CommitmentMigratedEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            pool: pool.key(),
+            old_days,
+            new_days: user_stake.committed_days,
+            bonus,
+            policy_version: user_stake.policy_version,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Compound accrued yields into the position instead of paying them out
+    pub fn compound_yields(ctx: Context<CompoundYields>) -> Result<()> {
+        require!(!ctx.accounts.pool.claims_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(!ctx.accounts.pool.deposits_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        // Yield paid in a reward token cannot become SOL principal
+        require!(ctx.accounts.pool.reward_mint.is_none(), ErrorCode::RewardsPaidInToken);
+
+        let clock = Clock::get()?;
+
+        // Settle exactly as claim_yields does, every second since the previous checkpoint
+        update_rewards(&ctx.accounts.pool, &mut ctx.accounts.user_stake, 0, clock.unix_timestamp)?;
+        let yield_amount = ctx.accounts.user_stake.rewards_owed;
+        require!(yield_amount > 0, ErrorCode::NoYieldToClaim);
+
+        // Compound up to the per-user cap and pay the remainder out within the payout caps;
+        // anything over those stays owed. No deposit fee applies.
+        let headroom = ctx.accounts.pool.max_stake_amount.saturating_sub(ctx.accounts.user_stake.amount);
+        let compounded_amount = yield_amount.min(headroom);
+        let payout_amount = take_capped_yield(
+            &ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            yield_amount.checked_sub(compounded_amount).unwrap(),
+            clock.unix_timestamp,
+        );
+        let settled_amount = compounded_amount.checked_add(payout_amount).unwrap();
+        require!(settled_amount > 0, ErrorCode::YieldCapReached);
+        require!(ctx.accounts.pool.reward_reserve >= settled_amount, ErrorCode::InsufficientRewardReserve);
+        // Only the paid-out part leaves the pool; compounded yield stays as principal
+        if payout_amount > 0 {
+            record_outflow(&mut ctx.accounts.pool, payout_amount, clock.unix_timestamp)?;
+        }
+
+        if compounded_amount > 0 {
+            // Mint receipt tokens for the compounded principal
+            let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+            let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    MintTo {
+                        mint: ctx.accounts.receipt_mint.to_account_info(),
+                        to: ctx.accounts.user_receipt_account.to_account_info(),
+                        authority: ctx.accounts.pool.to_account_info(),
+                    },
+                    &[pool_seeds],
+                ),
+                compounded_amount,
+            )?;
+
+            // The compounded yield becomes principal, so move its lamports into the pool vault
+            transfer_from_vault(
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.pool_vault.to_account_info(),
+                compounded_amount,
+            )?;
+        }
+
+        if payout_amount > 0 {
+            transfer_from_vault(
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.user.to_account_info(),
+                payout_amount,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        // Update user stake; settlement already advanced the checkpoint
+        user_stake.amount = user_stake.amount.checked_add(compounded_amount).unwrap();
+        user_stake.rewards_owed = yield_amount.checked_sub(settled_amount).unwrap();
+        user_stake.total_claimed = user_stake.total_claimed.checked_add(settled_amount).unwrap();
+        user_stake.total_yields_claimed = user_stake.total_yields_claimed.checked_add(settled_amount).unwrap();
+        user_stake.total_staked_lifetime = user_stake.total_staked_lifetime.checked_add(compounded_amount).unwrap();
+        // The larger lifetime stake may reach a new loyalty tier from here on
+        user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+
+        // Update pool state
+        pool.total_staked = pool.total_staked.checked_add(compounded_amount).unwrap();
+        pool.reward_reserve = pool.reward_reserve.checked_sub(settled_amount).unwrap();
+        pool.total_yields_paid = pool.total_yields_paid.checked_add(settled_amount).unwrap();
+        adjust_projected_liability(pool, user_stake, 0, settled_amount, clock.unix_timestamp);
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(CompoundEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            yields_compounded: compounded_amount,
+            yields_paid_out: payout_amount,
+            new_amount: user_stake.amount,
+            timestamp: clock.unix_timestamp,
+        });
+        record_history(&mut ctx.accounts.user_history, HISTORY_OP_COMPOUND, settled_amount, clock.unix_timestamp);
+
+        Ok(())
+    }
+
+    // Start a new commitment on a position whose commitment is met, in place of an unstake
+    // and a fresh stake. Accrued yield is compounded up to the stake cap, or paid out, within
+    // the payout caps; the new period locks the tier APY in effect now and is charged
+    // rollover_fee_bps of the principal instead of the deposit fee.
+    pub fn rollover(ctx: Context<Rollover>, new_committed_days: u64, compound: bool) -> Result<()> {
+        let clock = Clock::get()?;
+        require!(!ctx.accounts.pool.deposits_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+        require!(!ctx.accounts.pool.claims_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        // Rollover compounds or pays yield in SOL; token-paying pools claim it first
+        require!(ctx.accounts.pool.reward_mint.is_none(), ErrorCode::RewardsPaidInToken);
+
+        settle_for_rollover(&ctx.accounts.pool, &mut ctx.accounts.user_stake, new_committed_days, clock.unix_timestamp)?;
+
+        let (compounded_amount, payout_amount) =
+            split_rollover_yield(&ctx.accounts.pool, &mut ctx.accounts.user_stake, compound, clock.unix_timestamp);
+        let settled_amount = compounded_amount.checked_add(payout_amount).unwrap();
+        require!(ctx.accounts.pool.reward_reserve >= settled_amount, ErrorCode::InsufficientRewardReserve);
+        if payout_amount > 0 {
+            record_outflow(&mut ctx.accounts.pool, payout_amount, clock.unix_timestamp)?;
+        }
+        let fee_amount = compute_fee(
+            ctx.accounts.user_stake.amount.checked_add(compounded_amount).unwrap(),
+            ctx.accounts.pool.rollover_fee_bps,
+        )?;
+
+        if compounded_amount > 0 {
+            let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+            let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+            token::mint_to(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    MintTo {
+                        mint: ctx.accounts.receipt_mint.to_account_info(),
+                        to: ctx.accounts.user_receipt_account.to_account_info(),
+                        authority: ctx.accounts.pool.to_account_info(),
+                    },
+                    &[pool_seeds],
+                ),
+                compounded_amount,
+            )?;
+            transfer_from_vault(
+                &ctx.accounts.reward_vault,
+                &ctx.accounts.pool_vault.to_account_info(),
+                compounded_amount,
+            )?;
+        }
+
+        if payout_amount > 0 {
+            transfer_from_vault(&ctx.accounts.reward_vault, &ctx.accounts.user.to_account_info(), payout_amount)?;
+        }
+
+        // The fee leaves the principal, so its receipt tokens are burned with it
+        if fee_amount > 0 {
+            token::burn(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Burn {
+                        mint: ctx.accounts.receipt_mint.to_account_info(),
+                        from: ctx.accounts.user_receipt_account.to_account_info(),
+                        authority: ctx.accounts.user.to_account_info(),
+                    },
+                ),
+                fee_amount,
+            )?;
+            pay_from_pool_vault(
+                &ctx.accounts.pool,
+                &ctx.accounts.pool_vault,
+                &ctx.accounts.fee_vault.to_account_info(),
+                fee_amount,
+            )?;
+        }
+
+        let apy_bps = restart_commitment(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            compounded_amount,
+            settled_amount,
+            fee_amount,
+            new_committed_days,
+            clock.unix_timestamp,
+        )?;
+
+        emit!(RolloverEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            pool: ctx.accounts.pool.key(),
+            principal: ctx.accounts.user_stake.amount,
+            yields_handled: settled_amount,
+            yields_compounded: compounded_amount,
+            fee: fee_amount,
+            new_committed_days,
+            apy_bps,
+            timestamp: clock.unix_timestamp,
+        });
+        record_history(&mut ctx.accounts.user_history, HISTORY_OP_ROLLOVER, settled_amount, clock.unix_timestamp);
+
+        Ok(())
+    }
+
+    // Unstake function (full or partial); the instant path, when the admin allows it
+    pub fn unstake(ctx: Context<Unstake>, amount: u64, deadline: i64, min_return_amount: u64) -> Result<()> {
+        cu_checkpoint!("unstake: accounts loaded");
+        let recipient = ctx.accounts.user.to_account_info();
+        instant_unstake(ctx.accounts, amount, deadline, min_return_amount, recipient)?;
+        cu_checkpoint!("unstake: paid");
+
+        Ok(())
+    }
+
+    // Instant unstake paid into the user's wSOL token account instead of their wallet
+    pub fn unstake_to_wsol(
+        ctx: Context<UnstakeToWsol>,
+        amount: u64,
+        deadline: i64,
+        min_return_amount: u64,
+    ) -> Result<()> {
+        let recipient = ctx.accounts.user_wsol_account.to_account_info();
+        instant_unstake(&mut ctx.accounts.unstake, amount, deadline, min_return_amount, recipient)?;
+
+        // Lamports paid into a wSOL account only count as wrapped SOL once synced
+        token::sync_native(CpiContext::new(
+            ctx.accounts.unstake.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.user_wsol_account.to_account_info(),
+            },
+        ))
+    }
+
+    // Queue a withdrawal; the queued amount stops earning yield straight away
+    pub fn request_unstake(ctx: Context<ManageWithdrawal>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.pool.unstake_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require_with_context!(
+            amount <= ctx.accounts.user_stake.amount,
+            ErrorCode::InvalidAmount,
+            "staked={} got={}",
+            ctx.accounts.user_stake.amount,
+            amount
+        );
+        require!(ctx.accounts.user_stake.pending_withdrawal.is_none(), ErrorCode::PendingWithdrawalExists);
+        require!(votes_unlocked(&ctx.accounts.user_stake, Clock::get()?.unix_timestamp), ErrorCode::VotesLocked);
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        // Same remainder rule as an instant unstake, checked up front
+        let remaining_amount = user_stake.amount.checked_sub(amount).unwrap();
+        require_with_context!(
+            remaining_amount == 0 || remaining_amount >= pool.min_stake_amount,
+            ErrorCode::RemainderTooSmall,
+            "min={} remaining={}",
+            pool.min_stake_amount,
+            remaining_amount
+        );
+
+        // Accrual is switched off for the queued amount, so earlier days settle on the full amount
+        update_rewards(pool, user_stake, 0, clock.unix_timestamp)?;
+
+        user_stake.pending_withdrawal = Some(PendingWithdrawal {
+            amount,
+            requested_at: clock.unix_timestamp,
+        });
+        pool.total_pending_withdrawals = pool.total_pending_withdrawals.checked_add(amount).unwrap();
+
+        emit!(UnstakeRequestedEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            amount,
+            executable_at: clock.unix_timestamp.checked_add(pool.withdrawal_cooldown_seconds).unwrap(),
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Drop a queued withdrawal so the amount earns yield again
+    pub fn cancel_unstake_request(ctx: Context<ManageWithdrawal>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        let pending_withdrawal = user_stake.pending_withdrawal.ok_or(ErrorCode::NoPendingWithdrawal)?;
+
+        // Accrual restarts on the full amount, so earlier days settle on the unqueued part
+        update_rewards(pool, user_stake, 0, clock.unix_timestamp)?;
+
+        user_stake.pending_withdrawal = None;
+        unqueue_withdrawal(pool, pending_withdrawal.amount);
+
+        emit!(UnstakeRequestCancelledEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            amount: pending_withdrawal.amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Complete a queued withdrawal once the cooldown has passed
+    pub fn execute_unstake(ctx: Context<Unstake>) -> Result<()> {
+        require!(!ctx.accounts.pool.unstake_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+
+        let clock = Clock::get()?;
+        let pending_withdrawal = ctx.accounts.user_stake.pending_withdrawal.ok_or(ErrorCode::NoPendingWithdrawal)?;
+        require!(
+            withdrawal_cooldown_elapsed(
+                pending_withdrawal.requested_at,
+                ctx.accounts.pool.withdrawal_cooldown_seconds,
+                clock.unix_timestamp,
+            ),
+            ErrorCode::CooldownNotElapsed
+        );
+
+        ctx.accounts.user_stake.pending_withdrawal = None;
+        unqueue_withdrawal(&mut ctx.accounts.pool, pending_withdrawal.amount);
+        let recipient = ctx.accounts.user.to_account_info();
+        withdraw_principal(ctx.accounts, pending_withdrawal.amount, 0, NO_MIN_PAYOUT, recipient)
+    }
+
+    // Withdraw principal from a pool that has been paused for too long, closing the position
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+
+        let clock = Clock::get()?;
+
+        // Only available once withdrawals have been continuously paused past the delay
+        require!(
+            ctx.accounts.pool.unstake_paused(clock.unix_timestamp)
+                && emergency_withdraw_unlocked(
+                    ctx.accounts.pool.paused_at,
+                    ctx.accounts.pool.emergency_withdraw_delay,
+                    clock.unix_timestamp,
+                ),
+            ErrorCode::EmergencyWithdrawUnavailable
+        );
+
+        // Principal only: no yields are paid and no early-exit penalty is taken
+        let amount = ctx.accounts.user_stake.amount;
+
+        // Burn the receipt tokens backing the principal
+        token::burn(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Burn {
+                    mint: ctx.accounts.receipt_mint.to_account_info(),
+                    from: ctx.accounts.user_receipt_account.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        pay_from_pool_vault(
+            &ctx.accounts.pool,
+            &ctx.accounts.pool_vault,
+            &ctx.accounts.user.to_account_info(),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        // Update pool state; the stake account itself is closed by the context, and any
+        // credited yield goes back to the reserve rather than holding up the exit
+        pool.total_staked = pool.total_staked.checked_sub(amount).unwrap();
+        if let Some(pending_withdrawal) = user_stake.pending_withdrawal {
+            unqueue_withdrawal(pool, pending_withdrawal.amount);
+        }
+        return_credited_yields(pool, user_stake);
+        let projected_liability = user_stake.projected_liability;
+        adjust_projected_liability(pool, user_stake, 0, projected_liability, clock.unix_timestamp);
+        uncount_user(pool, user_stake);
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(EmergencyWithdrawEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Move a whole position, with its receipt tokens, to another wallet
+    pub fn transfer_stake(ctx: Context<TransferStake>) -> Result<()> {
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        require!(ctx.accounts.new_owner.key() != ctx.accounts.user.key(), ErrorCode::Unauthorized);
+        // Positions are never merged on transfer, so the destination must be empty
+        require!(ctx.accounts.destination_stake.amount == 0, ErrorCode::DestinationHasStake);
+        require!(ctx.accounts.user_stake.pending_withdrawal.is_none(), ErrorCode::PendingWithdrawalExists);
+        require!(votes_unlocked(&ctx.accounts.user_stake, Clock::get()?.unix_timestamp), ErrorCode::VotesLocked);
+
+        let clock = Clock::get()?;
+        let amount = ctx.accounts.user_stake.amount;
+
+        // The receipt tokens follow the principal so the new owner can unstake
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.user_receipt_account.to_account_info(),
+                    to: ctx.accounts.destination_receipt_account.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let source = &ctx.accounts.user_stake;
+        let destination = &mut ctx.accounts.destination_stake;
+
+        // Commitment clock, accrual checkpoint and locked rate carry over unchanged
+        destination.version = USER_STAKE_VERSION;
+        destination.bump = ctx.bumps.destination_stake;
+        claim_position(destination, ctx.accounts.new_owner.key())?;
+        destination.amount = amount;
+        destination.committed_days = source.committed_days;
+        destination.stake_timestamp = source.stake_timestamp;
+        destination.last_claim_timestamp = source.last_claim_timestamp;
+        destination.locked_apy_bps = source.locked_apy_bps;
+        destination.policy_version = source.policy_version;
+        destination.auto_renew_opted_out = source.auto_renew_opted_out;
+        destination.rewards_owed = destination.rewards_owed.checked_add(source.rewards_owed).unwrap();
+        destination.pending_yields = destination.pending_yields.checked_add(source.pending_yields).unwrap();
+        destination.accrual_rate_bps = source.accrual_rate_bps;
+        destination.reward_per_token_paid = source.reward_per_token_paid;
+        // Neither wallet's yearly payout window can be reset by a transfer
+        merge_yield_windows(destination, source, clock.unix_timestamp);
+        destination.projected_liability = destination.projected_liability
+            .checked_add(source.projected_liability).unwrap();
+        destination.claim_delegate = None;
+        destination.funded_by = None;
+        destination.last_stake_slot = destination.last_stake_slot.max(source.last_stake_slot);
+        destination.total_claimed = destination.total_claimed.checked_add(source.total_claimed).unwrap();
+        destination.total_staked_lifetime = destination.total_staked_lifetime
+            .checked_add(source.total_staked_lifetime).unwrap();
+        destination.total_yields_claimed = destination.total_yields_claimed
+            .checked_add(source.total_yields_claimed).unwrap();
+
+        // The counted position moves with the stake, so total_users is unchanged
+        destination.is_counted = source.is_counted;
+
+        ctx.accounts.pool.last_update = clock.unix_timestamp;
+
+        emit!(StakeTransferredEvent {
+            version: EVENT_VERSION,
+            from: ctx.accounts.user.key(),
+            to: ctx.accounts.new_owner.key(),
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Close an empty stake account and refund its rent to the user
+    pub fn close_user_stake(ctx: Context<CloseUserStake>) -> Result<()> {
+        let user_stake = &ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        // Emit lifetime statistics before the account is closed so history isn't lost
+        emit!(UserStakeClosedEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            total_staked_lifetime: user_stake.total_staked_lifetime,
+            total_yields_claimed: user_stake.total_yields_claimed,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Create the caller's operation history; stake, claim_yields, unstake and
+    // compound_yields append to it whenever it is passed
+    pub fn init_user_history(ctx: Context<InitUserHistory>) -> Result<()> {
+        let user_history = &mut ctx.accounts.user_history;
+
+        user_history.user = ctx.accounts.user.key();
+        user_history.bump = ctx.bumps.user_history;
+        user_history.clear();
+
+        Ok(())
+    }
+
+    // Forget every recorded operation, keeping the account
+    pub fn clear_user_history(ctx: Context<ManageUserHistory>) -> Result<()> {
+        ctx.accounts.user_history.clear();
+
+        Ok(())
+    }
+
+    // Close the history and refund its rent to the user
+    pub fn close_user_history(_ctx: Context<CloseUserHistory>) -> Result<()> {
+        Ok(())
+    }
+
+    // Create the token vault for the pool's stake mint (admin only)
+    pub fn initialize_token_vault(ctx: Context<InitializeTokenVault>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.token_vault_bump = ctx.bumps.token_vault;
+        pool.last_update = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Stake SPL tokens into the pool's token vault
+    pub fn stake_spl(
+        ctx: Context<StakeSpl>,
+        amount: u64,
+        committed_days: u64,
+        min_expected_amount: u64,
+    ) -> Result<()> {
+        // Security checks
+        require!(!ctx.accounts.pool.deposits_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive);
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require_with_context!(
+            amount >= ctx.accounts.pool.min_token_stake_amount,
+            ErrorCode::AmountTooSmall,
+            "min={} got={}",
+            ctx.accounts.pool.min_token_stake_amount,
+            amount
+        );
+        require_with_context!(
+            amount <= ctx.accounts.pool.max_token_stake_amount,
+            ErrorCode::AmountTooLarge,
+            "max={} got={}",
+            ctx.accounts.pool.max_token_stake_amount,
+            amount
+        );
+        require_with_context!(
+            (ctx.accounts.pool.min_commitment_days..=ctx.accounts.pool.max_commitment_days).contains(&committed_days),
+            ErrorCode::InvalidCommitmentDays,
+            "min_days={} max_days={} got={}",
+            ctx.accounts.pool.min_commitment_days,
+            ctx.accounts.pool.max_commitment_days,
+            committed_days
+        );
+        let access_entry = load_access_entry(&ctx.accounts.pool, ctx.accounts.access_entry.as_ref())?;
+        check_access(&ctx.accounts.pool, access_entry.as_ref())?;
+
+        let clock = Clock::get()?;
+
+        // Calculate fee; token deposits have no size tiers but do get the long-commitment discount
+        let fee_bps = apply_commitment_discount(
+            ctx.accounts.pool.deposit_fee_bps,
+            committed_days,
+            ctx.accounts.pool.long_commit_threshold_days,
+            ctx.accounts.pool.long_commit_fee_discount_bps,
+        );
+        let fee_amount = compute_fee(amount, fee_bps)?;
+        check_quote(amount, fee_amount, min_expected_amount, ctx.accounts.pool.max_slippage_bps)?;
+        let net_amount = amount.checked_sub(fee_amount).unwrap();
+
+        // Transfer tokens from user to the token vault
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    to: ctx.accounts.token_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        // Update user stake; a top-up is merged into the existing position. Yields are
+        // paid in SOL against SOL principal, so a token position locks no APY and never
+        // accrues any.
+        user_stake.version = USER_STAKE_VERSION;
+        user_stake.bump = ctx.bumps.user_stake;
+        claim_position(user_stake, ctx.accounts.user.key())?;
+        merge_principal(user_stake, net_amount, committed_days, 0, clock.unix_timestamp);
+        stamp_policy_version(pool, user_stake);
+        user_stake.total_staked_lifetime = user_stake.total_staked_lifetime.checked_add(net_amount).unwrap();
+        user_stake.last_stake_slot = clock.slot;
+
+        // Update pool state
+        pool.total_token_staked = pool.total_token_staked.checked_add(net_amount).unwrap();
+        count_user(pool, user_stake);
+        pool.total_token_fees_collected = pool.total_token_fees_collected.checked_add(fee_amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(SplStakeEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.stake_mint.key(),
+            amount: net_amount,
+            committed_days,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Token-pool counterpart of emergency_withdraw: returns the whole token principal with
+    // no fee or penalty once withdrawals have been paused past the delay
+    pub fn emergency_withdraw_spl(ctx: Context<EmergencyWithdrawSpl>) -> Result<()> {
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+
+        let clock = Clock::get()?;
+
+        require!(
+            ctx.accounts.pool.unstake_paused(clock.unix_timestamp)
+                && emergency_withdraw_unlocked(
+                    ctx.accounts.pool.paused_at,
+                    ctx.accounts.pool.emergency_withdraw_delay,
+                    clock.unix_timestamp,
+                ),
+            ErrorCode::EmergencyWithdrawUnavailable
+        );
+
+        let amount = ctx.accounts.user_stake.amount;
+
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.token_vault.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+
+        // Update pool state; the stake account itself is closed by the context
+        pool.total_token_staked = pool.total_token_staked.checked_sub(amount).unwrap();
+        uncount_user(pool, &mut ctx.accounts.user_stake);
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(SplEmergencyWithdrawEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.stake_mint.key(),
+            amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Unstake SPL tokens (full or partial)
+    pub fn unstake_spl(ctx: Context<UnstakeSpl>, amount: u64, deadline: i64, min_return_amount: u64) -> Result<()> {
+        check_transaction_deadline(deadline, Clock::get()?.unix_timestamp)?;
+        require!(!ctx.accounts.pool.unstake_paused(Clock::get()?.unix_timestamp), ErrorCode::PoolPaused);
+        require!(ctx.accounts.user_stake.amount > 0, ErrorCode::NoStake);
+        check_exit_slot(&ctx.accounts.pool, &ctx.accounts.user_stake, Clock::get()?.slot)?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require_with_context!(
+            amount <= ctx.accounts.user_stake.amount,
+            ErrorCode::InvalidAmount,
+            "staked={} got={}",
+            ctx.accounts.user_stake.amount,
+            amount
+        );
+
+        let clock = Clock::get()?;
+        let remaining_amount = ctx.accounts.user_stake.amount.checked_sub(amount).unwrap();
+        require_with_context!(
+            remaining_amount == 0 || remaining_amount >= ctx.accounts.pool.min_token_stake_amount,
+            ErrorCode::RemainderTooSmall,
+            "min={} remaining={}",
+            ctx.accounts.pool.min_token_stake_amount,
+            remaining_amount
+        );
+
+        // Calculate time staked
+        let time_staked = clock.unix_timestamp.checked_sub(ctx.accounts.user_stake.stake_timestamp).unwrap();
+
+        let unstake_amount = amount;
+
+        // Apply penalty for early exit on the withdrawn portion if commitment not met,
+        // unless the pool has been sunset or is in recovery
+        let penalty_bps =
+            if ctx.accounts.pool.penalties_waived() { 0 } else { ctx.accounts.pool.early_exit_penalty_bps };
+        let penalty_amount = compute_penalty(
+            unstake_amount,
+            penalty_bps,
+            time_staked,
+            ctx.accounts.user_stake.committed_days,
+            ctx.accounts.pool.sliding_penalty_enabled,
+            ctx.accounts.pool.penalty_grace_bps,
+        )?;
+
+        let final_amount = unstake_amount.checked_sub(penalty_amount).unwrap();
+        check_min_payout(final_amount, min_return_amount)?;
+
+        // Transfer tokens back to user, signed by the pool PDA
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.token_vault.to_account_info(),
+                    to: ctx.accounts.user_token_account.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            final_amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        // Update pool state
+        pool.total_token_staked = pool.total_token_staked.checked_sub(unstake_amount).unwrap();
+        pool.total_token_penalties_collected = pool.total_token_penalties_collected.checked_add(penalty_amount).unwrap();
+        if remaining_amount == 0 {
+            uncount_user(pool, user_stake);
+        }
+        pool.last_update = clock.unix_timestamp;
+
+        // Update user stake; the remainder keeps its original commitment and timestamps
+        user_stake.amount = remaining_amount;
+        if remaining_amount == 0 {
+            user_stake.committed_days = 0;
+            user_stake.stake_timestamp = 0;
+            user_stake.last_claim_timestamp = 0;
+            user_stake.locked_apy_bps = 0;
+            user_stake.total_claimed = 0;
+        }
+
+        emit!(SplUnstakeEvent {
+            version: EVENT_VERSION,
+            user: ctx.accounts.user.key(),
+            mint: ctx.accounts.stake_mint.key(),
+            amount: unstake_amount,
+            penalty: penalty_amount,
+            return_amount: final_amount,
+            remaining_amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Create Metaplex metadata for the receipt mint (admin only)
+    pub fn create_receipt_metadata(
+        ctx: Context<CreateReceiptMetadata>,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.metadata.data_is_empty(), ErrorCode::MetadataAlreadyExists);
+        require!(name.len() <= MAX_NAME_LENGTH, ErrorCode::InvalidMetadata);
+        require!(symbol.len() <= MAX_SYMBOL_LENGTH, ErrorCode::InvalidMetadata);
+        require!(uri.len() <= MAX_URI_LENGTH, ErrorCode::InvalidMetadata);
+
+        let clock = Clock::get()?;
+
+        // The pool PDA is both mint authority and update authority
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        metadata::create_metadata_accounts_v3(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                CreateMetadataAccountsV3 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    mint: ctx.accounts.receipt_mint.to_account_info(),
+                    mint_authority: ctx.accounts.pool.to_account_info(),
+                    payer: ctx.accounts.admin.to_account_info(),
+                    update_authority: ctx.accounts.pool.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    rent: ctx.accounts.rent.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            DataV2 {
+                name,
+                symbol,
+                uri,
+                seller_fee_basis_points: 0,
+                creators: None,
+                collection: None,
+                uses: None,
+            },
+            true,
+            true,
+            None,
+        )?;
+
+        ctx.accounts.pool.last_update = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Update the receipt mint metadata (admin only)
+    pub fn update_receipt_metadata(
+        ctx: Context<UpdateReceiptMetadata>,
+        name: String,
+        symbol: String,
+        uri: String,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(name.len() <= MAX_NAME_LENGTH, ErrorCode::InvalidMetadata);
+        require!(symbol.len() <= MAX_SYMBOL_LENGTH, ErrorCode::InvalidMetadata);
+        require!(uri.len() <= MAX_URI_LENGTH, ErrorCode::InvalidMetadata);
+
+        let clock = Clock::get()?;
+
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        metadata::update_metadata_accounts_v2(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_metadata_program.to_account_info(),
+                UpdateMetadataAccountsV2 {
+                    metadata: ctx.accounts.metadata.to_account_info(),
+                    update_authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            None,
+            Some(DataV2 {
+                name,
+                symbol,
+                uri,
+                seller_fee_basis_points: 0,
+                creators: None,
+                collection: None,
+                uses: None,
+            }),
+            None,
+            None,
+        )?;
+
+        ctx.accounts.pool.last_update = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Emergency pause (admin only); pause_flags picks which of deposits, claims and
+    // unstakes stop, PAUSE_ALL halting everything as the single pause flag used to
+    // Each pause opens an incident in the pool's incident log when it is passed.
+    pub fn emergency_pause(ctx: Context<AdminPause>, reason: String, pause_flags: u8) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        check_reason(&reason)?;
+        require!(pause_flags != 0 && pause_flags & !PAUSE_ALL == 0, ErrorCode::InvalidPauseFlags);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        apply_admin_pause(pool, pause_flags, ctx.accounts.admin.key(), clock.unix_timestamp);
+        pool.last_update = clock.unix_timestamp;
+        open_incident(&mut ctx.accounts.incident_log, pause_flags, &reason, clock.unix_timestamp);
+
+        emit!(EmergencyPauseEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            reason,
+            pause_flags,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Guardian pause: takes effect immediately but lapses unless the admin ratifies it
+    pub fn guardian_pause(ctx: Context<GuardianOnly>, reason: String) -> Result<()> {
+        require!(ctx.accounts.pool.guardian != Pubkey::default(), ErrorCode::Unauthorized);
+        require!(ctx.accounts.guardian.key() == ctx.accounts.pool.guardian, ErrorCode::Unauthorized);
+        require!(!ctx.accounts.pool.recovery_mode, ErrorCode::RecoveryModeActive);
+        check_reason(&reason)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        // A guardian cannot shorten a pause that is already in effect
+        require!(!pool_is_paused(pool, clock.unix_timestamp), ErrorCode::PoolPaused);
+
+        let expires_at = clock.unix_timestamp.checked_add(pool.guardian_pause_max_duration).unwrap();
+        pool.pause_flags = PAUSE_ALL;
+        pool.paused_at = clock.unix_timestamp;
+        pool.paused_by = ctx.accounts.guardian.key();
+        pool.pause_expires_at = expires_at;
+        pool.last_update = clock.unix_timestamp;
+        open_incident(&mut ctx.accounts.incident_log, PAUSE_ALL, &reason, clock.unix_timestamp);
+
+        emit!(GuardianPauseEvent {
+            version: EVENT_VERSION,
+            guardian: ctx.accounts.guardian.key(),
+            reason,
+            expires_at,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Create the pool's incident log (admin only); from then on pauses and unpauses should
+    // pass it so support pages and frontends can point users at the incident
+    pub fn init_incident_log(ctx: Context<InitIncidentLog>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let incident_log = &mut ctx.accounts.incident_log;
+        incident_log.pool = ctx.accounts.pool.key();
+        incident_log.bump = ctx.bumps.incident_log;
+        incident_log.total_incidents = 0;
+        incident_log.incidents = [Incident::default(); INCIDENT_LOG_LEN];
+
+        Ok(())
+    }
+
+    // Read-only: the incident behind the pause in effect, if the pool is paused and the
+    // pause was logged
+    pub fn get_active_incident(ctx: Context<ViewIncidents>) -> Result<Option<Incident>> {
+        let now = Clock::get()?.unix_timestamp;
+        let paused = pool_is_paused(&ctx.accounts.pool, now);
+
+        Ok(ctx.accounts.incident_log.latest().filter(|incident| paused && incident.closed_at == 0))
+    }
+
+    // Appoint the guardian and set how long a guardian pause lasts (admin only)
+    pub fn update_guardian(
+        ctx: Context<AdminOnly>,
+        new_guardian: Pubkey,
+        pause_max_duration: i64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 7 days
+        require_with_context!(
+            pause_max_duration > 0 && pause_max_duration <= 7 * 86400,
+            ErrorCode::InvalidPauseDuration,
+            "max={} got={}",
+            7 * 86400,
+            pause_max_duration
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_guardian = pool.guardian;
+
+        pool.guardian = new_guardian;
+        pool.guardian_pause_max_duration = pause_max_duration;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(GuardianUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            old_guardian,
+            new_guardian,
+            pause_max_duration,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Name the authority that takes over if the admin goes silent, and how long the admin
+    // must be inactive before it can (admin only). Every admin instruction restarts the clock.
+    pub fn update_recovery_policy(
+        ctx: Context<AdminOnly>,
+        recovery_authority: Pubkey,
+        admin_inactivity_timeout: i64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            (MIN_ADMIN_INACTIVITY_TIMEOUT..=MAX_ADMIN_INACTIVITY_TIMEOUT).contains(&admin_inactivity_timeout),
+            ErrorCode::InvalidRecoveryPolicy,
+            "min={} max={} got={}",
+            MIN_ADMIN_INACTIVITY_TIMEOUT,
+            MAX_ADMIN_INACTIVITY_TIMEOUT,
+            admin_inactivity_timeout
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_recovery_authority = pool.recovery_authority;
+
+        pool.recovery_authority = recovery_authority;
+        pool.admin_inactivity_timeout = admin_inactivity_timeout;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(RecoveryPolicyUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            old_recovery_authority,
+            new_recovery_authority: recovery_authority,
+            admin_inactivity_timeout,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Put a pool whose admin has been inactive past the timeout into recovery
+    // (permissionless). Pauses lift, deposits stay blocked, exit penalties are waived and
+    // the old admin key is refused until the recovery authority installs a new admin.
+    pub fn activate_recovery_mode(ctx: Context<Recovery>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        require!(!pool.recovery_mode, ErrorCode::RecoveryModeActive);
+        require!(pool.recovery_authority != Pubkey::default(), ErrorCode::NoRecoveryAuthority);
+        require_with_context!(
+            admin_inactive(pool, clock.unix_timestamp),
+            ErrorCode::AdminStillActive,
+            "last_admin_activity={} timeout={} now={}",
+            pool.last_admin_activity,
+            pool.admin_inactivity_timeout,
+            clock.unix_timestamp
+        );
+
+        pool.recovery_mode = true;
+        pool.pause_flags = 0;
+        pool.pause_expires_at = 0;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(RecoveryModeActivatedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            activated_by: ctx.accounts.caller.key(),
+            last_admin_activity: pool.last_admin_activity,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Install a new admin on a pool in recovery (recovery authority only); this ends
+    // recovery and restarts the inactivity clock
+    pub fn recovery_rotate_admin(ctx: Context<Recovery>, new_admin: Pubkey) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.recovery_mode, ErrorCode::NotInRecoveryMode);
+        require!(ctx.accounts.caller.key() == pool.recovery_authority, ErrorCode::Unauthorized);
+        require!(new_admin != Pubkey::default(), ErrorCode::InvalidRecoveryPolicy);
+
+        let clock = Clock::get()?;
+        let old_admin = pool.admin;
+
+        pool.admin = new_admin;
+        pool.recovery_mode = false;
+        pool.last_admin_activity = clock.unix_timestamp;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(RecoveryAdminRotatedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            recovery_authority: ctx.accounts.caller.key(),
+            old_admin,
+            new_admin,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Point fee and penalty withdrawals at a new treasury (admin only)
+    pub fn update_treasury(ctx: Context<AdminOnly>, new_treasury: Pubkey) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(new_treasury != Pubkey::default(), ErrorCode::InvalidTreasury);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_treasury = pool.treasury;
+
+        pool.treasury = new_treasury;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(TreasuryUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            old_treasury,
+            new_treasury,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Emergency unpause (admin only); closes the open incident when the log is passed
+    pub fn emergency_unpause(ctx: Context<AdminPause>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.pause_flags = 0;
+        pool.pause_expires_at = 0;
+        pool.last_update = clock.unix_timestamp;
+        if let Some(incident_log) = &mut ctx.accounts.incident_log {
+            incident_log.close_latest(clock.unix_timestamp);
+        }
+
+        emit!(EmergencyUnpauseEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Wind the pool down (admin only): no new stakes, rates frozen, no yield accrues after
+    // sunset_at and early exits are free so everyone can leave
+    pub fn sunset_pool(ctx: Context<AdminOnly>, sunset_at: i64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        require!(sunset_at >= clock.unix_timestamp, ErrorCode::InvalidSunsetTime);
+
+        pool.is_active = false;
+        pool.sunset_at = sunset_at;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(PoolSunsetEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            sunset_at,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Close a sunset pool that everyone has left, refunding the rent of the pool and its
+    // vaults to the admin (admin only)
+    pub fn close_pool(ctx: Context<ClosePool>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let pool = &ctx.accounts.pool;
+        require!(!pool.is_active, ErrorCode::PoolActive);
+        require!(
+            pool.total_staked == 0
+                && pool.total_fees_collected == 0
+                && pool.total_yields_credited == 0
+                && pool.insurance_balance == 0,
+            ErrorCode::PoolNotEmpty
+        );
+
+        emit!(PoolClosedEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            pool_id: pool.pool_id,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update APY (admin only); open positions see the new cap from their next settlement
+    pub fn update_apy(ctx: Context<AdminOnly>, new_apy: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive); // Rates are frozen at sunset
+        require_with_context!(
+            new_apy > 0 && new_apy <= MAX_APY_BPS,
+            ErrorCode::InvalidApy,
+            "max={} got={}",
+            MAX_APY_BPS,
+            new_apy
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_apy = pool.max_apy;
+
+        pool.max_apy = new_apy;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxApy,
+            old_value: old_apy,
+            new_value: new_apy,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the commitment APY tiers (admin only); existing stakes keep their locked rate
+    pub fn update_apy_tiers(ctx: Context<AdminOnly>, apy_tiers: Vec<ApyTier>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive); // Rates are frozen at sunset
+        validate_apy_tiers(&apy_tiers)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.apy_tiers = apy_tiers.clone();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ApyTiersUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            tiers: apy_tiers,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the lifetime-stake loyalty boosts (admin only); each position picks them up
+    // at its next settlement, so days already accrued keep the boost they earned under
+    pub fn update_loyalty_tiers(ctx: Context<AdminOnly>, loyalty_tiers: Vec<LoyaltyTier>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive); // Rates are frozen at sunset
+        validate_loyalty_tiers(&loyalty_tiers)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.loyalty_tiers = loyalty_tiers.clone();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(LoyaltyTiersUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            tiers: loyalty_tiers,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Set the NFT collection whose holders earn nft_boost_bps (admin only); None disables it
+    pub fn update_nft_boost(
+        ctx: Context<AdminOnly>,
+        boost_collection: Option<Pubkey>,
+        nft_boost_bps: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.is_active, ErrorCode::PoolInactive); // Rates are frozen at sunset
+        require_with_context!(
+            nft_boost_bps <= MAX_NFT_BOOST_BPS,
+            ErrorCode::InvalidNftBoost,
+            "max={} got={}",
+            MAX_NFT_BOOST_BPS,
+            nft_boost_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.boost_collection = boost_collection;
+        pool.nft_boost_bps = nft_boost_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(NftBoostUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            boost_collection,
+            nft_boost_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the deposit-size fee tiers (admin only); deposits below the first tier pay
+    // the base deposit fee
+    pub fn update_fee_tiers(ctx: Context<AdminOnly>, fee_tiers: Vec<FeeTier>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        validate_fee_tiers(&fee_tiers)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.fee_tiers = fee_tiers.clone();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(FeeTiersUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            tiers: fee_tiers,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the long-commitment fee discount and the early-exit grace share (admin only).
+    // A zero threshold turns the discount off, and a 10000 grace share charges the
+    // penalty until the commitment is fully served.
+    pub fn update_commitment_incentives(
+        ctx: Context<AdminOnly>,
+        long_commit_threshold_days: u64,
+        long_commit_fee_discount_bps: u64,
+        penalty_grace_bps: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            long_commit_threshold_days <= 365,
+            ErrorCode::InvalidCommitmentIncentives,
+            "max_threshold_days=365 got={}",
+            long_commit_threshold_days
+        );
+        require_with_context!(
+            long_commit_fee_discount_bps <= 10000,
+            ErrorCode::InvalidCommitmentIncentives,
+            "max_discount_bps=10000 got={}",
+            long_commit_fee_discount_bps
+        );
+        require_with_context!(
+            (5000..=10000).contains(&penalty_grace_bps),
+            ErrorCode::InvalidCommitmentIncentives,
+            "min_grace_bps=5000 max_grace_bps=10000 got={}",
+            penalty_grace_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_threshold_days = pool.long_commit_threshold_days;
+        let old_discount_bps = pool.long_commit_fee_discount_bps;
+        let old_grace_bps = pool.penalty_grace_bps;
+
+        pool.long_commit_threshold_days = long_commit_threshold_days;
+        pool.long_commit_fee_discount_bps = long_commit_fee_discount_bps;
+        pool.penalty_grace_bps = penalty_grace_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::LongCommitThresholdDays,
+            old_value: old_threshold_days,
+            new_value: long_commit_threshold_days,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::LongCommitFeeDiscountBps,
+            old_value: old_discount_bps,
+            new_value: long_commit_fee_discount_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::PenaltyGraceBps,
+            old_value: old_grace_bps,
+            new_value: penalty_grace_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update deposit fee (admin only)
+    pub fn update_deposit_fee(ctx: Context<AdminOnly>, new_fee_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 10%
+        require_with_context!(new_fee_bps <= 1000, ErrorCode::InvalidFee, "max=1000 got={}", new_fee_bps);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_fee = pool.deposit_fee_bps;
+
+        pool.deposit_fee_bps = new_fee_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::DepositFeeBps,
+            old_value: old_fee,
+            new_value: new_fee_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the fee a rollover charges on the principal it carries into a new commitment
+    // (admin only)
+    pub fn update_rollover_fee(ctx: Context<AdminOnly>, new_fee_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 10%, the same ceiling as the deposit fee
+        require_with_context!(new_fee_bps <= 1000, ErrorCode::InvalidFee, "max=1000 got={}", new_fee_bps);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_fee = pool.rollover_fee_bps;
+
+        pool.rollover_fee_bps = new_fee_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::RolloverFeeBps,
+            old_value: old_fee,
+            new_value: new_fee_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Switch the pool between open, whitelist and blacklist staking (admin only)
+    pub fn update_access_mode(ctx: Context<AdminOnly>, new_access_mode: u8) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            new_access_mode <= ACCESS_MODE_BLACKLIST,
+            ErrorCode::InvalidAccessMode,
+            "max={} got={}",
+            ACCESS_MODE_BLACKLIST,
+            new_access_mode
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_access_mode = pool.access_mode;
+
+        pool.access_mode = new_access_mode;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::AccessMode,
+            old_value: old_access_mode as u64,
+            new_value: new_access_mode as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Whitelist or blacklist a user (admin only); only staking is gated, never exits
+    pub fn set_access(ctx: Context<SetAccess>, whitelisted: bool, blacklisted: bool) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let access_entry = &mut ctx.accounts.access_entry;
+        let clock = Clock::get()?;
+
+        access_entry.user = ctx.accounts.user.key();
+        access_entry.whitelisted = whitelisted;
+        access_entry.blacklisted = blacklisted;
+
+        emit!(AccessChangedEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            user: ctx.accounts.user.key(),
+            whitelisted,
+            blacklisted,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the referrer's share of the deposit fee (admin only)
+    pub fn update_referral_share(ctx: Context<AdminOnly>, new_share_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 50% of the fee
+        require_with_context!(new_share_bps <= 5000, ErrorCode::InvalidFee, "max=5000 got={}", new_share_bps);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_share = pool.referral_share_bps;
+
+        pool.referral_share_bps = new_share_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::ReferralShareBps,
+            old_value: old_share,
+            new_value: new_share_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update how far the deposit fee may have moved from a staker's quote (admin only)
+    pub fn update_max_slippage(ctx: Context<AdminOnly>, new_max_slippage_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 10% of the deposit
+        require_with_context!(
+            new_max_slippage_bps <= 1000,
+            ErrorCode::InvalidFee,
+            "max=1000 got={}",
+            new_max_slippage_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_max_slippage = pool.max_slippage_bps;
+
+        pool.max_slippage_bps = new_max_slippage_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxSlippageBps,
+            old_value: old_max_slippage,
+            new_value: new_max_slippage_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the withdrawal queue cooldown and the instant-exit option (admin only)
+    pub fn update_withdrawal_settings(
+        ctx: Context<AdminOnly>,
+        cooldown_seconds: i64,
+        instant_exit_enabled: bool,
+        instant_exit_penalty_bps: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 30 days
+        require_with_context!(
+            (0..=30 * 86400).contains(&cooldown_seconds),
+            ErrorCode::InvalidCooldown,
+            "max={} got={}",
+            30 * 86400,
+            cooldown_seconds
+        );
+        // Max 20% surcharge
+        require_with_context!(
+            instant_exit_penalty_bps <= 2000,
+            ErrorCode::InvalidPenalty,
+            "max=2000 got={}",
+            instant_exit_penalty_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_cooldown = pool.withdrawal_cooldown_seconds;
+        let old_instant_exit_penalty = pool.instant_exit_penalty_bps;
+
+        pool.withdrawal_cooldown_seconds = cooldown_seconds;
+        pool.instant_exit_enabled = instant_exit_enabled;
+        pool.instant_exit_penalty_bps = instant_exit_penalty_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::WithdrawalCooldownSeconds,
+            old_value: old_cooldown as u64,
+            new_value: cooldown_seconds as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::InstantExitPenaltyBps,
+            old_value: old_instant_exit_penalty,
+            new_value: instant_exit_penalty_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Set how long withdrawals must stay paused before emergency_withdraw opens (admin only)
+    pub fn update_emergency_withdraw_delay(ctx: Context<AdminOnly>, delay_seconds: i64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            (0..=MAX_EMERGENCY_WITHDRAW_DELAY).contains(&delay_seconds),
+            ErrorCode::InvalidEmergencyWithdrawDelay,
+            "max={} got={}",
+            MAX_EMERGENCY_WITHDRAW_DELAY,
+            delay_seconds
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_delay = pool.emergency_withdraw_delay;
+
+        pool.emergency_withdraw_delay = delay_seconds;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::EmergencyWithdrawDelay,
+            old_value: old_delay as u64,
+            new_value: delay_seconds as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the share of TVL that may leave the pool per rolling 24h (admin only)
+    pub fn update_max_daily_outflow(ctx: Context<AdminOnly>, new_max_daily_outflow_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            new_max_daily_outflow_bps > 0 && new_max_daily_outflow_bps <= 10000,
+            ErrorCode::InvalidOutflowCap,
+            "max=10000 got={}",
+            new_max_daily_outflow_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_max_daily_outflow = pool.max_daily_outflow_bps;
+
+        pool.max_daily_outflow_bps = new_max_daily_outflow_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxDailyOutflowBps,
+            old_value: old_max_daily_outflow,
+            new_value: new_max_daily_outflow_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update early-exit penalty (admin only)
+    pub fn update_early_exit_penalty(
+        ctx: Context<AdminOnly>,
+        new_penalty_bps: u64,
+        sliding_scale: bool,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 20%
+        require_with_context!(new_penalty_bps <= 2000, ErrorCode::InvalidPenalty, "max=2000 got={}", new_penalty_bps);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_penalty = pool.early_exit_penalty_bps;
+        let old_sliding_scale = pool.sliding_penalty_enabled;
+
+        pool.early_exit_penalty_bps = new_penalty_bps;
+        pool.sliding_penalty_enabled = sliding_scale;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::EarlyExitPenaltyBps,
+            old_value: old_penalty,
+            new_value: new_penalty_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        if old_sliding_scale != sliding_scale {
+            emit!(ParameterUpdateEvent {
+                version: EVENT_VERSION,
+                admin: ctx.accounts.admin.key(),
+                parameter: PoolParameter::SlidingPenaltyEnabled,
+                old_value: old_sliding_scale as u64,
+                new_value: sliding_scale as u64,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Update pool limits (admin only)
+    pub fn update_pool_limits(
+        ctx: Context<AdminOnly>,
+        new_min_stake: u64,
+        new_max_stake: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            new_min_stake > 0 && new_max_stake > new_min_stake,
+            ErrorCode::InvalidAmount,
+            "min={} max={}",
+            new_min_stake,
+            new_max_stake
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        let old_min_stake = pool.min_stake_amount;
+        let old_max_stake = pool.max_stake_amount;
+
+        pool.min_stake_amount = new_min_stake;
+        pool.max_stake_amount = new_max_stake;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinStakeAmount,
+            old_value: old_min_stake,
+            new_value: new_min_stake,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxStakeAmount,
+            old_value: old_max_stake,
+            new_value: new_max_stake,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the token pool's per-stake limits, in the stake mint's base units (admin only)
+    pub fn update_token_stake_limits(
+        ctx: Context<AdminOnly>,
+        new_min_stake: u64,
+        new_max_stake: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.stake_mint.is_some(), ErrorCode::InvalidMint);
+        require_with_context!(
+            new_min_stake > 0 && new_max_stake > new_min_stake,
+            ErrorCode::InvalidAmount,
+            "min={} max={}",
+            new_min_stake,
+            new_max_stake
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        let old_min_stake = pool.min_token_stake_amount;
+        let old_max_stake = pool.max_token_stake_amount;
+
+        pool.min_token_stake_amount = new_min_stake;
+        pool.max_token_stake_amount = new_max_stake;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinTokenStakeAmount,
+            old_value: old_min_stake,
+            new_value: new_min_stake,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxTokenStakeAmount,
+            old_value: old_max_stake,
+            new_value: new_max_stake,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Share of the reward reserve that yield projected over every position's commitment
+    // may reach before stakes are refused (admin only); 0 turns the check off
+    pub fn update_solvency_factor(ctx: Context<AdminOnly>, solvency_factor_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            solvency_factor_bps <= MAX_SOLVENCY_FACTOR_BPS,
+            ErrorCode::InvalidSolvencyFactor,
+            "max={} got={}",
+            MAX_SOLVENCY_FACTOR_BPS,
+            solvency_factor_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_solvency_factor_bps = pool.solvency_factor_bps;
+
+        pool.solvency_factor_bps = solvency_factor_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::SolvencyFactorBps,
+            old_value: old_solvency_factor_bps,
+            new_value: solvency_factor_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Lamports the vaults may fall short of the books before verify_solvency pauses
+    // deposits (admin only)
+    pub fn update_max_reconciliation_delta(ctx: Context<AdminOnly>, max_reconciliation_delta: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_max_reconciliation_delta = pool.max_reconciliation_delta;
+
+        pool.max_reconciliation_delta = max_reconciliation_delta;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxReconciliationDelta,
+            old_value: old_max_reconciliation_delta,
+            new_value: max_reconciliation_delta,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Change the shortest commitment new stakes may choose (admin only). Each change starts
+    // a new policy version; open positions are grandfathered at the commitment they were
+    // opened under and may adopt the new minimum with migrate_commitment.
+    pub fn update_min_commitment_days(ctx: Context<AdminOnly>, min_commitment_days: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            min_commitment_days > 0 && min_commitment_days <= ctx.accounts.pool.max_commitment_days,
+            ErrorCode::InvalidCommitmentDays,
+            "max_days={} got={}",
+            ctx.accounts.pool.max_commitment_days,
+            min_commitment_days
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_min_days = pool.min_commitment_days;
+
+        if min_commitment_days != old_min_days {
+            pool.policy_version = pool.policy_version.checked_add(1).unwrap();
+        }
+        pool.min_commitment_days = min_commitment_days;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(CommitmentPolicyUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            pool: pool.key(),
+            old_min_days,
+            new_min_days: min_commitment_days,
+            policy_version: pool.policy_version,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // One-time bonus, in bps of principal, for moving a grandfathered position onto the
+    // current minimum commitment (admin only); capped at 10%
+    pub fn update_migration_bonus_bps(ctx: Context<AdminOnly>, migration_bonus_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            migration_bonus_bps <= 1000,
+            ErrorCode::InvalidCommitmentIncentives,
+            "max=1000 got={}",
+            migration_bonus_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_migration_bonus_bps = pool.migration_bonus_bps;
+
+        pool.migration_bonus_bps = migration_bonus_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MigrationBonusBps,
+            old_value: old_migration_bonus_bps,
+            new_value: migration_bonus_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Rolling commitments (admin only): a position whose commitment ended more than
+    // renewal_grace_seconds ago renews into another of the same length, and one whose
+    // owner opted out earns idle_apy_bps after its commitment. A grace of 0 turns renewal
+    // off. Turning it on also rolls open positions already past their grace window.
+    pub fn update_renewal_policy(
+        ctx: Context<AdminOnly>,
+        renewal_grace_seconds: i64,
+        idle_apy_bps: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            (0..=MAX_RENEWAL_GRACE_SECONDS).contains(&renewal_grace_seconds),
+            ErrorCode::InvalidRenewalPolicy,
+            "max={} got={}",
+            MAX_RENEWAL_GRACE_SECONDS,
+            renewal_grace_seconds
+        );
+        require_with_context!(
+            idle_apy_bps <= MAX_APY_BPS,
+            ErrorCode::InvalidRenewalPolicy,
+            "max={} got={}",
+            MAX_APY_BPS,
+            idle_apy_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_renewal_grace_seconds = pool.renewal_grace_seconds;
+        let old_idle_apy_bps = pool.idle_apy_bps;
+
+        pool.renewal_grace_seconds = renewal_grace_seconds;
+        pool.idle_apy_bps = idle_apy_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::RenewalGraceSeconds,
+            old_value: old_renewal_grace_seconds as u64,
+            new_value: renewal_grace_seconds as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::IdleApyBps,
+            old_value: old_idle_apy_bps,
+            new_value: idle_apy_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Smallest yield a claim may pay out (admin only); 0 turns the minimum off
+    pub fn update_min_claim_amount(ctx: Context<AdminOnly>, min_claim_amount: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            min_claim_amount <= MAX_MIN_CLAIM_AMOUNT,
+            ErrorCode::InvalidAmount,
+            "max={} got={}",
+            MAX_MIN_CLAIM_AMOUNT,
+            min_claim_amount
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_min_claim_amount = pool.min_claim_amount;
+
+        pool.min_claim_amount = min_claim_amount;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinClaimAmount,
+            old_value: old_min_claim_amount,
+            new_value: min_claim_amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Cap yield payouts per claim and per owner per year (admin only); 0 lifts a cap.
+    // Yield over a cap is never forfeited, it stays owed until the owner's next window.
+    pub fn update_yield_caps(
+        ctx: Context<AdminOnly>,
+        max_yield_per_claim: u64,
+        max_yield_per_user_per_year: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        let old_max_per_claim = pool.max_yield_per_claim;
+        let old_max_per_year = pool.max_yield_per_user_per_year;
+
+        pool.max_yield_per_claim = max_yield_per_claim;
+        pool.max_yield_per_user_per_year = max_yield_per_user_per_year;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxYieldPerClaim,
+            old_value: old_max_per_claim,
+            new_value: max_yield_per_claim,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MaxYieldPerUserPerYear,
+            old_value: old_max_per_year,
+            new_value: max_yield_per_user_per_year,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Fund the reward vault that yields are paid from (admin only)
+    pub fn fund_rewards(ctx: Context<FundRewards>, amount: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.admin.key(),
+            &ctx.accounts.reward_vault.key(),
+            amount,
+        );
+
+        anchor_lang::solana_program::program::invoke(
+            &transfer_instruction,
+            &[
+                ctx.accounts.admin.to_account_info(),
+                ctx.accounts.reward_vault.to_account_info(),
+            ],
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.reward_reserve = pool.reward_reserve.checked_add(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(RewardsFundedEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            amount,
+            reward_reserve: pool.reward_reserve,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Switch the pool to paying yields in an SPL reward token and create the vault that
+    // holds them (admin only). Principal stays in SOL. The switch is one-way: SOL yield
+    // paths refuse from here on, so the reward token price must be set before claims.
+    pub fn initialize_reward_token_vault(ctx: Context<InitializeRewardTokenVault>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.reward_mint.is_none(), ErrorCode::InvalidMint);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.reward_mint = Some(ctx.accounts.reward_mint.key());
+        pool.reward_token_vault_bump = ctx.bumps.reward_token_vault;
+        pool.last_update = clock.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Set how many reward token base units a claim pays per SOL of yield (admin only)
+    pub fn update_reward_token_price(ctx: Context<AdminOnly>, reward_tokens_per_sol: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(ctx.accounts.pool.reward_mint.is_some(), ErrorCode::InvalidMint);
+        require_with_context!(
+            reward_tokens_per_sol > 0,
+            ErrorCode::InvalidRewardTokenPrice,
+            "got={}",
+            reward_tokens_per_sol
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_price = pool.reward_tokens_per_sol;
+
+        pool.reward_tokens_per_sol = reward_tokens_per_sol;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::RewardTokensPerSol,
+            old_value: old_price,
+            new_value: reward_tokens_per_sol,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Add reward tokens to the pool's reward token vault from the admin's account
+    pub fn fund_token_rewards(ctx: Context<FundTokenRewards>, amount: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        token::transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.admin_token_account.to_account_info(),
+                    to: ctx.accounts.reward_token_vault.to_account_info(),
+                    authority: ctx.accounts.admin.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        ctx.accounts.reward_token_vault.reload()?;
+
+        let clock = Clock::get()?;
+        ctx.accounts.pool.last_update = clock.unix_timestamp;
+
+        emit!(TokenRewardsFundedEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            reward_mint: ctx.accounts.reward_mint.key(),
+            amount,
+            vault_balance: ctx.accounts.reward_token_vault.amount,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Create the pool's insurance vault (admin only); the rebalance that sweeps the
+    // insurance share of fees into it needs it to exist
+    pub fn initialize_insurance_vault(ctx: Context<InitializeInsuranceVault>) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+
+        ctx.accounts.pool.insurance_vault_bump = ctx.bumps.insurance_vault;
+        ctx.accounts.pool.last_update = Clock::get()?.unix_timestamp;
+
+        Ok(())
+    }
+
+    // Donate to the insurance fund (anyone)
+    pub fn fund_insurance(ctx: Context<FundInsurance>, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+            &ctx.accounts.funder.key(),
+            &ctx.accounts.insurance_vault.key(),
+            amount,
+        );
+
+        anchor_lang::solana_program::program::invoke(
+            &transfer_instruction,
+            &[
+                ctx.accounts.funder.to_account_info(),
+                ctx.accounts.insurance_vault.to_account_info(),
+            ],
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.insurance_balance = pool.insurance_balance.checked_add(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(InsuranceFundedEvent {
+            version: EVENT_VERSION,
+            funder: ctx.accounts.funder.key(),
+            amount,
+            insurance_balance: pool.insurance_balance,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Pay an insurance claim from the insurance vault (admin only); reason_hash commits
+    // to the off-chain incident report the payout is for
+    pub fn pay_insurance_claim(ctx: Context<PayInsuranceClaim>, amount: u64, reason_hash: [u8; 32]) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require!(ctx.accounts.pool.insurance_balance >= amount, ErrorCode::InsufficientFunds);
+
+        transfer_from_vault(
+            &ctx.accounts.insurance_vault,
+            &ctx.accounts.recipient.to_account_info(),
+            amount,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.insurance_balance = pool.insurance_balance.checked_sub(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(InsurancePayoutEvent {
+            version: EVENT_VERSION,
+            recipient: ctx.accounts.recipient.key(),
+            amount,
+            reason_hash,
+            insurance_balance: pool.insurance_balance,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the share of each deposit fee set aside for insurance (admin only)
+    pub fn update_insurance_share(ctx: Context<AdminOnly>, new_share_bps: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // Max 50% of the fee, so with the referral share it never exceeds the whole fee
+        require_with_context!(new_share_bps <= 5000, ErrorCode::InvalidFee, "max=5000 got={}", new_share_bps);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_share = pool.insurance_share_bps;
+
+        pool.insurance_share_bps = new_share_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::InsuranceShareBps,
+            old_value: old_share,
+            new_value: new_share_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Reward reserve as bps of one year of yield at the pool's highest APY (view)
+    pub fn solvency_ratio(ctx: Context<ViewPool>) -> Result<u64> {
+        let pool = &ctx.accounts.pool;
+
+        Ok(calculate_solvency_ratio_bps(pool.reward_reserve, pool.total_staked, highest_apy_bps(pool)))
+    }
+
+    // Update the leverage cap on new stakes (admin only)
+    pub fn update_leverage_ratio(ctx: Context<AdminOnly>, new_leverage_ratio: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        validate_proposal_value(PARAM_LEVERAGE_RATIO, new_leverage_ratio)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_leverage_ratio = pool.leverage_ratio;
+
+        pool.leverage_ratio = new_leverage_ratio;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::LeverageRatio,
+            old_value: old_leverage_ratio,
+            new_value: new_leverage_ratio,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update how many slots a deposit must wait before the position can exit or claim
+    // (admin only)
+    pub fn update_min_slots_before_exit(ctx: Context<AdminOnly>, new_min_slots_before_exit: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        validate_proposal_value(PARAM_MIN_SLOTS_BEFORE_EXIT, new_min_slots_before_exit)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_min_slots_before_exit = pool.min_slots_before_exit;
+
+        pool.min_slots_before_exit = new_min_slots_before_exit;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinSlotsBeforeExit,
+            old_value: old_min_slots_before_exit,
+            new_value: new_min_slots_before_exit,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the share of deposit fees rebalance moves into the reward reserve (admin only)
+    pub fn update_reinvestment_rate(ctx: Context<AdminOnly>, new_reinvestment_rate: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        validate_proposal_value(PARAM_REINVESTMENT_RATE, new_reinvestment_rate)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_reinvestment_rate = pool.reinvestment_rate;
+
+        pool.reinvestment_rate = new_reinvestment_rate;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::ReinvestmentRate,
+            old_value: old_reinvestment_rate,
+            new_value: new_reinvestment_rate,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Rebalance crank, open to anyone at most once per rebalance_interval: reinvest the
+    // fee share due to the reward reserve, refresh projected_apy from the reserve runway,
+    // and pause the pool if the runway has fallen below the configured minimum
+    pub fn rebalance(ctx: Context<Rebalance>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        let next_rebalance_at = pool.last_rebalance_timestamp.checked_add(pool.rebalance_interval).unwrap();
+        require!(clock.unix_timestamp >= next_rebalance_at, ErrorCode::RebalanceTooEarly);
+
+        // The insurance share of fees collected since the last rebalance moves to its vault
+        let insurance_swept = pool.insurance_pending;
+        if insurance_swept > 0 {
+            transfer_from_vault(
+                &ctx.accounts.fee_vault,
+                &ctx.accounts.insurance_vault.to_account_info(),
+                insurance_swept,
+            )?;
+
+            pool.total_fees_collected = pool.total_fees_collected.checked_sub(insurance_swept).unwrap();
+            pool.insurance_balance = pool.insurance_balance.checked_add(insurance_swept).unwrap();
+            pool.insurance_pending = 0;
+        }
+
+        let fees_reinvested = reinvestment_due(pool)?;
+        if fees_reinvested > 0 {
+            transfer_from_vault(
+                &ctx.accounts.fee_vault,
+                &ctx.accounts.reward_vault.to_account_info(),
+                fees_reinvested,
+            )?;
+
+            pool.total_fees_collected = pool.total_fees_collected.checked_sub(fees_reinvested).unwrap();
+            pool.reward_reserve = pool.reward_reserve.checked_add(fees_reinvested).unwrap();
+        }
+        pool.fees_awaiting_rebalance = 0;
+
+        let apy_bps = highest_apy_bps(pool);
+        let runway_days = reserve_runway_days(pool.reward_reserve, pool.total_staked, apy_bps);
+        pool.projected_apy = projected_apy_bps(apy_bps, runway_days);
+        pool.last_rebalance_timestamp = clock.unix_timestamp;
+        pool.last_update = clock.unix_timestamp;
+
+        // The pool pauses itself with no expiry; only the admin can lift it
+        if runway_days < pool.min_runway_days && !pool_is_paused(pool, clock.unix_timestamp) {
+            pool.pause_flags = PAUSE_ALL;
+            pool.paused_at = clock.unix_timestamp;
+            pool.paused_by = pool.key();
+            pool.pause_expires_at = 0;
+
+            emit!(ReserveRunwayPauseEvent {
+                version: EVENT_VERSION,
+                runway_days,
+                min_runway_days: pool.min_runway_days,
+                timestamp: clock.unix_timestamp,
+            });
+        }
+
+        emit!(RebalanceEvent {
+            version: EVENT_VERSION,
+            fees_reinvested,
+            insurance_swept,
+            reward_reserve: pool.reward_reserve,
+            projected_apy: pool.projected_apy,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Reconcile the books against the lamports the pool, fee and reward vaults hold above
+    // their rent-exempt minimums (anyone). A shortfall beyond max_reconciliation_delta
+    // pauses deposits until the admin has looked into it.
+    pub fn verify_solvency(ctx: Context<VerifySolvency>) -> Result<()> {
+        let rent = Rent::get()?;
+        let actual = [&ctx.accounts.pool_vault, &ctx.accounts.fee_vault, &ctx.accounts.reward_vault]
+            .iter()
+            .map(|vault| {
+                let vault_info = vault.to_account_info();
+                vault_info.lamports().saturating_sub(rent.minimum_balance(vault_info.data_len()))
+            })
+            .fold(0u64, |total, held| total.saturating_add(held));
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let expected = expected_vault_lamports(pool);
+        let delta = reconciliation_delta(expected, actual);
+        let ok = books_balance(delta, pool.max_reconciliation_delta);
+
+        // Like the runway pause, this never overrides a pause already in effect
+        let deposits_paused = !ok && !pool_is_paused(pool, clock.unix_timestamp);
+        if deposits_paused {
+            pool.pause_flags = PAUSE_DEPOSITS;
+            pool.paused_at = clock.unix_timestamp;
+            pool.paused_by = pool.key();
+            pool.pause_expires_at = 0;
+            pool.last_update = clock.unix_timestamp;
+        }
+
+        emit!(SolvencyCheckEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            expected,
+            actual,
+            delta,
+            ok,
+            deposits_paused,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Record the pool's accounting in a new snapshot PDA; anyone may call it once per
+    // snapshot_interval, and the caller's rent is returned when the snapshot is pruned
+    pub fn take_snapshot(ctx: Context<TakeSnapshot>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        let next_snapshot_at = pool.last_snapshot_timestamp.checked_add(pool.snapshot_interval).unwrap();
+        require!(clock.unix_timestamp >= next_snapshot_at, ErrorCode::SnapshotTooEarly);
+
+        let snapshot = &mut ctx.accounts.snapshot;
+        snapshot.epoch_index = pool.snapshot_count;
+        snapshot.payer = ctx.accounts.payer.key();
+        snapshot.total_staked = pool.total_staked;
+        snapshot.total_users = pool.total_users;
+        snapshot.total_fees_collected = pool.total_fees_collected;
+        snapshot.total_yields_paid = pool.total_yields_paid;
+        snapshot.reward_reserve = pool.reward_reserve;
+        snapshot.timestamp = clock.unix_timestamp;
+
+        pool.snapshot_count = pool.snapshot_count.checked_add(1).unwrap();
+        pool.last_snapshot_timestamp = clock.unix_timestamp;
+
+        emit!(SnapshotTakenEvent {
+            version: EVENT_VERSION,
+            epoch_index: snapshot.epoch_index,
+            total_staked: snapshot.total_staked,
+            reward_reserve: snapshot.reward_reserve,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Prune a snapshot that has fallen out of the retention window; anyone may call it and
+    // the rent goes back to whoever paid for the snapshot
+    pub fn close_snapshot(ctx: Context<CloseSnapshot>) -> Result<()> {
+        require!(
+            snapshot_expired(
+                ctx.accounts.snapshot.epoch_index,
+                ctx.accounts.pool.snapshot_count,
+                ctx.accounts.pool.snapshot_retention_epochs,
+            ),
+            ErrorCode::SnapshotRetained
+        );
+
+        Ok(())
+    }
+
+    // Update the snapshot cadence and how many epochs are kept (admin only)
+    pub fn update_snapshot_settings(
+        ctx: Context<AdminOnly>,
+        snapshot_interval: i64,
+        retention_epochs: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            (1..=30 * 86400).contains(&snapshot_interval) && retention_epochs > 0,
+            ErrorCode::InvalidSnapshotSettings,
+            "max_interval={} interval={} retention_epochs={}",
+            30 * 86400,
+            snapshot_interval,
+            retention_epochs
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_interval = pool.snapshot_interval;
+        let old_retention_epochs = pool.snapshot_retention_epochs;
+
+        pool.snapshot_interval = snapshot_interval;
+        pool.snapshot_retention_epochs = retention_epochs;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::SnapshotInterval,
+            old_value: old_interval as u64,
+            new_value: snapshot_interval as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::SnapshotRetentionEpochs,
+            old_value: old_retention_epochs,
+            new_value: retention_epochs,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the per-slot volume that triggers the block delay and the delay's length in
+    // slots (admin only)
+    pub fn update_mev_settings(
+        ctx: Context<AdminOnly>,
+        large_operation_threshold: u64,
+        min_block_delay: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        // A delay of up to about a minute
+        require_with_context!(
+            large_operation_threshold > 0 && (1..=150).contains(&min_block_delay),
+            ErrorCode::InvalidMevSettings,
+            "max_delay=150 threshold={} delay={}",
+            large_operation_threshold,
+            min_block_delay
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_threshold = pool.large_operation_threshold;
+        let old_min_block_delay = pool.min_block_delay;
+
+        pool.large_operation_threshold = large_operation_threshold;
+        pool.min_block_delay = min_block_delay;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::LargeOperationThreshold,
+            old_value: old_threshold,
+            new_value: large_operation_threshold,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinBlockDelay,
+            old_value: old_min_block_delay,
+            new_value: min_block_delay,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update how often rebalance may run and the runway that trips the automatic pause
+    // (admin only); a zero minimum runway turns the automatic pause off
+    pub fn update_rebalance_settings(
+        ctx: Context<AdminOnly>,
+        rebalance_interval: i64,
+        min_runway_days: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            (1..=30 * 86400).contains(&rebalance_interval) && min_runway_days <= 3650,
+            ErrorCode::InvalidRebalanceSettings,
+            "max_interval={} interval={} max_runway_days=3650 runway_days={}",
+            30 * 86400,
+            rebalance_interval,
+            min_runway_days
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_interval = pool.rebalance_interval;
+        let old_min_runway_days = pool.min_runway_days;
+
+        pool.rebalance_interval = rebalance_interval;
+        pool.min_runway_days = min_runway_days;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::RebalanceInterval,
+            old_value: old_interval as u64,
+            new_value: rebalance_interval as u64,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::MinRunwayDays,
+            old_value: old_min_runway_days,
+            new_value: min_runway_days,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Propose a parameter change; the proposer must hold at least the pool's threshold
+    pub fn create_proposal(
+        ctx: Context<CreateProposal>,
+        param_key: u8,
+        new_value: u64,
+        voting_ends_at: i64,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        require!(pool.proposal_threshold > 0, ErrorCode::GovernanceDisabled);
+        require!(
+            accruing_amount(&ctx.accounts.user_stake) >= pool.proposal_threshold,
+            ErrorCode::ProposalThresholdNotMet
+        );
+        validate_proposal_value(param_key, new_value)?;
+        let voting_period = voting_ends_at.saturating_sub(clock.unix_timestamp);
+        require!(
+            (MIN_VOTING_PERIOD..=MAX_VOTING_PERIOD).contains(&voting_period),
+            ErrorCode::InvalidProposal
+        );
+
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.proposal_id = pool.next_proposal_id;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.param_key = param_key;
+        proposal.new_value = new_value;
+        proposal.voting_ends_at = voting_ends_at;
+        // Quorum is fixed at creation so stake moving later cannot change the bar
+        proposal.quorum_votes = quorum_votes(pool.total_staked, pool.governance_quorum_bps);
+        proposal.votes_for = 0;
+        proposal.votes_against = 0;
+        proposal.status = PROPOSAL_STATUS_ACTIVE;
+        proposal.created_at = clock.unix_timestamp;
+
+        pool.next_proposal_id = pool.next_proposal_id.checked_add(1).unwrap();
+
+        emit!(ProposalCreatedEvent {
+            version: EVENT_VERSION,
+            proposal_id: proposal.proposal_id,
+            proposer: proposal.proposer,
+            param_key,
+            new_value,
+            voting_ends_at,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Vote with the current stake; the vote record PDA can only be created once, and the
+    // position cannot leave until voting ends so the same stake never counts twice
+    pub fn vote(ctx: Context<Vote>, support: bool) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        let user_stake = &mut ctx.accounts.user_stake;
+        let clock = Clock::get()?;
+
+        require!(proposal.status == PROPOSAL_STATUS_ACTIVE, ErrorCode::ProposalNotActive);
+        require!(clock.unix_timestamp < proposal.voting_ends_at, ErrorCode::VotingClosed);
+
+        let weight = accruing_amount(user_stake);
+        require!(weight > 0, ErrorCode::NoStake);
+
+        if support {
+            proposal.votes_for = proposal.votes_for.checked_add(weight).unwrap();
+        } else {
+            proposal.votes_against = proposal.votes_against.checked_add(weight).unwrap();
+        }
+        user_stake.vote_locked_until = user_stake.vote_locked_until.max(proposal.voting_ends_at);
+
+        let vote_record = &mut ctx.accounts.vote_record;
+        vote_record.proposal_id = proposal.proposal_id;
+        vote_record.voter = ctx.accounts.voter.key();
+        vote_record.support = support;
+        vote_record.weight = weight;
+        vote_record.voted_at = clock.unix_timestamp;
+
+        emit!(VoteCastEvent {
+            version: EVENT_VERSION,
+            proposal_id: proposal.proposal_id,
+            voter: vote_record.voter,
+            support,
+            weight,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Settle a proposal once voting and the execution delay are over; anyone may call it
+    pub fn finalize_proposal(ctx: Context<FinalizeProposal>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let proposal = &mut ctx.accounts.proposal;
+        let clock = Clock::get()?;
+
+        require!(proposal.status == PROPOSAL_STATUS_ACTIVE, ErrorCode::ProposalNotActive);
+        let executable_at = proposal.voting_ends_at.checked_add(EXECUTION_DELAY).unwrap();
+        require!(clock.unix_timestamp >= executable_at, ErrorCode::ExecutionDelayNotElapsed);
+
+        let passed = proposal_passed(proposal.votes_for, proposal.votes_against, proposal.quorum_votes);
+        let old_value = if passed {
+            proposal.status = PROPOSAL_STATUS_EXECUTED;
+            pool.last_update = clock.unix_timestamp;
+            apply_proposal(pool, proposal.param_key, proposal.new_value)?
+        } else {
+            proposal.status = PROPOSAL_STATUS_REJECTED;
+            0
+        };
+
+        emit!(ProposalFinalizedEvent {
+            version: EVENT_VERSION,
+            proposal_id: proposal.proposal_id,
+            passed,
+            param_key: proposal.param_key,
+            old_value,
+            new_value: proposal.new_value,
+            votes_for: proposal.votes_for,
+            votes_against: proposal.votes_against,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Update the proposal threshold and quorum (admin only); a zero threshold turns
+    // governance off
+    pub fn update_governance_settings(
+        ctx: Context<AdminOnly>,
+        proposal_threshold: u64,
+        quorum_bps: u64,
+    ) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require_with_context!(
+            quorum_bps > 0 && quorum_bps <= 10000,
+            ErrorCode::InvalidProposal,
+            "max_quorum_bps=10000 got={}",
+            quorum_bps
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let old_threshold = pool.proposal_threshold;
+        let old_quorum_bps = pool.governance_quorum_bps;
+
+        pool.proposal_threshold = proposal_threshold;
+        pool.governance_quorum_bps = quorum_bps;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::ProposalThreshold,
+            old_value: old_threshold,
+            new_value: proposal_threshold,
+            timestamp: clock.unix_timestamp,
+        });
+
+        emit!(ParameterUpdateEvent {
+            version: EVENT_VERSION,
+            admin: ctx.accounts.admin.key(),
+            parameter: PoolParameter::GovernanceQuorumBps,
+            old_value: old_quorum_bps,
+            new_value: quorum_bps,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Upgrade a pool written by an older program version; the data is preserved, so
+    // anyone may pay for the migration
+    pub fn migrate_pool(ctx: Context<MigrateAccount>) -> Result<()> {
+        let account = ctx.accounts.legacy_account.to_account_info();
+        let mut pool = {
+            let data = account.try_borrow_data()?;
+            let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION)?;
+            upgrade_pool(version, body)?
+        };
+        derive_pool_bumps(&mut pool);
+        write_migrated_account(&account, &ctx.accounts.payer, &pool, 8 + Pool::INIT_SPACE)?;
+
+        emit!(AccountMigratedEvent {
+            version: EVENT_VERSION,
+            account: account.key(),
+            layout_version: POOL_VERSION,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Upgrade a SOL or SPL position written by an older program version. A position does
+    // not store its pool, so the caller names it, plus the mint for an SPL position, and
+    // the canonical bump is only recorded if those seeds derive the position's address.
+    pub fn migrate_user_stake(ctx: Context<MigrateAccount>, pool_id: u64, stake_mint: Option<Pubkey>) -> Result<()> {
+        let account = ctx.accounts.legacy_account.to_account_info();
+        let mut user_stake = {
+            let data = account.try_borrow_data()?;
+            let (version, body) =
+                split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION)?;
+            upgrade_user_stake(version, body)?
+        };
+        let (address, bump) = position_address(pool_id, stake_mint, &user_stake.user);
+        require_keys_eq!(address, account.key(), ErrorCode::InvalidPositionAddress);
+        user_stake.bump = bump;
+        write_migrated_account(&account, &ctx.accounts.payer, &user_stake, 8 + UserStake::INIT_SPACE)?;
+
+        emit!(AccountMigratedEvent {
+            version: EVENT_VERSION,
+            account: account.key(),
+            layout_version: USER_STAKE_VERSION,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Read-only: yields a claim would pay right now, plus days left on the commitment.
+    // The NFT boost needs the holder's accounts, so it is not included here.
+    pub fn get_pending_yields(ctx: Context<ViewStake>) -> Result<PendingYields> {
+        pending_yields(&ctx.accounts.pool, &ctx.accounts.user_stake, Clock::get()?.unix_timestamp)
+    }
+
+    // Read-only: what a position is worth, for programs valuing it over CPI. The layout
+    // is published without program dependencies in the defi-trust-fund-interface crate.
+    pub fn get_position_value(ctx: Context<ViewStake>) -> Result<PositionValue> {
+        position_value(&ctx.accounts.pool, &ctx.accounts.user_stake, Clock::get()?.unix_timestamp)
+    }
+
+    // Read-only: pool totals for CPI callers, in the interface crate's layout
+    pub fn get_pool_stats(ctx: Context<ViewPool>) -> Result<PoolStats> {
+        Ok(pool_stats(&ctx.accounts.pool))
+    }
+
+    // Read-only: what stake(amount, committed_days) would do for the user right now, for
+    // wallets to show before signing. A refused stake is reported in the result rather
+    // than failing, and nothing the preview evaluates is kept.
+    pub fn preview_stake(ctx: Context<PreviewStake>, amount: u64, committed_days: u64) -> Result<OperationPreview> {
+        let user_stake = ctx.accounts.user_stake.as_deref().cloned().unwrap_or_else(|| UserStake {
+            user: ctx.accounts.user.key(),
+            ..UserStake::default()
+        });
+
+        let access_entry = match load_access_entry(&ctx.accounts.pool, ctx.accounts.access_entry.as_ref()) {
+            Ok(access_entry) => access_entry,
+            Err(error) => return Ok(preview_outcome(Err(error))),
+        };
+
+        Ok(stake_preview(
+            &ctx.accounts.pool,
+            &user_stake,
+            access_entry.as_ref(),
+            ctx.accounts.referral_account.as_deref(),
+            amount,
+            committed_days,
+            &Clock::get()?,
+        ))
+    }
+
+    // Read-only: what an instant unstake of the whole position would pay right now
+    pub fn preview_unstake(ctx: Context<PreviewUnstake>) -> Result<OperationPreview> {
+        let vault_available = vault_available_lamports(&ctx.accounts.pool_vault.to_account_info())?;
+
+        Ok(unstake_preview(&ctx.accounts.pool, &ctx.accounts.user_stake, vault_available, &Clock::get()?))
+    }
+
+    // Withdraw fees (admin only)
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        require!(withdrawable_fees(pool)? >= amount, ErrorCode::InsufficientFunds);
+
+        // Transfer fees to the treasury; only the fee vault is debited, never principal
+        transfer_from_vault(
+            &ctx.accounts.fee_vault,
+            &ctx.accounts.treasury.to_account_info(),
+            amount,
+        )?;
+
+        pool.total_fees_collected = pool.total_fees_collected.checked_sub(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(FeesWithdrawnEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            admin: ctx.accounts.admin.key(),
+            treasury: ctx.accounts.treasury.key(),
+            amount,
+            fees_remaining: pool.total_fees_collected,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Withdraw early-exit penalties (admin only)
+    pub fn withdraw_penalties(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        authorize_admin(&mut ctx.accounts.pool, &ctx.accounts.admin.key())?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        // Check if pool has sufficient penalties
+        require!(pool.total_penalties_collected >= amount, ErrorCode::InsufficientFunds);
+
+        // Transfer penalties to the treasury
+        pay_from_pool_vault(
+            pool,
+            &ctx.accounts.pool_vault,
+            &ctx.accounts.treasury.to_account_info(),
+            amount,
+        )?;
+
+        pool.total_penalties_collected = pool.total_penalties_collected.checked_sub(amount).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(PenaltiesWithdrawnEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            admin: ctx.accounts.admin.key(),
+            treasury: ctx.accounts.treasury.key(),
+            amount,
+            penalties_remaining: pool.total_penalties_collected,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Share fee revenue and early-exit penalties with the pool's stakers pro rata to their
+    // principal (admin only). The lamports join the reward reserve and each position
+    // collects its share through the reward-per-token accumulator.
+    pub fn distribute_to_stakers(ctx: Context<DistributeToStakers>, fee_amount: u64, penalty_amount: u64) -> Result<()> {
+        note_admin_activity(&mut ctx.accounts.pool, Clock::get()?.unix_timestamp)?;
+        let amount = fee_amount.checked_add(penalty_amount).unwrap();
+        require!(amount > 0, ErrorCode::InvalidAmount);
+        require!(withdrawable_fees(&ctx.accounts.pool)? >= fee_amount, ErrorCode::InsufficientFunds);
+        require!(ctx.accounts.pool.total_penalties_collected >= penalty_amount, ErrorCode::InsufficientFunds);
+
+        // Shared only over principal still accruing, as update_rewards credits it
+        let increment = reward_per_token_increment(amount, accruing_total(&ctx.accounts.pool))?;
+        let reward_vault = ctx.accounts.reward_vault.to_account_info();
+        if fee_amount > 0 {
+            transfer_from_vault(&ctx.accounts.fee_vault, &reward_vault, fee_amount)?;
+        }
+        if penalty_amount > 0 {
+            pay_from_pool_vault(&ctx.accounts.pool, &ctx.accounts.pool_vault, &reward_vault, penalty_amount)?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+
+        pool.total_fees_collected = pool.total_fees_collected.checked_sub(fee_amount).unwrap();
+        pool.total_penalties_collected = pool.total_penalties_collected.checked_sub(penalty_amount).unwrap();
+        pool.reward_reserve = pool.reward_reserve.checked_add(amount).unwrap();
+        pool.reward_per_token_stored = pool.reward_per_token_stored.checked_add(increment).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(RewardsDistributedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            admin: ctx.accounts.admin.key(),
+            fee_amount,
+            penalty_amount,
+            reward_per_token_stored: pool.reward_per_token_stored,
+            reward_reserve: pool.reward_reserve,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Deploy idle principal from the pool vault into Marinade for mSOL held by the pool
+    // (admin only). Marinade can only take a deposit from a system account, so the
+    // lamports pass through the pool's staging PDA on the way.
+    pub fn deploy_to_marinade(ctx: Context<DeployToMarinade>, amount: u64, min_msol_out: u64) -> Result<()> {
+        note_admin_activity(&mut ctx.accounts.pool, Clock::get()?.unix_timestamp)?;
+        require!(amount > 0, ErrorCode::InvalidAmount);
+
+        // Only staked principal is deployed; fees and penalties stay liquid for the admin
+        let deployed_total = ctx.accounts.pool.deployed_to_marinade.checked_add(amount).unwrap();
+        require!(
+            marinade_principal(&ctx.accounts.pool).checked_add(amount).unwrap() <= ctx.accounts.pool.total_staked,
+            ErrorCode::InvalidAmount
+        );
+        let msol_price = marinade_msol_price(&ctx.accounts.marinade_state)?;
+
+        transfer_from_vault(&ctx.accounts.pool_vault, &ctx.accounts.marinade_staging.to_account_info(), amount)?;
+
+        let msol_before = ctx.accounts.pool_msol_account.amount;
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let staging_seeds: &[&[u8]] = &[b"marinade_staging", &pool_id_bytes, &[ctx.bumps.marinade_staging]];
+        let mut data = MARINADE_DEPOSIT_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&amount.to_le_bytes());
+        let accounts = &ctx.accounts;
+        // Marinade's deposit accounts, in its order: (account, writable, signer)
+        invoke_marinade(
+            &accounts.marinade_program,
+            &[
+                (accounts.marinade_state.to_account_info(), true, false),
+                (accounts.msol_mint.to_account_info(), true, false),
+                (accounts.liq_pool_sol_leg_pda.to_account_info(), true, false),
+                (accounts.liq_pool_msol_leg.to_account_info(), true, false),
+                (accounts.liq_pool_msol_leg_authority.to_account_info(), false, false),
+                (accounts.reserve_pda.to_account_info(), true, false),
+                (accounts.marinade_staging.to_account_info(), true, true),
+                (accounts.pool_msol_account.to_account_info(), true, false),
+                (accounts.msol_mint_authority.to_account_info(), false, false),
+                (accounts.system_program.to_account_info(), false, false),
+                (accounts.token_program.to_account_info(), false, false),
+            ],
+            data,
+            staging_seeds,
+        )?;
+
+        // Marinade applies its own rate, which may not fall further below its msol_price
+        // than the tolerance, nor below the admin's floor
+        ctx.accounts.pool_msol_account.reload()?;
+        let msol_received = ctx.accounts.pool_msol_account.amount.checked_sub(msol_before).unwrap();
+        let rate_floor = marinade_rate_floor(msol_for_lamports(amount, msol_price)?);
+        require!(msol_received >= min_msol_out.max(rate_floor), ErrorCode::SlippageExceeded);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        pool.deployed_to_marinade = deployed_total;
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(MarinadeDeployedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            amount,
+            msol_received,
+            deployed_total,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Liquid-unstake mSOL back into the pool vault when withdrawals need the liquidity
+    // (admin only). The principal it stood for returns to the vault; staking yield earned
+    // on top of it goes to the reward reserve, and the reward reserve makes good any
+    // shortfall, booked as a Marinade loss. When the liquidity pool is too thin or costly,
+    // order_marinade_unstake waits out the stake's cooldown instead.
+    pub fn recall_from_marinade(ctx: Context<RecallFromMarinade>, msol_amount: u64, min_sol_out: u64) -> Result<()> {
+        note_admin_activity(&mut ctx.accounts.pool, Clock::get()?.unix_timestamp)?;
+        let msol_balance = ctx.accounts.pool_msol_account.amount;
+        require!(msol_amount > 0 && msol_amount <= msol_balance, ErrorCode::InvalidAmount);
+        let msol_price = marinade_msol_price(&ctx.accounts.marinade_state)?;
+
+        let staging_before = ctx.accounts.marinade_staging.lamports();
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        let mut data = MARINADE_LIQUID_UNSTAKE_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&msol_amount.to_le_bytes());
+        let accounts = &ctx.accounts;
+        // Marinade's liquid_unstake accounts, in its order: (account, writable, signer)
+        invoke_marinade(
+            &accounts.marinade_program,
+            &[
+                (accounts.marinade_state.to_account_info(), true, false),
+                (accounts.msol_mint.to_account_info(), true, false),
+                (accounts.liq_pool_sol_leg_pda.to_account_info(), true, false),
+                (accounts.liq_pool_msol_leg.to_account_info(), true, false),
+                (accounts.treasury_msol_account.to_account_info(), true, false),
+                (accounts.pool_msol_account.to_account_info(), true, false),
+                (accounts.pool.to_account_info(), false, true),
+                (accounts.marinade_staging.to_account_info(), true, false),
+                (accounts.system_program.to_account_info(), false, false),
+                (accounts.token_program.to_account_info(), false, false),
+            ],
+            data,
+            pool_seeds,
+        )?;
+
+        // Held to the same tolerance below msol_price as a deposit, and the admin's floor
+        let sol_received = ctx.accounts.marinade_staging.lamports().checked_sub(staging_before).unwrap();
+        let rate_floor = marinade_rate_floor(msol_value(msol_amount, msol_price)?);
+        require!(sol_received >= min_sol_out.max(rate_floor), ErrorCode::SlippageExceeded);
+
+        let cost_released = recalled_principal(ctx.accounts.pool.deployed_to_marinade, msol_amount, msol_balance);
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let staging_seeds: &[&[u8]] = &[b"marinade_staging", &pool_id_bytes, &[ctx.bumps.marinade_staging]];
+        let (yield_to_reserve, loss_covered) = settle_marinade_return(
+            &mut ctx.accounts.pool,
+            &ctx.accounts.pool_vault,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.marinade_staging,
+            staging_seeds,
+            &ctx.accounts.system_program,
+            sol_received,
+            cost_released,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        pool.deployed_to_marinade = pool.deployed_to_marinade.checked_sub(cost_released).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(MarinadeRecalledEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            msol_amount,
+            sol_received,
+            cost_released,
+            yield_to_reserve,
+            loss_covered,
+            deployed_total: pool.deployed_to_marinade,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Order a delayed unstake of mSOL, which pays its full value at msol_price once the stake
+    // has cooled down, usually an epoch or two later (admin only). Marinade writes the
+    // ticket into an empty account it owns, which the admin creates in the same transaction.
+    // The principal the mSOL stood for moves out of deployed_to_marinade into the tickets
+    // until claim_marinade_ticket brings it home.
+    pub fn order_marinade_unstake(ctx: Context<OrderMarinadeUnstake>, msol_amount: u64) -> Result<()> {
+        note_admin_activity(&mut ctx.accounts.pool, Clock::get()?.unix_timestamp)?;
+        let msol_balance = ctx.accounts.pool_msol_account.amount;
+        require!(msol_amount > 0 && msol_amount <= msol_balance, ErrorCode::InvalidAmount);
+
+        // Marinade makes whoever burns the mSOL the ticket's beneficiary, which has to be a
+        // system account, so the staging PDA burns it as the pool's delegate
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[ctx.accounts.pool.bump]];
+        token::approve(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Approve {
+                    to: ctx.accounts.pool_msol_account.to_account_info(),
+                    delegate: ctx.accounts.marinade_staging.to_account_info(),
+                    authority: ctx.accounts.pool.to_account_info(),
+                },
+                &[pool_seeds],
+            ),
+            msol_amount,
+        )?;
+
+        let staging_seeds: &[&[u8]] = &[b"marinade_staging", &pool_id_bytes, &[ctx.bumps.marinade_staging]];
+        let mut data = MARINADE_ORDER_UNSTAKE_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&msol_amount.to_le_bytes());
+        let accounts = &ctx.accounts;
+        // Marinade's order_unstake accounts, in its order: (account, writable, signer)
+        invoke_marinade(
+            &accounts.marinade_program,
+            &[
+                (accounts.marinade_state.to_account_info(), true, false),
+                (accounts.msol_mint.to_account_info(), true, false),
+                (accounts.pool_msol_account.to_account_info(), true, false),
+                (accounts.marinade_staging.to_account_info(), false, true),
+                (accounts.new_ticket_account.to_account_info(), true, false),
+                (accounts.clock.to_account_info(), false, false),
+                (accounts.rent.to_account_info(), false, false),
+                (accounts.token_program.to_account_info(), false, false),
+            ],
+            data,
+            staging_seeds,
+        )?;
+
+        let (beneficiary, ticket_lamports) =
+            read_marinade_ticket(&ctx.accounts.new_ticket_account, &ctx.accounts.marinade_state.key())?;
+        require!(beneficiary == ctx.accounts.marinade_staging.key(), ErrorCode::InvalidMarinadeAccount);
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        let cost_released = recalled_principal(pool.deployed_to_marinade, msol_amount, msol_balance);
+        pool.deployed_to_marinade = pool.deployed_to_marinade.checked_sub(cost_released).unwrap();
+        pool.marinade_ticket_principal = pool.marinade_ticket_principal.checked_add(cost_released).unwrap();
+        pool.marinade_ticket_lamports = pool.marinade_ticket_lamports.checked_add(ticket_lamports).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(MarinadeUnstakeOrderedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            ticket: ctx.accounts.new_ticket_account.key(),
+            msol_amount,
+            ticket_lamports,
+            cost_released,
+            deployed_total: pool.deployed_to_marinade,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+
+    // Claim a delayed-unstake ticket once Marinade will pay it (admin only). The principal
+    // the ticket stood for is settled like a recall, and the rent Marinade refunds as it
+    // closes the ticket goes back to the admin who paid for it.
+    pub fn claim_marinade_ticket(ctx: Context<ClaimMarinadeTicket>) -> Result<()> {
+        note_admin_activity(&mut ctx.accounts.pool, Clock::get()?.unix_timestamp)?;
+        let (beneficiary, ticket_lamports) =
+            read_marinade_ticket(&ctx.accounts.ticket_account, &ctx.accounts.marinade_state.key())?;
+        require!(beneficiary == ctx.accounts.marinade_staging.key(), ErrorCode::InvalidMarinadeAccount);
+
+        let staging_before = ctx.accounts.marinade_staging.lamports();
+        let pool_id_bytes = ctx.accounts.pool.pool_id.to_le_bytes();
+        let staging_seeds: &[&[u8]] = &[b"marinade_staging", &pool_id_bytes, &[ctx.bumps.marinade_staging]];
+        let accounts = &ctx.accounts;
+        // Marinade's claim accounts, in its order: (account, writable, signer)
+        invoke_marinade(
+            &accounts.marinade_program,
+            &[
+                (accounts.marinade_state.to_account_info(), true, false),
+                (accounts.reserve_pda.to_account_info(), true, false),
+                (accounts.ticket_account.to_account_info(), true, false),
+                (accounts.marinade_staging.to_account_info(), true, false),
+                (accounts.clock.to_account_info(), false, false),
+                (accounts.system_program.to_account_info(), false, false),
+            ],
+            MARINADE_CLAIM_DISCRIMINATOR.to_vec(),
+            staging_seeds,
+        )?;
+
+        let received = ctx.accounts.marinade_staging.lamports().checked_sub(staging_before).unwrap();
+        require!(received >= ticket_lamports, ErrorCode::InsufficientFunds);
+        let rent_refund = received - ticket_lamports;
+        if rent_refund > 0 {
+            system_program::transfer(
+                CpiContext::new_with_signer(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.marinade_staging.to_account_info(),
+                        to: ctx.accounts.admin.to_account_info(),
+                    },
+                    &[staging_seeds],
+                ),
+                rent_refund,
+            )?;
+        }
+
+        // Each ticket releases principal in proportion to the lamports it was owed
+        let cost_released = recalled_principal(
+            ctx.accounts.pool.marinade_ticket_principal,
+            ticket_lamports,
+            ctx.accounts.pool.marinade_ticket_lamports,
+        );
+        let (yield_to_reserve, loss_covered) = settle_marinade_return(
+            &mut ctx.accounts.pool,
+            &ctx.accounts.pool_vault,
+            &ctx.accounts.reward_vault,
+            &ctx.accounts.marinade_staging,
+            staging_seeds,
+            &ctx.accounts.system_program,
+            ticket_lamports,
+            cost_released,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let clock = Clock::get()?;
+        pool.marinade_ticket_principal = pool.marinade_ticket_principal.checked_sub(cost_released).unwrap();
+        pool.marinade_ticket_lamports = pool.marinade_ticket_lamports.checked_sub(ticket_lamports).unwrap();
+        pool.last_update = clock.unix_timestamp;
+
+        emit!(MarinadeTicketClaimedEvent {
+            version: EVENT_VERSION,
+            pool: pool.key(),
+            ticket: ctx.accounts.ticket_account.key(),
+            sol_received: ticket_lamports,
+            cost_released,
+            yield_to_reserve,
+            loss_covered,
+            ticket_principal_total: pool.marinade_ticket_principal,
+            timestamp: clock.unix_timestamp,
+        });
+
+        Ok(())
+    }
+}
+
+// Split an account written by an older program version into its layout version and the
+// bytes that follow it. Version 1 predates the version byte and is recognised by its size.
+fn split_layout_version<'a>(
+    data: &'a [u8],
+    discriminator: &[u8; 8],
+    v1_space: usize,
+    current_version: u8,
+) -> Result<(u8, &'a [u8])> {
+    require!(data.len() > 8 && data[..8] == discriminator[..], ErrorCode::AccountVersionMismatch);
+
+    let (version, body) = if data.len() == v1_space { (1, &data[8..]) } else { (data[8], &data[9..]) };
+    require!(version >= 1 && version < current_version, ErrorCode::AccountVersionMismatch);
+
+    Ok((version, body))
+}
+
+// Read a pool body written at an older version; versions 1 and 2 share the same body.
+// Fields are read in layout order, and those added after the given version take their
+// default instead.
+fn upgrade_pool(version: u8, body: &[u8]) -> Result<Pool> {
+    let buf = &mut &body[..];
+    let mut pool = Pool {
+        version: POOL_VERSION,
+        pool_id: AnchorDeserialize::deserialize(buf)?,
+        admin: AnchorDeserialize::deserialize(buf)?,
+        max_apy: AnchorDeserialize::deserialize(buf)?,
+        apy_tiers: AnchorDeserialize::deserialize(buf)?,
+        loyalty_tiers: if version >= 3 { AnchorDeserialize::deserialize(buf)? } else { Vec::new() },
+        boost_collection: if version >= 4 { AnchorDeserialize::deserialize(buf)? } else { None },
+        nft_boost_bps: if version >= 4 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Governance stays off on pools older than version 5 until the admin sets it
+        proposal_threshold: if version >= 5 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        governance_quorum_bps: if version >= 5 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        next_proposal_id: if version >= 5 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        leverage_ratio: if version >= 6 { AnchorDeserialize::deserialize(buf)? } else { DEFAULT_LEVERAGE_RATIO },
+        reinvestment_rate: if version >= 6 { AnchorDeserialize::deserialize(buf)? } else { DEFAULT_REINVESTMENT_RATE },
+        fees_awaiting_rebalance: if version >= 6 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Before version 7 the first rebalance sets the rest
+        rebalance_interval: if version >= 7 { AnchorDeserialize::deserialize(buf)? } else { 86400 },
+        last_rebalance_timestamp: if version >= 7 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        projected_apy: if version >= 7 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        min_runway_days: if version >= 7 { AnchorDeserialize::deserialize(buf)? } else { 30 },
+        // Yields paid before version 8 are not counted
+        total_yields_paid: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        snapshot_count: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        snapshot_interval: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 86400 },
+        last_snapshot_timestamp: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        snapshot_retention_epochs: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 365 },
+        large_operation_threshold: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 10_000_000_000 },
+        min_block_delay: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 2 },
+        current_slot: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        current_slot_volume: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        slot_delay_until: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        max_slippage_bps: if version >= 10 { AnchorDeserialize::deserialize(buf)? } else { 100 },
+        min_slots_before_exit: if version >= 11 { AnchorDeserialize::deserialize(buf)? } else { 1 },
+        fee_tiers: if version >= 12 { AnchorDeserialize::deserialize(buf)? } else { Vec::new() },
+        total_yields_credited: if version >= 13 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        insurance_share_bps: if version >= 14 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        insurance_pending: if version >= 14 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        insurance_balance: if version >= 14 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        is_active: if version >= 16 { AnchorDeserialize::deserialize(buf)? } else { true },
+        sunset_at: if version >= 16 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Before version 17 migrate_pool derives these from the pool id
+        bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        pool_vault_bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        fee_vault_bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        reward_vault_bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        insurance_vault_bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        token_vault_bump: if version >= 17 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Before version 18 pools keep their current fees and penalties until the admin
+        // turns the incentives on
+        long_commit_threshold_days: if version >= 18 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        long_commit_fee_discount_bps: if version >= 18 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        penalty_grace_bps: if version >= 18 { AnchorDeserialize::deserialize(buf)? } else { 10000 },
+        deployed_to_marinade: if version >= 19 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Nothing was shared before version 20, so the accumulator starts from zero
+        reward_per_token_stored: if version >= 20 {
+            let reward_per_token_stored = AnchorDeserialize::deserialize(buf)?;
+            // Versions 20 to 31 stored a last_accrual_ts here that nothing read
+            if version < 32 {
+                let _last_accrual_ts: i64 = AnchorDeserialize::deserialize(buf)?;
+            }
+            reward_per_token_stored
+        } else {
+            0
+        },
+        // Payouts stay uncapped on older pools until the admin sets the caps
+        max_yield_per_claim: if version >= 21 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        max_yield_per_user_per_year: if version >= 21 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        treasury: if version >= 22 { AnchorDeserialize::deserialize(buf)? } else { Pubkey::default() },
+        // Positions opened before version 23 carry no projected yield, so the check stays
+        // off until the admin turns it on
+        total_projected_liability: if version >= 23 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        solvency_factor_bps: if version >= 23 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        max_reconciliation_delta: if version >= 24 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Every position on an older pool was opened under policy 0, as is the pool
+        policy_version: if version >= 25 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        migration_bonus_bps: if version >= 25 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        min_claim_amount: if version >= 26 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        renewal_grace_seconds: if version >= 27 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        idle_apy_bps: if version >= 27 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Older pools have no recovery authority, so they cannot enter recovery until the
+        // admin names one, which also starts the inactivity clock
+        recovery_authority: if version >= 28 { AnchorDeserialize::deserialize(buf)? } else { Pubkey::default() },
+        admin_inactivity_timeout: if version >= 28 {
+            AnchorDeserialize::deserialize(buf)?
+        } else {
+            DEFAULT_ADMIN_INACTIVITY_TIMEOUT
+        },
+        last_admin_activity: if version >= 28 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        recovery_mode: if version >= 28 { AnchorDeserialize::deserialize(buf)? } else { false },
+        rollover_fee_bps: if version >= 29 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        reward_mint: if version >= 30 { AnchorDeserialize::deserialize(buf)? } else { None },
+        reward_token_vault_bump: if version >= 30 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        reward_tokens_per_sol: if version >= 30 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        min_token_stake_amount: if version >= 31 { AnchorDeserialize::deserialize(buf)? } else { 1 },
+        max_token_stake_amount: if version >= 31 { AnchorDeserialize::deserialize(buf)? } else { u64::MAX },
+        // Withdrawals queued before version 32 were not counted, so they keep sharing in
+        // distributions until they leave the queue
+        total_pending_withdrawals: if version >= 32 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        marinade_loss: if version >= 32 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        marinade_ticket_principal: if version >= 32 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        marinade_ticket_lamports: if version >= 32 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        min_commitment_days: AnchorDeserialize::deserialize(buf)?,
+        max_commitment_days: AnchorDeserialize::deserialize(buf)?,
+        min_stake_amount: AnchorDeserialize::deserialize(buf)?,
+        max_stake_amount: AnchorDeserialize::deserialize(buf)?,
+        total_staked: AnchorDeserialize::deserialize(buf)?,
+        total_users: AnchorDeserialize::deserialize(buf)?,
+        total_fees_collected: AnchorDeserialize::deserialize(buf)?,
+        total_penalties_collected: AnchorDeserialize::deserialize(buf)?,
+        reward_reserve: AnchorDeserialize::deserialize(buf)?,
+        total_referral_rewards_pending: AnchorDeserialize::deserialize(buf)?,
+        deposit_fee_bps: AnchorDeserialize::deserialize(buf)?,
+        referral_share_bps: AnchorDeserialize::deserialize(buf)?,
+        access_mode: AnchorDeserialize::deserialize(buf)?,
+        early_exit_penalty_bps: AnchorDeserialize::deserialize(buf)?,
+        sliding_penalty_enabled: AnchorDeserialize::deserialize(buf)?,
+        withdrawal_cooldown_seconds: AnchorDeserialize::deserialize(buf)?,
+        instant_exit_enabled: AnchorDeserialize::deserialize(buf)?,
+        instant_exit_penalty_bps: AnchorDeserialize::deserialize(buf)?,
+        max_daily_outflow_bps: AnchorDeserialize::deserialize(buf)?,
+        outflow_window_start: AnchorDeserialize::deserialize(buf)?,
+        outflow_in_window: AnchorDeserialize::deserialize(buf)?,
+        // Before version 15 this was a single flag that paused everything
+        pause_flags: if version >= 15 {
+            AnchorDeserialize::deserialize(buf)?
+        } else if bool::deserialize(buf)? {
+            PAUSE_ALL
+        } else {
+            0
+        },
+        guardian: AnchorDeserialize::deserialize(buf)?,
+        guardian_pause_max_duration: AnchorDeserialize::deserialize(buf)?,
+        paused_at: AnchorDeserialize::deserialize(buf)?,
+        paused_by: AnchorDeserialize::deserialize(buf)?,
+        pause_expires_at: AnchorDeserialize::deserialize(buf)?,
+        emergency_withdraw_delay: AnchorDeserialize::deserialize(buf)?,
+        stake_mint: AnchorDeserialize::deserialize(buf)?,
+        total_token_staked: AnchorDeserialize::deserialize(buf)?,
+        total_token_fees_collected: AnchorDeserialize::deserialize(buf)?,
+        total_token_penalties_collected: AnchorDeserialize::deserialize(buf)?,
+        created_at: AnchorDeserialize::deserialize(buf)?,
+        last_update: AnchorDeserialize::deserialize(buf)?,
+    };
+    // Withdrawals were paid to the admin before version 22, so it stays the treasury
+    if version < 22 {
+        pool.treasury = pool.admin;
+    }
+
+    Ok(pool)
+}
+
+// Read a position body written at an older version; versions 1 and 2 share the same
+// body, and fields added since the given version take their zero value
+fn upgrade_user_stake(version: u8, body: &[u8]) -> Result<UserStake> {
+    let buf = &mut &body[..];
+    let mut user_stake = UserStake {
+        version: USER_STAKE_VERSION,
+        user: AnchorDeserialize::deserialize(buf)?,
+        amount: AnchorDeserialize::deserialize(buf)?,
+        committed_days: AnchorDeserialize::deserialize(buf)?,
+        stake_timestamp: AnchorDeserialize::deserialize(buf)?,
+        last_claim_timestamp: AnchorDeserialize::deserialize(buf)?,
+        locked_apy_bps: AnchorDeserialize::deserialize(buf)?,
+        is_counted: AnchorDeserialize::deserialize(buf)?,
+        claim_delegate: AnchorDeserialize::deserialize(buf)?,
+        referrer: AnchorDeserialize::deserialize(buf)?,
+        pending_withdrawal: AnchorDeserialize::deserialize(buf)?,
+        total_claimed: AnchorDeserialize::deserialize(buf)?,
+        total_staked_lifetime: AnchorDeserialize::deserialize(buf)?,
+        total_yields_claimed: AnchorDeserialize::deserialize(buf)?,
+        funded_by: if version >= 3 { AnchorDeserialize::deserialize(buf)? } else { None },
+        vote_locked_until: if version >= 4 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        last_stake_slot: if version >= 5 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        pending_yields: if version >= 6 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Added in version 7; migrate_user_stake derives it again either way
+        bump: if version >= 7 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        rewards_owed: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        accrual_rate_bps: if version >= 8 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        // Before version 9 the position's principal was counted in every distribution
+        // since the pool's accumulator started at zero, so its whole share is still owed
+        reward_per_token_paid: if version >= 9 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        yields_claimed_in_year: if version >= 10 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        year_window_start: if version >= 10 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        projected_liability: if version >= 11 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        policy_version: if version >= 12 { AnchorDeserialize::deserialize(buf)? } else { 0 },
+        auto_renew_opted_out: if version >= 13 { AnchorDeserialize::deserialize(buf)? } else { false },
+        initialized: if version >= 14 { AnchorDeserialize::deserialize(buf)? } else { false },
+    };
+    // Every position written before version 14 was written by a deposit for its owner
+    if version < 14 {
+        user_stake.initialized = user_stake.user != Pubkey::default();
+    }
+    // Settlement came in version 8; until the next one the position accrues at its
+    // locked rate, without boosts
+    if version < 8 {
+        user_stake.accrual_rate_bps = user_stake.locked_apy_bps;
+    }
+
+    Ok(user_stake)
+}
+
+// Canonical bumps of a pool's PDAs for a pool created before they were stored. The
+// token vault is only derived when the pool has a stake mint.
+fn derive_pool_bumps(pool: &mut Pool) {
+    let pool_id_bytes = pool.pool_id.to_le_bytes();
+    let bump_of = |prefix: &[u8]| Pubkey::find_program_address(&[prefix, &pool_id_bytes], &crate::ID).1;
+
+    pool.bump = bump_of(b"pool");
+    pool.pool_vault_bump = bump_of(b"pool_vault");
+    pool.fee_vault_bump = bump_of(b"fee_vault");
+    pool.reward_vault_bump = bump_of(b"reward_vault");
+    pool.insurance_vault_bump = bump_of(b"insurance_vault");
+    if let Some(stake_mint) = pool.stake_mint {
+        pool.token_vault_bump =
+            Pubkey::find_program_address(&[b"token_vault", &pool_id_bytes, stake_mint.as_ref()], &crate::ID).1;
+    }
+}
+
+// Address and canonical bump of a SOL position, or of an SPL position when a mint is given
+fn position_address(pool_id: u64, stake_mint: Option<Pubkey>, user: &Pubkey) -> (Pubkey, u8) {
+    let pool_id_bytes = pool_id.to_le_bytes();
+    match stake_mint {
+        Some(stake_mint) => Pubkey::find_program_address(
+            &[b"user_token_stake", &pool_id_bytes, stake_mint.as_ref(), user.as_ref()],
+            &crate::ID,
+        ),
+        None => Pubkey::find_program_address(&[b"user_stake", &pool_id_bytes, user.as_ref()], &crate::ID),
+    }
+}
+
+// Rewrite a migrated account in the current layout; the payer covers any extra rent
+fn write_migrated_account<'info, T: AccountSerialize>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    value: &T,
+    space: usize,
+) -> Result<()> {
+    let rent_shortfall = Rent::get()?.minimum_balance(space).saturating_sub(account.lamports());
+    if rent_shortfall > 0 {
+        let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+            &payer.key(),
+            account.key,
+            rent_shortfall,
+        );
+
+        anchor_lang::solana_program::program::invoke(
+            &transfer_instruction,
+            &[payer.to_account_info(), account.clone()],
+        )?;
+    }
+
+    account.realloc(space, false)?;
+    let mut data = account.try_borrow_mut_data()?;
+    data.fill(0);
+    let mut writer: &mut [u8] = &mut data;
+    value.try_serialize(&mut writer)?;
+
+    Ok(())
+}
+
+// Checks shared by the instant unstake paths, which differ only in where the payout goes
+fn instant_unstake<'info>(
+    accounts: &mut Unstake<'info>,
+    amount: u64,
+    deadline: i64,
+    min_return_amount: u64,
+    recipient: AccountInfo<'info>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    check_transaction_deadline(deadline, clock.unix_timestamp)?;
+    check_instant_exit(&mut accounts.pool, &accounts.user_stake, amount, &clock)?;
+
+    let instant_exit_penalty_bps = accounts.pool.instant_exit_penalty_bps;
+    withdraw_principal(accounts, amount, instant_exit_penalty_bps, min_return_amount, recipient)
+}
+
+// Whether the instant path is open to this position now, counting the amount towards
+// the slot's volume; shared with preview_unstake
+fn check_instant_exit(pool: &mut Pool, user_stake: &UserStake, amount: u64, clock: &Clock) -> Result<()> {
+    require!(!pool.unstake_paused(clock.unix_timestamp), ErrorCode::PoolPaused);
+    require!(pool.instant_exit_enabled, ErrorCode::InstantExitDisabled);
+    require!(user_stake.pending_withdrawal.is_none(), ErrorCode::PendingWithdrawalExists);
+    require!(votes_unlocked(user_stake, clock.unix_timestamp), ErrorCode::VotesLocked);
+    check_exit_slot(pool, user_stake, clock.slot)?;
+    record_slot_volume(pool, amount, clock.slot)
+}
+
+// Pay out principal from the pool vault, burning receipts and closing out a fully
+// withdrawn position; shared by instant unstakes and executed withdrawal requests
+fn withdraw_principal<'info>(
+    accounts: &mut Unstake<'info>,
+    amount: u64,
+    extra_penalty_bps: u64,
+    min_return_amount: u64,
+    recipient: AccountInfo<'info>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let (penalty_amount, final_amount, time_staked) = settle_withdrawal(
+        &mut accounts.pool,
+        &mut accounts.user_stake,
+        amount,
+        extra_penalty_bps,
+        min_return_amount,
+        clock.unix_timestamp,
+    )?;
+
+    // Burn the receipt tokens backing the withdrawn principal
+    token::burn(
+        CpiContext::new(
+            accounts.token_program.to_account_info(),
+            Burn {
+                mint: accounts.receipt_mint.to_account_info(),
+                from: accounts.user_receipt_account.to_account_info(),
+                authority: accounts.user.to_account_info(),
+            },
+        ),
+        amount,
+    )?;
+
+    // Transfer funds back to user
+    pay_from_pool_vault(
+        &accounts.pool,
+        &accounts.pool_vault,
+        &recipient,
+        final_amount,
+    )?;
+
+    emit!(UnstakeEvent {
+        version: EVENT_VERSION,
+        user: accounts.user.key(),
+        pool: accounts.pool.key(),
+        amount,
+        penalty: penalty_amount,
+        return_amount: final_amount,
+        remaining_amount: accounts.user_stake.amount,
+        elapsed_seconds: time_staked,
+        timestamp: clock.unix_timestamp,
+    });
+    record_history(&mut accounts.user_history, HISTORY_OP_UNSTAKE, amount, clock.unix_timestamp);
+
+    Ok(())
+}
+
+// Penalty, payout and the pool and position accounting for a withdrawal of principal,
+// shared with preview_unstake; returns the penalty, the payout and the seconds staked
+fn settle_withdrawal(
+    pool: &mut Pool,
+    user_stake: &mut UserStake,
+    amount: u64,
+    extra_penalty_bps: u64,
+    min_return_amount: u64,
+    now: i64,
+) -> Result<(u64, u64, i64)> {
+    require!(user_stake.amount > 0, ErrorCode::NoStake);
+    require!(amount > 0, ErrorCode::InvalidAmount);
+    require_with_context!(
+        amount <= user_stake.amount,
+        ErrorCode::InvalidAmount,
+        "staked={} got={}",
+        user_stake.amount,
+        amount
+    );
+
+    // Settle before the principal shrinks so the withdrawn part keeps the days it earned;
+    // they stay claimable after the exit
+    update_rewards(pool, user_stake, 0, now)?;
+
+    // A partial unstake must leave a position that is still above the pool minimum
+    let remaining_amount = user_stake.amount.checked_sub(amount).unwrap();
+    require_with_context!(
+        remaining_amount == 0 || remaining_amount >= pool.min_stake_amount,
+        ErrorCode::RemainderTooSmall,
+        "min={} remaining={}",
+        pool.min_stake_amount,
+        remaining_amount
+    );
+
+    // Calculate time staked
+    let time_staked = now.checked_sub(user_stake.stake_timestamp).unwrap();
+
+    let unstake_amount = amount;
+
+    // Apply penalty for early exit on the withdrawn portion if commitment not met,
+    // plus the surcharge for skipping the withdrawal queue; a sunset pool or one in
+    // recovery waives both
+    let (penalty_bps, extra_penalty_bps) = if pool.penalties_waived() {
+        (0, 0)
+    } else {
+        (pool.early_exit_penalty_bps, extra_penalty_bps)
+    };
+    let penalty_amount = compute_penalty(
+        unstake_amount,
+        penalty_bps,
+        time_staked,
+        user_stake.committed_days,
+        pool.sliding_penalty_enabled,
+        pool.penalty_grace_bps,
+    )?
+    .checked_add(compute_fee(unstake_amount, extra_penalty_bps)?)
+    .unwrap();
+
+    let final_amount = unstake_amount.checked_sub(penalty_amount).unwrap();
+    check_min_payout(final_amount, min_return_amount)?;
+
+    // Count the payout against the pool's daily outflow cap before principal leaves
+    record_outflow(pool, final_amount, now)?;
+
+    // The withdrawn share of the principal no longer carries projected yield
+    let released_liability =
+        (user_stake.projected_liability as u128 * unstake_amount as u128 / user_stake.amount as u128) as u64;
+    adjust_projected_liability(pool, user_stake, 0, released_liability, now);
+
+    // Update pool state
+    pool.total_staked = pool.total_staked.checked_sub(unstake_amount).unwrap();
+    pool.total_penalties_collected = pool.total_penalties_collected.checked_add(penalty_amount).unwrap();
+    if remaining_amount == 0 {
+        uncount_user(pool, user_stake);
+    }
+    pool.last_update = now;
+
+    // Update user stake; the remainder keeps its original commitment and timestamps
+    user_stake.amount = remaining_amount;
+    if remaining_amount == 0 {
+        user_stake.committed_days = 0;
+        user_stake.stake_timestamp = 0;
+        user_stake.last_claim_timestamp = 0;
+        user_stake.locked_apy_bps = 0;
+        user_stake.total_claimed = 0;
+    }
+
+    Ok((penalty_amount, final_amount, time_staked))
+}
+
+// Reject a transaction executed after the deadline its signer set, so a relayed unstake or
+// claim cannot land later under different conditions; NO_DEADLINE opts out
+fn check_transaction_deadline(deadline: i64, now: i64) -> Result<()> {
+    if deadline == NO_DEADLINE {
+        return Ok(());
+    }
+    require_with_context!(now <= deadline, ErrorCode::TransactionExpired, "deadline={} now={}", deadline, now);
+
+    Ok(())
+}
+
+// Floor on what an unstake or claim pays out; NO_MIN_PAYOUT accepts any amount
+fn check_min_payout(payout: u64, min_payout: u64) -> Result<()> {
+    if min_payout == NO_MIN_PAYOUT {
+        return Ok(());
+    }
+    require_with_context!(payout >= min_payout, ErrorCode::SlippageExceeded, "min={} got={}", min_payout, payout);
+
+    Ok(())
+}
+
+fn withdrawal_cooldown_elapsed(requested_at: i64, cooldown_seconds: i64, now: i64) -> bool {
+    now >= requested_at.checked_add(cooldown_seconds).unwrap()
+}
+
+// A gated pool needs the depositor's access PDA every time, or a blacklisted wallet
+// could simply leave its entry out. The seeds are checked by the context; a PDA that
+// was never created reads as no entry.
+fn load_access_entry(pool: &Pool, account: Option<&UncheckedAccount>) -> Result<Option<AccessEntry>> {
+    if pool.access_mode == ACCESS_MODE_OPEN {
+        return Ok(None);
+    }
+    let account = account.ok_or(ErrorCode::Unauthorized)?;
+    if account.owner != &crate::ID || account.data_is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(AccessEntry::try_deserialize(&mut &account.try_borrow_data()?[..])?))
+}
+
+// Gate new stakes by the pool's access mode; unstaking and claiming are never gated
+fn check_access(pool: &Pool, access_entry: Option<&AccessEntry>) -> Result<()> {
+    match pool.access_mode {
+        ACCESS_MODE_WHITELIST => {
+            require!(access_entry.is_some_and(|entry| entry.whitelisted), ErrorCode::NotWhitelisted);
+        }
+        ACCESS_MODE_BLACKLIST => {
+            require!(!access_entry.is_some_and(|entry| entry.blacklisted), ErrorCode::Blacklisted);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Whether any pause is in effect; instructions outside deposits, claims and unstakes
+// stop under any of them
+fn pool_is_paused(pool: &Pool, now: i64) -> bool {
+    pause_in_effect(pool.pause_flags, pool.pause_expires_at, now)
+}
+
+// Lamports the books say the pool, fee and reward vaults hold above their rent-exempt
+// minimums: principal not out in Marinade, uncollected penalties, fees, the reward
+// reserve, and yields credited to positions but not yet withdrawn
+fn expected_vault_lamports(pool: &Pool) -> u64 {
+    pool.total_staked
+        .checked_add(pool.total_penalties_collected).unwrap()
+        .saturating_sub(marinade_principal(pool))
+        .checked_add(pool.total_fees_collected).unwrap()
+        .checked_add(pool.reward_reserve).unwrap()
+        .checked_add(pool.total_yields_credited).unwrap()
+}
+
+// A pause is in effect until it is lifted or, for a guardian pause, until it lapses
+fn pause_in_effect(pause_flags: u8, pause_expires_at: i64, now: i64) -> bool {
+    pause_flags != 0 && (pause_expires_at == 0 || now < pause_expires_at)
+}
+
+impl Pool {
+    // A pool in recovery takes no deposits until a new admin is installed
+    pub fn deposits_paused(&self, now: i64) -> bool {
+        self.recovery_mode || pause_in_effect(self.pause_flags & PAUSE_DEPOSITS, self.pause_expires_at, now)
+    }
+
+    pub fn claims_paused(&self, now: i64) -> bool {
+        pause_in_effect(self.pause_flags & PAUSE_CLAIMS, self.pause_expires_at, now)
+    }
+
+    pub fn unstake_paused(&self, now: i64) -> bool {
+        pause_in_effect(self.pause_flags & PAUSE_UNSTAKE, self.pause_expires_at, now)
+    }
+
+    pub fn penalties_waived(&self) -> bool {
+        !self.is_active || self.recovery_mode
+    }
+}
+
+// Every admin instruction restarts the dead-man clock. Once the pool is in recovery the
+// old admin key is refused until the recovery authority installs a new one.
+fn authorize_admin(pool: &mut Pool, admin: &Pubkey) -> Result<()> {
+    require!(*admin == pool.admin, ErrorCode::Unauthorized);
+    note_admin_activity(pool, Clock::get()?.unix_timestamp)
+}
+
+fn note_admin_activity(pool: &mut Pool, now: i64) -> Result<()> {
+    require!(!pool.recovery_mode, ErrorCode::RecoveryModeActive);
+    pool.last_admin_activity = now;
+    Ok(())
+}
+
+// Recovery opens strictly after admin_inactivity_timeout seconds without admin activity
+fn admin_inactive(pool: &Pool, now: i64) -> bool {
+    now.saturating_sub(pool.last_admin_activity) > pool.admin_inactivity_timeout
+}
+
+fn emergency_withdraw_unlocked(paused_at: i64, delay: i64, now: i64) -> bool {
+    now >= paused_at.checked_add(delay).unwrap()
+}
+
+// An admin pause has no expiry and replaces the flags in effect, which also ratifies (or
+// narrows) a running guardian pause. paused_at only moves when the pool goes from unpaused
+// to paused or withdrawals newly stop, so re-pausing never pushes back emergency_withdraw.
+fn apply_admin_pause(pool: &mut Pool, pause_flags: u8, admin: Pubkey, now: i64) {
+    let newly_paused = !pool_is_paused(pool, now)
+        || (pause_flags & PAUSE_UNSTAKE != 0 && !pool.unstake_paused(now));
+    pool.pause_flags = pause_flags;
+    if newly_paused {
+        pool.paused_at = now;
+    }
+    pool.paused_by = admin;
+    pool.pause_expires_at = 0;
+}
+
+// init_if_needed hands back an existing position as readily as a new one, so every write
+// of a position's owner goes through here: the account must be blank or already belong
+// to owner. History without an owner only comes from corrupt or resurrected data.
+fn claim_position(user_stake: &mut UserStake, owner: Pubkey) -> Result<()> {
+    if user_stake.user == Pubkey::default() {
+        require!(
+            !user_stake.initialized && !has_position_history(user_stake),
+            ErrorCode::InvalidPositionState
+        );
+    } else {
+        require_keys_eq!(user_stake.user, owner, ErrorCode::Unauthorized);
+    }
+
+    user_stake.user = owner;
+    user_stake.initialized = true;
+
+    Ok(())
+}
+
+// Anything only a position that has been deposited into can carry
+fn has_position_history(user_stake: &UserStake) -> bool {
+    user_stake.amount > 0
+        || user_stake.total_staked_lifetime > 0
+        || user_stake.total_yields_claimed > 0
+        || user_stake.total_claimed > 0
+        || user_stake.rewards_owed > 0
+        || user_stake.pending_yields > 0
+        || user_stake.projected_liability > 0
+}
+
+// A position counts towards total_users once, however many top-ups it receives
+fn count_user(pool: &mut Pool, user_stake: &mut UserStake) {
+    if !user_stake.is_counted {
+        pool.total_users = pool.total_users.checked_add(1).unwrap();
+        user_stake.is_counted = true;
+    }
+}
+
+// Only a counted position is released, so a drifted flag can never underflow total_users
+fn uncount_user(pool: &mut Pool, user_stake: &mut UserStake) {
+    if user_stake.is_counted {
+        pool.total_users = pool.total_users.saturating_sub(1);
+        user_stake.is_counted = false;
+    }
+}
+
+// Add and release yield projected on a position, keeping the pool total in step, and
+// report utilization of the yield capacity whenever it crosses an alert level. A
+// release never exceeds what the position still carries.
+fn adjust_projected_liability(pool: &mut Pool, user_stake: &mut UserStake, added: u64, released: u64, now: i64) {
+    let utilization_before =
+        yield_capacity_utilization_bps(pool.total_projected_liability, pool.reward_reserve, pool.solvency_factor_bps);
+
+    let released = released.min(user_stake.projected_liability.checked_add(added).unwrap());
+    user_stake.projected_liability = user_stake.projected_liability.checked_add(added).unwrap() - released;
+    pool.total_projected_liability = pool.total_projected_liability
+        .checked_add(added).unwrap()
+        .saturating_sub(released);
+
+    let utilization_bps =
+        yield_capacity_utilization_bps(pool.total_projected_liability, pool.reward_reserve, pool.solvency_factor_bps);
+    if pool.solvency_factor_bps > 0 && crossed_solvency_alert(utilization_before, utilization_bps) {
+        emit!(SolvencyEvent {
+            version: EVENT_VERSION,
+            projected_liability: pool.total_projected_liability,
+            reward_reserve: pool.reward_reserve,
+            solvency_factor_bps: pool.solvency_factor_bps,
+            utilization_bps,
+            timestamp: now,
+        });
+    }
+}
+
+// Pool-level checks shared by every SOL deposit path
+fn validate_stake(
+    pool: &Pool,
+    access_entry: Option<&AccessEntry>,
+    amount: u64,
+    committed_days: u64,
+    now: i64,
+) -> Result<()> {
+    require!(!pool.deposits_paused(now), ErrorCode::PoolPaused);
+    require!(pool.is_active, ErrorCode::PoolInactive);
+    require_with_context!(
+        amount >= pool.min_stake_amount,
+        ErrorCode::AmountTooSmall,
+        "min={} got={}",
+        pool.min_stake_amount,
+        amount
+    );
+    require_with_context!(
+        amount <= pool.max_stake_amount,
+        ErrorCode::AmountTooLarge,
+        "max={} got={}",
+        pool.max_stake_amount,
+        amount
+    );
+    require_with_context!(
+        (pool.min_commitment_days..=pool.max_commitment_days).contains(&committed_days),
+        ErrorCode::InvalidCommitmentDays,
+        "min_days={} max_days={} got={}",
+        pool.min_commitment_days,
+        pool.max_commitment_days,
+        committed_days
+    );
+    require_with_context!(
+        within_leverage_cap(
+            pool.total_staked.saturating_add(amount),
+            highest_apy_bps(pool),
+            pool.reward_reserve,
+            pool.leverage_ratio,
+        ),
+        ErrorCode::LeverageCapExceeded,
+        "total_staked={} reward_reserve={} leverage_ratio={} got={}",
+        pool.total_staked,
+        pool.reward_reserve,
+        pool.leverage_ratio,
+        amount
+    );
+    check_access(pool, access_entry)
+}
+
+// A snapshot may be pruned once retention_epochs newer snapshots exist
+fn snapshot_expired(epoch_index: u64, snapshot_count: u64, retention_epochs: u64) -> bool {
+    epoch_index.saturating_add(retention_epochs) < snapshot_count
+}
+
+// Highest rate any new stake could lock in; leverage and solvency are measured against it
+fn highest_apy_bps(pool: &Pool) -> u64 {
+    pool.apy_tiers.iter().map(|tier| tier.apy_bps).fold(pool.max_apy, u64::max)
+}
+
+// Share of the fees collected since the last rebalance that belongs to the reward reserve
+fn reinvestment_due(pool: &Pool) -> Result<u64> {
+    compute_fee(pool.fees_awaiting_rebalance, pool.reinvestment_rate)
+}
+
+// Fees the admin may take or share out: the part owed to referrers and the parts due to
+// the reward reserve and insurance vault at the next rebalance are held back
+fn withdrawable_fees(pool: &Pool) -> Result<u64> {
+    Ok(pool.total_fees_collected
+        .checked_sub(pool.total_referral_rewards_pending).unwrap()
+        .checked_sub(pool.insurance_pending).unwrap()
+        .checked_sub(reinvestment_due(pool)?).unwrap())
+}
+
+// Validation, MEV accounting and fee for a stake, shared by every SOL deposit path and
+// by preview_stake; returns the fee rate and amount
+fn quote_stake(
+    pool: &mut Pool,
+    access_entry: Option<&AccessEntry>,
+    amount: u64,
+    committed_days: u64,
+    clock: &Clock,
+) -> Result<(u64, u64)> {
+    validate_stake(pool, access_entry, amount, committed_days, clock.unix_timestamp)?;
+    record_slot_volume(pool, amount, clock.slot)?;
+
+    // Calculate fee at the rate for the deposit's size and commitment
+    let fee_bps = deposit_fee_bps(pool, amount, committed_days);
+    let fee_amount = compute_fee(amount, fee_bps)?;
+
+    Ok((fee_bps, fee_amount))
+}
+
+// Quote for a stake from the native and wSOL paths, once the caller's quote is honoured
+fn price_stake(accounts: &mut Stake, amount: u64, committed_days: u64, min_expected_amount: u64) -> Result<(u64, u64)> {
+    let clock = Clock::get()?;
+    let access_entry = load_access_entry(&accounts.pool, accounts.access_entry.as_ref())?;
+    let (fee_bps, fee_amount) =
+        quote_stake(&mut accounts.pool, access_entry.as_ref(), amount, committed_days, &clock)?;
+    check_quote(amount, fee_amount, min_expected_amount, accounts.pool.max_slippage_bps)?;
+
+    Ok((fee_bps, fee_amount))
+}
+
+// Price, deposit and record a SOL stake from the user's wallet
+fn stake_lamports(
+    accounts: &mut Stake,
+    user_stake_bump: u8,
+    amount: u64,
+    committed_days: u64,
+    min_expected_amount: u64,
+    referrer: Option<Pubkey>,
+) -> Result<()> {
+    let (fee_bps, fee_amount) = price_stake(accounts, amount, committed_days, min_expected_amount)?;
+    cu_checkpoint!("stake: priced");
+
+    deposit_lamports(
+        &accounts.user.to_account_info(),
+        &accounts.pool_vault,
+        &accounts.fee_vault,
+        amount.checked_sub(fee_amount).unwrap(),
+        fee_amount,
+    )?;
+    cu_checkpoint!("stake: deposited");
+
+    open_stake(accounts, user_stake_bump, amount, fee_bps, fee_amount, committed_days, referrer)?;
+    cu_checkpoint!("stake: recorded");
+
+    Ok(())
+}
+
+// Deposit that stakes exactly net_amount after the fee. The rate depends on the deposit's
+// size, so each rate the pool can charge is tried and the smallest deposit that is
+// charged the rate it was sized at wins. A net just above what the last lamport below a
+// cheaper tier's threshold can stake is unreachable at any size and is refused.
+fn exact_net_deposit(pool: &Pool, net_amount: u64, committed_days: u64) -> Result<u64> {
+    let tier_floors = std::iter::once(0).chain(pool.fee_tiers.iter().map(|tier| tier.min_amount));
+    let mut gross_amount = None;
+    for tier_floor in tier_floors {
+        let fee_bps = deposit_fee_bps(pool, tier_floor, committed_days);
+        let candidate = gross_for_net(net_amount, fee_bps)?;
+        if deposit_fee_bps(pool, candidate, committed_days) == fee_bps {
+            gross_amount = Some(gross_amount.map_or(candidate, |gross: u64| gross.min(candidate)));
+        }
+    }
+
+    match gross_amount {
+        Some(gross_amount) => Ok(gross_amount),
+        None => {
+            msg!("net={} falls between fee tiers", net_amount);
+            err!(ErrorCode::InvalidAmount)
+        }
+    }
+}
+
+// Receipts, referral and position accounting for a stake whose lamports are already in
+// the pool and fee vaults
+fn open_stake(
+    accounts: &mut Stake,
+    user_stake_bump: u8,
+    amount: u64,
+    fee_bps: u64,
+    fee_amount: u64,
+    committed_days: u64,
+    referrer: Option<Pubkey>,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let user = accounts.user.key();
+    let net_amount = amount.checked_sub(fee_amount).unwrap();
+
+    // Mint receipt tokens 1:1 with the net staked amount
+    let pool_id_bytes = accounts.pool.pool_id.to_le_bytes();
+    let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[accounts.pool.bump]];
+    token::mint_to(
+        CpiContext::new_with_signer(
+            accounts.token_program.to_account_info(),
+            MintTo {
+                mint: accounts.receipt_mint.to_account_info(),
+                to: accounts.user_receipt_account.to_account_info(),
+                authority: accounts.pool.to_account_info(),
+            },
+            &[pool_seeds],
+        ),
+        net_amount,
+    )?;
+
+    let user_stake = &mut accounts.user_stake;
+
+    if let Some(referrer) = referrer {
+        attribute_referrer(user_stake, user, referrer)?;
+
+        emit!(ReferralAttributedEvent {
+            version: EVENT_VERSION,
+            user,
+            referrer,
+            timestamp: clock.unix_timestamp,
+        });
+    }
+
+    record_stake(
+        &mut accounts.pool,
+        user_stake,
+        accounts.referral_account.as_deref_mut(),
+        user,
+        amount,
+        fee_amount,
+        committed_days,
+        &clock,
+    )?;
+    user_stake.bump = user_stake_bump;
+
+    emit!(StakeEvent {
+        version: EVENT_VERSION,
+        user,
+        pool: accounts.pool.key(),
+        payer: user,
+        amount: net_amount,
+        fee_bps,
+        committed_days,
+        user_total_after: accounts.user_stake.amount,
+        apy_bps_applied: accounts.user_stake.locked_apy_bps,
+        timestamp: clock.unix_timestamp,
+    });
+    record_history(&mut accounts.user_history, HISTORY_OP_STAKE, net_amount, clock.unix_timestamp);
+
+    Ok(())
+}
+
+// Move a deposit from the funding wallet into the vaults: principal to the pool vault,
+// the fee to its own vault so fee withdrawals can never reach principal
+fn deposit_lamports<'info>(
+    from: &AccountInfo<'info>,
+    pool_vault: &Account<'info, PoolVault>,
+    fee_vault: &Account<'info, PoolVault>,
+    net_amount: u64,
+    fee_amount: u64,
+) -> Result<()> {
+    let transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+        from.key,
+        &pool_vault.key(),
+        net_amount,
+    );
+
+    anchor_lang::solana_program::program::invoke(
+        &transfer_instruction,
+        &[from.clone(), pool_vault.to_account_info()],
+    )?;
+
+    if fee_amount > 0 {
+        let fee_transfer_instruction = anchor_lang::solana_program::system_instruction::transfer(
+            from.key,
+            &fee_vault.key(),
+            fee_amount,
+        );
+
+        anchor_lang::solana_program::program::invoke(
+            &fee_transfer_instruction,
+            &[from.clone(), fee_vault.to_account_info()],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Close a pool-owned wSOL account into the pool vault. The vault then holds the wrapped
+// amount plus the account's rent; the rent goes back to the user and the fee on to the
+// fee vault, leaving exactly the net deposit behind.
+fn unwrap_into_pool_vault<'info>(
+    stake: &Stake<'info>,
+    unwrap_account: &Account<'info, TokenAccount>,
+    unwrap_rent: u64,
+    fee_amount: u64,
+) -> Result<()> {
+    let pool_id_bytes = stake.pool.pool_id.to_le_bytes();
+    let pool_seeds: &[&[u8]] = &[b"pool", &pool_id_bytes, &[stake.pool.bump]];
+
+    token::close_account(CpiContext::new_with_signer(
+        stake.token_program.to_account_info(),
+        CloseAccount {
+            account: unwrap_account.to_account_info(),
+            destination: stake.pool_vault.to_account_info(),
+            authority: stake.pool.to_account_info(),
+        },
+        &[pool_seeds],
+    ))?;
+
+    transfer_from_vault(&stake.pool_vault, &stake.user.to_account_info(), unwrap_rent)?;
+    transfer_from_vault(&stake.pool_vault, &stake.fee_vault.to_account_info(), fee_amount)
+}
+
+// A referrer can only be recorded on the very first stake and never changed afterwards
+fn attribute_referrer(user_stake: &mut UserStake, owner: Pubkey, referrer: Pubkey) -> Result<()> {
+    require!(referrer != owner, ErrorCode::SelfReferral);
+    require!(user_stake.total_staked_lifetime == 0, ErrorCode::ReferrerAlreadySet);
+    user_stake.referrer = Some(referrer);
+
+    Ok(())
+}
+
+// Book a deposit that has already reached the vaults against the position and the pool
+#[allow(clippy::too_many_arguments)]
+fn record_stake(
+    pool: &mut Pool,
+    user_stake: &mut UserStake,
+    referral_account: Option<&mut ReferralAccount>,
+    owner: Pubkey,
+    amount: u64,
+    fee_amount: u64,
+    committed_days: u64,
+    clock: &Clock,
+) -> Result<()> {
+    let now = clock.unix_timestamp;
+    let net_amount = amount.checked_sub(fee_amount).unwrap();
+
+    // Credit the referrer's share of the deposit fee; it stays in the fee vault until claimed
+    let mut referral_reward = 0;
+    if let Some(referrer) = user_stake.referrer {
+        let referral_account = referral_account.ok_or(ErrorCode::InvalidReferrer)?;
+        require!(referral_account.referrer == referrer, ErrorCode::InvalidReferrer);
+
+        referral_reward = compute_fee(fee_amount, pool.referral_share_bps)?;
+        referral_account.pending_rewards = referral_account.pending_rewards.checked_add(referral_reward).unwrap();
+        referral_account.total_referred_volume = referral_account.total_referred_volume.checked_add(amount).unwrap();
+        pool.total_referral_rewards_pending = pool.total_referral_rewards_pending.checked_add(referral_reward).unwrap();
+    }
+
+    // Update user stake; a top-up is merged into the existing position. The tier APY is
+    // locked in now so later tier changes do not alter this deposit's yield.
+    let apy_bps = resolve_apy_bps(&pool.apy_tiers, pool.max_apy, committed_days);
+
+    // Refuse a deposit the reserve could not pay its committed yield on
+    let projected_yield = compute_yields(net_amount, apy_bps, (committed_days as i64).checked_mul(86400).unwrap())?;
+    if pool.solvency_factor_bps > 0 {
+        require_with_context!(
+            within_yield_capacity(
+                pool.total_projected_liability.checked_add(projected_yield).unwrap(),
+                pool.reward_reserve,
+                pool.solvency_factor_bps,
+            ),
+            ErrorCode::InsufficientYieldCapacity,
+            "projected_liability={} reward_reserve={} solvency_factor_bps={} projected_yield={}",
+            pool.total_projected_liability,
+            pool.reward_reserve,
+            pool.solvency_factor_bps,
+            projected_yield
+        );
+    }
+    // Claimed before its liability is raised, which would make a blank account look like
+    // one with history
+    user_stake.version = USER_STAKE_VERSION;
+    claim_position(user_stake, owner)?;
+    adjust_projected_liability(pool, user_stake, projected_yield, 0, now);
+    merge_into_position(pool, user_stake, net_amount, committed_days, apy_bps, now)?;
+    stamp_policy_version(pool, user_stake);
+    user_stake.total_staked_lifetime = user_stake.total_staked_lifetime.checked_add(net_amount).unwrap();
+    user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+    user_stake.last_stake_slot = clock.slot;
+
+    // Update pool state; referral and insurance shares remain part of the collected fees
+    // until paid or swept
+    let insurance_share = compute_fee(fee_amount, pool.insurance_share_bps)?;
+    pool.total_staked = pool.total_staked.checked_add(net_amount).unwrap();
+    count_user(pool, user_stake);
+    pool.total_fees_collected = pool.total_fees_collected.checked_add(fee_amount).unwrap();
+    pool.insurance_pending = pool.insurance_pending.checked_add(insurance_share).unwrap();
+    pool.fees_awaiting_rebalance = pool.fees_awaiting_rebalance
+        .checked_add(fee_amount.checked_sub(referral_reward).unwrap().checked_sub(insurance_share).unwrap()).unwrap();
+    pool.last_update = now;
+
+    Ok(())
+}
+
+// Add a deposit to a position. The position is first settled up to now at its old rate,
+// then its commitment clock moves to the amount-weighted average of the old
+// stake_timestamp and now. Invariant: a top-up never changes yield already accrued, and
+// the new principal only earns, and only counts towards the commitment, from now. A large
+// deposit just before a small position unlocks therefore earns no back-dated yield and
+// pushes the unlock out almost a full commitment, while repeated small top-ups barely
+// move the clock of a large position.
+fn merge_into_position(
+    pool: &Pool,
+    user_stake: &mut UserStake,
+    net_amount: u64,
+    committed_days: u64,
+    apy_bps: u64,
+    now: i64,
+) -> Result<()> {
+    update_rewards(pool, user_stake, 0, now)?;
+    merge_principal(user_stake, net_amount, committed_days, apy_bps, now);
+    Ok(())
+}
+
+// The weighting merge_into_position applies once yield is settled; token positions,
+// which earn no yield, merge through here directly
+fn merge_principal(user_stake: &mut UserStake, net_amount: u64, committed_days: u64, apy_bps: u64, now: i64) {
+    if user_stake.amount == 0 {
+        user_stake.amount = net_amount;
+        user_stake.committed_days = committed_days;
+        user_stake.locked_apy_bps = apy_bps;
+        user_stake.stake_timestamp = now;
+        user_stake.last_claim_timestamp = now;
+        user_stake.total_claimed = 0;
+        return;
+    }
+
+    let old_amount = user_stake.amount as i128;
+    let new_amount = net_amount as i128;
+    let total_amount = old_amount + new_amount;
+
+    user_stake.stake_timestamp =
+        ((old_amount * user_stake.stake_timestamp as i128 + new_amount * now as i128) / total_amount) as i64;
+    user_stake.last_claim_timestamp =
+        ((old_amount * user_stake.last_claim_timestamp as i128 + new_amount * now as i128) / total_amount) as i64;
+    // Round the commitment up so the merged unlock never lands before the weighted average
+    user_stake.committed_days = ((old_amount * user_stake.committed_days as i128
+        + new_amount * committed_days as i128
+        + total_amount
+        - 1)
+        / total_amount) as u64;
+    // Round the APY down so a top-up never earns more than either deposit's own rate
+    user_stake.locked_apy_bps = ((old_amount * user_stake.locked_apy_bps as i128
+        + new_amount * apy_bps as i128)
+        / total_amount) as u64;
+    user_stake.amount = user_stake.amount.checked_add(net_amount).unwrap();
+}
+
+// A deposit puts the position under the current commitment policy, unless merging it
+// into a grandfathered position left the commitment below today's minimum
+fn stamp_policy_version(pool: &Pool, user_stake: &mut UserStake) {
+    if user_stake.committed_days >= pool.min_commitment_days {
+        user_stake.policy_version = pool.policy_version;
+    }
+}
+
+// Settle a position about to roll over and check its commitment is met. Settlement runs
+// first, so a position already renewed into another cycle is mid-commitment again.
+fn settle_for_rollover(pool: &Pool, user_stake: &mut UserStake, new_committed_days: u64, now: i64) -> Result<()> {
+    require_with_context!(
+        (pool.min_commitment_days..=pool.max_commitment_days).contains(&new_committed_days),
+        ErrorCode::InvalidCommitmentDays,
+        "min={} max={} got={}",
+        pool.min_commitment_days,
+        pool.max_commitment_days,
+        new_committed_days
+    );
+    update_rewards(pool, user_stake, 0, now)?;
+    require_with_context!(
+        now >= commitment_end(user_stake),
+        ErrorCode::CommitmentNotMet,
+        "commitment_end={} now={}",
+        commitment_end(user_stake),
+        now
+    );
+
+    Ok(())
+}
+
+// Split a rollover's settled yield into what is compounded and what is paid out, as
+// compound_yields does; anything over the stake cap and the payout caps stays owed
+fn split_rollover_yield(pool: &Pool, user_stake: &mut UserStake, compound: bool, now: i64) -> (u64, u64) {
+    let owed = user_stake.rewards_owed;
+    let compounded = if compound { owed.min(pool.max_stake_amount.saturating_sub(user_stake.amount)) } else { 0 };
+    let paid = take_capped_yield(pool, user_stake, owed.checked_sub(compounded).unwrap(), now);
+    (compounded, paid)
+}
+
+// Move a rolled-over position onto its new commitment, returning the APY it locks in.
+// The old commitment's projected yield is released and the new one must fit within the
+// yield capacity like a fresh deposit.
+fn restart_commitment(
+    pool: &mut Pool,
+    user_stake: &mut UserStake,
+    compounded: u64,
+    settled: u64,
+    fee: u64,
+    new_committed_days: u64,
+    now: i64,
+) -> Result<u64> {
+    let principal = user_stake.amount.checked_add(compounded).unwrap().checked_sub(fee).unwrap();
+    require_with_context!(
+        principal >= pool.min_stake_amount,
+        ErrorCode::RemainderTooSmall,
+        "min={} remaining={}",
+        pool.min_stake_amount,
+        principal
+    );
+
+    let apy_bps = resolve_apy_bps(&pool.apy_tiers, pool.max_apy, new_committed_days);
+    let projected_yield = compute_yields(principal, apy_bps, (new_committed_days as i64).checked_mul(86400).unwrap())?;
+    if pool.solvency_factor_bps > 0 {
+        let projected_liability = pool.total_projected_liability
+            .saturating_sub(user_stake.projected_liability)
+            .checked_add(projected_yield).unwrap();
+        require_with_context!(
+            within_yield_capacity(projected_liability, pool.reward_reserve, pool.solvency_factor_bps),
+            ErrorCode::InsufficientYieldCapacity,
+            "projected_liability={} reward_reserve={} solvency_factor_bps={} projected_yield={}",
+            pool.total_projected_liability,
+            pool.reward_reserve,
+            pool.solvency_factor_bps,
+            projected_yield
+        );
+    }
+    let released = user_stake.projected_liability;
+    adjust_projected_liability(pool, user_stake, projected_yield, released, now);
+
+    // Settlement already advanced the accrual checkpoint to now
+    user_stake.amount = principal;
+    user_stake.stake_timestamp = now;
+    user_stake.committed_days = new_committed_days;
+    user_stake.locked_apy_bps = apy_bps;
+    user_stake.rewards_owed = user_stake.rewards_owed.checked_sub(settled).unwrap();
+    user_stake.total_claimed = user_stake.total_claimed.checked_add(settled).unwrap();
+    user_stake.total_yields_claimed = user_stake.total_yields_claimed.checked_add(settled).unwrap();
+    user_stake.total_staked_lifetime = user_stake.total_staked_lifetime.checked_add(compounded).unwrap();
+    user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+    stamp_policy_version(pool, user_stake);
+
+    // The fee is collected like a deposit fee, less the referral share that only deposits pay
+    let insurance_share = compute_fee(fee, pool.insurance_share_bps)?;
+    pool.total_staked = pool.total_staked.checked_add(compounded).unwrap().checked_sub(fee).unwrap();
+    pool.reward_reserve = pool.reward_reserve.checked_sub(settled).unwrap();
+    pool.total_yields_paid = pool.total_yields_paid.checked_add(settled).unwrap();
+    pool.total_fees_collected = pool.total_fees_collected.checked_add(fee).unwrap();
+    pool.insurance_pending = pool.insurance_pending.checked_add(insurance_share).unwrap();
+    pool.fees_awaiting_rebalance = pool.fees_awaiting_rebalance.checked_add(fee - insurance_share).unwrap();
+    pool.last_update = now;
+
+    Ok(apy_bps)
+}
+
+// Move a grandfathered position onto the pool's current minimum commitment, returning
+// the migration bonus. Time so far settles at the old rate, the lock still runs from
+// the original stake time, and the bonus is owed alongside the accrued yield.
+fn adopt_commitment_policy(pool: &Pool, user_stake: &mut UserStake, now: i64) -> Result<u64> {
+    require!(user_stake.policy_version < pool.policy_version, ErrorCode::NotGrandfathered);
+    require!(user_stake.committed_days < pool.min_commitment_days, ErrorCode::NotGrandfathered);
+
+    update_rewards(pool, user_stake, 0, now)?;
+
+    let bonus = compute_fee(user_stake.amount, pool.migration_bonus_bps)?;
+    user_stake.committed_days = pool.min_commitment_days;
+    let apy_bps = resolve_apy_bps(&pool.apy_tiers, pool.max_apy, user_stake.committed_days);
+    user_stake.locked_apy_bps = user_stake.locked_apy_bps.max(apy_bps);
+    user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+    user_stake.rewards_owed = user_stake.rewards_owed.checked_add(bonus).unwrap();
+    user_stake.policy_version = pool.policy_version;
+
+    Ok(bonus)
+}
+
+// The staker's min_expected_amount is the net deposit they were quoted and is a hard
+// floor. The fee it implies must also be within max_slippage_bps of the deposit from the
+// fee charged now, so a zero or stale floor cannot switch the protection off.
+fn check_quote(amount: u64, fee_amount: u64, min_expected_amount: u64, max_slippage_bps: u64) -> Result<()> {
+    require_with_context!(
+        fee_within_quote_tolerance(amount, fee_amount, min_expected_amount, max_slippage_bps)?,
+        ErrorCode::FeeChangedSinceQuote,
+        "fee={} max_slippage_bps={} min_expected={}",
+        fee_amount,
+        max_slippage_bps,
+        min_expected_amount
+    );
+    require_with_context!(
+        amount.checked_sub(fee_amount).unwrap() >= min_expected_amount,
+        ErrorCode::SlippageExceeded,
+        "min_expected={} got={}",
+        min_expected_amount,
+        amount.checked_sub(fee_amount).unwrap()
+    );
+
+    Ok(())
+}
+
+// Add a stake or instant unstake to the current slot's volume. Once the slot's total
+// reaches large_operation_threshold, however many operations it took to get there,
+// further stakes and unstakes wait min_block_delay slots so a split-up sandwich cannot
+// land in the same block.
+fn record_slot_volume(pool: &mut Pool, amount: u64, slot: u64) -> Result<()> {
+    require_with_context!(
+        slot >= pool.slot_delay_until,
+        ErrorCode::BlockDelayActive,
+        "delay_until={} slot={}",
+        pool.slot_delay_until,
+        slot
+    );
+
+    let current_slot_volume = if slot == pool.current_slot { pool.current_slot_volume } else { 0 };
+    let current_slot_volume = current_slot_volume.saturating_add(amount);
+    if current_slot_volume >= pool.large_operation_threshold {
+        pool.slot_delay_until = slot.saturating_add(pool.min_block_delay);
+    }
+
+    pool.current_slot = slot;
+    pool.current_slot_volume = current_slot_volume;
+
+    Ok(())
+}
+
+// Add a payout to the rolling 24h outflow window, opening a fresh window once the
+// previous one has run a full day; emergency withdrawals deliberately skip this
+fn record_outflow(pool: &mut Pool, amount: u64, now: i64) -> Result<()> {
+    let (window_start, outflow_in_window) =
+        roll_outflow_window(pool.outflow_window_start, pool.outflow_in_window, now);
+    require_with_context!(
+        outflow_within_cap(pool.total_staked, outflow_in_window, amount, pool.max_daily_outflow_bps),
+        ErrorCode::DailyOutflowExceeded,
+        "headroom={} got={}",
+        outflow_headroom(pool.total_staked, outflow_in_window, pool.max_daily_outflow_bps),
+        amount
+    );
+
+    pool.outflow_window_start = window_start;
+    pool.outflow_in_window = outflow_in_window.checked_add(amount).unwrap();
+
+    Ok(())
+}
+
+fn roll_outflow_window(window_start: i64, outflow_in_window: u64, now: i64) -> (i64, u64) {
+    if now >= window_start.checked_add(86400).unwrap() {
+        (now, 0)
+    } else {
+        (window_start, outflow_in_window)
+    }
+}
+
+// Rejects an exit or claim in the slot of the position's last deposit, or before
+// min_slots_before_exit slots have passed, so stake and exit cannot be composed atomically
+fn check_exit_slot(pool: &Pool, user_stake: &UserStake, slot: u64) -> Result<()> {
+    require_with_context!(
+        exit_slot_reached(user_stake.last_stake_slot, pool.min_slots_before_exit, slot),
+        ErrorCode::SameSlotOperation,
+        "earliest_slot={} slot={}",
+        user_stake.last_stake_slot.saturating_add(pool.min_slots_before_exit.max(1)),
+        slot
+    );
+
+    Ok(())
+}
+
+fn exit_slot_reached(last_stake_slot: u64, min_slots_before_exit: u64, slot: u64) -> bool {
+    slot >= last_stake_slot.saturating_add(min_slots_before_exit.max(1))
+}
+
+// A position that voted stays put until the last vote it cast has closed
+fn votes_unlocked(user_stake: &UserStake, now: i64) -> bool {
+    now >= user_stake.vote_locked_until
+}
+
+// A claim needs principal still earning or yield settled before a full exit
+fn has_yield_source(user_stake: &UserStake) -> bool {
+    user_stake.amount > 0 || user_stake.rewards_owed > 0
+}
+
+// Principal still earning yield; an amount queued for withdrawal stops accruing
+fn accruing_amount(user_stake: &UserStake) -> u64 {
+    let pending_amount = user_stake.pending_withdrawal.map_or(0, |pending| pending.amount);
+    user_stake.amount.saturating_sub(pending_amount)
+}
+
+// Pool-wide principal still earning yield, the total the accruing_amount of every
+// position adds up to
+fn accruing_total(pool: &Pool) -> u64 {
+    pool.total_staked.checked_sub(pool.total_pending_withdrawals).unwrap()
+}
+
+// Drop a queued amount from the pool total once it is cancelled or leaves. Queues from
+// before layout version 32 were never counted, so they must not take it below zero.
+fn unqueue_withdrawal(pool: &mut Pool, amount: u64) {
+    pool.total_pending_withdrawals = pool.total_pending_withdrawals.saturating_sub(amount);
+}
+
+// Pay accrued yield from the reward vault to the position owner; returns
+// the amount paid
+fn pay_accrued_yield<'info>(
+    pool: &mut Account<'info, Pool>,
+    user_stake: &mut UserStake,
+    reward_vault: &Account<'info, PoolVault>,
+    recipient: &AccountInfo<'info>,
+    nft_boost_bps: u64,
+    min_yields: u64,
+) -> Result<u64> {
+    let clock = Clock::get()?;
+    require!(pool.reward_mint.is_none(), ErrorCode::RewardsPaidInToken);
+
+    let (yield_amount, apy_bps, seconds_accrued) =
+        take_claimable_yield(pool, user_stake, nft_boost_bps, clock.unix_timestamp)?;
+    check_min_payout(yield_amount, min_yields)?;
+    require_with_context!(
+        pool.reward_reserve >= yield_amount,
+        ErrorCode::InsufficientRewardReserve,
+        "reward_reserve={} got={}",
+        pool.reward_reserve,
+        yield_amount
+    );
+    record_outflow(pool, yield_amount, clock.unix_timestamp)?;
+
+    // Transfer yield to user from the reward vault; principal in the pool vault is never touched
+    transfer_from_vault(reward_vault, recipient, yield_amount)?;
+
+    book_yield_claim(pool, user_stake, yield_amount, clock.unix_timestamp);
+    pool.reward_reserve = pool.reward_reserve.checked_sub(yield_amount).unwrap();
+
+    emit!(YieldsClaimedEvent {
+        version: EVENT_VERSION,
+        user: user_stake.user,
+        pool: pool.key(),
+        amount: yield_amount,
+        apy_bps,
+        loyalty_boost_bps: resolve_loyalty_boost_bps(&pool.loyalty_tiers, user_stake.total_staked_lifetime),
+        nft_boost_bps,
+        accrual_seconds: seconds_accrued as u64,
+        timestamp: clock.unix_timestamp,
+    });
+
+    Ok(yield_amount)
+}
+
+// Settle a position and take the yield a claim may pay now, in lamports, along with the
+// APY it accrued at and the seconds settled. Anything over the payout caps stays owed
+// for a later claim.
+fn take_claimable_yield(pool: &Pool, user_stake: &mut UserStake, nft_boost_bps: u64, now: i64) -> Result<(u64, u64, i64)> {
+    // Settlement runs up to now and the checkpoint only moves forward, so a period is
+    // never paid twice
+    let apy_bps = accrual_apy_bps(pool, user_stake, nft_boost_bps);
+    let seconds_accrued = update_rewards(pool, user_stake, nft_boost_bps, now)?;
+    let owed = user_stake.rewards_owed;
+    require!(owed > 0, ErrorCode::NoYieldToClaim);
+    check_min_claim(pool, user_stake, owed)?;
+
+    let yield_amount = take_capped_yield(pool, user_stake, owed, now);
+    require_with_context!(
+        yield_amount > 0,
+        ErrorCode::YieldCapReached,
+        "max_per_year={} claimed_in_year={} window_start={}",
+        pool.max_yield_per_user_per_year,
+        user_stake.yields_claimed_in_year,
+        user_stake.year_window_start
+    );
+
+    Ok((yield_amount, apy_bps, seconds_accrued))
+}
+
+// Record a paid claim on the position and the pool, whatever it was paid in; settlement
+// already advanced the checkpoint
+fn book_yield_claim(pool: &mut Pool, user_stake: &mut UserStake, yield_amount: u64, now: i64) {
+    user_stake.rewards_owed = user_stake.rewards_owed.checked_sub(yield_amount).unwrap();
+    adjust_projected_liability(pool, user_stake, 0, yield_amount, now);
+    user_stake.total_claimed = user_stake.total_claimed.checked_add(yield_amount).unwrap();
+    user_stake.total_yields_claimed = user_stake.total_yields_claimed.checked_add(yield_amount).unwrap();
+
+    // Yields are not principal, so total_staked is untouched
+    pool.total_yields_paid = pool.total_yields_paid.checked_add(yield_amount).unwrap();
+    pool.last_update = now;
+}
+
+// Claims below the pool minimum are refused before the yearly payout window is counted,
+// so retrying later costs nothing. A position with no principal left may always sweep
+// what it is still owed, or its dust could never leave and the account never close.
+fn check_min_claim(pool: &Pool, user_stake: &UserStake, owed: u64) -> Result<()> {
+    require_with_context!(
+        owed >= pool.min_claim_amount || user_stake.amount == 0,
+        ErrorCode::YieldBelowMinimum,
+        "min={} got={}",
+        pool.min_claim_amount,
+        owed
+    );
+
+    Ok(())
+}
+
+// Share of `available` yield a payout may release under the pool's caps, counted against
+// the owner's yearly window, which rolls over first once a year has passed since it opened
+fn take_capped_yield(pool: &Pool, user_stake: &mut UserStake, available: u64, now: i64) -> u64 {
+    if user_stake.year_window_start == 0 || now >= user_stake.year_window_start.saturating_add(YIELD_CAP_WINDOW) {
+        user_stake.year_window_start = now;
+        user_stake.yields_claimed_in_year = 0;
+    }
+
+    let mut amount = available;
+    if pool.max_yield_per_claim > 0 {
+        amount = amount.min(pool.max_yield_per_claim);
+    }
+    if pool.max_yield_per_user_per_year > 0 {
+        amount = amount.min(pool.max_yield_per_user_per_year.saturating_sub(user_stake.yields_claimed_in_year));
+    }
+    user_stake.yields_claimed_in_year = user_stake.yields_claimed_in_year.checked_add(amount).unwrap();
+
+    amount
+}
+
+// Combine the yearly payout windows of a transferred position and the wallet receiving
+// it: the later window still open and the larger amount claimed in an open one, so moving
+// a position never frees up allowance on either side
+fn merge_yield_windows(destination: &mut UserStake, source: &UserStake, now: i64) {
+    let open_window = |user_stake: &UserStake| {
+        if user_stake.year_window_start == 0 || now >= user_stake.year_window_start.saturating_add(YIELD_CAP_WINDOW) {
+            (0, 0)
+        } else {
+            (user_stake.year_window_start, user_stake.yields_claimed_in_year)
+        }
+    };
+    let (destination_start, destination_claimed) = open_window(destination);
+    let (source_start, source_claimed) = open_window(source);
+    destination.year_window_start = destination_start.max(source_start);
+    destination.yields_claimed_in_year = destination_claimed.max(source_claimed);
+}
+
+// Append to the user's history when they passed it
+fn record_history(user_history: &mut Option<Account<UserHistory>>, op_kind: u8, amount: u64, now: i64) {
+    if let Some(user_history) = user_history {
+        user_history.record(op_kind, amount, now);
+    }
+}
+
+// Pause reasons go out in events and are hashed into the incident log. Borsh has already
+// rejected invalid UTF-8, so only the length is left to check.
+fn check_reason(reason: &str) -> Result<()> {
+    require_with_context!(
+        reason.len() <= MAX_REASON_LEN,
+        ErrorCode::StringTooLong,
+        "max={} got={}",
+        MAX_REASON_LEN,
+        reason.len()
+    );
+
+    Ok(())
+}
+
+// Log a pause in the incident log when one was passed; only a hash of the reason is
+// stored, the text itself goes out in the pause event
+fn open_incident(incident_log: &mut Option<Account<IncidentLog>>, flags: u8, reason: &str, now: i64) {
+    if let Some(incident_log) = incident_log {
+        incident_log.open(flags, anchor_lang::solana_program::hash::hash(reason.as_bytes()).to_bytes(), now);
+    }
+}
+
+// Move accrued yield from the reward reserve into the position's pending_yields,
+// advancing the checkpoint to now; returns the amount credited, zero when there is
+// nothing to credit or the reserve cannot cover it
+fn credit_accrued_yield(pool: &mut Pool, user_stake: &mut UserStake, now: i64) -> Result<u64> {
+    if user_stake.amount == 0 {
+        return Ok(0);
+    }
+
+    let mut settled = user_stake.clone();
+    update_rewards(pool, &mut settled, 0, now)?;
+    let yield_amount = settled.rewards_owed;
+    if yield_amount == 0 || pool.reward_reserve < yield_amount {
+        return Ok(0);
+    }
+
+    settled.rewards_owed = 0;
+    settled.pending_yields = settled.pending_yields.checked_add(yield_amount).unwrap();
+
+    pool.reward_reserve = pool.reward_reserve.checked_sub(yield_amount).unwrap();
+    pool.total_yields_credited = pool.total_yields_credited.checked_add(yield_amount).unwrap();
+    adjust_projected_liability(pool, &mut settled, 0, yield_amount, now);
+    *user_stake = settled;
+
+    Ok(yield_amount)
+}
+
+// Share of the position's credited yield withdraw_credited_yields pays now. Credited
+// yields count against the same payout caps as claims while the position holds principal;
+// once the principal has left, the rest is paid in full so the caps never stop the
+// account from being closed.
+fn credited_payout(pool: &mut Pool, user_stake: &mut UserStake, now: i64) -> Result<u64> {
+    let credited = user_stake.pending_yields;
+    if user_stake.amount == 0 {
+        return Ok(credited);
+    }
+
+    let amount = take_capped_yield(pool, user_stake, credited, now);
+    require!(amount > 0, ErrorCode::YieldCapReached);
+    record_outflow(pool, amount, now)?;
+
+    Ok(amount)
+}
+
+// Hand credited yield back to the reward reserve it was taken from; its lamports never
+// left the reward vault. Used by the principal-only emergency exit.
+fn return_credited_yields(pool: &mut Pool, user_stake: &mut UserStake) {
+    pool.reward_reserve = pool.reward_reserve.checked_add(user_stake.pending_yields).unwrap();
+    pool.total_yields_credited = pool.total_yields_credited.checked_sub(user_stake.pending_yields).unwrap();
+    user_stake.pending_yields = 0;
+}
+
+// A copy of the position settled up to `now`, renewals included; the views read this,
+// and claims and compounding settle through the same update_rewards, so they can never
+// disagree
+fn settled_position(pool: &Pool, user_stake: &UserStake, nft_boost_bps: u64, now: i64) -> Result<UserStake> {
+    let mut settled = user_stake.clone();
+    update_rewards(pool, &mut settled, nft_boost_bps, now)?;
+    Ok(settled)
+}
+
+// Bring a position's rewards_owed up to `now`; every instruction that reads or changes
+// a position's balance or rate runs this first. Two terms add up:
+// - its share of revenue distributed since it last collected, from the pool's
+//   reward-per-token accumulator
+// - every second since the checkpoint at the rate snapshotted by the previous update,
+//   yield = amount * apy_bps * seconds / (365 days of seconds * 10000 bps)
+// The rate in force now is then snapshotted, so a rate change on the pool only reaches
+// a position from its next update, never backwards. Returns the seconds settled.
+fn update_rewards(pool: &Pool, user_stake: &mut UserStake, nft_boost_bps: u64, now: i64) -> Result<i64> {
+    // A clock reading from before the pool existed is a broken clock, not elapsed time
+    require_with_context!(
+        now >= pool.created_at,
+        ErrorCode::ClockBeforePoolCreation,
+        "created_at={} now={}",
+        pool.created_at,
+        now
+    );
+
+    let shared = rewards_earned(
+        accruing_amount(user_stake),
+        pool.reward_per_token_stored,
+        user_stake.reward_per_token_paid,
+    )?;
+    user_stake.rewards_owed = user_stake.rewards_owed.checked_add(shared).unwrap();
+    user_stake.reward_per_token_paid = pool.reward_per_token_stored;
+
+    // Nothing accrues after a sunset pool's sunset_at
+    let accrual_end = if pool.is_active { now } else { now.min(pool.sunset_at) };
+    let seconds_accrued = accrual_end.saturating_sub(user_stake.last_claim_timestamp).max(0);
+    renew_commitment(pool, user_stake, now);
+
+    if seconds_accrued > 0 {
+        // A renewing position earns its rate throughout, renewed cycles included; one
+        // opted out of renewal drops to the idle rate once its commitment has ended
+        let apy_bps = accrual_apy_bps(pool, user_stake, nft_boost_bps);
+        let committed_seconds = match idle_since(pool, user_stake) {
+            Some(idle_start) => idle_start.saturating_sub(user_stake.last_claim_timestamp).clamp(0, seconds_accrued),
+            None => seconds_accrued,
+        };
+        let amount = accruing_amount(user_stake);
+        let yield_amount = compute_yields(amount, apy_bps, committed_seconds)?
+            .checked_add(compute_yields(amount, pool.idle_apy_bps.min(apy_bps), seconds_accrued - committed_seconds)?)
+            .unwrap();
+        user_stake.rewards_owed = user_stake.rewards_owed.checked_add(yield_amount).unwrap();
+        user_stake.last_claim_timestamp = user_stake.last_claim_timestamp.checked_add(seconds_accrued).unwrap();
+    }
+    user_stake.accrual_rate_bps = effective_apy_bps(pool, user_stake, 0);
+
+    Ok(seconds_accrued)
+}
+
+// Roll a position whose commitment ended more than the pool's grace window ago into every
+// fresh commitment of the same length begun since, each starting where the last ended.
+// The locked rate carries over. Only active pools with renewal on roll positions, and
+// never ones whose owner opted out or that hold no principal.
+fn renew_commitment(pool: &Pool, user_stake: &mut UserStake, now: i64) {
+    if !pool.is_active || pool.renewal_grace_seconds == 0 || user_stake.auto_renew_opted_out || user_stake.amount == 0 {
+        return;
+    }
+
+    let commitment_seconds = (user_stake.committed_days as i64).checked_mul(86400).unwrap();
+    let cycles = renewal_cycles(user_stake.stake_timestamp, commitment_seconds, pool.renewal_grace_seconds, now);
+    if cycles == 0 {
+        return;
+    }
+    user_stake.stake_timestamp = user_stake.stake_timestamp
+        .checked_add(commitment_seconds.checked_mul(cycles as i64).unwrap()).unwrap();
+
+    emit!(CommitmentRenewedEvent {
+        version: EVENT_VERSION,
+        user: user_stake.user,
+        pool_id: pool.pool_id,
+        cycles,
+        committed_days: user_stake.committed_days,
+        stake_timestamp: user_stake.stake_timestamp,
+        timestamp: now,
+    });
+}
+
+// When an opted-out position on a renewing pool stopped earning its committed rate
+fn idle_since(pool: &Pool, user_stake: &UserStake) -> Option<i64> {
+    (pool.renewal_grace_seconds > 0 && user_stake.auto_renew_opted_out).then(|| commitment_end(user_stake))
+}
+
+// Snapshotted rate plus the NFT boost proven for this claim; a lower max_apy since the
+// snapshot never cuts the snapshotted part
+fn accrual_apy_bps(pool: &Pool, user_stake: &UserStake, nft_boost_bps: u64) -> u64 {
+    apply_apy_boost(user_stake.accrual_rate_bps, nft_boost_bps, pool.max_apy)
+}
+
+// Locked APY plus the loyalty boost the owner's lifetime stake has earned and any
+// verified NFT boost
+fn effective_apy_bps(pool: &Pool, user_stake: &UserStake, nft_boost_bps: u64) -> u64 {
+    let loyalty_boost_bps = resolve_loyalty_boost_bps(&pool.loyalty_tiers, user_stake.total_staked_lifetime);
+    apply_apy_boost(user_stake.locked_apy_bps, loyalty_boost_bps + nft_boost_bps, pool.max_apy)
+}
+
+// NFT boost for a claim: zero without remaining accounts, otherwise they must prove
+// the owner holds an NFT from the pool's verified boost collection
+fn resolve_nft_boost_bps(pool: &Pool, owner: &Pubkey, remaining_accounts: &[AccountInfo]) -> Result<u64> {
+    let boost_collection = match pool.boost_collection {
+        Some(collection) if !remaining_accounts.is_empty() => collection,
+        _ => return Ok(0),
+    };
+    require!(remaining_accounts.len() == 2, ErrorCode::NftBoostVerificationFailed);
+    let (token_info, metadata_info) = (&remaining_accounts[0], &remaining_accounts[1]);
+
+    require!(token_info.owner == &Token::id(), ErrorCode::NftBoostVerificationFailed);
+    let token_account = TokenAccount::try_deserialize(&mut &token_info.try_borrow_data()?[..])?;
+    require!(token_account.owner == *owner && token_account.amount >= 1, ErrorCode::NftBoostVerificationFailed);
+
+    // Only the canonical metadata PDA, owned by the Metadata program, can be trusted
+    require!(metadata_info.owner == &Metadata::id(), ErrorCode::NftBoostVerificationFailed);
+    require!(
+        metadata_info.key() == MetadataAccount::find_pda(&token_account.mint).0,
+        ErrorCode::NftBoostVerificationFailed
+    );
+    let metadata = MetadataAccount::safe_deserialize(&metadata_info.try_borrow_data()?)
+        .map_err(|_| ErrorCode::NftBoostVerificationFailed)?;
+    require!(
+        nft_in_verified_collection(&metadata, &token_account.mint, &boost_collection),
+        ErrorCode::NftBoostVerificationFailed
+    );
+
+    Ok(pool.nft_boost_bps)
+}
+
+// Metadata must describe this mint and carry the collection with its verified flag set
+fn nft_in_verified_collection(metadata: &MetadataAccount, mint: &Pubkey, boost_collection: &Pubkey) -> bool {
+    metadata.mint == *mint
+        && metadata
+            .collection
+            .as_ref()
+            .is_some_and(|collection| collection.verified && collection.key == *boost_collection)
+}
+
+// What get_pending_yields reports for a position at the given time; public so off-chain
+// clients quote exactly what the program would
+pub fn pending_yields(pool: &Pool, user_stake: &UserStake, now: i64) -> Result<PendingYields> {
+    // Settled as a claim would be, so a renewal due by now shows in the days left
+    let settled = settled_position(pool, user_stake, 0, now)?;
+
+    Ok(PendingYields {
+        yields: settled.rewards_owed,
+        credited_yields: settled.pending_yields,
+        days_until_commitment_met: days_until_commitment_met(&settled, now),
+    })
+}
+
+// Principal, everything owed on it and when it unlocks; like get_pending_yields, this
+// leaves out the NFT boost
+pub fn position_value(pool: &Pool, user_stake: &UserStake, now: i64) -> Result<PositionValue> {
+    let settled = settled_position(pool, user_stake, 0, now)?;
+
+    Ok(PositionValue {
+        amount: settled.amount,
+        accrued_yields: settled.rewards_owed.checked_add(settled.pending_yields).unwrap(),
+        unlock_ts: commitment_end(&settled),
+        apy_bps: accrual_apy_bps(pool, &settled, 0),
+    })
+}
+
+pub fn pool_stats(pool: &Pool) -> PoolStats {
+    PoolStats {
+        total_staked: pool.total_staked,
+        total_users: pool.total_users,
+        utilization_bps: yield_capacity_utilization_bps(
+            pool.total_projected_liability,
+            pool.reward_reserve,
+            pool.solvency_factor_bps,
+        ),
+    }
+}
+
+// What preview_stake reports. The stake's own validation and accounting run on copies
+// of the accounts, so the slot volume and limits are evaluated but never kept. The
+// caller's quote and wallet balance are left out: the preview is the quote.
+pub fn stake_preview(
+    pool: &Pool,
+    user_stake: &UserStake,
+    access_entry: Option<&AccessEntry>,
+    referral_account: Option<&ReferralAccount>,
+    amount: u64,
+    committed_days: u64,
+    clock: &Clock,
+) -> OperationPreview {
+    let mut pool = pool.clone();
+    let mut user_stake = user_stake.clone();
+    let mut referral_account = referral_account.cloned();
+
+    preview_outcome(simulate_stake(
+        &mut pool,
+        &mut user_stake,
+        access_entry,
+        referral_account.as_mut(),
+        amount,
+        committed_days,
+        clock,
+    ))
+}
+
+fn simulate_stake(
+    pool: &mut Pool,
+    user_stake: &mut UserStake,
+    access_entry: Option<&AccessEntry>,
+    referral_account: Option<&mut ReferralAccount>,
+    amount: u64,
+    committed_days: u64,
+    clock: &Clock,
+) -> Result<OperationPreview> {
+    let (_, fee_amount) = quote_stake(pool, access_entry, amount, committed_days, clock)?;
+    let liability_before = user_stake.projected_liability;
+    let owner = user_stake.user;
+    record_stake(pool, user_stake, referral_account, owner, amount, fee_amount, committed_days, clock)?;
+
+    Ok(OperationPreview {
+        would_succeed: true,
+        fee: fee_amount,
+        net: amount.checked_sub(fee_amount).unwrap(),
+        yields: user_stake.projected_liability.checked_sub(liability_before).unwrap(),
+        ..OperationPreview::default()
+    })
+}
+
+// What preview_unstake reports: an instant exit of the whole position through the same
+// checks and settlement as unstake, on copies of the accounts
+pub fn unstake_preview(pool: &Pool, user_stake: &UserStake, vault_available: u64, clock: &Clock) -> OperationPreview {
+    let mut pool = pool.clone();
+    let mut user_stake = user_stake.clone();
+
+    preview_outcome(simulate_unstake(&mut pool, &mut user_stake, vault_available, clock))
+}
+
+fn simulate_unstake(
+    pool: &mut Pool,
+    user_stake: &mut UserStake,
+    vault_available: u64,
+    clock: &Clock,
+) -> Result<OperationPreview> {
+    let amount = user_stake.amount;
+    check_instant_exit(pool, user_stake, amount, clock)?;
+    let instant_exit_penalty_bps = pool.instant_exit_penalty_bps;
+    let (penalty_amount, final_amount, _) = settle_withdrawal(
+        pool,
+        user_stake,
+        amount,
+        instant_exit_penalty_bps,
+        NO_MIN_PAYOUT,
+        clock.unix_timestamp,
+    )?;
+    check_principal_liquidity(pool, vault_available, final_amount)?;
+
+    Ok(OperationPreview {
+        would_succeed: true,
+        net: amount,
+        yields: user_stake.rewards_owed.checked_add(user_stake.pending_yields).unwrap(),
+        penalty: penalty_amount,
+        return_amount: final_amount,
+        ..OperationPreview::default()
+    })
+}
+
+// A refused operation is reported with the code it would fail with; builtin program
+// errors carry theirs in the upper half of the u64
+fn preview_outcome(outcome: Result<OperationPreview>) -> OperationPreview {
+    outcome.unwrap_or_else(|error| {
+        let failure_code = match ProgramError::from(error) {
+            ProgramError::Custom(code) => code,
+            builtin => (u64::from(builtin) >> 32) as u32,
+        };
+        OperationPreview { failure_code, ..OperationPreview::default() }
+    })
+}
+
+// Whole days left until the commitment is met, rounded up; zero once it has been
+fn days_until_commitment_met(user_stake: &UserStake, now: i64) -> u64 {
+    let remaining_seconds = commitment_end(user_stake).saturating_sub(now).max(0) as u64;
+    remaining_seconds.div_ceil(86400)
+}
+
+fn commitment_end(user_stake: &UserStake) -> i64 {
+    user_stake.stake_timestamp
+        .checked_add(user_stake.committed_days.checked_mul(86400).unwrap() as i64).unwrap()
+}
+
+// Tiers must fit the fixed table, list strictly increasing thresholds and carry a valid APY
+fn validate_apy_tiers(apy_tiers: &[ApyTier]) -> Result<()> {
+    require!(apy_tiers.len() <= MAX_APY_TIERS, ErrorCode::InvalidApyTiers);
+    for (index, tier) in apy_tiers.iter().enumerate() {
+        require!(tier.apy_bps > 0 && tier.apy_bps <= MAX_APY_BPS, ErrorCode::InvalidApy);
+        if index > 0 {
+            require!(tier.min_days > apy_tiers[index - 1].min_days, ErrorCode::InvalidApyTiers);
+        }
+    }
+
+    Ok(())
+}
+
+// APY for a commitment: the highest tier whose threshold it reaches, or the pool's
+// base APY when no tier applies
+fn resolve_apy_bps(apy_tiers: &[ApyTier], base_apy_bps: u64, committed_days: u64) -> u64 {
+    apy_tiers
+        .iter()
+        .rev()
+        .find(|tier| committed_days >= tier.min_days)
+        .map_or(base_apy_bps, |tier| tier.apy_bps)
+}
+
+// Loyalty tiers must fit the fixed table, list strictly increasing thresholds and stay
+// within the boost cap
+fn validate_loyalty_tiers(loyalty_tiers: &[LoyaltyTier]) -> Result<()> {
+    require!(loyalty_tiers.len() <= MAX_LOYALTY_TIERS, ErrorCode::InvalidLoyaltyTiers);
+    for (index, tier) in loyalty_tiers.iter().enumerate() {
+        require!(tier.boost_bps > 0 && tier.boost_bps <= MAX_LOYALTY_BOOST_BPS, ErrorCode::InvalidLoyaltyTiers);
+        if index > 0 {
+            require!(
+                tier.min_lifetime_staked > loyalty_tiers[index - 1].min_lifetime_staked,
+                ErrorCode::InvalidLoyaltyTiers
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Boost for a lifetime stake: the highest tier it reaches, or none
+fn resolve_loyalty_boost_bps(loyalty_tiers: &[LoyaltyTier], total_staked_lifetime: u64) -> u64 {
+    loyalty_tiers
+        .iter()
+        .rev()
+        .find(|tier| total_staked_lifetime >= tier.min_lifetime_staked)
+        .map_or(0, |tier| tier.boost_bps)
+}
+
+// Fee tiers must fit the fixed table, list strictly increasing thresholds and stay within
+// the 10% fee cap
+fn validate_fee_tiers(fee_tiers: &[FeeTier]) -> Result<()> {
+    require!(fee_tiers.len() <= MAX_FEE_TIERS, ErrorCode::InvalidFeeTiers);
+    for (index, tier) in fee_tiers.iter().enumerate() {
+        require!(tier.fee_bps <= 1000, ErrorCode::InvalidFeeTiers);
+        if index > 0 {
+            require!(tier.min_amount > fee_tiers[index - 1].min_amount, ErrorCode::InvalidFeeTiers);
+        }
+    }
+
+    Ok(())
+}
+
+// Deposit fee for a stake of the given size: the highest tier it reaches, or the pool's
+// base fee when no tier applies
+fn resolve_fee_bps(fee_tiers: &[FeeTier], base_fee_bps: u64, amount: u64) -> u64 {
+    fee_tiers
+        .iter()
+        .rev()
+        .find(|tier| amount >= tier.min_amount)
+        .map_or(base_fee_bps, |tier| tier.fee_bps)
+}
+
+// Deposit fee for a SOL stake: the size tier's rate, discounted for long commitments
+fn deposit_fee_bps(pool: &Pool, amount: u64, committed_days: u64) -> u64 {
+    apply_commitment_discount(
+        resolve_fee_bps(&pool.fee_tiers, pool.deposit_fee_bps, amount),
+        committed_days,
+        pool.long_commit_threshold_days,
+        pool.long_commit_fee_discount_bps,
+    )
+}
+
+// Pay lamports out of a program-owned vault, never dipping below its rent-exempt minimum
+fn transfer_from_vault<'info>(
+    pool_vault: &Account<'info, PoolVault>,
+    recipient: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let vault_info = pool_vault.to_account_info();
+    let available = vault_available_lamports(&vault_info)?;
+    require!(available >= amount, ErrorCode::InsufficientFunds);
+
+    **vault_info.try_borrow_mut_lamports()? -= amount;
+    **recipient.try_borrow_mut_lamports()? += amount;
+
+    Ok(())
+}
+
+// Pay principal out of the pool vault. While capital is deployed to Marinade a vault
+// shortfall means the admin has to recall liquidity first, not that the pool is insolvent.
+fn pay_from_pool_vault<'info>(
+    pool: &Pool,
+    pool_vault: &Account<'info, PoolVault>,
+    recipient: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    check_principal_liquidity(pool, vault_available_lamports(&pool_vault.to_account_info())?, amount)?;
+
+    transfer_from_vault(pool_vault, recipient, amount)
+}
+
+// Whether the pool vault, holding `available` lamports above rent, can pay out principal
+fn check_principal_liquidity(pool: &Pool, available: u64, amount: u64) -> Result<()> {
+    if marinade_principal(pool) > 0 {
+        require!(available >= amount, ErrorCode::LiquidityBeingRecalled);
+    }
+    require!(available >= amount, ErrorCode::InsufficientFunds);
+
+    Ok(())
+}
+
+// Lamports a program-owned vault holds above its rent-exempt minimum
+fn vault_available_lamports(vault_info: &AccountInfo) -> Result<u64> {
+    let rent_exempt_minimum = Rent::get()?.minimum_balance(vault_info.data_len());
+    Ok(vault_info.lamports().saturating_sub(rent_exempt_minimum))
+}
+
+// Principal out of the pool vault in Marinade, as mSOL or in unstake tickets not yet claimed
+fn marinade_principal(pool: &Pool) -> u64 {
+    pool.deployed_to_marinade.checked_add(pool.marinade_ticket_principal).unwrap()
+}
+
+// Marinade's current msol_price, read from its state account
+fn marinade_msol_price(marinade_state: &AccountInfo) -> Result<u64> {
+    let data = marinade_state.try_borrow_data()?;
+    require!(
+        *marinade_state.owner == MARINADE_PROGRAM_ID
+            && data.len() >= MARINADE_MSOL_PRICE_OFFSET + 8
+            && data[..8] == MARINADE_STATE_DISCRIMINATOR,
+        ErrorCode::InvalidMarinadeAccount
+    );
+
+    Ok(u64::from_le_bytes(data[MARINADE_MSOL_PRICE_OFFSET..MARINADE_MSOL_PRICE_OFFSET + 8].try_into().unwrap()))
+}
+
+// The beneficiary of a Marinade delayed-unstake ticket and the lamports it is owed, once the
+// ticket is known to belong to the given Marinade state
+fn read_marinade_ticket(ticket: &AccountInfo, marinade_state: &Pubkey) -> Result<(Pubkey, u64)> {
+    let data = ticket.try_borrow_data()?;
+    require!(
+        *ticket.owner == MARINADE_PROGRAM_ID
+            && data.len() >= MARINADE_TICKET_SPACE
+            && data[..8] == MARINADE_TICKET_DISCRIMINATOR
+            && data[8..40] == marinade_state.to_bytes(),
+        ErrorCode::InvalidMarinadeAccount
+    );
+
+    let beneficiary = Pubkey::try_from(&data[40..72]).unwrap();
+    Ok((beneficiary, u64::from_le_bytes(data[72..80].try_into().unwrap())))
+}
+
+// Pay SOL back from Marinade out of the staging PDA against the principal it stood for:
+// the principal to the pool vault and staking yield above it to the reward vault, with
+// any shortfall made good from the reward reserve and booked as a Marinade loss. Returns
+// the yield and the loss.
+#[allow(clippy::too_many_arguments)]
+fn settle_marinade_return<'info>(
+    pool: &mut Pool,
+    pool_vault: &Account<'info, PoolVault>,
+    reward_vault: &Account<'info, PoolVault>,
+    marinade_staging: &SystemAccount<'info>,
+    staging_seeds: &[&[u8]],
+    system_program_account: &Program<'info, System>,
+    sol_received: u64,
+    principal: u64,
+) -> Result<(u64, u64)> {
+    let (principal_returned, yield_to_reserve, loss_covered) = split_marinade_return(sol_received, principal);
+    require_with_context!(
+        pool.reward_reserve >= loss_covered,
+        ErrorCode::InsufficientRewardReserve,
+        "reserve={} loss={}",
+        pool.reward_reserve,
+        loss_covered
+    );
+
+    for (destination, amount) in [
+        (pool_vault.to_account_info(), principal_returned),
+        (reward_vault.to_account_info(), yield_to_reserve),
+    ] {
+        if amount > 0 {
+            system_program::transfer(
+                CpiContext::new_with_signer(
+                    system_program_account.to_account_info(),
+                    system_program::Transfer {
+                        from: marinade_staging.to_account_info(),
+                        to: destination,
+                    },
+                    &[staging_seeds],
+                ),
+                amount,
+            )?;
+        }
+    }
+    if loss_covered > 0 {
+        transfer_from_vault(reward_vault, &pool_vault.to_account_info(), loss_covered)?;
+    }
+
+    pool.reward_reserve = pool.reward_reserve
+        .checked_add(yield_to_reserve).unwrap()
+        .checked_sub(loss_covered).unwrap();
+    pool.marinade_loss = pool.marinade_loss.checked_add(loss_covered).unwrap();
+
+    Ok((yield_to_reserve, loss_covered))
+}
+
+// Call a Marinade instruction with its accounts given in order as (account, writable,
+// signer), signed by the pool PDA the seeds belong to
+fn invoke_marinade<'info>(
+    marinade_program: &UncheckedAccount<'info>,
+    accounts: &[(AccountInfo<'info>, bool, bool)],
+    data: Vec<u8>,
+    signer_seeds: &[&[u8]],
+) -> Result<()> {
+    let instruction = Instruction {
+        program_id: marinade_program.key(),
+        accounts: accounts
+            .iter()
+            .map(|(account, is_writable, is_signer)| AccountMeta {
+                pubkey: account.key(),
+                is_signer: *is_signer,
+                is_writable: *is_writable,
+            })
+            .collect(),
+        data,
+    };
+    let account_infos: Vec<AccountInfo<'info>> = accounts.iter().map(|(account, _, _)| account.clone()).collect();
+    anchor_lang::solana_program::program::invoke_signed(&instruction, &account_infos, &[signer_seeds])?;
+
+    Ok(())
+}
+
+// Account contexts
+#[derive(Accounts)]
+#[instruction(pool_id: u64)]
+pub struct InitializePool<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = 8 + PoolRegistry::INIT_SPACE,
+        seeds = [b"pool_registry"],
+        bump
+    )]
+    pub pool_registry: Account<'info, PoolRegistry>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Pool::INIT_SPACE,
+        seeds = [b"pool", pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PoolVault::INIT_SPACE,
+        seeds = [b"pool_vault", pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PoolVault::INIT_SPACE,
+        seeds = [b"reward_vault", pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PoolVault::INIT_SPACE,
+        seeds = [b"fee_vault", pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"receipt_mint", pool_id.to_le_bytes().as_ref()],
+        bump,
+        mint::decimals = 9,
+        mint::authority = pool
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.deposits_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump,
+        constraint = user_stake.version == 0 || user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    // Required once the position has a referrer
+    #[account(
+        mut,
+        seeds = [b"referral", pool.pool_id.to_le_bytes().as_ref(), referral_account.referrer.as_ref()],
+        bump
+    )]
+    pub referral_account: Option<Account<'info, ReferralAccount>>,
+    
+    /// CHECK: The user's access PDA, required whenever the pool is gated; read by
+    /// load_access_entry, which treats an account never created as no entry
+    #[account(
+        seeds = [b"access", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub access_entry: Option<UncheckedAccount<'info>>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct StakeWsol<'info> {
+    pub stake: Stake<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_wsol_account.mint == native_mint::ID @ ErrorCode::InvalidWsolAccount,
+        constraint = user_wsol_account.owner == stake.user.key() @ ErrorCode::InvalidWsolAccount
+    )]
+    pub user_wsol_account: Box<Account<'info, TokenAccount>>,
+    
+    // Holds the wrapped amount only until it is closed into the pool vault
+    #[account(
+        init,
+        payer = stake.user,
+        seeds = [b"wsol_unwrap", stake.pool.pool_id.to_le_bytes().as_ref(), stake.user.key().as_ref()],
+        bump,
+        token::mint = native_mint,
+        token::authority = stake.pool
+    )]
+    pub unwrap_account: Box<Account<'info, TokenAccount>>,
+    
+    #[account(address = native_mint::ID)]
+    pub native_mint: Box<Account<'info, Mint>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StakeWithSwap<'info> {
+    pub stake: Stake<'info>,
+    
+    // Any SPL token but wSOL, which goes through stake_wsol
+    #[account(
+        mut,
+        constraint = user_input_account.mint != native_mint::ID @ ErrorCode::InvalidSwapRoute,
+        constraint = user_input_account.owner == stake.user.key() @ ErrorCode::InvalidSwapRoute
+    )]
+    pub user_input_account: Box<Account<'info, TokenAccount>>,
+    
+    // Receives the swap output and is closed into the pool vault in the same instruction
+    #[account(
+        init,
+        payer = stake.user,
+        seeds = [b"swap_output", stake.pool.pool_id.to_le_bytes().as_ref(), stake.user.key().as_ref()],
+        bump,
+        token::mint = native_mint,
+        token::authority = stake.pool
+    )]
+    pub swap_output_account: Box<Account<'info, TokenAccount>>,
+    
+    #[account(address = native_mint::ID)]
+    pub native_mint: Box<Account<'info, Mint>>,
+    
+    /// CHECK: only the swap router is ever invoked; the route itself is checked by its output
+    #[account(executable, address = SWAP_PROGRAM_ID @ ErrorCode::InvalidSwapRoute)]
+    pub swap_program: UncheckedAccount<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StakeFor<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    pub beneficiary: SystemAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.deposits_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), beneficiary.key().as_ref()],
+        bump,
+        constraint = user_stake.version == 0 || user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        init_if_needed,
+        payer = payer,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = beneficiary
+    )]
+    pub beneficiary_receipt_account: Account<'info, TokenAccount>,
+    
+    // Required once the position has a referrer
+    #[account(
+        mut,
+        seeds = [b"referral", pool.pool_id.to_le_bytes().as_ref(), referral_account.referrer.as_ref()],
+        bump
+    )]
+    pub referral_account: Option<Account<'info, ReferralAccount>>,
+    
+    /// CHECK: Gated pools check the beneficiary, not the payer; read by load_access_entry
+    #[account(
+        seeds = [b"access", pool.pool_id.to_le_bytes().as_ref(), beneficiary.key().as_ref()],
+        bump
+    )]
+    pub access_entry: Option<UncheckedAccount<'info>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+    
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init,
+        payer = referrer,
+        space = 8 + ReferralAccount::INIT_SPACE,
+        seeds = [b"referral", pool.pool_id.to_le_bytes().as_ref(), referrer.key().as_ref()],
+        bump
+    )]
+    pub referral_account: Account<'info, ReferralAccount>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BatchCreditYields<'info> {
+    pub keeper: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawCreditedYields<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"referral", pool.pool_id.to_le_bytes().as_ref(), referrer.key().as_ref()],
+        bump,
+        has_one = referrer
+    )]
+    pub referral_account: Account<'info, ReferralAccount>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimYields<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimTokenYields<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        constraint = pool.reward_mint == Some(reward_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub reward_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_token_vault", pool.pool_id.to_le_bytes().as_ref(), reward_mint.key().as_ref()],
+        bump = pool.reward_token_vault_bump
+    )]
+    pub reward_token_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = reward_mint,
+        associated_token::authority = user
+    )]
+    pub user_reward_token_account: Account<'info, TokenAccount>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimYieldsDelegated<'info> {
+    pub delegate: Signer<'info>,
+    
+    /// CHECK: Receives the payout; must be the owner recorded on the stake account
+    #[account(mut)]
+    pub user: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ ErrorCode::Unauthorized,
+        constraint = user_stake.claim_delegate == Some(delegate.key()) @ ErrorCode::Unauthorized,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetClaimDelegate<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ ErrorCode::Unauthorized,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct ManageWithdrawal<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ ErrorCode::Unauthorized,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct ExtendCommitment<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        constraint = !pool_is_paused(&pool, Clock::get()?.unix_timestamp),
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct CompoundYields<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = !pool.deposits_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Rollover<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.claims_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = !pool.deposits_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = !pool.unstake_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    // Appended to when passed
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Option<Account<'info, UserHistory>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnstakeToWsol<'info> {
+    pub unstake: Unstake<'info>,
+    
+    #[account(
+        mut,
+        constraint = user_wsol_account.mint == native_mint::ID @ ErrorCode::InvalidWsolAccount,
+        constraint = user_wsol_account.owner == unstake.user.key() @ ErrorCode::InvalidWsolAccount
+    )]
+    pub user_wsol_account: Box<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        close = user,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        mut,
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TransferStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    /// CHECK: Any wallet may receive a position
+    pub new_owner: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool_is_paused(&pool, Clock::get()?.unix_timestamp),
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // The source is emptied by the transfer, so its rent goes back to the user
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        close = user,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), new_owner.key().as_ref()],
+        bump,
+        constraint = destination_stake.version == 0 || destination_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub destination_stake: Account<'info, UserStake>,
+    
+    #[account(
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = user
+    )]
+    pub user_receipt_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = receipt_mint,
+        associated_token::authority = new_owner
+    )]
+    pub destination_receipt_account: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUserStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // An empty position accrues nothing, but yield settled before its exit must be claimed first
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.amount == 0 @ ErrorCode::StakeNotEmpty,
+        close = user,
+        constraint = user_stake.pending_yields == 0 @ ErrorCode::CreditedYieldsPending,
+        constraint = user_stake.rewards_owed == 0 @ ErrorCode::UnclaimedYields,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct InitUserHistory<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        init,
+        payer = user,
+        space = 8 + UserHistory::INIT_SPACE,
+        seeds = [b"history", user.key().as_ref()],
+        bump
+    )]
+    pub user_history: Account<'info, UserHistory>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ManageUserHistory<'info> {
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump
+    )]
+    pub user_history: Account<'info, UserHistory>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUserHistory<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"history", user.key().as_ref()],
+        bump = user_history.bump,
+        close = user
+    )]
+    pub user_history: Account<'info, UserHistory>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeRewardTokenVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    pub reward_mint: Account<'info, Mint>,
+    
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"reward_token_vault", pool.pool_id.to_le_bytes().as_ref(), reward_mint.key().as_ref()],
+        bump,
+        token::mint = reward_mint,
+        token::authority = pool
+    )]
+    pub reward_token_vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct FundTokenRewards<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        constraint = pool.reward_mint == Some(reward_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub reward_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_token_vault", pool.pool_id.to_le_bytes().as_ref(), reward_mint.key().as_ref()],
+        bump = pool.reward_token_vault_bump
+    )]
+    pub reward_token_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        token::mint = reward_mint,
+        token::authority = admin
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeTokenVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        constraint = pool.stake_mint == Some(stake_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub stake_mint: Account<'info, Mint>,
+    
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"token_vault", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref()],
+        bump,
+        token::mint = stake_mint,
+        token::authority = pool
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct StakeSpl<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.deposits_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        constraint = pool.stake_mint == Some(stake_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub stake_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_mint.key() @ ErrorCode::InvalidMint,
+        constraint = user_token_account.owner == user.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"token_vault", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref()],
+        bump = pool.token_vault_bump
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_token_stake", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref(), user.key().as_ref()],
+        bump,
+        constraint = user_stake.version == 0 || user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    /// CHECK: The user's access PDA, required whenever the pool is gated; read by
+    /// load_access_entry, which treats an account never created as no entry
+    #[account(
+        seeds = [b"access", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub access_entry: Option<UncheckedAccount<'info>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UnstakeSpl<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool.unstake_paused(Clock::get()?.unix_timestamp) @ ErrorCode::PoolPaused,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        constraint = pool.stake_mint == Some(stake_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub stake_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_mint.key() @ ErrorCode::InvalidMint,
+        constraint = user_token_account.owner == user.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"token_vault", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref()],
+        bump = pool.token_vault_bump
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_token_stake", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdrawSpl<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        constraint = pool.stake_mint == Some(stake_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub stake_mint: Account<'info, Mint>,
+    
+    #[account(
+        mut,
+        constraint = user_token_account.mint == stake_mint.key() @ ErrorCode::InvalidMint,
+        constraint = user_token_account.owner == user.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"token_vault", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref()],
+        bump = pool.token_vault_bump
+    )]
+    pub token_vault: Account<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_token_stake", pool.pool_id.to_le_bytes().as_ref(), stake_mint.key().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        close = user,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct CreateReceiptMetadata<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    /// CHECK: Validated by seeds against the Metadata program; created by the CPI
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), receipt_mint.key().as_ref()],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata: UncheckedAccount<'info>,
+    
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReceiptMetadata<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"receipt_mint", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub receipt_mint: Account<'info, Mint>,
+    
+    /// CHECK: Validated by seeds against the Metadata program; owned and checked by the CPI
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), receipt_mint.key().as_ref()],
+        bump,
+        seeds::program = token_metadata_program.key()
+    )]
+    pub metadata: UncheckedAccount<'info>,
+    
+    pub token_metadata_program: Program<'info, Metadata>,
+}
+
+#[derive(Accounts)]
+pub struct GuardianOnly<'info> {
+    pub guardian: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // Logs the pause when passed
+    #[account(
+        mut,
+        seeds = [b"incidents", pool.key().as_ref()],
+        bump = incident_log.bump
+    )]
+    pub incident_log: Option<Account<'info, IncidentLog>>,
+}
+
+#[derive(Accounts)]
+pub struct Recovery<'info> {
+    pub caller: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct AdminPause<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // Opens or closes out an incident when passed
+    #[account(
+        mut,
+        seeds = [b"incidents", pool.key().as_ref()],
+        bump = incident_log.bump
+    )]
+    pub incident_log: Option<Account<'info, IncidentLog>>,
+}
+
+#[derive(Accounts)]
+pub struct InitIncidentLog<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + IncidentLog::INIT_SPACE,
+        seeds = [b"incidents", pool.key().as_ref()],
+        bump
+    )]
+    pub incident_log: Account<'info, IncidentLog>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ViewIncidents<'info> {
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"incidents", pool.key().as_ref()],
+        bump = incident_log.bump
+    )]
+    pub incident_log: Account<'info, IncidentLog>,
+}
+
+#[derive(Accounts)]
+pub struct SetAccess<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    /// CHECK: Only used as the key the access entry is about
+    pub user: UncheckedAccount<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = 8 + AccessEntry::INIT_SPACE,
+        seeds = [b"access", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub access_entry: Account<'info, AccessEntry>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Rebalance<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"insurance_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.insurance_vault_bump
+    )]
+    pub insurance_vault: Account<'info, PoolVault>,
+}
+
+#[derive(Accounts)]
+pub struct VerifySolvency<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+}
+
+#[derive(Accounts)]
+pub struct TakeSnapshot<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + PoolSnapshot::INIT_SPACE,
+        seeds = [b"snapshot", pool.pool_id.to_le_bytes().as_ref(), pool.snapshot_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub snapshot: Account<'info, PoolSnapshot>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseSnapshot<'info> {
+    #[account(
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"snapshot", pool.pool_id.to_le_bytes().as_ref(), snapshot.epoch_index.to_le_bytes().as_ref()],
+        bump,
+        close = payer
+    )]
+    pub snapshot: Account<'info, PoolSnapshot>,
+    
+    /// CHECK: Only receives the rent refund; must be the wallet that paid for the snapshot
+    #[account(mut, address = snapshot.payer)]
+    pub payer: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool_is_paused(&pool, Clock::get()?.unix_timestamp),
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), proposer.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        init,
+        payer = proposer,
+        space = 8 + Proposal::INIT_SPACE,
+        seeds = [b"proposal", pool.pool_id.to_le_bytes().as_ref(), pool.next_proposal_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Vote<'info> {
+    #[account(mut)]
+    pub voter: Signer<'info>,
+    
+    #[account(
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = !pool_is_paused(&pool, Clock::get()?.unix_timestamp),
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"proposal", pool.pool_id.to_le_bytes().as_ref(), proposal.proposal_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), voter.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    
+    #[account(
+        init,
+        payer = voter,
+        space = 8 + VoteRecord::INIT_SPACE,
+        seeds = [
+            b"vote",
+            pool.pool_id.to_le_bytes().as_ref(),
+            proposal.proposal_id.to_le_bytes().as_ref(),
+            voter.key().as_ref()
+        ],
+        bump
+    )]
+    pub vote_record: Account<'info, VoteRecord>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeProposal<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"proposal", pool.pool_id.to_le_bytes().as_ref(), proposal.proposal_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub proposal: Account<'info, Proposal>,
+}
+
+#[derive(Accounts)]
+pub struct AdminOnly<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct FundRewards<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = admin.key() == pool.admin,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePool<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        close = admin,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump,
+        close = admin
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump,
+        close = admin
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    // Whatever is left of the reward reserve goes back to the admin who funded it
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump,
+        close = admin
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    // Only pools that created an insurance vault pass one
+    #[account(
+        mut,
+        seeds = [b"insurance_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.insurance_vault_bump,
+        close = admin
+    )]
+    pub insurance_vault: Option<Account<'info, PoolVault>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeInsuranceVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PoolVault::INIT_SPACE,
+        seeds = [b"insurance_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub insurance_vault: Account<'info, PoolVault>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FundInsurance<'info> {
+    #[account(mut)]
+    pub funder: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"insurance_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.insurance_vault_bump
+    )]
+    pub insurance_vault: Account<'info, PoolVault>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PayInsuranceClaim<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"insurance_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.insurance_vault_bump
+    )]
+    pub insurance_vault: Account<'info, PoolVault>,
+    
+    /// CHECK: Any wallet may receive a payout
+    #[account(mut)]
+    pub recipient: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ViewPool<'info> {
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct ViewStake<'info> {
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user_stake.user.as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewStake<'info> {
+    /// CHECK: Only its key is read; a preview is simulated, so no signature is needed
+    pub user: UncheckedAccount<'info>,
+    
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // Omitted for a user with no position yet
+    #[account(
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Option<Account<'info, UserStake>>,
+    
+    // Required once the position has a referrer
+    #[account(
+        seeds = [b"referral", pool.pool_id.to_le_bytes().as_ref(), referral_account.referrer.as_ref()],
+        bump
+    )]
+    pub referral_account: Option<Account<'info, ReferralAccount>>,
+    
+    /// CHECK: The user's access PDA, required whenever the pool is gated; read by
+    /// load_access_entry, which treats an account never created as no entry
+    #[account(
+        seeds = [b"access", pool.pool_id.to_le_bytes().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub access_entry: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewUnstake<'info> {
+    #[account(
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        seeds = [b"user_stake", pool.pool_id.to_le_bytes().as_ref(), user_stake.user.as_ref()],
+        bump = user_stake.bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateAccount<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// CHECK: Still in the legacy layout, so it cannot be loaded as a typed account; the
+    /// discriminator and length are checked before it is rewritten
+    #[account(
+        mut,
+        owner = crate::ID
+    )]
+    pub legacy_account: UncheckedAccount<'info>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        constraint = admin.key() == pool.admin,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    /// CHECK: only receives lamports, and must be the pool's stored treasury
+    #[account(mut, address = pool.treasury @ ErrorCode::InvalidTreasury)]
+    pub treasury: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeToStakers<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = admin.key() == pool.admin @ ErrorCode::Unauthorized,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"fee_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.fee_vault_bump
+    )]
+    pub fee_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+}
+
+#[derive(Accounts)]
+pub struct DeployToMarinade<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = admin.key() == pool.admin @ ErrorCode::Unauthorized,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    // System-owned PDA the lamports pass through on their way into and out of Marinade
+    #[account(
+        mut,
+        seeds = [b"marinade_staging", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub marinade_staging: SystemAccount<'info>,
+    
+    #[account(
+        init_if_needed,
+        payer = admin,
+        seeds = [b"marinade_msol", pool.pool_id.to_le_bytes().as_ref()],
+        bump,
+        token::mint = msol_mint,
+        token::authority = pool
+    )]
+    pub pool_msol_account: Box<Account<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    pub msol_mint: Box<Account<'info, Mint>>,
+    
+    /// CHECK: Marinade state, validated by Marinade
+    #[account(mut)]
+    pub marinade_state: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub liq_pool_sol_leg_pda: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub liq_pool_msol_leg: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    pub liq_pool_msol_leg_authority: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub reserve_pda: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    pub msol_mint_authority: UncheckedAccount<'info>,
+    
+    /// CHECK: only Marinade is ever invoked
+    #[account(executable, address = MARINADE_PROGRAM_ID)]
+    pub marinade_program: UncheckedAccount<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RecallFromMarinade<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = admin.key() == pool.admin @ ErrorCode::Unauthorized,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"marinade_staging", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub marinade_staging: SystemAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marinade_msol", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pool_msol_account: Box<Account<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    pub msol_mint: Box<Account<'info, Mint>>,
+    
+    /// CHECK: Marinade state, validated by Marinade
+    #[account(mut)]
+    pub marinade_state: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub liq_pool_sol_leg_pda: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub liq_pool_msol_leg: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub treasury_msol_account: UncheckedAccount<'info>,
+    
+    /// CHECK: only Marinade is ever invoked
+    #[account(executable, address = MARINADE_PROGRAM_ID)]
+    pub marinade_program: UncheckedAccount<'info>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OrderMarinadeUnstake<'info> {
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = admin.key() == pool.admin @ ErrorCode::Unauthorized,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    // Burns the mSOL as the pool's delegate and becomes the ticket's beneficiary
+    #[account(
+        seeds = [b"marinade_staging", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub marinade_staging: SystemAccount<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"marinade_msol", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub pool_msol_account: Box<Account<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    pub msol_mint: Box<Account<'info, Mint>>,
+    
+    /// CHECK: Marinade state, validated by Marinade
+    #[account(mut)]
+    pub marinade_state: UncheckedAccount<'info>,
+    
+    /// CHECK: empty account of MARINADE_TICKET_SPACE owned by Marinade, validated by Marinade,
+    /// which writes the ticket into it
+    #[account(mut)]
+    pub new_ticket_account: UncheckedAccount<'info>,
+    
+    /// CHECK: only Marinade is ever invoked
+    #[account(executable, address = MARINADE_PROGRAM_ID)]
+    pub marinade_program: UncheckedAccount<'info>,
+    
+    pub clock: Sysvar<'info, Clock>,
+    pub rent: Sysvar<'info, Rent>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimMarinadeTicket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = admin.key() == pool.admin @ ErrorCode::Unauthorized,
+        constraint = pool.version == POOL_VERSION @ ErrorCode::AccountVersionMismatch
+    )]
+    pub pool: Account<'info, Pool>,
+    
+    #[account(
+        mut,
+        seeds = [b"pool_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.pool_vault_bump
+    )]
+    pub pool_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.pool_id.to_le_bytes().as_ref()],
+        bump = pool.reward_vault_bump
+    )]
+    pub reward_vault: Account<'info, PoolVault>,
+    
+    #[account(
+        mut,
+        seeds = [b"marinade_staging", pool.pool_id.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub marinade_staging: SystemAccount<'info>,
+    
+    /// CHECK: Marinade state, validated by Marinade
+    #[account(mut)]
+    pub marinade_state: UncheckedAccount<'info>,
+    
+    /// CHECK: validated by Marinade
+    #[account(mut)]
+    pub reserve_pda: UncheckedAccount<'info>,
+    
+    /// CHECK: read as a Marinade ticket and validated by Marinade
+    #[account(mut)]
+    pub ticket_account: UncheckedAccount<'info>,
+    
+    /// CHECK: only Marinade is ever invoked
+    #[account(executable, address = MARINADE_PROGRAM_ID)]
+    pub marinade_program: UncheckedAccount<'info>,
+    
+    pub clock: Sysvar<'info, Clock>,
+    pub system_program: Program<'info, System>,
+}
+
+// Account structures
+
+pub const MAX_APY_TIERS: usize = 8;
+pub const MAX_LOYALTY_TIERS: usize = 4;
+pub const MAX_FEE_TIERS: usize = 5;
+// Pool.pause_flags bits
+pub const PAUSE_DEPOSITS: u8 = 1 << 0;
+pub const PAUSE_CLAIMS: u8 = 1 << 1;
+pub const PAUSE_UNSTAKE: u8 = 1 << 2;
+pub const PAUSE_ALL: u8 = PAUSE_DEPOSITS | PAUSE_CLAIMS | PAUSE_UNSTAKE;
+// Positions one batch_credit_yields call may credit; 16 fits a legacy transaction and
+// stays well inside the default compute budget
+pub const MAX_CREDIT_BATCH: usize = 16;
+pub const MAX_LOYALTY_BOOST_BPS: u64 = 300;
+pub const MAX_NFT_BOOST_BPS: u64 = 500;
+// Outer bounds on pool parameters; the math module is tested to never overflow inside them
+pub const MAX_APY_BPS: u64 = 10000;
+pub const MAX_COMMITMENT_DAYS: u64 = 365;
+pub const DEFAULT_MAX_STAKE_AMOUNT: u64 = 1_000_000_000_000; // 1000 SOL
+// Most a pool's projected yield may exceed its reward reserve by: 10x
+pub const MAX_SOLVENCY_FACTOR_BPS: u64 = 100_000;
+// Longest renewal grace window: 30 days
+pub const MAX_RENEWAL_GRACE_SECONDS: i64 = 30 * 86400;
+// Longest a pool may keep withdrawals paused before emergency_withdraw opens: 30 days
+pub const MAX_EMERGENCY_WITHDRAW_DELAY: i64 = 30 * 86400;
+// Bounds on how long an admin may be inactive before the pool can enter recovery
+pub const MIN_ADMIN_INACTIVITY_TIMEOUT: i64 = 30 * 86400;
+pub const MAX_ADMIN_INACTIVITY_TIMEOUT: i64 = 365 * 86400;
+pub const DEFAULT_ADMIN_INACTIVITY_TIMEOUT: i64 = 90 * 86400;
+// Longest pause reason in bytes of UTF-8; reasons are only logged in events, never stored
+pub const MAX_REASON_LEN: usize = 64;
+// Highest min_claim_amount; above 1 SOL ordinary positions could go months without a claim
+pub const MAX_MIN_CLAIM_AMOUNT: u64 = 1_000_000_000;
+// Length of the per-owner window max_yield_per_user_per_year applies to
+pub const YIELD_CAP_WINDOW: i64 = 365 * 86400;
+// Incidents an IncidentLog keeps before the oldest is overwritten
+pub const INCIDENT_LOG_LEN: usize = 16;
+// Operations a UserHistory keeps before the oldest is overwritten
+pub const USER_HISTORY_LEN: usize = 32;
+// HistoryEntry.op_kind values
+pub const HISTORY_OP_STAKE: u8 = 0;
+pub const HISTORY_OP_CLAIM: u8 = 1;
+pub const HISTORY_OP_UNSTAKE: u8 = 2;
+pub const HISTORY_OP_COMPOUND: u8 = 3;
+pub const HISTORY_OP_ROLLOVER: u8 = 4;
+// Leverage is promised annual yield over the reward reserve, scaled by 1000 (2000 = 2x)
+pub const DEFAULT_LEVERAGE_RATIO: u64 = 6000;
+pub const DEFAULT_REINVESTMENT_RATE: u64 = 1000;
+
+// Sentinels for the deadline and payout floor taken by unstake and claim_yields: a
+// deadline of i64::MAX never expires and a floor of 0 accepts any payout
+pub const NO_DEADLINE: i64 = i64::MAX;
+pub const NO_MIN_PAYOUT: u64 = 0;
+
+// Schema version carried first in every event, bumped whenever an event's fields change
+// so indexers can tell old and new payloads apart
+pub const EVENT_VERSION: u8 = 5;
+
+// Layout versions, bumped on every change to the account layout; migrate_pool and
+// migrate_user_stake upgrade older versions. Version 1 predates the version byte, so
+// it is recognised by its account size.
+pub const POOL_VERSION: u8 = 32;
+pub const USER_STAKE_VERSION: u8 = 14;
+pub const POOL_V1_SPACE: usize = 505;
+pub const USER_STAKE_V1_SPACE: usize = 188;
+
+// Jupiter aggregator v6, the only program stake_with_swap routes through
+pub const SWAP_PROGRAM_ID: Pubkey = anchor_lang::solana_program::pubkey!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+// Marinade liquid staking, where idle pool vault SOL can be deployed for mSOL
+pub const MARINADE_PROGRAM_ID: Pubkey = anchor_lang::solana_program::pubkey!("MarBmsSgKXdrN1egZf5sqe1TMai9K1rChYNDJgjq7aD");
+// Anchor discriminators of Marinade's deposit, liquid_unstake, order_unstake and claim
+// instructions
+const MARINADE_DEPOSIT_DISCRIMINATOR: [u8; 8] = [242, 35, 198, 137, 82, 225, 242, 182];
+const MARINADE_LIQUID_UNSTAKE_DISCRIMINATOR: [u8; 8] = [30, 30, 119, 240, 191, 227, 12, 16];
+const MARINADE_ORDER_UNSTAKE_DISCRIMINATOR: [u8; 8] = [97, 167, 144, 107, 117, 190, 128, 36];
+const MARINADE_CLAIM_DISCRIMINATOR: [u8; 8] = [62, 198, 214, 193, 213, 159, 108, 210];
+// Anchor discriminators of Marinade's State and TicketAccountData accounts
+const MARINADE_STATE_DISCRIMINATOR: [u8; 8] = [216, 146, 107, 94, 104, 75, 182, 177];
+const MARINADE_TICKET_DISCRIMINATOR: [u8; 8] = [133, 77, 18, 98, 211, 1, 231, 3];
+// State.msol_price, lamports per mSOL scaled by 2^32, sits this far into the state account
+const MARINADE_MSOL_PRICE_OFFSET: usize = 512;
+// A ticket holds its state, its beneficiary, the lamports it is owed and the epoch it was
+// ordered in, after the discriminator
+pub const MARINADE_TICKET_SPACE: usize = 88;
+
+pub const ACCESS_MODE_OPEN: u8 = 0;
+pub const ACCESS_MODE_WHITELIST: u8 = 1;
+pub const ACCESS_MODE_BLACKLIST: u8 = 2;
+
+// Commitment tier: stakes locked for at least min_days earn apy_bps
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct ApyTier {
+    pub min_days: u64,
+    pub apy_bps: u64,
+}
+
+// Fee tier: deposits of at least min_amount lamports pay fee_bps instead of the base fee
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct FeeTier {
+    pub min_amount: u64,
+    pub fee_bps: u64,
+}
+
+// Loyalty tier: owners who have staked at least min_lifetime_staked lamports in total
+// earn boost_bps on top of their locked APY
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct LoyaltyTier {
+    pub min_lifetime_staked: u64,
+    pub boost_bps: u64,
+}
+
+// Returned by get_pending_yields
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingYields {
+    pub yields: u64,
+    pub credited_yields: u64,
+    pub days_until_commitment_met: u64,
+}
+
+// Returned by get_position_value; mirrored field for field in the interface crate
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PositionValue {
+    pub amount: u64,
+    pub accrued_yields: u64,
+    pub unlock_ts: i64,
+    pub apy_bps: u64,
+}
+
+// Returned by preview_stake and preview_unstake. net is the principal the operation adds
+// or withdraws; yields is the yield a stake projects over its commitment, or what an
+// unstake leaves claimable; return_amount is what an unstake pays to the wallet.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OperationPreview {
+    pub would_succeed: bool,
+    pub failure_code: u32,
+    pub fee: u64,
+    pub net: u64,
+    pub yields: u64,
+    pub penalty: u64,
+    pub return_amount: u64,
+}
+
+// Returned by get_pool_stats; mirrored in the interface crate
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolStats {
+    pub total_staked: u64,
+    pub total_users: u64,
+    pub utilization_bps: u64,
+}
+
+// Setting named by a ParameterUpdateEvent; logged as a single byte, so new variants
+// are only ever appended
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolParameter {
+    MaxApy,
+    LongCommitThresholdDays,
+    LongCommitFeeDiscountBps,
+    PenaltyGraceBps,
+    DepositFeeBps,
+    AccessMode,
+    ReferralShareBps,
+    MaxSlippageBps,
+    WithdrawalCooldownSeconds,
+    InstantExitPenaltyBps,
+    MaxDailyOutflowBps,
+    EarlyExitPenaltyBps,
+    SlidingPenaltyEnabled,
+    MinStakeAmount,
+    MaxStakeAmount,
+    InsuranceShareBps,
+    LeverageRatio,
+    MinSlotsBeforeExit,
+    ReinvestmentRate,
+    SnapshotInterval,
+    SnapshotRetentionEpochs,
+    LargeOperationThreshold,
+    MinBlockDelay,
+    RebalanceInterval,
+    MinRunwayDays,
+    ProposalThreshold,
+    GovernanceQuorumBps,
+    MaxYieldPerClaim,
+    MaxYieldPerUserPerYear,
+    SolvencyFactorBps,
+    MaxReconciliationDelta,
+    MigrationBonusBps,
+    MinClaimAmount,
+    RenewalGraceSeconds,
+    IdleApyBps,
+    RolloverFeeBps,
+    RewardTokensPerSol,
+    EmergencyWithdrawDelay,
+    MinTokenStakeAmount,
+    MaxTokenStakeAmount,
+}
+
+// Principal queued for withdrawal, claimable once the pool cooldown has passed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub struct PendingWithdrawal {
+    pub amount: u64,
+    pub requested_at: i64,
+}
+
+// Program-owned lamport vault; separate ones hold principal, the reward reserve and deposit fees
+#[account]
+#[derive(InitSpace)]
+pub struct PoolVault {}
+
+// Tracks how many pools exist and the id the next one must use
+#[account]
+#[derive(InitSpace)]
+pub struct PoolRegistry {
+    pub next_pool_id: u64,
+    pub total_pools: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Pool {
+    pub version: u8,
+    pub pool_id: u64,
+    pub admin: Pubkey,
+    pub max_apy: u64,
+    #[max_len(8)]
+    pub apy_tiers: Vec<ApyTier>,
+    #[max_len(4)]
+    pub loyalty_tiers: Vec<LoyaltyTier>,
+    pub boost_collection: Option<Pubkey>,
+    pub nft_boost_bps: u64,
+    pub proposal_threshold: u64,
+    pub governance_quorum_bps: u64,
+    pub next_proposal_id: u64,
+    pub leverage_ratio: u64,
+    pub reinvestment_rate: u64,
+    pub fees_awaiting_rebalance: u64,
+    pub rebalance_interval: i64,
+    pub last_rebalance_timestamp: i64,
+    pub projected_apy: u64,
+    pub min_runway_days: u64,
+    pub total_yields_paid: u64,
+    pub snapshot_count: u64,
+    pub snapshot_interval: i64,
+    pub last_snapshot_timestamp: i64,
+    pub snapshot_retention_epochs: u64,
+    pub large_operation_threshold: u64,
+    pub min_block_delay: u64,
+    pub current_slot: u64,
+    pub current_slot_volume: u64,
+    pub slot_delay_until: u64,
+    pub max_slippage_bps: u64,
+    pub min_slots_before_exit: u64,
+    #[max_len(5)]
+    pub fee_tiers: Vec<FeeTier>,
+    pub total_yields_credited: u64,
+    pub insurance_share_bps: u64,
+    pub insurance_pending: u64,
+    pub insurance_balance: u64,
+    pub is_active: bool,
+    pub sunset_at: i64,
+    pub bump: u8,
+    pub pool_vault_bump: u8,
+    pub fee_vault_bump: u8,
+    pub reward_vault_bump: u8,
+    pub insurance_vault_bump: u8,
+    pub token_vault_bump: u8,
+    pub long_commit_threshold_days: u64,
+    pub long_commit_fee_discount_bps: u64,
+    pub penalty_grace_bps: u64,
+    pub deployed_to_marinade: u64,
+    // Revenue shared pro rata to stakers, per lamport accruing and scaled by
+    // REWARD_PER_TOKEN_PRECISION
+    pub reward_per_token_stored: u128,
+    // Most yield one claim may pay out and one owner may receive per year; 0 is unlimited
+    pub max_yield_per_claim: u64,
+    pub max_yield_per_user_per_year: u64,
+    // Where fee and penalty withdrawals are paid
+    pub treasury: Pubkey,
+    // Yield projected over the commitment of every open position, and the share of the
+    // reward reserve it may reach in bps; 0 turns the check off
+    pub total_projected_liability: u64,
+    pub solvency_factor_bps: u64,
+    // Most the vaults may fall short of the books before verify_solvency pauses deposits
+    pub max_reconciliation_delta: u64,
+    // Bumped whenever min_commitment_days changes; positions stamped with an older version
+    // keep the commitment they were opened under
+    pub policy_version: u64,
+    // One-time bonus in bps of principal for adopting the current minimum commitment
+    pub migration_bonus_bps: u64,
+    // Smallest yield a claim may pay, so dust does not cost a transaction each; 0 turns it off
+    pub min_claim_amount: u64,
+    // Seconds after a commitment ends before the position renews into another of the same
+    // length, 0 when commitments never renew; positions opted out of renewal earn
+    // idle_apy_bps once their commitment has ended
+    pub renewal_grace_seconds: i64,
+    pub idle_apy_bps: u64,
+    // Dead-man switch: once no admin instruction has succeeded for admin_inactivity_timeout
+    // seconds anyone may put the pool into recovery, after which recovery_authority can
+    // install a new admin. Pubkey::default() as the authority turns recovery off.
+    pub recovery_authority: Pubkey,
+    pub admin_inactivity_timeout: i64,
+    pub last_admin_activity: i64,
+    pub recovery_mode: bool,
+    // Charged on the principal a rollover carries into a new commitment, in place of the
+    // deposit fee
+    pub rollover_fee_bps: u64,
+    // When set, yields are paid in this SPL token from the reward token vault instead of
+    // SOL, at reward_tokens_per_sol token base units per SOL of yield
+    pub reward_mint: Option<Pubkey>,
+    pub reward_token_vault_bump: u8,
+    pub reward_tokens_per_sol: u64,
+    pub min_token_stake_amount: u64,
+    pub max_token_stake_amount: u64,
+    // Principal queued in pending withdrawals, which stops accruing when it is queued
+    pub total_pending_withdrawals: u64,
+    // Principal Marinade returned short of what was deployed, made good from the reward reserve
+    pub marinade_loss: u64,
+    // Principal in delayed-unstake tickets not yet claimed, and the lamports they are owed
+    pub marinade_ticket_principal: u64,
+    pub marinade_ticket_lamports: u64,
+    pub min_commitment_days: u64,
+    pub max_commitment_days: u64,
+    pub min_stake_amount: u64,
+    pub max_stake_amount: u64,
+    pub total_staked: u64,
+    pub total_users: u64,
+    pub total_fees_collected: u64,
+    pub total_penalties_collected: u64,
+    pub reward_reserve: u64,
+    pub total_referral_rewards_pending: u64,
+    pub deposit_fee_bps: u64,
+    pub referral_share_bps: u64,
+    pub access_mode: u8,
+    pub early_exit_penalty_bps: u64,
+    pub sliding_penalty_enabled: bool,
+    pub withdrawal_cooldown_seconds: i64,
+    pub instant_exit_enabled: bool,
+    pub instant_exit_penalty_bps: u64,
+    pub max_daily_outflow_bps: u64,
+    pub outflow_window_start: i64,
+    pub outflow_in_window: u64,
+    pub pause_flags: u8,
+    pub guardian: Pubkey,
+    pub guardian_pause_max_duration: i64,
+    pub paused_at: i64,
+    pub paused_by: Pubkey,
+    pub pause_expires_at: i64,
+    pub emergency_withdraw_delay: i64,
+    pub stake_mint: Option<Pubkey>,
+    pub total_token_staked: u64,
+    pub total_token_fees_collected: u64,
+    pub total_token_penalties_collected: u64,
+    pub created_at: i64,
+    pub last_update: i64,
 }
 
-// Account contexts
-#[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    
-    #[account(
-        init,
-        payer = admin,
-        space = 8 + Pool::INIT_SPACE,
-        seeds = [b"pool"],
-        bump
-    )]
-    pub pool: Account<'info, Pool>,
-    
-    #[account(
-        init,
-        payer = admin,
-        space = 0,
-        seeds = [b"pool_vault"],
-        bump
-    )]
-    pub pool_vault: SystemAccount<'info>,
-    
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+#[account]
+#[derive(InitSpace, Default)]
+pub struct UserStake {
+    pub version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub committed_days: u64,
+    pub stake_timestamp: i64,
+    pub last_claim_timestamp: i64,
+    pub locked_apy_bps: u64,
+    pub is_counted: bool,
+    pub claim_delegate: Option<Pubkey>,
+    pub referrer: Option<Pubkey>,
+    pub pending_withdrawal: Option<PendingWithdrawal>,
+    pub total_claimed: u64,
+    pub total_staked_lifetime: u64,
+    pub total_yields_claimed: u64,
+    pub funded_by: Option<Pubkey>,
+    pub vote_locked_until: i64,
+    pub last_stake_slot: u64,
+    pub pending_yields: u64,
+    pub bump: u8,
+    // Yield settled at earlier rates and shared revenue not yet paid, and the rate the
+    // position accrues at until its next settlement
+    pub rewards_owed: u64,
+    pub accrual_rate_bps: u64,
+    // Pool accumulator value the position last collected its share up to
+    pub reward_per_token_paid: u128,
+    // Yield paid to the owner in the current yearly window, which opens at the first
+    // payout after the previous one ends
+    pub yields_claimed_in_year: u64,
+    pub year_window_start: i64,
+    // This position's part of the pool's total_projected_liability
+    pub projected_liability: u64,
+    // Pool policy_version in effect when the position was last staked into or migrated
+    pub policy_version: u64,
+    // Set by set_auto_renew(false): the commitment does not roll over when it ends
+    pub auto_renew_opted_out: bool,
+    // Set the first time a deposit claims the account for its owner
+    pub initialized: bool,
+}
+
+// A stake-weighted proposal to change one pool parameter
+#[account]
+#[derive(InitSpace)]
+pub struct Proposal {
+    pub proposal_id: u64,
+    pub proposer: Pubkey,
+    pub param_key: u8,
+    pub new_value: u64,
+    pub voting_ends_at: i64,
+    pub quorum_votes: u64,
+    pub votes_for: u64,
+    pub votes_against: u64,
+    pub status: u8,
+    pub created_at: i64,
+}
+
+// Point-in-time copy of a pool's accounting, one per epoch, so TVL and fee flow can be
+// charted without replaying transactions
+#[account]
+#[derive(InitSpace)]
+pub struct PoolSnapshot {
+    pub epoch_index: u64,
+    pub payer: Pubkey,
+    pub total_staked: u64,
+    pub total_users: u64,
+    pub total_fees_collected: u64,
+    pub total_yields_paid: u64,
+    pub reward_reserve: u64,
+    pub timestamp: i64,
+}
+
+// One per voter per proposal; its existence is what rejects a second vote
+#[account]
+#[derive(InitSpace)]
+pub struct VoteRecord {
+    pub proposal_id: u64,
+    pub voter: Pubkey,
+    pub support: bool,
+    pub weight: u64,
+    pub voted_at: i64,
+}
+
+// Accumulates a referrer's share of deposit fees
+#[account]
+#[derive(InitSpace)]
+pub struct ReferralAccount {
+    pub referrer: Pubkey,
+    pub pending_rewards: u64,
+    pub total_referred_volume: u64,
+    pub total_rewards_claimed: u64,
+}
+
+// One operation in a user's history; amount is what was staked, claimed, unstaked or
+// compounded, or the yield a rollover compounded and paid out
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace)]
+pub struct HistoryEntry {
+    pub op_kind: u8,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+// Opt-in record of a user's last USER_HISTORY_LEN operations across every pool, for
+// wallets that want history without an indexer
+#[account]
+#[derive(InitSpace)]
+pub struct UserHistory {
+    pub user: Pubkey,
+    pub bump: u8,
+    // Operations recorded since creation or the last clear; the next one is written to
+    // entries[total_entries % USER_HISTORY_LEN]
+    pub total_entries: u64,
+    pub entries: [HistoryEntry; 32],
+}
+
+impl UserHistory {
+    pub fn record(&mut self, op_kind: u8, amount: u64, timestamp: i64) {
+        let index = (self.total_entries % USER_HISTORY_LEN as u64) as usize;
+        self.entries[index] = HistoryEntry { op_kind, amount, timestamp };
+        self.total_entries = self.total_entries.checked_add(1).unwrap();
+    }
+
+    // Entries still held, oldest first
+    pub fn recent(&self) -> Vec<HistoryEntry> {
+        let held = self.total_entries.min(USER_HISTORY_LEN as u64) as usize;
+        let next = (self.total_entries % USER_HISTORY_LEN as u64) as usize;
+        (0..held).map(|i| self.entries[(next + USER_HISTORY_LEN - held + i) % USER_HISTORY_LEN]).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.total_entries = 0;
+        self.entries = [HistoryEntry::default(); USER_HISTORY_LEN];
+    }
+}
+
+// A pause as the incident log records it; closed_at stays 0 while it is open
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace)]
+pub struct Incident {
+    pub opened_at: i64,
+    pub closed_at: i64,
+    pub flags: u8,
+    // sha256 of the reason given in the pause event
+    pub reason_hash: [u8; 32],
+}
+
+// A pool's last INCIDENT_LOG_LEN pauses, so support can point users at a reason after
+// the fact without an indexer
+#[account]
+#[derive(InitSpace)]
+pub struct IncidentLog {
+    pub pool: Pubkey,
+    pub bump: u8,
+    // Incidents logged since creation; the next one is written to
+    // incidents[total_incidents % INCIDENT_LOG_LEN]
+    pub total_incidents: u64,
+    pub incidents: [Incident; 16],
+}
+
+impl IncidentLog {
+    // A new pause supersedes any incident still open
+    pub fn open(&mut self, flags: u8, reason_hash: [u8; 32], now: i64) {
+        self.close_latest(now);
+        let index = (self.total_incidents % INCIDENT_LOG_LEN as u64) as usize;
+        self.incidents[index] = Incident { opened_at: now, closed_at: 0, flags, reason_hash };
+        self.total_incidents = self.total_incidents.checked_add(1).unwrap();
+    }
+
+    pub fn close_latest(&mut self, now: i64) {
+        if self.total_incidents == 0 {
+            return;
+        }
+        let latest = &mut self.incidents[((self.total_incidents - 1) % INCIDENT_LOG_LEN as u64) as usize];
+        if latest.closed_at == 0 {
+            latest.closed_at = now;
+        }
+    }
+
+    pub fn latest(&self) -> Option<Incident> {
+        self.recent().last().copied()
+    }
+
+    // Incidents still held, oldest first
+    pub fn recent(&self) -> Vec<Incident> {
+        let held = self.total_incidents.min(INCIDENT_LOG_LEN as u64) as usize;
+        let next = (self.total_incidents % INCIDENT_LOG_LEN as u64) as usize;
+        (0..held).map(|i| self.incidents[(next + INCIDENT_LOG_LEN - held + i) % INCIDENT_LOG_LEN]).collect()
+    }
+}
+
+// Per-user access list entry for permissioned pools
+#[account]
+#[derive(InitSpace)]
+pub struct AccessEntry {
+    pub user: Pubkey,
+    pub whitelisted: bool,
+    pub blacklisted: bool,
+}
+
+// Error codes
+#[error_code]
+pub enum ErrorCode {
+    #[msg("Invalid APY")]
+    InvalidApy,
+    #[msg("Invalid commitment days")]
+    InvalidCommitmentDays,
+    #[msg("Invalid amount")]
+    InvalidAmount,
+    #[msg("Invalid fee")]
+    InvalidFee,
+    // No longer raised, over-long reasons fail with StringTooLong; kept so the codes after it
+    // do not shift
+    #[msg("Invalid reason")]
+    InvalidReason,
+    #[msg("Amount too small")]
+    AmountTooSmall,
+    #[msg("Amount too large")]
+    AmountTooLarge,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("No stake found")]
+    NoStake,
+    #[msg("No yield to claim")]
+    NoYieldToClaim,
+    #[msg("Insufficient funds")]
+    InsufficientFunds,
+    #[msg("Commitment not met")]
+    CommitmentNotMet,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Remaining stake would fall below the pool minimum")]
+    RemainderTooSmall,
+    #[msg("Token mint does not match the pool stake mint")]
+    InvalidMint,
+    #[msg("Receipt metadata already exists")]
+    MetadataAlreadyExists,
+    #[msg("Invalid metadata")]
+    InvalidMetadata,
+    #[msg("Stake account still holds a position")]
+    StakeNotEmpty,
+    #[msg("Invalid penalty")]
+    InvalidPenalty,
+    #[msg("Invalid APY tiers")]
+    InvalidApyTiers,
+    #[msg("Pool id does not match the next id in the registry")]
+    InvalidPoolId,
+    #[msg("Invalid pause duration")]
+    InvalidPauseDuration,
+    #[msg("Emergency withdrawal is not available yet")]
+    EmergencyWithdrawUnavailable,
+    #[msg("Reward reserve cannot cover this payout")]
+    InsufficientRewardReserve,
+    #[msg("Claim accrued yields first")]
+    UnclaimedYields,
+    #[msg("Destination already holds a position")]
+    DestinationHasStake,
+    #[msg("Cannot refer yourself")]
+    SelfReferral,
+    #[msg("Referrer can only be set on the first stake")]
+    ReferrerAlreadySet,
+    #[msg("Referral account does not match the referrer")]
+    InvalidReferrer,
+    #[msg("Invalid access mode")]
+    InvalidAccessMode,
+    #[msg("User is not whitelisted")]
+    NotWhitelisted,
+    #[msg("User is blacklisted")]
+    Blacklisted,
+    #[msg("A withdrawal request is already pending")]
+    PendingWithdrawalExists,
+    #[msg("No withdrawal request is pending")]
+    NoPendingWithdrawal,
+    #[msg("Withdrawal cooldown has not elapsed")]
+    CooldownNotElapsed,
+    #[msg("Instant exit is disabled for this pool")]
+    InstantExitDisabled,
+    #[msg("Invalid withdrawal cooldown")]
+    InvalidCooldown,
+    #[msg("Daily outflow cap reached")]
+    DailyOutflowExceeded,
+    #[msg("Invalid outflow cap")]
+    InvalidOutflowCap,
+    #[msg("Account layout version does not match this program")]
+    AccountVersionMismatch,
+    #[msg("Arithmetic overflow")]
+    ArithmeticOverflow,
+    #[msg("Invalid loyalty tiers")]
+    InvalidLoyaltyTiers,
+    #[msg("Invalid NFT boost")]
+    InvalidNftBoost,
+    #[msg("NFT boost accounts could not be verified")]
+    NftBoostVerificationFailed,
+    #[msg("Invalid proposal")]
+    InvalidProposal,
+    #[msg("Governance is disabled for this pool")]
+    GovernanceDisabled,
+    #[msg("Stake is below the proposal threshold")]
+    ProposalThresholdNotMet,
+    #[msg("Proposal is not active")]
+    ProposalNotActive,
+    #[msg("Voting has closed")]
+    VotingClosed,
+    #[msg("Execution delay has not elapsed")]
+    ExecutionDelayNotElapsed,
+    #[msg("Stake is locked until the votes it cast have closed")]
+    VotesLocked,
+    #[msg("Stake would push promised yield past the leverage cap")]
+    LeverageCapExceeded,
+    #[msg("Rebalance interval has not elapsed")]
+    RebalanceTooEarly,
+    #[msg("Invalid rebalance settings")]
+    InvalidRebalanceSettings,
+    #[msg("Snapshot interval has not elapsed")]
+    SnapshotTooEarly,
+    #[msg("Snapshot is still within the retention window")]
+    SnapshotRetained,
+    #[msg("Invalid snapshot settings")]
+    InvalidSnapshotSettings,
+    #[msg("Slot volume limit reached; wait for the block delay")]
+    BlockDelayActive,
+    #[msg("Invalid MEV protection settings")]
+    InvalidMevSettings,
+    #[msg("Deposit fee has changed too far from the quote")]
+    FeeChangedSinceQuote,
+    #[msg("Amount received is below the minimum expected")]
+    SlippageExceeded,
+    #[msg("Transaction deadline has passed")]
+    TransactionExpired,
+    #[msg("Position cannot exit or claim this soon after staking")]
+    SameSlotOperation,
+    #[msg("Invalid fee tiers")]
+    InvalidFeeTiers,
+    #[msg("Not a wrapped SOL account owned by the user")]
+    InvalidWsolAccount,
+    #[msg("Pool has been sunset")]
+    PoolInactive,
+    #[msg("Pool has not been sunset")]
+    PoolActive,
+    #[msg("Pool still holds stakes, fees or insurance")]
+    PoolNotEmpty,
+    #[msg("Sunset time is in the past")]
+    InvalidSunsetTime,
+    #[msg("Invalid pause flags")]
+    InvalidPauseFlags,
+    #[msg("Too many positions in one batch")]
+    BatchTooLarge,
+    #[msg("Credited yields must be withdrawn first")]
+    CreditedYieldsPending,
+    #[msg("Position address does not match its seeds")]
+    InvalidPositionAddress,
+    #[msg("Invalid commitment incentives")]
+    InvalidCommitmentIncentives,
+    #[msg("Swap received less SOL than the minimum")]
+    SwapSlippageExceeded,
+    #[msg("Invalid swap input or route")]
+    InvalidSwapRoute,
+    #[msg("Vault liquidity is deployed to Marinade and being recalled")]
+    LiquidityBeingRecalled,
+    #[msg("Yield payout cap reached for this window")]
+    YieldCapReached,
+    #[msg("Treasury account does not match the pool")]
+    InvalidTreasury,
+    #[msg("Reward reserve cannot cover the yield this stake would commit")]
+    InsufficientYieldCapacity,
+    #[msg("Invalid solvency factor")]
+    InvalidSolvencyFactor,
+    #[msg("Position already follows the current commitment policy")]
+    NotGrandfathered,
+    #[msg("Yield is below the pool's minimum claim")]
+    YieldBelowMinimum,
+    #[msg("Invalid renewal grace window or idle APY")]
+    InvalidRenewalPolicy,
+    #[msg("Clock reads earlier than the pool's creation")]
+    ClockBeforePoolCreation,
+    #[msg("Invalid recovery authority or admin inactivity timeout")]
+    InvalidRecoveryPolicy,
+    #[msg("Pool is in recovery mode")]
+    RecoveryModeActive,
+    #[msg("Pool is not in recovery mode")]
+    NotInRecoveryMode,
+    #[msg("Pool has no recovery authority")]
+    NoRecoveryAuthority,
+    #[msg("Admin has been active within the inactivity timeout")]
+    AdminStillActive,
+    #[msg("String input is too long")]
+    StringTooLong,
+    #[msg("Pool pays yields in its reward token")]
+    RewardsPaidInToken,
+    #[msg("Reward token price must be set and nonzero")]
+    InvalidRewardTokenPrice,
+    #[msg("Position account holds history but no owner")]
+    InvalidPositionState,
+    #[msg("Emergency withdraw delay out of range")]
+    InvalidEmergencyWithdrawDelay,
+    #[msg("Not a Marinade state or ticket account the pool can use")]
+    InvalidMarinadeAccount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMOUNT: u64 = 1_000_000_000;
+    const DAY: i64 = 86400;
+
+    #[test]
+    fn guardian_pause_lapses_at_expiry() {
+        let paused_at = 1_000_000;
+        let expires_at = paused_at + 72 * 3600;
+        assert!(pause_in_effect(PAUSE_ALL, expires_at, paused_at));
+        assert!(pause_in_effect(PAUSE_ALL, expires_at, expires_at - 1));
+        assert!(!pause_in_effect(PAUSE_ALL, expires_at, expires_at));
+    }
+
+    #[test]
+    fn admin_pause_never_lapses() {
+        assert!(pause_in_effect(PAUSE_ALL, 0, i64::MAX));
+        assert!(!pause_in_effect(0, 0, 0));
+    }
+
+    #[test]
+    fn each_operation_checks_only_its_pause_bit() {
+        let mut pool = pool_with(2000, Vec::new());
+        for pause_flags in 0..=PAUSE_ALL {
+            pool.pause_flags = pause_flags;
+            assert_eq!(pool.deposits_paused(0), pause_flags & PAUSE_DEPOSITS != 0);
+            assert_eq!(pool.claims_paused(0), pause_flags & PAUSE_CLAIMS != 0);
+            assert_eq!(pool.unstake_paused(0), pause_flags & PAUSE_UNSTAKE != 0);
+            assert_eq!(pool_is_paused(&pool, 0), pause_flags != 0);
+        }
+    }
+
+    #[test]
+    fn legacy_pause_flag_migrates_to_a_full_pause() {
+        let mut legacy = pool_with(2000, Vec::new());
+        legacy.version = 14;
+        legacy.pause_flags = 1; // The old is_paused = true
+        legacy.insurance_balance = 0x5a5a_5a5a_5a5a_5a5a;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let marker = 0x5a5a_5a5a_5a5a_5a5au64.to_le_bytes();
+        let offset = data.windows(8).position(|window| window == marker).unwrap() + 8;
+        data.drain(offset..offset + 290); // is_active, sunset_at and the fields after them came later
+
+        let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION).unwrap();
+        let migrated = upgrade_pool(version, body).unwrap();
+        assert_eq!(migrated.pause_flags, PAUSE_ALL);
+        assert_eq!(migrated.insurance_balance, 0x5a5a_5a5a_5a5a_5a5a);
+        assert!(migrated.is_active);
+    }
+
+    #[test]
+    fn version_16_pool_keeps_its_sunset_and_gets_canonical_bumps() {
+        let mut legacy = pool_with(2000, Vec::new());
+        legacy.version = 16;
+        legacy.pool_id = 7;
+        legacy.is_active = false;
+        legacy.sunset_at = 0x5a5a_5a5a_5a5a_5a5a;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let marker = 0x5a5a_5a5a_5a5a_5a5au64.to_le_bytes();
+        let offset = data.windows(8).position(|window| window == marker).unwrap() + 8;
+        data.drain(offset..offset + 281); // the bumps and everything after them came later
+
+        let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION).unwrap();
+        let mut migrated = upgrade_pool(version, body).unwrap();
+        assert!(!migrated.is_active);
+        assert_eq!(migrated.sunset_at, 0x5a5a_5a5a_5a5a_5a5a);
+        assert_eq!(migrated.long_commit_fee_discount_bps, 0);
+        assert_eq!(migrated.penalty_grace_bps, 10000);
+
+        derive_pool_bumps(&mut migrated);
+        let pool_id_bytes = 7u64.to_le_bytes();
+        let (address, bump) = Pubkey::find_program_address(&[b"pool", &pool_id_bytes], &crate::ID);
+        assert_eq!(migrated.bump, bump);
+        assert_eq!(
+            Pubkey::create_program_address(&[b"pool", &pool_id_bytes, &[migrated.bump]], &crate::ID).unwrap(),
+            address
+        );
+        assert_eq!(migrated.token_vault_bump, 0); // No stake mint, so no token vault
+    }
+
+    #[test]
+    fn version_18_pool_keeps_its_incentives_with_nothing_deployed() {
+        let mut legacy = pool_with(2000, Vec::new());
+        legacy.version = 18;
+        legacy.long_commit_fee_discount_bps = 5000;
+        legacy.penalty_grace_bps = 0x5a5a_5a5a_5a5a_5a5a;
+        legacy.deployed_to_marinade = 0x1111_1111_1111_1111;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let marker = 0x5a5a_5a5a_5a5a_5a5au64.to_le_bytes();
+        let offset = data.windows(8).position(|window| window == marker).unwrap() + 8;
+        data.drain(offset..offset + 251); // deployed_to_marinade and everything after it came later
+
+        let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION).unwrap();
+        let migrated = upgrade_pool(version, body).unwrap();
+        assert_eq!(migrated.long_commit_fee_discount_bps, 5000);
+        assert_eq!(migrated.penalty_grace_bps, 0x5a5a_5a5a_5a5a_5a5a);
+        assert_eq!(migrated.deployed_to_marinade, 0);
+        assert_eq!(migrated.max_reconciliation_delta, 0);
+        assert_eq!((migrated.min_token_stake_amount, migrated.max_token_stake_amount), (1, u64::MAX));
+        assert_eq!((migrated.policy_version, migrated.migration_bonus_bps), (0, 0));
+        assert_eq!(migrated.min_claim_amount, 0);
+        assert_eq!(migrated.treasury, legacy.admin);
+        assert_eq!(migrated.min_commitment_days, legacy.min_commitment_days);
+    }
+
+    #[test]
+    fn version_31_pool_drops_its_accrual_timestamp() {
+        let mut legacy = pool_with(2000, Vec::new());
+        legacy.version = 31;
+        legacy.reward_per_token_stored = 0x5a5a_5a5a_5a5a_5a5a;
+        legacy.max_yield_per_claim = 7;
+        legacy.max_token_stake_amount = 0x3c3c_3c3c_3c3c_3c3c;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let marker = 0x3c3c_3c3c_3c3c_3c3cu64.to_le_bytes();
+        let offset = data.windows(8).position(|window| window == marker).unwrap() + 8;
+        data.drain(offset..offset + 32); // total_pending_withdrawals and the Marinade books came later
+        let marker = 0x5a5a_5a5a_5a5a_5a5au128.to_le_bytes();
+        let offset = data.windows(16).position(|window| window == marker).unwrap() + 16;
+        data.splice(offset..offset, 1_234i64.to_le_bytes()); // last_accrual_ts
+
+        let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION).unwrap();
+        let migrated = upgrade_pool(version, body).unwrap();
+        assert_eq!(migrated.reward_per_token_stored, 0x5a5a_5a5a_5a5a_5a5a);
+        assert_eq!(migrated.max_yield_per_claim, 7);
+        assert_eq!(migrated.max_token_stake_amount, 0x3c3c_3c3c_3c3c_3c3c);
+        assert_eq!((migrated.total_pending_withdrawals, migrated.marinade_loss), (0, 0));
+        assert_eq!((migrated.marinade_ticket_principal, migrated.marinade_ticket_lamports), (0, 0));
+        assert_eq!(migrated.min_commitment_days, legacy.min_commitment_days);
+    }
+
+    #[test]
+    fn position_migration_only_matches_the_named_pool_and_mint() {
+        let user = Pubkey::new_unique();
+        let (sol_position, _) = position_address(3, None, &user);
+        assert_ne!(position_address(4, None, &user).0, sol_position);
+        assert_ne!(position_address(3, Some(Pubkey::new_unique()), &user).0, sol_position);
+    }
+
+    #[test]
+    fn emergency_withdraw_unlocks_after_delay() {
+        let paused_at = 1_000_000;
+        let delay = 7 * DAY;
+        assert!(!emergency_withdraw_unlocked(paused_at, delay, paused_at + delay - 1));
+        assert!(emergency_withdraw_unlocked(paused_at, delay, paused_at + delay));
+    }
+
+    #[test]
+    fn re_pausing_does_not_push_back_emergency_withdraw() {
+        let mut pool: Pool = zeroed();
+        pool.emergency_withdraw_delay = 7 * DAY;
+        let admin = Pubkey::new_unique();
+        let paused_at = 1_000_000;
+        apply_admin_pause(&mut pool, PAUSE_ALL, admin, paused_at);
+        let unlocks_at = paused_at + pool.emergency_withdraw_delay;
+
+        // Re-issuing or narrowing the pause while withdrawals stay stopped keeps the clock
+        apply_admin_pause(&mut pool, PAUSE_ALL, admin, unlocks_at - 1);
+        apply_admin_pause(&mut pool, PAUSE_UNSTAKE, admin, unlocks_at - 1);
+        assert_eq!(pool.paused_at, paused_at);
+        assert!(emergency_withdraw_unlocked(pool.paused_at, pool.emergency_withdraw_delay, unlocks_at));
+
+        // Withdrawals stopping anew restarts it
+        apply_admin_pause(&mut pool, PAUSE_DEPOSITS, admin, unlocks_at);
+        assert_eq!(pool.paused_at, paused_at);
+        apply_admin_pause(&mut pool, PAUSE_ALL, admin, unlocks_at + DAY);
+        assert_eq!(pool.paused_at, unlocks_at + DAY);
+
+        // So does an admin pause after a guardian pause has lapsed
+        pool.pause_expires_at = unlocks_at + 2 * DAY;
+        apply_admin_pause(&mut pool, PAUSE_CLAIMS, admin, unlocks_at + 3 * DAY);
+        assert_eq!(pool.paused_at, unlocks_at + 3 * DAY);
+    }
+
+    #[test]
+    fn recovery_opens_only_after_the_full_inactivity_timeout() {
+        let mut pool: Pool = zeroed();
+        pool.admin_inactivity_timeout = DEFAULT_ADMIN_INACTIVITY_TIMEOUT;
+        pool.last_admin_activity = 1_000_000;
+
+        let deadline = pool.last_admin_activity + DEFAULT_ADMIN_INACTIVITY_TIMEOUT;
+        assert!(!admin_inactive(&pool, deadline));
+        assert!(admin_inactive(&pool, deadline + 1));
+    }
+
+    #[test]
+    fn admin_activity_keeps_restarting_the_recovery_clock() {
+        let mut pool: Pool = zeroed();
+        pool.admin_inactivity_timeout = MIN_ADMIN_INACTIVITY_TIMEOUT;
+
+        // An admin acting every 29 days never lets the pool fall into recovery
+        let mut now = 0;
+        for _ in 0..12 {
+            now += 29 * DAY;
+            assert!(!admin_inactive(&pool, now));
+            note_admin_activity(&mut pool, now).unwrap();
+        }
+        assert_eq!(pool.last_admin_activity, now);
+        assert!(admin_inactive(&pool, now + MIN_ADMIN_INACTIVITY_TIMEOUT + 1));
+
+        // In recovery the old admin's activity is refused and the clock stays put
+        pool.recovery_mode = true;
+        assert!(note_admin_activity(&mut pool, now + 1).is_err());
+        assert_eq!(pool.last_admin_activity, now);
+    }
+
+    #[test]
+    fn recovery_blocks_deposits_and_waives_penalties() {
+        let mut pool: Pool = zeroed();
+        pool.is_active = true;
+        assert!(!pool.deposits_paused(0));
+        assert!(!pool.penalties_waived());
+
+        pool.recovery_mode = true;
+        assert!(pool.deposits_paused(0));
+        assert!(!pool.claims_paused(0) && !pool.unstake_paused(0));
+        assert!(pool.penalties_waived());
+    }
+
+    #[test]
+    fn solvency_ratio_compares_reserve_with_a_year_of_yield() {
+        // 100 SOL at 10% owes 10 SOL a year; a 5 SOL reserve covers half
+        assert_eq!(calculate_solvency_ratio_bps(5 * AMOUNT, 100 * AMOUNT, 1000), 5000);
+        assert_eq!(calculate_solvency_ratio_bps(10 * AMOUNT, 100 * AMOUNT, 1000), 10000);
+        assert_eq!(calculate_solvency_ratio_bps(0, 0, 1000), u64::MAX);
+    }
+
+    fn tiers() -> Vec<ApyTier> {
+        vec![
+            ApyTier { min_days: 30, apy_bps: 1000 },
+            ApyTier { min_days: 90, apy_bps: 2000 },
+            ApyTier { min_days: 180, apy_bps: 3500 },
+        ]
+    }
+
+    #[test]
+    fn tier_applies_exactly_at_its_threshold() {
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 29), 500);
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 30), 1000);
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 89), 1000);
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 90), 2000);
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 180), 3500);
+        assert_eq!(resolve_apy_bps(&tiers(), 500, 365), 3500);
+    }
+
+    #[test]
+    fn empty_tier_table_falls_back_to_base_apy() {
+        assert_eq!(resolve_apy_bps(&[], 500, 365), 500);
+    }
+
+    #[test]
+    fn tier_table_must_be_strictly_increasing_and_bounded() {
+        assert!(validate_apy_tiers(&tiers()).is_ok());
+
+        let mut unordered = tiers();
+        unordered.swap(0, 1);
+        assert!(validate_apy_tiers(&unordered).is_err());
+
+        let too_many: Vec<ApyTier> = (1..=MAX_APY_TIERS as u64 + 1)
+            .map(|min_days| ApyTier { min_days, apy_bps: 1000 })
+            .collect();
+        assert!(validate_apy_tiers(&too_many).is_err());
+    }
+
+    #[test]
+    fn withdrawal_cooldown_elapses_exactly_at_deadline() {
+        let requested_at = 1_000;
+        assert!(!withdrawal_cooldown_elapsed(requested_at, DAY, requested_at + DAY - 1));
+        assert!(withdrawal_cooldown_elapsed(requested_at, DAY, requested_at + DAY));
+        assert!(withdrawal_cooldown_elapsed(requested_at, 0, requested_at));
+    }
+
+    #[test]
+    fn outflow_window_rolls_over_exactly_after_a_day() {
+        let start = 1_000;
+        assert_eq!(roll_outflow_window(start, AMOUNT, start + DAY - 1), (start, AMOUNT));
+        assert_eq!(roll_outflow_window(start, AMOUNT, start + DAY), (start + DAY, 0));
+    }
+
+    #[test]
+    fn several_users_together_hit_the_outflow_cap() {
+        // 20% cap on 10 SOL staked: two users take 1 SOL each, a third cannot take 0.5 SOL
+        let mut total_staked = 10 * AMOUNT;
+        let mut outflow_in_window = 0;
+        assert_eq!(outflow_headroom(total_staked, outflow_in_window, 2000), 2 * AMOUNT);
+        for _ in 0..2 {
+            assert!(outflow_within_cap(total_staked, outflow_in_window, AMOUNT, 2000));
+            total_staked -= AMOUNT;
+            outflow_in_window += AMOUNT;
+        }
+        assert_eq!(outflow_headroom(total_staked, outflow_in_window, 2000), 0);
+        assert!(!outflow_within_cap(total_staked, outflow_in_window, AMOUNT / 2, 2000));
+        assert!(outflow_within_cap(total_staked, 0, AMOUNT / 2, 2000));
+    }
+
+    #[test]
+    fn version_1_user_stake_migrates_to_current_layout() {
+        // Version 1 layout byte for byte: discriminator, then the fields with no version byte
+        let user = Pubkey::new_unique();
+        let referrer = Pubkey::new_unique();
+        let mut data = UserStake::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(user.as_ref());
+        data.extend_from_slice(&AMOUNT.to_le_bytes()); // amount
+        data.extend_from_slice(&90u64.to_le_bytes()); // committed_days
+        data.extend_from_slice(&1_000i64.to_le_bytes()); // stake_timestamp
+        data.extend_from_slice(&(1_000 + DAY).to_le_bytes()); // last_claim_timestamp
+        data.extend_from_slice(&2000u64.to_le_bytes()); // locked_apy_bps
+        data.push(1); // is_counted
+        data.push(0); // claim_delegate: None
+        data.push(1); // referrer: Some
+        data.extend_from_slice(referrer.as_ref());
+        data.push(0); // pending_withdrawal: None
+        data.extend_from_slice(&5u64.to_le_bytes()); // total_claimed
+        data.extend_from_slice(&AMOUNT.to_le_bytes()); // total_staked_lifetime
+        data.extend_from_slice(&5u64.to_le_bytes()); // total_yields_claimed
+        data.resize(USER_STAKE_V1_SPACE, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 1);
+
+        let mut rewritten = Vec::new();
+        upgrade_user_stake(version, body).unwrap().try_serialize(&mut rewritten).unwrap();
+        let migrated = UserStake::try_deserialize(&mut rewritten.as_slice()).unwrap();
+        assert_eq!(migrated.version, USER_STAKE_VERSION);
+        assert_eq!(migrated.user, user);
+        assert_eq!(migrated.amount, AMOUNT);
+        assert_eq!(migrated.committed_days, 90);
+        assert_eq!(migrated.last_claim_timestamp, 1_000 + DAY);
+        assert_eq!(migrated.locked_apy_bps, 2000);
+        assert!(migrated.is_counted);
+        assert_eq!(migrated.claim_delegate, None);
+        assert_eq!(migrated.referrer, Some(referrer));
+        assert_eq!(migrated.pending_withdrawal, None);
+        assert_eq!(migrated.total_yields_claimed, 5);
+        assert_eq!(migrated.funded_by, None);
+    }
+
+    #[test]
+    fn version_2_user_stake_does_not_read_stale_tail_bytes() {
+        // Version 2 accounts end at total_yields_claimed; anything after it is padding
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 2;
+        legacy.funded_by = None;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 17); // funded_by, vote_locked_until and last_stake_slot came later
+        data.resize(USER_STAKE_V1_SPACE + 1, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 2);
+
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.version, USER_STAKE_VERSION);
+        assert_eq!(migrated.user, legacy.user);
+        assert_eq!(migrated.amount, AMOUNT);
+        assert_eq!(migrated.funded_by, None);
+    }
+
+    #[test]
+    fn version_3_user_stake_keeps_its_funder() {
+        let funder = Pubkey::new_unique();
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 3;
+        legacy.funded_by = Some(funder);
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 25); // vote_locked_until, last_stake_slot, pending_yields and bump came later
+        data.resize(USER_STAKE_V1_SPACE + 34, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 3);
+
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.funded_by, Some(funder));
+        assert_eq!(migrated.vote_locked_until, 0);
+    }
+
+    #[test]
+    fn version_11_pool_keeps_fields_added_since_version_4() {
+        // Version 11 is the current layout without fee_tiers and the fields added after it,
+        // which sit between older fields, so their bytes are cut out of the middle
+        let collection = Pubkey::new_unique();
+        let mut legacy = pool_with(2000, loyalty_tiers());
+        legacy.version = 11;
+        legacy.boost_collection = Some(collection);
+        legacy.leverage_ratio = 3000;
+        legacy.max_slippage_bps = 250;
+        legacy.min_slots_before_exit = 0x5a5a_5a5a_5a5a_5a5a;
+        legacy.min_stake_amount = AMOUNT;
+        legacy.last_update = 1_234;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        let marker = 0x5a5a_5a5a_5a5a_5a5au64.to_le_bytes();
+        let offset = data.windows(8).position(|window| window == marker).unwrap() + 8;
+        data.drain(offset..offset + 326); // fee_tiers, an empty vec, and the fields added since
+
+        let (version, body) = split_layout_version(&data, &Pool::DISCRIMINATOR, POOL_V1_SPACE, POOL_VERSION).unwrap();
+        assert_eq!(version, 11);
+
+        let migrated = upgrade_pool(version, body).unwrap();
+        assert_eq!(migrated.version, POOL_VERSION);
+        assert_eq!(migrated.loyalty_tiers, loyalty_tiers());
+        assert_eq!(migrated.boost_collection, Some(collection));
+        assert_eq!(migrated.leverage_ratio, 3000);
+        assert_eq!(migrated.max_slippage_bps, 250);
+        assert_eq!(migrated.min_slots_before_exit, 0x5a5a_5a5a_5a5a_5a5a);
+        assert!(migrated.fee_tiers.is_empty());
+        assert_eq!(migrated.total_yields_credited, 0);
+        assert!(migrated.is_active);
+        assert_eq!(migrated.min_stake_amount, AMOUNT);
+        assert_eq!(migrated.last_update, 1_234);
+    }
+
+    #[test]
+    fn current_version_accounts_are_not_migrated_again() {
+        let mut data = Vec::new();
+        position(AMOUNT, 2000).try_serialize(&mut data).unwrap();
+        data.resize(8 + UserStake::INIT_SPACE, 0);
+
+        assert!(split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).is_err());
+    }
+
+    fn position(amount: u64, locked_apy_bps: u64) -> UserStake {
+        UserStake {
+            version: USER_STAKE_VERSION,
+            user: Pubkey::new_unique(),
+            amount,
+            committed_days: 30,
+            stake_timestamp: 0,
+            last_claim_timestamp: 0,
+            locked_apy_bps,
+            is_counted: true,
+            claim_delegate: None,
+            referrer: None,
+            pending_withdrawal: None,
+            total_claimed: 0,
+            total_staked_lifetime: amount,
+            total_yields_claimed: 0,
+            funded_by: None,
+            vote_locked_until: 0,
+            last_stake_slot: 0,
+            pending_yields: 0,
+            bump: 0,
+            rewards_owed: 0,
+            accrual_rate_bps: locked_apy_bps,
+            reward_per_token_paid: 0,
+            yields_claimed_in_year: 0,
+            year_window_start: 0,
+            projected_liability: 0,
+            policy_version: 0,
+            auto_renew_opted_out: false,
+            initialized: true,
+        }
+    }
+
+    // All-zero pool, which is what a freshly initialised account decodes to, still active
+    fn zeroed<T: AnchorDeserialize>() -> T {
+        T::deserialize(&mut &vec![0u8; 2048][..]).unwrap()
+    }
+
+    fn serialized_len<T: AnchorSerialize>(account: &T) -> usize {
+        account.try_to_vec().unwrap().len()
+    }
+
+    // Each account at its largest, with every Option filled and every Vec at its max_len,
+    // must serialize to exactly the space it is created with; a field added without
+    // updating max_len or the space calculation fails here before it fails on chain
+    #[test]
+    fn init_space_matches_the_largest_serialized_accounts() {
+        let mut pool: Pool = zeroed();
+        pool.apy_tiers = vec![zeroed(); MAX_APY_TIERS];
+        pool.loyalty_tiers = vec![zeroed(); MAX_LOYALTY_TIERS];
+        pool.fee_tiers = vec![zeroed(); MAX_FEE_TIERS];
+        pool.boost_collection = Some(Pubkey::new_unique());
+        pool.reward_mint = Some(Pubkey::new_unique());
+        pool.stake_mint = Some(Pubkey::new_unique());
+        assert_eq!(serialized_len(&pool), Pool::INIT_SPACE);
+
+        let mut user_stake: UserStake = zeroed();
+        user_stake.claim_delegate = Some(Pubkey::new_unique());
+        user_stake.referrer = Some(Pubkey::new_unique());
+        user_stake.pending_withdrawal = Some(PendingWithdrawal { amount: 1, requested_at: 1 });
+        user_stake.funded_by = Some(Pubkey::new_unique());
+        assert_eq!(serialized_len(&user_stake), UserStake::INIT_SPACE);
+
+        // The rest hold fixed-width fields only
+        assert_eq!(serialized_len(&zeroed::<PoolVault>()), PoolVault::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<PoolRegistry>()), PoolRegistry::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<Proposal>()), Proposal::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<PoolSnapshot>()), PoolSnapshot::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<VoteRecord>()), VoteRecord::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<ReferralAccount>()), ReferralAccount::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<UserHistory>()), UserHistory::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<IncidentLog>()), IncidentLog::INIT_SPACE);
+        assert_eq!(serialized_len(&zeroed::<AccessEntry>()), AccessEntry::INIT_SPACE);
+    }
+
+    fn pool_with(max_apy: u64, loyalty_tiers: Vec<LoyaltyTier>) -> Pool {
+        let mut pool: Pool = zeroed();
+        pool.is_active = true;
+        pool.max_apy = max_apy;
+        pool.loyalty_tiers = loyalty_tiers;
+        pool
+    }
+
+    fn loyalty_tiers() -> Vec<LoyaltyTier> {
+        vec![
+            LoyaltyTier { min_lifetime_staked: 100 * AMOUNT, boost_bps: 100 },
+            LoyaltyTier { min_lifetime_staked: 1000 * AMOUNT, boost_bps: 300 },
+        ]
+    }
+
+    #[test]
+    fn pending_yields_match_the_claim_formula_for_the_same_clock() {
+        let user_stake = position(365 * AMOUNT, 1000);
+
+        // 10% APY on 365 SOL is 0.1 SOL a day, accrued by the second
+        let pool = pool_with(1000, Vec::new());
+        assert_eq!(settled_position(&pool, &user_stake, 0, 3 * DAY).unwrap().rewards_owed, 3 * AMOUNT / 10);
+        assert_eq!(
+            settled_position(&pool, &user_stake, 0, 3 * DAY + 5).unwrap().rewards_owed,
+            compute_yields(user_stake.amount, user_stake.locked_apy_bps, 3 * DAY + 5).unwrap()
+        );
+        assert_eq!(
+            settled_position(&pool, &user_stake, 0, DAY - 1).unwrap().rewards_owed,
+            AMOUNT / 10 - AMOUNT / 10 / DAY as u64 - 1
+        );
+    }
+
+    #[test]
+    fn views_decode_with_the_interface_crate() {
+        use defi_trust_fund_interface as interface;
+
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        user_stake.pending_yields = 7;
+        let value = position_value(&pool_with(1000, Vec::new()), &user_stake, 3 * DAY).unwrap();
+        assert_eq!(value.accrued_yields, 3 * AMOUNT / 10 + 7);
+        assert_eq!(value.unlock_ts, 30 * DAY);
+        let decoded = interface::PositionValue::try_from_slice(&value.try_to_vec().unwrap()).unwrap();
+        assert_eq!(
+            (decoded.amount, decoded.accrued_yields, decoded.unlock_ts, decoded.apy_bps),
+            (value.amount, value.accrued_yields, value.unlock_ts, value.apy_bps)
+        );
+
+        let stats = PoolStats { total_staked: 1, total_users: 2, utilization_bps: 3 };
+        let decoded = interface::PoolStats::try_from_slice(&stats.try_to_vec().unwrap()).unwrap();
+        assert_eq!((decoded.total_staked, decoded.total_users, decoded.utilization_bps), (1, 2, 3));
+
+        assert_eq!(interface::PROGRAM_ID, ID.to_bytes());
+        assert_eq!(interface::GET_POSITION_VALUE_DISCRIMINATOR, instruction::GetPositionValue::DISCRIMINATOR);
+        assert_eq!(interface::GET_POOL_STATS_DISCRIMINATOR, instruction::GetPoolStats::DISCRIMINATOR);
+    }
+
+    #[test]
+    fn claims_below_the_minimum_are_refused_unless_the_principal_is_gone() {
+        let mut pool = pool_with(1000, Vec::new());
+        let mut user_stake = position(AMOUNT, 1000);
+        assert!(check_min_claim(&pool, &user_stake, 1).is_ok());
+
+        pool.min_claim_amount = 10_000;
+        assert!(check_min_claim(&pool, &user_stake, 9_999).is_err());
+        assert!(check_min_claim(&pool, &user_stake, 10_000).is_ok());
+
+        // A fully exited position sweeps whatever dust it is still owed
+        user_stake.amount = 0;
+        assert!(check_min_claim(&pool, &user_stake, 1).is_ok());
+    }
+
+    #[test]
+    fn commitment_and_accrual_are_exact_to_the_second() {
+        let pool = pool_with(1000, Vec::new());
+        let user_stake = position(365 * AMOUNT, 1000);
+        let commitment = user_stake.committed_days as i64 * DAY;
+        let penalty_at = |elapsed| {
+            compute_penalty(AMOUNT, 500, elapsed, user_stake.committed_days, false, 10000).unwrap()
+        };
+
+        // A second short of a 30 day commitment still pays the penalty; the commitment
+        // itself does not
+        assert_eq!(penalty_at(commitment - 1), AMOUNT / 20);
+        assert_eq!(penalty_at(commitment), 0);
+        assert_eq!(penalty_at(commitment + 1), 0);
+
+        // Each second around the boundary earns its own yield, and 23 hours past the
+        // commitment earn 23 hours more than the commitment, to within a lamport
+        let accrued = |elapsed| settled_position(&pool, &user_stake, 0, elapsed).unwrap().rewards_owed;
+        assert!(accrued(commitment - 1) < accrued(commitment));
+        assert!(accrued(commitment) < accrued(commitment + 1));
+        assert_eq!(accrued(commitment), 3 * AMOUNT);
+        let extra = accrued(commitment + 23 * 3600) - accrued(commitment);
+        assert!(extra.abs_diff(compute_yields(365 * AMOUNT, 1000, 23 * 3600).unwrap()) <= 1);
+    }
+
+    #[test]
+    fn queued_withdrawals_do_not_accrue() {
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        user_stake.pending_withdrawal = Some(PendingWithdrawal { amount: 365 * AMOUNT / 2, requested_at: 0 });
+        let settled = settled_position(&pool_with(1000, Vec::new()), &user_stake, 0, 2 * DAY).unwrap();
+        assert_eq!(settled.rewards_owed, AMOUNT / 10);
+    }
+
+    #[test]
+    fn days_until_commitment_round_up_and_stop_at_zero() {
+        let user_stake = position(AMOUNT, 1000);
+        assert_eq!(days_until_commitment_met(&user_stake, 0), 30);
+        assert_eq!(days_until_commitment_met(&user_stake, 29 * DAY + 1), 1);
+        assert_eq!(days_until_commitment_met(&user_stake, 30 * DAY), 0);
+        assert_eq!(days_until_commitment_met(&user_stake, 40 * DAY), 0);
+    }
+
+    #[test]
+    fn loyalty_boost_follows_lifetime_stake_thresholds() {
+        assert_eq!(resolve_loyalty_boost_bps(&loyalty_tiers(), 100 * AMOUNT - 1), 0);
+        assert_eq!(resolve_loyalty_boost_bps(&loyalty_tiers(), 100 * AMOUNT), 100);
+        assert_eq!(resolve_loyalty_boost_bps(&loyalty_tiers(), 5000 * AMOUNT), 300);
+        assert_eq!(resolve_loyalty_boost_bps(&[], 5000 * AMOUNT), 0);
+    }
+
+    #[test]
+    fn loyalty_tiers_are_capped_and_strictly_increasing() {
+        assert!(validate_loyalty_tiers(&loyalty_tiers()).is_ok());
+
+        let over_cap = vec![LoyaltyTier { min_lifetime_staked: AMOUNT, boost_bps: MAX_LOYALTY_BOOST_BPS + 1 }];
+        assert!(validate_loyalty_tiers(&over_cap).is_err());
+
+        let mut unordered = loyalty_tiers();
+        unordered.reverse();
+        assert!(validate_loyalty_tiers(&unordered).is_err());
+    }
+
+    #[test]
+    fn loyalty_boost_never_lifts_effective_apy_above_max_apy() {
+        let mut user_stake = position(365 * AMOUNT, 1900);
+        user_stake.total_staked_lifetime = 1000 * AMOUNT;
+
+        // +300 bps would reach 22%, but the pool tops out at 20%
+        let pool = pool_with(2000, loyalty_tiers());
+        assert_eq!(effective_apy_bps(&pool, &user_stake, 0), 2000);
+        update_rewards(&pool, &mut user_stake, 0, 0).unwrap();
+        assert_eq!(
+            settled_position(&pool, &user_stake, 0, DAY).unwrap().rewards_owed,
+            compute_yields(user_stake.amount, 2000, DAY).unwrap()
+        );
+
+        // Below the cap the full boost applies
+        let pool = pool_with(5000, loyalty_tiers());
+        assert_eq!(effective_apy_bps(&pool, &user_stake, 0), 2200);
+    }
+
+    fn nft_metadata(mint: Pubkey, collection: Option<(Pubkey, bool)>) -> MetadataAccount {
+        use anchor_spl::metadata::mpl_token_metadata::types::{Collection, Key};
+
+        MetadataAccount {
+            key: Key::MetadataV1,
+            update_authority: Pubkey::new_unique(),
+            mint,
+            name: String::new(),
+            symbol: String::new(),
+            uri: String::new(),
+            seller_fee_basis_points: 0,
+            creators: None,
+            primary_sale_happened: false,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard: None,
+            collection: collection.map(|(key, verified)| Collection { verified, key }),
+            uses: None,
+            collection_details: None,
+            programmable_config: None,
+        }
+    }
+
+    #[test]
+    fn nft_boost_requires_the_verified_collection_for_the_same_mint() {
+        let mint = Pubkey::new_unique();
+        let collection = Pubkey::new_unique();
+
+        assert!(nft_in_verified_collection(&nft_metadata(mint, Some((collection, true))), &mint, &collection));
+
+        // Anyone can name a collection; only the collection authority can verify it
+        assert!(!nft_in_verified_collection(&nft_metadata(mint, Some((collection, false))), &mint, &collection));
+        assert!(!nft_in_verified_collection(&nft_metadata(mint, Some((Pubkey::new_unique(), true))), &mint, &collection));
+        assert!(!nft_in_verified_collection(&nft_metadata(mint, None), &mint, &collection));
+        assert!(!nft_in_verified_collection(
+            &nft_metadata(Pubkey::new_unique(), Some((collection, true))),
+            &mint,
+            &collection
+        ));
+    }
+
+    #[test]
+    fn claims_without_nft_accounts_get_no_boost() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.boost_collection = Some(Pubkey::new_unique());
+        pool.nft_boost_bps = 200;
+        assert_eq!(resolve_nft_boost_bps(&pool, &Pubkey::new_unique(), &[]).unwrap(), 0);
+
+        let user_stake = position(365 * AMOUNT, 1000);
+        assert_eq!(effective_apy_bps(&pool, &user_stake, pool.nft_boost_bps), 1200);
+    }
+
+    #[test]
+    fn snapshots_are_kept_for_the_retention_window() {
+        // With 3 epochs retained and 5 taken, epochs 2, 3 and 4 stay
+        assert!(snapshot_expired(0, 5, 3));
+        assert!(snapshot_expired(1, 5, 3));
+        assert!(!snapshot_expired(2, 5, 3));
+        assert!(!snapshot_expired(4, 5, 3));
+        assert!(!snapshot_expired(0, 5, u64::MAX));
+    }
+
+    #[test]
+    fn split_operations_in_one_slot_trip_the_block_delay() {
+        let mut pool = pool_with(1000, vec![]);
+        pool.large_operation_threshold = 10 * AMOUNT;
+        pool.min_block_delay = 2;
+
+        // Eleven chunks under the threshold: the eleventh brings the slot past 10 SOL
+        for _ in 0..11 {
+            record_slot_volume(&mut pool, 99 * AMOUNT / 100, 100).unwrap();
+        }
+        assert_eq!(pool.slot_delay_until, 102);
+        assert!(record_slot_volume(&mut pool, AMOUNT, 100).is_err());
+        assert!(record_slot_volume(&mut pool, AMOUNT, 101).is_err());
+
+        // Once the delay has passed the next slot starts counting from zero
+        record_slot_volume(&mut pool, AMOUNT, 102).unwrap();
+        assert_eq!(pool.current_slot_volume, AMOUNT);
+    }
+
+    #[test]
+    fn volume_spread_across_slots_does_not_trip_the_block_delay() {
+        let mut pool = pool_with(1000, vec![]);
+        pool.large_operation_threshold = 10 * AMOUNT;
+        pool.min_block_delay = 2;
+
+        for slot in 100..120 {
+            record_slot_volume(&mut pool, 9 * AMOUNT, slot).unwrap();
+        }
+        assert_eq!(pool.slot_delay_until, 0);
+    }
+
+    #[test]
+    fn quote_floor_is_exact_and_cannot_be_disabled() {
+        // 0.5% fee on 1 SOL, quoted at that fee
+        let fee = compute_fee(AMOUNT, 50).unwrap();
+        assert!(check_quote(AMOUNT, fee, AMOUNT - fee, 100).is_ok());
+
+        // Fee raised to 1% after the quote: within tolerance, but below the floor
+        let raised_fee = compute_fee(AMOUNT, 100).unwrap();
+        assert!(check_quote(AMOUNT, raised_fee, AMOUNT - fee, 100).is_err());
+
+        // A zero floor implies a 100% fee, far outside the tolerance
+        assert!(check_quote(AMOUNT, fee, 0, 100).is_err());
+    }
+
+    #[test]
+    fn deadlines_expire_after_the_given_second_unless_unset() {
+        assert!(check_transaction_deadline(1_000, 1_000).is_ok());
+        assert!(check_transaction_deadline(1_000, 1_001).is_err());
+        assert!(check_transaction_deadline(NO_DEADLINE, i64::MAX).is_ok());
+    }
+
+    #[test]
+    fn payout_floor_rejects_short_payouts_unless_unset() {
+        assert!(check_min_payout(AMOUNT, AMOUNT).is_ok());
+        assert!(check_min_payout(AMOUNT - 1, AMOUNT).is_err());
+        assert!(check_min_payout(0, NO_MIN_PAYOUT).is_ok());
+    }
+
+    #[test]
+    fn exit_waits_for_the_slot_after_the_last_stake() {
+        assert!(!exit_slot_reached(100, 1, 100));
+        assert!(exit_slot_reached(100, 1, 101));
+        // A zero window still blocks the staking slot itself
+        assert!(!exit_slot_reached(100, 0, 100));
+        assert!(!exit_slot_reached(100, 5, 104));
+        assert!(exit_slot_reached(100, 5, 105));
+    }
+
+    #[test]
+    fn version_4_user_stake_keeps_its_vote_lock() {
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 4;
+        legacy.vote_locked_until = 1_000;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 17); // last_stake_slot, pending_yields and bump came later
+        data.resize(USER_STAKE_V1_SPACE + 42, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 4);
+
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.vote_locked_until, 1_000);
+        assert_eq!(migrated.last_stake_slot, 0);
+    }
+
+    #[test]
+    fn version_5_user_stake_keeps_its_last_stake_slot() {
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 5;
+        legacy.last_stake_slot = 4_242;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 74); // pending_yields, bump and everything after them came later
+        data.resize(USER_STAKE_V1_SPACE + 50, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 5);
+
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.last_stake_slot, 4_242);
+        assert_eq!(migrated.pending_yields, 0);
+    }
+
+    #[test]
+    fn version_8_user_stake_keeps_its_settled_rewards() {
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 8;
+        legacy.rewards_owed = 0x5a5a_5a5a;
+        legacy.accrual_rate_bps = 2300;
+        legacy.reward_per_token_paid = u128::MAX;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 40); // reward_per_token_paid and everything after it came later
+        data.resize(8 + UserStake::INIT_SPACE, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.rewards_owed, 0x5a5a_5a5a);
+        assert_eq!(migrated.accrual_rate_bps, 2300);
+        assert_eq!(migrated.reward_per_token_paid, 0);
+        assert_eq!(migrated.year_window_start, 0);
+    }
+
+    #[test]
+    fn version_9_user_stake_keeps_its_accumulator_reading() {
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 9;
+        legacy.reward_per_token_paid = 0x5a5a_5a5a_5a5a;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 24); // The yield window and projected liability came later
+        data.resize(8 + UserStake::INIT_SPACE, 0xff);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert_eq!(migrated.reward_per_token_paid, 0x5a5a_5a5a_5a5a);
+        assert_eq!((migrated.yields_claimed_in_year, migrated.year_window_start), (0, 0));
+    }
+
+    #[test]
+    fn version_13_positions_migrate_as_initialized() {
+        let mut legacy = position(AMOUNT, 2000);
+        legacy.version = 13;
+        let mut data = Vec::new();
+        legacy.try_serialize(&mut data).unwrap();
+        data.truncate(data.len() - 1); // initialized came later
+        data.resize(8 + UserStake::INIT_SPACE, 0);
+
+        let (version, body) =
+            split_layout_version(&data, &UserStake::DISCRIMINATOR, USER_STAKE_V1_SPACE, USER_STAKE_VERSION).unwrap();
+        assert_eq!(version, 13);
+        let migrated = upgrade_user_stake(version, body).unwrap();
+        assert!(migrated.initialized);
+        assert_eq!(migrated.user, legacy.user);
+    }
+
+    #[test]
+    fn positions_are_only_ever_claimed_for_their_owner() {
+        let owner = Pubkey::new_unique();
+
+        // A freshly created account is claimed on its first deposit
+        let mut blank = UserStake::default();
+        claim_position(&mut blank, owner).unwrap();
+        assert_eq!(blank.user, owner);
+        assert!(blank.initialized);
+        claim_position(&mut blank, owner).unwrap();
+
+        // Someone else's position is never rewritten, whatever state it is in
+        let mut theirs = position(AMOUNT, 1000);
+        assert_eq!(claim_position(&mut theirs, owner).unwrap_err(), ErrorCode::Unauthorized.into());
+        theirs.amount = 0;
+        theirs.total_staked_lifetime = 0;
+        assert_eq!(claim_position(&mut theirs, owner).unwrap_err(), ErrorCode::Unauthorized.into());
+
+        // Stale data with its owner wiped cannot be resurrected under a new one
+        let mut stale = position(0, 1000);
+        stale.user = Pubkey::default();
+        stale.total_yields_claimed = 5;
+        stale.initialized = false;
+        assert_eq!(claim_position(&mut stale, owner).unwrap_err(), ErrorCode::InvalidPositionState.into());
+
+        let mut ownerless = UserStake { initialized: true, ..UserStake::default() };
+        assert_eq!(claim_position(&mut ownerless, owner).unwrap_err(), ErrorCode::InvalidPositionState.into());
+    }
+
+    #[test]
+    fn a_first_stake_claims_a_blank_position_under_the_solvency_check() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT;
+        pool.solvency_factor_bps = 10000;
+        let owner = Pubkey::new_unique();
+        let mut blank = UserStake::default();
+
+        record_stake(&mut pool, &mut blank, None, owner, AMOUNT, 0, 30, &clock_at(100, DAY)).unwrap();
+        assert_eq!(blank.user, owner);
+        assert_eq!(blank.amount, AMOUNT);
+        assert!(blank.projected_liability > 0);
+    }
+
+    #[test]
+    fn projected_liability_follows_positions_in_and_out() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT;
+        pool.solvency_factor_bps = 10000;
+        let mut first = position(AMOUNT, 1000);
+        let mut second = position(AMOUNT, 1000);
+
+        adjust_projected_liability(&mut pool, &mut first, 300, 0, 0);
+        adjust_projected_liability(&mut pool, &mut second, 500, 0, 0);
+        assert_eq!(pool.total_projected_liability, 800);
+
+        // A payout releases what it settles, but never more than the position carries
+        adjust_projected_liability(&mut pool, &mut first, 0, 100, 0);
+        adjust_projected_liability(&mut pool, &mut second, 0, 900, 0);
+        assert_eq!((first.projected_liability, second.projected_liability), (200, 0));
+        assert_eq!(pool.total_projected_liability, 200);
+    }
+
+    #[test]
+    fn a_claim_exactly_at_the_cap_pays_it_all() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.max_yield_per_claim = AMOUNT;
+        pool.max_yield_per_user_per_year = 2 * AMOUNT;
+        let mut user_stake = position(AMOUNT, 1000);
+
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, AMOUNT, DAY), AMOUNT);
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, AMOUNT, 2 * DAY), AMOUNT);
+        assert_eq!(user_stake.yields_claimed_in_year, 2 * AMOUNT);
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, AMOUNT, 3 * DAY), 0);
+    }
+
+    #[test]
+    fn a_claim_across_the_cap_pays_up_to_it_and_keeps_the_rest_owed() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.max_yield_per_claim = 3 * AMOUNT;
+        pool.max_yield_per_user_per_year = 5 * AMOUNT;
+        let mut user_stake = position(AMOUNT, 1000);
+        user_stake.year_window_start = DAY;
+        user_stake.yields_claimed_in_year = 4 * AMOUNT;
+
+        // Only a fifth of the year's allowance is left, below the per-claim cap
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, 2 * AMOUNT, 10 * DAY), AMOUNT);
+        assert_eq!(user_stake.yields_claimed_in_year, 5 * AMOUNT);
+
+        // Without the yearly cap the per-claim cap alone applies
+        pool.max_yield_per_user_per_year = 0;
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, 4 * AMOUNT, 10 * DAY), 3 * AMOUNT);
+    }
+
+    #[test]
+    fn the_yearly_window_rolls_over_and_restores_claims() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.max_yield_per_user_per_year = AMOUNT;
+        let mut user_stake = position(AMOUNT, 1000);
+
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, 2 * AMOUNT, 10 * DAY), AMOUNT);
+        assert_eq!(user_stake.year_window_start, 10 * DAY);
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, AMOUNT, 10 * DAY + YIELD_CAP_WINDOW - 1), 0);
+
+        // A year after the window opened the carried-over yield is claimable again
+        let now = 10 * DAY + YIELD_CAP_WINDOW;
+        assert_eq!(take_capped_yield(&pool, &mut user_stake, AMOUNT, now), AMOUNT);
+        assert_eq!((user_stake.year_window_start, user_stake.yields_claimed_in_year), (now, AMOUNT));
+    }
+
+    #[test]
+    fn a_transfer_cannot_reset_either_yearly_window() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.max_yield_per_user_per_year = AMOUNT;
+        let mut source = position(AMOUNT, 1000);
+        let mut destination = position(0, 0);
+
+        // The receiving wallet used up its allowance on a position it has since emptied
+        assert_eq!(take_capped_yield(&pool, &mut destination, AMOUNT, 10 * DAY), AMOUNT);
+        assert_eq!(take_capped_yield(&pool, &mut source, AMOUNT / 2, 20 * DAY), AMOUNT / 2);
+        merge_yield_windows(&mut destination, &source, 30 * DAY);
+        assert_eq!((destination.year_window_start, destination.yields_claimed_in_year), (20 * DAY, AMOUNT));
+        assert_eq!(take_capped_yield(&pool, &mut destination, AMOUNT, 30 * DAY), 0);
+
+        // A window that has run its year no longer counts
+        let mut fresh = position(0, 0);
+        merge_yield_windows(&mut fresh, &source, 20 * DAY + YIELD_CAP_WINDOW);
+        assert_eq!((fresh.year_window_start, fresh.yields_claimed_in_year), (0, 0));
+    }
+
+    #[test]
+    fn credits_accrued_yield_and_only_moves_the_checkpoint_forward() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+
+        // 10% on 365 SOL is 0.1 SOL a day, so two and a half days credit 0.25 SOL
+        let credited = credit_accrued_yield(&mut pool, &mut user_stake, 2 * DAY + DAY / 2).unwrap();
+        assert_eq!(credited, AMOUNT / 4);
+        assert_eq!(user_stake.pending_yields, credited);
+        assert_eq!(user_stake.last_claim_timestamp, 2 * DAY + DAY / 2);
+        assert_eq!(pool.reward_reserve, AMOUNT - credited);
+        assert_eq!(pool.total_yields_credited, credited);
+
+        // Crediting again at the same time adds nothing
+        assert_eq!(credit_accrued_yield(&mut pool, &mut user_stake, 2 * DAY + DAY / 2).unwrap(), 0);
+        assert_eq!(user_stake.last_claim_timestamp, 2 * DAY + DAY / 2);
+    }
+
+    #[test]
+    fn capped_credited_yield_is_paid_in_full_once_the_principal_has_left() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT;
+        pool.max_yield_per_user_per_year = AMOUNT / 100;
+        pool.max_daily_outflow_bps = 10000;
+        pool.total_staked = 365 * AMOUNT;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        let credited = credit_accrued_yield(&mut pool, &mut user_stake, 2 * DAY + DAY / 2).unwrap();
+
+        // The yearly cap lets a hundredth out, then nothing more while principal is staked
+        assert_eq!(credited_payout(&mut pool, &mut user_stake, 3 * DAY).unwrap(), AMOUNT / 100);
+        user_stake.pending_yields -= AMOUNT / 100;
+        assert_eq!(
+            failure_code(credited_payout(&mut pool, &mut user_stake, 3 * DAY).unwrap_err()),
+            failure_code(ErrorCode::YieldCapReached.into())
+        );
+
+        // Once the principal has left, the rest can be withdrawn and the account closed
+        user_stake.amount = 0;
+        assert_eq!(credited_payout(&mut pool, &mut user_stake, 3 * DAY).unwrap(), credited - AMOUNT / 100);
+    }
+
+    #[test]
+    fn an_emergency_exit_returns_credited_yield_to_the_reserve() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        let credited = credit_accrued_yield(&mut pool, &mut user_stake, 2 * DAY + DAY / 2).unwrap();
+
+        return_credited_yields(&mut pool, &mut user_stake);
+        assert_eq!(user_stake.pending_yields, 0);
+        assert_eq!(pool.reward_reserve, AMOUNT);
+        assert_eq!(pool.total_yields_credited, 0);
+        assert!(credited > 0);
+    }
+
+    #[test]
+    fn yields_stop_accruing_at_sunset() {
+        let mut pool = pool_with(2000, Vec::new());
+        let user_stake = position(365 * AMOUNT, 1000);
+        let daily_yield = AMOUNT / 10;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 10 * DAY).unwrap().rewards_owed, 10 * daily_yield);
+
+        // Time accrued before the sunset is honoured, later time is not
+        pool.is_active = false;
+        pool.sunset_at = 3 * DAY + DAY / 2;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 2 * DAY).unwrap().rewards_owed, 2 * daily_yield);
+        assert_eq!(settled_position(&pool, &user_stake, 0, 10 * DAY).unwrap().rewards_owed, 7 * daily_yield / 2);
+    }
+
+    #[test]
+    fn each_period_is_paid_at_the_rate_in_force_during_it() {
+        // 9% locked plus the 3% loyalty boost is 12% on 365 SOL, 0.12 SOL a day
+        let mut pool = pool_with(2000, loyalty_tiers());
+        let mut user_stake = position(365 * AMOUNT, 900);
+        user_stake.total_staked_lifetime = 1000 * AMOUNT;
+        update_rewards(&pool, &mut user_stake, 0, 0).unwrap();
+        assert_eq!(user_stake.accrual_rate_bps, 1200);
+
+        // The cap drops to 9% halfway; days before the change are not repriced
+        pool.max_apy = 900;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 10 * DAY).unwrap().rewards_owed, 10 * 12 * AMOUNT / 100);
+        assert_eq!(update_rewards(&pool, &mut user_stake, 0, 10 * DAY).unwrap(), 10 * DAY);
+        assert_eq!(user_stake.accrual_rate_bps, 900);
+
+        // The second half earns 9%, so the 20 days average 10.5%
+        let paid = settled_position(&pool, &user_stake, 0, 20 * DAY).unwrap().rewards_owed;
+        assert_eq!(paid, 10 * 12 * AMOUNT / 100 + 10 * 9 * AMOUNT / 100);
+        assert_eq!(paid, compute_yields(365 * AMOUNT, 1050, 20 * DAY).unwrap());
+    }
+
+    #[test]
+    fn accumulator_accounting_matches_the_single_period_formula() {
+        // (amount, apy_bps, total days, day of an update in between)
+        let scenarios = [
+            (365 * AMOUNT, 1000, 30, 10),
+            (AMOUNT + 7, 1164, 365, 1),
+            (123_456_789, 2327, 90, 89),
+            (1, 10000, 3650, 1825),
+        ];
+        let pool = pool_with(10000, Vec::new());
+        for (amount, apy_bps, days, update_day) in scenarios {
+            let mut user_stake = position(amount, apy_bps);
+            update_rewards(&pool, &mut user_stake, 0, update_day * DAY + DAY / 3).unwrap();
+            update_rewards(&pool, &mut user_stake, 0, days * DAY).unwrap();
+
+            let single_period = compute_yields(amount, apy_bps, days * DAY).unwrap();
+            assert!(user_stake.rewards_owed.abs_diff(single_period) <= 1);
+        }
+    }
+
+    #[test]
+    fn shared_revenue_is_collected_once_per_distribution() {
+        let mut pool = pool_with(2000, Vec::new());
+        let mut small = position(AMOUNT, 0);
+        let mut large = position(3 * AMOUNT, 0);
+        update_rewards(&pool, &mut small, 0, 0).unwrap();
+        update_rewards(&pool, &mut large, 0, 0).unwrap();
+
+        pool.reward_per_token_stored += reward_per_token_increment(AMOUNT, 4 * AMOUNT).unwrap();
+        update_rewards(&pool, &mut small, 0, DAY / 2).unwrap();
+        update_rewards(&pool, &mut large, 0, DAY / 2).unwrap();
+        assert_eq!(small.rewards_owed, AMOUNT / 4);
+        assert_eq!(large.rewards_owed, 3 * AMOUNT / 4);
+
+        // A second update without a new distribution adds nothing
+        update_rewards(&pool, &mut small, 0, DAY / 2).unwrap();
+        assert_eq!(small.rewards_owed, AMOUNT / 4);
+    }
+
+    #[test]
+    fn shared_revenue_skips_queued_principal() {
+        let mut pool = pool_with(2000, Vec::new());
+        let mut queued = position(2 * AMOUNT, 0);
+        let mut earning = position(2 * AMOUNT, 0);
+        update_rewards(&pool, &mut queued, 0, 0).unwrap();
+        update_rewards(&pool, &mut earning, 0, 0).unwrap();
+        queued.pending_withdrawal = Some(PendingWithdrawal { amount: AMOUNT, requested_at: 0 });
+        pool.total_staked = 4 * AMOUNT;
+        pool.total_pending_withdrawals = AMOUNT;
+
+        // Every lamport distributed is credited to the three accruing
+        pool.reward_per_token_stored += reward_per_token_increment(3 * AMOUNT, accruing_total(&pool)).unwrap();
+        update_rewards(&pool, &mut queued, 0, DAY).unwrap();
+        update_rewards(&pool, &mut earning, 0, DAY).unwrap();
+        assert_eq!(queued.rewards_owed, AMOUNT);
+        assert_eq!(earning.rewards_owed, 2 * AMOUNT);
+
+        unqueue_withdrawal(&mut pool, AMOUNT);
+        assert_eq!(accruing_total(&pool), 4 * AMOUNT);
+        unqueue_withdrawal(&mut pool, AMOUNT); // Queued before the pool counted it
+        assert_eq!(pool.total_pending_withdrawals, 0);
+    }
+
+    #[test]
+    fn settled_yields_survive_a_smaller_principal() {
+        let pool = pool_with(2000, Vec::new());
+        let mut user_stake = position(365 * AMOUNT, 1000);
+
+        // Ten and a half days on the full amount settle before half of it is withdrawn
+        update_rewards(&pool, &mut user_stake, 0, 10 * DAY + DAY / 2).unwrap();
+        assert_eq!(user_stake.rewards_owed, AMOUNT + AMOUNT / 20);
+        assert_eq!(user_stake.last_claim_timestamp, 10 * DAY + DAY / 2);
+        user_stake.amount /= 2;
+
+        // The remaining nine and a half days earn on the half left
+        assert_eq!(
+            settled_position(&pool, &user_stake, 0, 20 * DAY).unwrap().rewards_owed,
+            AMOUNT + AMOUNT / 20 + 19 * AMOUNT / 40
+        );
+    }
+
+    #[test]
+    fn credit_is_skipped_when_the_reserve_falls_short() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.reward_reserve = AMOUNT / 10 - 1;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+
+        assert_eq!(credit_accrued_yield(&mut pool, &mut user_stake, DAY).unwrap(), 0);
+        assert_eq!(user_stake.last_claim_timestamp, 0);
+        assert_eq!(user_stake.pending_yields, 0);
+    }
+
+    #[test]
+    fn grandfathered_positions_keep_their_commitment_after_the_minimum_rises() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.min_commitment_days = 30;
+        pool.reward_reserve = 10 * AMOUNT;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        stamp_policy_version(&pool, &mut user_stake);
+
+        pool.min_commitment_days = 90;
+        pool.policy_version = 1;
+
+        // Claims pay exactly what accrued, and the 30-day lock still ends on day 30
+        assert_eq!(credit_accrued_yield(&mut pool, &mut user_stake, 10 * DAY).unwrap(), AMOUNT);
+        assert_eq!(user_stake.committed_days, 30);
+        assert_eq!(user_stake.policy_version, 0);
+        assert_eq!(compute_penalty(AMOUNT, 500, 30 * DAY, user_stake.committed_days, false, 10000).unwrap(), 0);
+
+        // A top-up that leaves the merged commitment below the new minimum stays grandfathered
+        merge_into_position(&pool, &mut user_stake, 365 * AMOUNT, 90, 1000, 10 * DAY).unwrap();
+        stamp_policy_version(&pool, &mut user_stake);
+        assert_eq!(user_stake.committed_days, 60);
+        assert_eq!(user_stake.policy_version, 0);
+    }
+
+    #[test]
+    fn token_positions_merge_without_accruing_sol_yield() {
+        let pool = pool_with(1000, Vec::new());
+        let mut user_stake = UserStake { initialized: true, ..Default::default() };
+
+        // A token deposit locks no APY, so a top-up a year later still owes nothing
+        merge_principal(&mut user_stake, AMOUNT, 30, 0, 0);
+        merge_principal(&mut user_stake, AMOUNT, 30, 0, 365 * DAY);
+        assert_eq!(user_stake.amount, 2 * AMOUNT);
+        assert_eq!(user_stake.locked_apy_bps, 0);
+        assert_eq!(user_stake.rewards_owed, 0);
+        assert_eq!(settled_position(&pool, &user_stake, 0, 730 * DAY).unwrap().rewards_owed, 0);
+    }
+
+    #[test]
+    fn a_large_top_up_just_before_unlock_earns_no_back_dated_yield() {
+        let pool = pool_with(1000, Vec::new());
+        let mut user_stake = position(AMOUNT, 1000);
+        user_stake.committed_days = 365;
+
+        // 1 SOL has earned 364 days; 99 SOL arrives a day before it unlocks
+        let accrued = settled_position(&pool, &user_stake, 0, 364 * DAY).unwrap().rewards_owed;
+        merge_into_position(&pool, &mut user_stake, 99 * AMOUNT, 365, 1000, 364 * DAY).unwrap();
+        assert_eq!(user_stake.rewards_owed, accrued);
+
+        // The clock moves to the weighted average, so the position is nowhere near unlocked
+        assert_eq!(user_stake.stake_timestamp, 99 * 364 * DAY / 100);
+        assert_eq!(user_stake.committed_days, 365);
+        assert!(days_until_commitment_met(&user_stake, 365 * DAY) > 360);
+        let time_staked = 365 * DAY - user_stake.stake_timestamp;
+        assert!(compute_penalty(100 * AMOUNT, 500, time_staked, 365, false, 10000).unwrap() > 0);
+
+        // A day later the 100 SOL has earned one day, not a year
+        let settled = settled_position(&pool, &user_stake, 0, 365 * DAY).unwrap();
+        let one_day = compute_yields(100 * AMOUNT, 1000, DAY).unwrap();
+        assert!((settled.rewards_owed - accrued).abs_diff(one_day) <= 1);
+    }
+
+    fn rollover_pool() -> Pool {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.apy_tiers = vec![ApyTier { min_days: 30, apy_bps: 800 }, ApyTier { min_days: 90, apy_bps: 1000 }];
+        pool.min_commitment_days = 30;
+        pool.max_commitment_days = 365;
+        pool.max_stake_amount = DEFAULT_MAX_STAKE_AMOUNT;
+        pool.total_staked = 100 * AMOUNT;
+        pool.reward_reserve = 100 * AMOUNT;
+        pool
+    }
+
+    #[test]
+    fn a_claim_books_the_same_yield_whatever_it_is_paid_in() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.reward_mint = Some(Pubkey::new_unique());
+        pool.reward_tokens_per_sol = 250_000_000;
+        pool.reward_reserve = 7;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+
+        let (yield_amount, apy_bps, seconds) = take_claimable_yield(&pool, &mut user_stake, 0, DAY).unwrap();
+        assert_eq!((yield_amount, apy_bps, seconds), (AMOUNT / 10, 1000, DAY));
+        assert_eq!(lamports_to_reward_tokens(yield_amount, pool.reward_tokens_per_sol).unwrap(), 25_000_000);
+
+        book_yield_claim(&mut pool, &mut user_stake, yield_amount, DAY);
+        assert_eq!(user_stake.rewards_owed, 0);
+        assert_eq!(user_stake.total_yields_claimed, AMOUNT / 10);
+        assert_eq!(pool.total_yields_paid, AMOUNT / 10);
+        // Token payouts leave the SOL reward reserve alone
+        assert_eq!(pool.reward_reserve, 7);
+
+        // Nothing is left to claim at the same clock
+        assert_eq!(take_claimable_yield(&pool, &mut user_stake, 0, DAY).unwrap_err(), ErrorCode::NoYieldToClaim.into());
+    }
+
+    #[test]
+    fn rollover_is_refused_before_the_commitment_is_met() {
+        let pool = rollover_pool();
+        let mut user_stake = position(100 * AMOUNT, 800);
+
+        let error = settle_for_rollover(&pool, &mut user_stake, 90, 30 * DAY - 1).unwrap_err();
+        assert_eq!(error, ErrorCode::CommitmentNotMet.into());
+        assert!(settle_for_rollover(&pool, &mut user_stake, 90, 30 * DAY).is_ok());
+
+        // The new commitment must be one the pool offers
+        let error = settle_for_rollover(&pool, &mut user_stake, 366, 30 * DAY).unwrap_err();
+        assert_eq!(error, ErrorCode::InvalidCommitmentDays.into());
+    }
+
+    #[test]
+    fn rollover_with_compound_carries_the_yield_into_the_new_commitment() {
+        let mut pool = rollover_pool();
+        let mut user_stake = position(100 * AMOUNT, 800);
+        let now = 31 * DAY;
+
+        settle_for_rollover(&pool, &mut user_stake, 90, now).unwrap();
+        let owed = user_stake.rewards_owed;
+        assert_eq!(owed, compute_yields(100 * AMOUNT, 800, now).unwrap());
+        let (compounded, paid) = split_rollover_yield(&pool, &mut user_stake, true, now);
+        assert_eq!((compounded, paid), (owed, 0));
+
+        let apy_bps = restart_commitment(&mut pool, &mut user_stake, compounded, compounded, 0, 90, now).unwrap();
+        assert_eq!(apy_bps, 1000); // The tier in effect now, not the old 8%
+        assert_eq!(user_stake.amount, 100 * AMOUNT + owed);
+        assert_eq!((user_stake.stake_timestamp, user_stake.committed_days), (now, 90));
+        assert_eq!(user_stake.rewards_owed, 0);
+        assert_eq!(pool.total_staked, 100 * AMOUNT + owed);
+        assert_eq!(pool.reward_reserve, 100 * AMOUNT - owed);
+        assert_eq!(days_until_commitment_met(&user_stake, now), 90);
+
+        // The new period accrues from the rollover at the new rate
+        let settled = settled_position(&pool, &user_stake, 0, now + DAY).unwrap();
+        assert_eq!(settled.rewards_owed, compute_yields(user_stake.amount, 1000, DAY).unwrap());
+    }
+
+    #[test]
+    fn rollover_with_payout_keeps_the_principal_less_the_fee() {
+        let mut pool = rollover_pool();
+        pool.rollover_fee_bps = 10; // 0.1%
+        let mut user_stake = position(100 * AMOUNT, 800);
+        let now = 30 * DAY;
+
+        settle_for_rollover(&pool, &mut user_stake, 30, now).unwrap();
+        let owed = user_stake.rewards_owed;
+        let (compounded, paid) = split_rollover_yield(&pool, &mut user_stake, false, now);
+        assert_eq!((compounded, paid), (0, owed));
+
+        let fee = compute_fee(100 * AMOUNT, pool.rollover_fee_bps).unwrap();
+        restart_commitment(&mut pool, &mut user_stake, 0, paid, fee, 30, now).unwrap();
+        assert_eq!(user_stake.amount, 100 * AMOUNT - fee);
+        assert_eq!(user_stake.total_yields_claimed, owed);
+        assert_eq!(user_stake.rewards_owed, 0);
+        assert_eq!(pool.total_staked, 100 * AMOUNT - fee);
+        assert_eq!(pool.total_fees_collected, fee);
+        assert_eq!(pool.total_yields_paid, owed);
+    }
+
+    #[test]
+    fn small_top_ups_barely_move_a_large_positions_clock() {
+        let pool = pool_with(1000, Vec::new());
+        let mut user_stake = position(100 * AMOUNT, 1000);
+
+        // Ten minimum deposits over the last days of the commitment, as a hostile UI might send
+        for day in 20..30 {
+            merge_into_position(&pool, &mut user_stake, AMOUNT / 10, 30, 1000, day * DAY).unwrap();
+        }
+        assert_eq!(user_stake.committed_days, 30);
+        assert!(user_stake.stake_timestamp < DAY / 3);
+
+        // Each deposit earned only from the day it arrived, on top of the original 100 SOL
+        let expected = compute_yields(100 * AMOUNT, 1000, 29 * DAY).unwrap()
+            + (20..29).map(|day| compute_yields(AMOUNT / 10, 1000, (29 - day) * DAY).unwrap()).sum::<u64>();
+        assert!(user_stake.rewards_owed.abs_diff(expected) <= 10);
+    }
 
-#[derive(Accounts)]
-pub struct Stake<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        mut,
-        constraint = !pool.is_paused
-    )]
-    pub pool: Account<'info, Pool>,
-    
-    #[account(
-        mut,
-        seeds = [b"pool_vault"],
-        bump
-    )]
-    pub pool_vault: SystemAccount<'info>,
-    
-    #[account(
-        init,
-        payer = user,
-        space = 8 + UserStake::INIT_SPACE,
-        seeds = [b"user_stake", user.key().as_ref()],
-        bump
-    )]
-    pub user_stake: Account<'info, UserStake>,
-    
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+    #[test]
+    fn settlement_refuses_a_clock_from_before_the_pool() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.created_at = 10 * DAY;
+        let user_stake = position(AMOUNT, 1000);
+        assert!(settled_position(&pool, &user_stake, 0, 10 * DAY - 1).is_err());
+        assert!(settled_position(&pool, &user_stake, 0, 10 * DAY).is_ok());
+    }
 
-#[derive(Accounts)]
-pub struct ClaimYields<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        mut,
-        constraint = !pool.is_paused
-    )]
-    pub pool: Account<'info, Pool>,
-    
-    #[account(
-        mut,
-        seeds = [b"pool_vault"],
-        bump
-    )]
-    pub pool_vault: SystemAccount<'info>,
-    
-    #[account(
-        mut,
-        seeds = [b"user_stake", user.key().as_ref()],
-        bump
-    )]
-    pub user_stake: Account<'info, UserStake>,
-    
-    pub system_program: Program<'info, System>,
-}
+    #[test]
+    fn rolling_commitments_renew_once_the_grace_window_passes() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.renewal_grace_seconds = DAY;
+        let user_stake = position(365 * AMOUNT, 1000);
+        let penalty_at = |settled: &UserStake, now: i64| {
+            compute_penalty(AMOUNT, 500, now - settled.stake_timestamp, settled.committed_days, false, 10000).unwrap()
+        };
 
-#[derive(Accounts)]
-pub struct Unstake<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-    
-    #[account(
-        mut,
-        constraint = !pool.is_paused
-    )]
-    pub pool: Account<'info, Pool>,
-    
-    #[account(
-        mut,
-        seeds = [b"pool_vault"],
-        bump
-    )]
-    pub pool_vault: SystemAccount<'info>,
-    
-    #[account(
-        mut,
-        seeds = [b"user_stake", user.key().as_ref()],
-        bump
-    )]
-    pub user_stake: Account<'info, UserStake>,
-    
-    pub system_program: Program<'info, System>,
-}
+        // Inside the grace window the commitment has ended and an exit is free
+        let in_grace = settled_position(&pool, &user_stake, 0, 30 * DAY + DAY - 1).unwrap();
+        assert_eq!(in_grace.stake_timestamp, 0);
+        assert_eq!(penalty_at(&in_grace, 30 * DAY + DAY - 1), 0);
 
-#[derive(Accounts)]
-pub struct AdminOnly<'info> {
-    pub admin: Signer<'info>,
-    
-    #[account(mut)]
-    pub pool: Account<'info, Pool>,
-}
+        // Three commitments later the fourth is a day old, and every day earned the
+        // locked rate of 0.1 SOL
+        let renewed = settled_position(&pool, &user_stake, 0, 91 * DAY).unwrap();
+        assert_eq!(renewed.stake_timestamp, 90 * DAY);
+        assert_eq!(renewed.rewards_owed, 91 * AMOUNT / 10);
+        assert_eq!(renewed.locked_apy_bps, 1000);
+        assert_eq!(penalty_at(&renewed, 91 * DAY), AMOUNT / 20);
+        assert_eq!(days_until_commitment_met(&renewed, 91 * DAY), 29);
 
-#[derive(Accounts)]
-pub struct WithdrawFees<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    
-    #[account(
-        mut,
-        constraint = admin.key() == pool.admin
-    )]
-    pub pool: Account<'info, Pool>,
-    
-    #[account(
-        mut,
-        seeds = [b"pool_vault"],
-        bump
-    )]
-    pub pool_vault: SystemAccount<'info>,
-}
+        // Settling in two steps renews the same way
+        let mut stepped = settled_position(&pool, &user_stake, 0, 45 * DAY).unwrap();
+        stepped = settled_position(&pool, &stepped, 0, 91 * DAY).unwrap();
+        assert_eq!((stepped.stake_timestamp, stepped.rewards_owed), (renewed.stake_timestamp, renewed.rewards_owed));
 
-// Account structures
-#[account]
-#[derive(InitSpace)]
-pub struct Pool {
-    pub admin: Pubkey,
-    pub max_apy: u64,
-    pub min_commitment_days: u64,
-    pub max_commitment_days: u64,
-    pub min_stake_amount: u64,
-    pub max_stake_amount: u64,
-    pub total_staked: u64,
-    pub total_users: u64,
-    pub total_fees_collected: u64,
-    pub deposit_fee_bps: u64,
-    pub is_paused: bool,
-    pub created_at: i64,
-    pub last_update: i64,
-}
+        // Without renewal on the pool, or once the pool is sunset, nothing rolls
+        pool.renewal_grace_seconds = 0;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 91 * DAY).unwrap().stake_timestamp, 0);
+        pool.renewal_grace_seconds = DAY;
+        pool.is_active = false;
+        pool.sunset_at = 91 * DAY;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 91 * DAY).unwrap().stake_timestamp, 0);
+    }
 
-#[account]
-#[derive(InitSpace)]
-pub struct UserStake {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub committed_days: u64,
-    pub stake_timestamp: i64,
-    pub last_claim_timestamp: i64,
-    pub total_claimed: u64,
-}
+    #[test]
+    fn opted_out_positions_earn_the_idle_rate_after_their_commitment() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.renewal_grace_seconds = DAY;
+        pool.idle_apy_bps = 500;
+        let mut user_stake = position(365 * AMOUNT, 1000);
 
-// Error codes
-#[error_code]
-pub enum ErrorCode {
-    #[msg("Invalid APY")]
-    InvalidApy,
-    #[msg("Invalid commitment days")]
-    InvalidCommitmentDays,
-    #[msg("Invalid amount")]
-    InvalidAmount,
-    #[msg("Invalid fee")]
-    InvalidFee,
-    #[msg("Invalid reason")]
-    InvalidReason,
-    #[msg("Amount too small")]
-    AmountTooSmall,
-    #[msg("Amount too large")]
-    AmountTooLarge,
-    #[msg("Pool is paused")]
-    PoolPaused,
-    #[msg("No stake found")]
-    NoStake,
-    #[msg("No yield to claim")]
-    NoYieldToClaim,
-    #[msg("Insufficient funds")]
-    InsufficientFunds,
-    #[msg("Commitment not met")]
-    CommitmentNotMet,
-    #[msg("Unauthorized")]
-    Unauthorized,
-}
+        // Opting out mid-commitment keeps the full rate until the commitment ends
+        update_rewards(&pool, &mut user_stake, 0, 10 * DAY).unwrap();
+        user_stake.auto_renew_opted_out = true;
+        let settled = settled_position(&pool, &user_stake, 0, 40 * DAY).unwrap();
+        assert_eq!(settled.rewards_owed, 30 * AMOUNT / 10 + 10 * AMOUNT / 20);
+        assert_eq!(settled.stake_timestamp, 0);
+
+        // An idle rate above the locked one never raises it
+        pool.idle_apy_bps = 2000;
+        assert_eq!(settled_position(&pool, &user_stake, 0, 40 * DAY).unwrap().rewards_owed, 4 * AMOUNT);
+    }
+
+    #[test]
+    fn migrating_to_the_raised_minimum_credits_the_bonus_once() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.min_commitment_days = 90;
+        pool.max_commitment_days = 365;
+        pool.policy_version = 1;
+        pool.migration_bonus_bps = 50;
+        let mut user_stake = position(365 * AMOUNT, 1000);
+
+        // Ten days settle at the old rate and the 0.5% bonus is owed on top
+        let bonus = adopt_commitment_policy(&pool, &mut user_stake, 10 * DAY).unwrap();
+        assert_eq!(bonus, 365 * AMOUNT / 200);
+        assert_eq!(user_stake.rewards_owed, AMOUNT + bonus);
+        assert_eq!(user_stake.committed_days, 90);
+        assert_eq!(user_stake.stake_timestamp, 0);
+        assert_eq!(user_stake.policy_version, 1);
+
+        // Once migrated, or when opened under the current policy, there is nothing to adopt
+        assert!(adopt_commitment_policy(&pool, &mut user_stake, 11 * DAY).is_err());
+        let mut fresh = position(AMOUNT, 1000);
+        fresh.committed_days = 90;
+        stamp_policy_version(&pool, &mut fresh);
+        assert!(adopt_commitment_policy(&pool, &mut fresh, DAY).is_err());
+    }
+
+    #[test]
+    fn crediting_yield_leaves_the_expected_vault_balance_unchanged() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.total_staked = 365 * AMOUNT;
+        pool.total_penalties_collected = 3 * AMOUNT;
+        pool.deployed_to_marinade = 100 * AMOUNT;
+        pool.total_fees_collected = 2 * AMOUNT;
+        pool.reward_reserve = 10 * AMOUNT;
+        let expected = 365 * AMOUNT + 3 * AMOUNT - 100 * AMOUNT + 2 * AMOUNT + 10 * AMOUNT;
+        assert_eq!(expected_vault_lamports(&pool), expected);
+
+        // Credited yield moves out of the reserve but stays in the reward vault
+        let mut user_stake = position(365 * AMOUNT, 1000);
+        assert!(credit_accrued_yield(&mut pool, &mut user_stake, DAY).unwrap() > 0);
+        assert_eq!(expected_vault_lamports(&pool), expected);
+    }
+
+    #[test]
+    fn principal_in_unclaimed_tickets_is_still_out_of_the_vault() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.total_staked = 10 * AMOUNT;
+        pool.marinade_ticket_principal = 4 * AMOUNT;
+        assert_eq!(expected_vault_lamports(&pool), 6 * AMOUNT);
+        assert_eq!(
+            failure_code(check_principal_liquidity(&pool, AMOUNT, 2 * AMOUNT).unwrap_err()),
+            failure_code(ErrorCode::LiquidityBeingRecalled.into())
+        );
+        assert!(check_principal_liquidity(&pool, 2 * AMOUNT, 2 * AMOUNT).is_ok());
+    }
+
+    #[test]
+    fn marinade_accounts_are_read_only_when_marinade_owns_them() {
+        let key = Pubkey::new_unique();
+        let state = Pubkey::new_unique();
+        let staging = Pubkey::new_unique();
+        let mut lamports = 0;
+
+        let mut data = vec![0u8; MARINADE_MSOL_PRICE_OFFSET + 8];
+        data[..8].copy_from_slice(&MARINADE_STATE_DISCRIMINATOR);
+        data[MARINADE_MSOL_PRICE_OFFSET..].copy_from_slice(&(5u64 << 30).to_le_bytes());
+        let account = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &MARINADE_PROGRAM_ID, false, 0);
+        assert_eq!(marinade_msol_price(&account).unwrap(), 5 << 30);
+        let mut data = account.data.borrow().to_vec();
+        let impostor = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &crate::ID, false, 0);
+        assert!(marinade_msol_price(&impostor).is_err());
 
+        let mut data = vec![0u8; MARINADE_TICKET_SPACE];
+        data[..8].copy_from_slice(&MARINADE_TICKET_DISCRIMINATOR);
+        data[8..40].copy_from_slice(state.as_ref());
+        data[40..72].copy_from_slice(staging.as_ref());
+        data[72..80].copy_from_slice(&(3 * AMOUNT).to_le_bytes());
+        let ticket = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &MARINADE_PROGRAM_ID, false, 0);
+        assert_eq!(read_marinade_ticket(&ticket, &state).unwrap(), (staging, 3 * AMOUNT));
+        assert!(read_marinade_ticket(&ticket, &Pubkey::new_unique()).is_err());
+    }
+
+    fn fee_tiers() -> Vec<FeeTier> {
+        vec![
+            FeeTier { min_amount: 10 * AMOUNT, fee_bps: 40 },
+            FeeTier { min_amount: 100 * AMOUNT, fee_bps: 30 },
+            FeeTier { min_amount: 500 * AMOUNT, fee_bps: 20 },
+        ]
+    }
+
+    #[test]
+    fn fee_tier_applies_exactly_at_its_threshold() {
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, AMOUNT), 50);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 10 * AMOUNT - 1), 50);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 10 * AMOUNT), 40);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 100 * AMOUNT - 1), 40);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 100 * AMOUNT), 30);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 500 * AMOUNT - 1), 30);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, 500 * AMOUNT), 20);
+        assert_eq!(resolve_fee_bps(&fee_tiers(), 50, u64::MAX), 20);
+        assert_eq!(resolve_fee_bps(&[], 50, u64::MAX), 50);
+    }
+
+    #[test]
+    fn long_commit_discount_applies_to_the_size_tier_rate() {
+        let mut pool = pool_with(2000, Vec::new());
+        pool.deposit_fee_bps = 50;
+        pool.fee_tiers = fee_tiers();
+        pool.long_commit_threshold_days = 180;
+        pool.long_commit_fee_discount_bps = 5000;
+
+        assert_eq!(deposit_fee_bps(&pool, AMOUNT, 179), 50);
+        assert_eq!(deposit_fee_bps(&pool, AMOUNT, 180), 25);
+        assert_eq!(deposit_fee_bps(&pool, 10 * AMOUNT, 179), 40);
+        assert_eq!(deposit_fee_bps(&pool, 10 * AMOUNT, 180), 20);
+    }
+
+    #[test]
+    fn exact_net_deposits_credit_exactly_the_requested_net() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.deposit_fee_bps = 333;
+        pool.fee_tiers = fee_tiers();
+        pool.long_commit_threshold_days = 180;
+        pool.long_commit_fee_discount_bps = 5000;
+
+        // Each net is sized at the rate of the tier its deposit lands in, discounted or not
+        for committed_days in [30, 180] {
+            for net in [1, 333, AMOUNT, 10 * AMOUNT, 100 * AMOUNT - 7, 500 * AMOUNT] {
+                let gross = exact_net_deposit(&pool, net, committed_days).unwrap();
+                let fee = compute_fee(gross, deposit_fee_bps(&pool, gross, committed_days)).unwrap();
+                assert_eq!(gross - fee, net);
+            }
+        }
+
+        // Below 10 SOL the fee is 3.33%, from it 0.4%, so no deposit nets 9.8 SOL
+        assert!(exact_net_deposit(&pool, 98 * AMOUNT / 10, 30).is_err());
+    }
+
+    #[test]
+    fn fee_tiers_are_capped_and_strictly_increasing() {
+        assert!(validate_fee_tiers(&fee_tiers()).is_ok());
+
+        let mut unordered = fee_tiers();
+        unordered.swap(1, 2);
+        assert!(validate_fee_tiers(&unordered).is_err());
+
+        let mut duplicate = fee_tiers();
+        duplicate[1].min_amount = duplicate[0].min_amount;
+        assert!(validate_fee_tiers(&duplicate).is_err());
+
+        let mut too_expensive = fee_tiers();
+        too_expensive[0].fee_bps = 1001;
+        assert!(validate_fee_tiers(&too_expensive).is_err());
+
+        let too_many: Vec<FeeTier> = (1..=MAX_FEE_TIERS as u64 + 1)
+            .map(|min_amount| FeeTier { min_amount, fee_bps: 10 })
+            .collect();
+        assert!(validate_fee_tiers(&too_many).is_err());
+    }
+
+    #[test]
+    fn parameter_updates_log_the_parameter_as_one_byte() {
+        assert_eq!(PoolParameter::MaxApy.try_to_vec().unwrap(), vec![0]);
+        assert_eq!(PoolParameter::GovernanceQuorumBps.try_to_vec().unwrap(), vec![26]);
+    }
+    #[test]
+    fn pause_reasons_are_limited_in_bytes() {
+        assert!(check_reason("").is_ok());
+        assert!(check_reason(&"a".repeat(MAX_REASON_LEN)).is_ok());
+        assert_eq!(check_reason(&"a".repeat(500)).unwrap_err(), ErrorCode::StringTooLong.into());
+
+        // 22 three-byte characters are 66 bytes, over the limit despite being 22 characters
+        assert_eq!(check_reason(&"€".repeat(22)).unwrap_err(), ErrorCode::StringTooLong.into());
+    }
+
+    #[test]
+    fn the_17th_incident_overwrites_the_oldest() {
+        let mut incident_log: IncidentLog = zeroed();
+        assert_eq!(incident_log.latest(), None);
+
+        // Each pause is lifted before the next, except the last
+        for i in 1..=INCIDENT_LOG_LEN as i64 + 1 {
+            incident_log.open(PAUSE_ALL, [i as u8; 32], i * 100);
+            if i <= INCIDENT_LOG_LEN as i64 {
+                incident_log.close_latest(i * 100 + 50);
+            }
+        }
+
+        assert_eq!(incident_log.total_incidents, 17);
+        assert_eq!(incident_log.incidents[0].reason_hash, [17; 32]);
+        let recent = incident_log.recent();
+        assert_eq!(recent.len(), INCIDENT_LOG_LEN);
+        assert_eq!(recent[0], Incident { opened_at: 200, closed_at: 250, flags: PAUSE_ALL, reason_hash: [2; 32] });
+        assert_eq!(incident_log.latest(), Some(Incident { opened_at: 1700, closed_at: 0, flags: PAUSE_ALL, reason_hash: [17; 32] }));
+    }
+
+    #[test]
+    fn a_new_pause_closes_the_incident_it_supersedes() {
+        let mut incident_log: IncidentLog = zeroed();
+        incident_log.close_latest(5); // Nothing logged yet
+        incident_log.open(PAUSE_ALL, [1; 32], 10);
+        incident_log.open(PAUSE_DEPOSITS, [2; 32], 20);
+
+        let recent = incident_log.recent();
+        assert_eq!((recent[0].closed_at, recent[1].closed_at), (20, 0));
+
+        // Closing twice keeps the first close time
+        incident_log.close_latest(30);
+        incident_log.close_latest(40);
+        assert_eq!(incident_log.latest().unwrap().closed_at, 30);
+    }
+
+    fn empty_history() -> UserHistory {
+        UserHistory {
+            user: Pubkey::new_unique(),
+            bump: 255,
+            total_entries: 0,
+            entries: [HistoryEntry::default(); USER_HISTORY_LEN],
+        }
+    }
+
+    #[test]
+    fn history_keeps_entries_in_order_until_full() {
+        let mut history = empty_history();
+        history.record(HISTORY_OP_STAKE, 1_000, 10);
+        history.record(HISTORY_OP_CLAIM, 7, 20);
+
+        assert_eq!(
+            history.recent(),
+            vec![
+                HistoryEntry { op_kind: HISTORY_OP_STAKE, amount: 1_000, timestamp: 10 },
+                HistoryEntry { op_kind: HISTORY_OP_CLAIM, amount: 7, timestamp: 20 },
+            ]
+        );
+    }
+
+    #[test]
+    fn the_33rd_entry_overwrites_the_oldest() {
+        let mut history = empty_history();
+        for i in 1..=USER_HISTORY_LEN as u64 {
+            history.record(HISTORY_OP_STAKE, i, i as i64);
+        }
+        assert_eq!(history.recent().len(), USER_HISTORY_LEN);
+        assert_eq!(history.recent()[0].amount, 1);
+
+        history.record(HISTORY_OP_UNSTAKE, 33, 33);
+        assert_eq!(history.total_entries, 33);
+        assert_eq!(history.entries[0], HistoryEntry { op_kind: HISTORY_OP_UNSTAKE, amount: 33, timestamp: 33 });
+        let recent = history.recent();
+        assert_eq!(recent.len(), USER_HISTORY_LEN);
+        assert_eq!(recent[0].amount, 2);
+        assert_eq!(recent[USER_HISTORY_LEN - 1].amount, 33);
+    }
+
+    #[test]
+    fn clearing_history_starts_over() {
+        let mut history = empty_history();
+        for i in 0..40 {
+            history.record(HISTORY_OP_COMPOUND, i, i as i64);
+        }
+        history.clear();
+        assert!(history.recent().is_empty());
+
+        history.record(HISTORY_OP_CLAIM, 5, 50);
+        assert_eq!(history.entries[0].amount, 5);
+        assert_eq!(history.recent().len(), 1);
+    }
+
+    fn clock_at(slot: u64, unix_timestamp: i64) -> Clock {
+        Clock { slot, unix_timestamp, ..Clock::default() }
+    }
+
+    fn failure_code(error: Error) -> u32 {
+        u64::from(ProgramError::from(error)) as u32
+    }
+
+    #[test]
+    fn a_refused_preview_reports_the_error_and_keeps_nothing() {
+        let mut pool = pool_with(1000, Vec::new());
+        pool.min_commitment_days = 30;
+        pool.max_commitment_days = 365;
+        pool.min_stake_amount = AMOUNT;
+        pool.max_stake_amount = 100 * AMOUNT;
+        pool.large_operation_threshold = AMOUNT;
+        pool.min_block_delay = 5;
+        let owner = UserStake { user: Pubkey::new_unique(), ..UserStake::default() };
+
+        let preview = stake_preview(&pool, &owner, None, None, AMOUNT / 2, 30, &clock_at(10, DAY));
+        let expected_code = failure_code(ErrorCode::AmountTooSmall.into());
+        assert_eq!(preview, OperationPreview { failure_code: expected_code, ..OperationPreview::default() });
+
+        // A stake big enough to start the block delay leaves the pool's slot volume alone
+        let preview = stake_preview(&pool, &owner, None, None, 2 * AMOUNT, 30, &clock_at(10, DAY));
+        assert_eq!(preview.failure_code, failure_code(ErrorCode::LeverageCapExceeded.into()));
+        pool.reward_reserve = 100 * AMOUNT;
+        pool.leverage_ratio = 6000;
+        let preview = stake_preview(&pool, &owner, None, None, 2 * AMOUNT, 30, &clock_at(10, DAY));
+        assert!(preview.would_succeed);
+        assert_eq!((pool.current_slot_volume, pool.slot_delay_until), (0, 0));
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(50))]
+
+        // The previews run the instructions' own validation and accounting on copies of the
+        // accounts, so whatever the pool settings, executing the stake and then an instant
+        // unstake must match what was previewed, failures and their codes included
+        #[test]
+        fn previews_match_execution(
+            (deposit_fee_bps, early_exit_penalty_bps, instant_exit_penalty_bps) in (0u64..=1000, 0u64..=2000, 0u64..=500),
+            (min_stake, max_stake) in (1u64..=AMOUNT, AMOUNT..=1000 * AMOUNT),
+            (reward_reserve, leverage_ratio) in (0u64..=100 * AMOUNT, 2000u64..=6000),
+            solvency_factor_bps in proptest::prop_oneof![proptest::strategy::Just(0u64), 1000u64..=20000],
+            large_operation_threshold in AMOUNT..=500 * AMOUNT,
+            (max_daily_outflow_bps, others_staked) in (0u64..=10000, 0u64..=1000 * AMOUNT),
+            existing in proptest::prop_oneof![proptest::strategy::Just(0u64), AMOUNT..=50 * AMOUNT],
+            amount in 0u64..=200 * AMOUNT,
+            committed_days in 0u64..=400,
+            (days_held, slots_later) in (0i64..=400, 0u64..=8),
+            vault_available in 0u64..=300 * AMOUNT,
+        ) {
+            let mut pool = pool_with(1000, Vec::new());
+            pool.apy_tiers = vec![ApyTier { min_days: 90, apy_bps: 1500 }];
+            pool.min_commitment_days = 30;
+            pool.max_commitment_days = 365;
+            pool.deposit_fee_bps = deposit_fee_bps;
+            pool.min_stake_amount = min_stake;
+            pool.max_stake_amount = max_stake;
+            pool.reward_reserve = reward_reserve;
+            pool.leverage_ratio = leverage_ratio;
+            pool.solvency_factor_bps = solvency_factor_bps;
+            pool.large_operation_threshold = large_operation_threshold;
+            pool.min_block_delay = 2;
+            pool.max_daily_outflow_bps = max_daily_outflow_bps;
+            pool.early_exit_penalty_bps = early_exit_penalty_bps;
+            pool.instant_exit_penalty_bps = instant_exit_penalty_bps;
+            pool.instant_exit_enabled = true;
+            pool.min_slots_before_exit = 1;
+            pool.total_staked = existing + others_staked;
+            let mut user_stake = if existing > 0 {
+                position(existing, 1000)
+            } else {
+                UserStake { user: Pubkey::new_unique(), ..UserStake::default() }
+            };
+
+            let stake_clock = clock_at(100, DAY);
+            let preview = stake_preview(&pool, &user_stake, None, None, amount, committed_days, &stake_clock);
+
+            let (pool_before, stake_before) = (pool.clone(), user_stake.clone());
+            let executed = quote_stake(&mut pool, None, amount, committed_days, &stake_clock).and_then(|(_, fee)| {
+                // The wallet signs the previewed net as its quote
+                check_quote(amount, fee, amount - fee, pool.max_slippage_bps)?;
+                let owner = user_stake.user;
+                record_stake(&mut pool, &mut user_stake, None, owner, amount, fee, committed_days, &stake_clock)?;
+                Ok(fee)
+            });
+            match executed {
+                Ok(fee) => {
+                    proptest::prop_assert!(preview.would_succeed);
+                    proptest::prop_assert_eq!(preview.fee, fee);
+                    proptest::prop_assert_eq!(preview.net, pool.total_staked - pool_before.total_staked);
+                    proptest::prop_assert_eq!(
+                        preview.yields,
+                        pool.total_projected_liability - pool_before.total_projected_liability
+                    );
+                }
+                Err(error) => {
+                    let failure_code = failure_code(error);
+                    proptest::prop_assert_eq!(preview, OperationPreview { failure_code, ..OperationPreview::default() });
+                    // A failed transaction keeps nothing
+                    pool = pool_before;
+                    user_stake = stake_before;
+                }
+            }
+
+            if user_stake.amount > 0 {
+                let unstake_clock = clock_at(100 + slots_later, DAY + days_held * DAY);
+                let preview = unstake_preview(&pool, &user_stake, vault_available, &unstake_clock);
+
+                let principal = user_stake.amount;
+                let executed = check_instant_exit(&mut pool, &user_stake, principal, &unstake_clock).and_then(|_| {
+                    let penalty_bps = pool.instant_exit_penalty_bps;
+                    let now = unstake_clock.unix_timestamp;
+                    let (penalty, paid, _) =
+                        settle_withdrawal(&mut pool, &mut user_stake, principal, penalty_bps, NO_MIN_PAYOUT, now)?;
+                    check_principal_liquidity(&pool, vault_available, paid)?;
+                    Ok((penalty, paid))
+                });
+                match executed {
+                    Ok((penalty, paid)) => {
+                        proptest::prop_assert!(preview.would_succeed);
+                        proptest::prop_assert_eq!(preview.net, principal);
+                        proptest::prop_assert_eq!(user_stake.amount, 0);
+                        proptest::prop_assert_eq!((preview.penalty, preview.return_amount), (penalty, paid));
+                        proptest::prop_assert_eq!(preview.yields, user_stake.rewards_owed + user_stake.pending_yields);
+                    }
+                    Err(error) => {
+                        let failure_code = failure_code(error);
+                        proptest::prop_assert_eq!(preview, OperationPreview { failure_code, ..OperationPreview::default() });
+                    }
+                }
+            }
+        }
+    }
+}
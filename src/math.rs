@@ -0,0 +1,690 @@
+use anchor_lang::prelude::*;
+
+use crate::ErrorCode;
+
+// Pure yield, fee and penalty math shared by the instructions. Nothing here reads
+// accounts or sysvars, so every function can be exercised without a validator.
+// Products of amounts, rates and durations are taken in u128 and narrowed back to u64
+// once, at the end, so only a result that really does not fit is an error.
+
+const BPS_DENOMINATOR: u128 = 10000;
+const SECONDS_PER_DAY: i64 = 86400;
+const LAMPORTS_PER_SOL: u128 = 1_000_000_000;
+// Denominator of a second's yield: a 365-day year of seconds in basis points
+const SECOND_BPS_PER_YEAR: u128 = 365 * SECONDS_PER_DAY as u128 * BPS_DENOMINATOR;
+// Fixed-point scale of the reward-per-token accumulator
+pub const REWARD_PER_TOKEN_PRECISION: u128 = 1_000_000_000_000;
+// Marinade quotes msol_price as lamports per mSOL scaled by 2^32
+const MARINADE_PRICE_DENOMINATOR: u128 = 1 << 32;
+// How far a Marinade deposit or liquid unstake may land below its value at msol_price:
+// the highest fee Marinade's liquidity pool charges
+pub const MARINADE_RATE_TOLERANCE_BPS: u64 = 300;
+
+// The one checked narrowing every amount goes through on its way out of u128
+fn to_u64(value: u128) -> Result<u64> {
+    u64::try_from(value).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+// Simple interest accrued by the second, rounded down to the lamport
+pub fn compute_yields(amount: u64, apy_bps: u64, elapsed_seconds: i64) -> Result<u64> {
+    let yield_amount = (amount as u128)
+        .checked_mul(apy_bps as u128).ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(elapsed_seconds.max(0) as u128).ok_or(ErrorCode::ArithmeticOverflow)?
+        / SECOND_BPS_PER_YEAR;
+
+    to_u64(yield_amount)
+}
+
+// Basis-point share of an amount, rounded down; used for deposit fees and exit surcharges
+pub fn compute_fee(amount: u64, fee_bps: u64) -> Result<u64> {
+    let fee = (amount as u128)
+        .checked_mul(fee_bps as u128).ok_or(ErrorCode::ArithmeticOverflow)?
+        / BPS_DENOMINATOR;
+
+    to_u64(fee)
+}
+
+// Smallest deposit that leaves exactly net_amount once compute_fee takes fee_bps of it.
+// The fee rounds down, so the net grows by at most a lamport per lamport deposited and
+// every net is reachable: gross - floor(gross * fee_bps / 10000) >= net holds from
+// gross = (net - 1) * 10000 / (10000 - fee_bps) + 1, where it is an equality.
+pub fn gross_for_net(net_amount: u64, fee_bps: u64) -> Result<u64> {
+    require!((fee_bps as u128) < BPS_DENOMINATOR, ErrorCode::InvalidFee);
+    if net_amount == 0 {
+        return Ok(0);
+    }
+    let gross = (net_amount as u128 - 1) * BPS_DENOMINATOR / (BPS_DENOMINATOR - fee_bps as u128) + 1;
+
+    to_u64(gross)
+}
+
+// Deposit fee rate after the long-commitment discount: discount_bps of the rate comes
+// off for commitments of at least threshold_days, and a zero threshold turns it off
+pub fn apply_commitment_discount(fee_bps: u64, committed_days: u64, threshold_days: u64, discount_bps: u64) -> u64 {
+    if threshold_days == 0 || committed_days < threshold_days {
+        return fee_bps;
+    }
+
+    let discount = fee_bps as u128 * discount_bps.min(10000) as u128 / BPS_DENOMINATOR;
+    fee_bps - discount as u64
+}
+
+// Early-exit penalty on a withdrawn amount; zero once grace_bps of the commitment has
+// been served, so 10000 waives it only when the commitment is met. With the sliding
+// scale the rate falls linearly from penalty_bps at stake time to zero at the end of
+// the commitment instead of staying flat.
+pub fn compute_penalty(
+    amount: u64,
+    penalty_bps: u64,
+    elapsed_seconds: i64,
+    committed_days: u64,
+    sliding_scale: bool,
+    grace_bps: u64,
+) -> Result<u64> {
+    let commitment_seconds = (committed_days as u128)
+        .checked_mul(SECONDS_PER_DAY as u128).ok_or(ErrorCode::ArithmeticOverflow)?;
+    let elapsed_seconds = elapsed_seconds.max(0) as u128;
+    if elapsed_seconds * BPS_DENOMINATOR >= commitment_seconds * grace_bps.min(10000) as u128 {
+        return Ok(0);
+    }
+
+    if !sliding_scale {
+        return compute_fee(amount, penalty_bps);
+    }
+
+    let remaining_seconds = commitment_seconds - elapsed_seconds;
+    let penalty = (amount as u128)
+        .checked_mul(penalty_bps as u128).ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(remaining_seconds).ok_or(ErrorCode::ArithmeticOverflow)?
+        / commitment_seconds.checked_mul(BPS_DENOMINATOR).ok_or(ErrorCode::ArithmeticOverflow)?;
+
+    to_u64(penalty)
+}
+
+// APY after loyalty or NFT boosts: the boost may lift the rate up to max_apy_bps but never
+// past it, and never lowers a locked rate that already sits above it
+pub fn apply_apy_boost(locked_apy_bps: u64, boost_bps: u64, max_apy_bps: u64) -> u64 {
+    let boosted_bps = locked_apy_bps.saturating_add(boost_bps).min(max_apy_bps);
+    locked_apy_bps.max(boosted_bps)
+}
+
+// Whether a year of yield at apy_bps on total_staked stays within leverage_ratio
+// (scaled by 1000) times the reward reserve
+pub fn within_leverage_cap(total_staked: u64, apy_bps: u64, reward_reserve: u64, leverage_ratio: u64) -> bool {
+    let annual_yield = total_staked as u128 * apy_bps as u128 / BPS_DENOMINATOR;
+    annual_yield * 1000 <= reward_reserve as u128 * leverage_ratio as u128
+}
+
+// Utilization levels at which a pool reports its yield capacity
+pub const SOLVENCY_ALERT_BPS: [u64; 3] = [8000, 9000, 10000];
+
+// Whether yield projected over every position's commitment fits within
+// solvency_factor_bps of the reward reserve
+pub fn within_yield_capacity(projected_liability: u64, reward_reserve: u64, solvency_factor_bps: u64) -> bool {
+    projected_liability as u128 * BPS_DENOMINATOR <= reward_reserve as u128 * solvency_factor_bps as u128
+}
+
+// Projected liability as a share of the yield capacity, in bps; unbounded once anything
+// is projected against no capacity
+pub fn yield_capacity_utilization_bps(projected_liability: u64, reward_reserve: u64, solvency_factor_bps: u64) -> u64 {
+    let capacity = reward_reserve as u128 * solvency_factor_bps as u128;
+    if capacity == 0 {
+        return if projected_liability == 0 { 0 } else { u64::MAX };
+    }
+
+    (projected_liability as u128 * BPS_DENOMINATOR * BPS_DENOMINATOR / capacity).min(u64::MAX as u128) as u64
+}
+
+// Whether utilization moved across any alert level, in either direction
+pub fn crossed_solvency_alert(before_bps: u64, after_bps: u64) -> bool {
+    SOLVENCY_ALERT_BPS.iter().any(|&level| (before_bps >= level) != (after_bps >= level))
+}
+
+// Whole days the reward reserve can pay a year's yield rate on total_staked; unbounded
+// when nothing is owed
+pub fn reserve_runway_days(reward_reserve: u64, total_staked: u64, apy_bps: u64) -> u64 {
+    let annual_yield = total_staked as u128 * apy_bps as u128 / BPS_DENOMINATOR;
+    if annual_yield == 0 {
+        return u64::MAX;
+    }
+
+    (reward_reserve as u128 * 365 / annual_yield).min(u64::MAX as u128) as u64
+}
+
+// reserve / (staked * apy_bps / 10000) in bps; fully solvent when nothing is owed
+pub fn calculate_solvency_ratio_bps(reward_reserve: u64, total_staked: u64, apy_bps: u64) -> u64 {
+    let annual_yield = total_staked as u128 * apy_bps as u128 / BPS_DENOMINATOR;
+    if annual_yield == 0 {
+        return u64::MAX;
+    }
+
+    (reward_reserve as u128 * BPS_DENOMINATOR / annual_yield).min(u64::MAX as u128) as u64
+}
+
+// Whether amount may leave under a daily cap of max_daily_outflow_bps. The cap is a share
+// of the TVL at the start of the window, which is what is staked now plus what has
+// already left during the window.
+pub fn outflow_within_cap(total_staked: u64, outflow_in_window: u64, amount: u64, max_daily_outflow_bps: u64) -> bool {
+    amount <= outflow_headroom(total_staked, outflow_in_window, max_daily_outflow_bps)
+}
+
+// What may still leave in the current window before the cap is reached
+pub fn outflow_headroom(total_staked: u64, outflow_in_window: u64, max_daily_outflow_bps: u64) -> u64 {
+    let window_tvl = total_staked as u128 + outflow_in_window as u128;
+    let cap = window_tvl.saturating_mul(max_daily_outflow_bps as u128) / BPS_DENOMINATOR;
+    cap.saturating_sub(outflow_in_window as u128).min(u64::MAX as u128) as u64
+}
+
+// APY the reserve can keep paying for a full year: the full rate with a year or more of
+// runway, scaled down linearly below that
+pub fn projected_apy_bps(apy_bps: u64, runway_days: u64) -> u64 {
+    (apy_bps as u128 * runway_days.min(365) as u128 / 365) as u64
+}
+
+// Share of the principal deployed to Marinade that burning msol_amount of the pool's
+// msol_balance recalls, rounded down; recalling the whole balance releases all of it
+pub fn recalled_principal(deployed: u64, msol_amount: u64, msol_balance: u64) -> u64 {
+    if msol_balance == 0 {
+        return 0;
+    }
+
+    (deployed as u128 * msol_amount.min(msol_balance) as u128 / msol_balance as u128) as u64
+}
+
+// Lamports an mSOL amount is worth at Marinade's msol_price, rounded down
+pub fn msol_value(msol_amount: u64, msol_price: u64) -> Result<u64> {
+    to_u64(msol_amount as u128 * msol_price as u128 / MARINADE_PRICE_DENOMINATOR)
+}
+
+// mSOL a lamport amount buys at Marinade's msol_price, rounded down
+pub fn msol_for_lamports(lamports: u64, msol_price: u64) -> Result<u64> {
+    require!(msol_price > 0, ErrorCode::InvalidMarinadeAccount);
+    to_u64(lamports as u128 * MARINADE_PRICE_DENOMINATOR / msol_price as u128)
+}
+
+// Least a Marinade deposit or liquid unstake worth `value` at msol_price may return
+pub fn marinade_rate_floor(value: u64) -> u64 {
+    value - (value as u128 * MARINADE_RATE_TOLERANCE_BPS as u128 / BPS_DENOMINATOR) as u64
+}
+
+// How SOL back from Marinade divides against the principal it stood for: the principal
+// returned to the vault, staking yield above it, and any shortfall below it
+pub fn split_marinade_return(sol_received: u64, principal: u64) -> (u64, u64, u64) {
+    let principal_returned = sol_received.min(principal);
+    (principal_returned, sol_received - principal_returned, principal - principal_returned)
+}
+
+// Lamports the vaults hold over (positive) or short of (negative) what the books say,
+// clamped to the i64 range
+pub fn reconciliation_delta(expected: u64, actual: u64) -> i64 {
+    (actual as i128 - expected as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+// The books balance unless the vaults are short by more than the tolerance. A surplus
+// never counts against them: anyone can send lamports to a vault.
+pub fn books_balance(delta: i64, max_shortfall: u64) -> bool {
+    delta >= 0 || delta.unsigned_abs() <= max_shortfall
+}
+
+// Growth of the reward-per-token accumulator when amount is shared pro rata across
+// total_staked, rounded down so the shares never add up to more than amount
+pub fn reward_per_token_increment(amount: u64, total_staked: u64) -> Result<u128> {
+    require!(total_staked > 0, ErrorCode::NoStake);
+    Ok(amount as u128 * REWARD_PER_TOKEN_PRECISION / total_staked as u128)
+}
+
+// What a balance earned while the accumulator moved from reward_per_token_paid to
+// reward_per_token, rounded down
+pub fn rewards_earned(balance: u64, reward_per_token: u128, reward_per_token_paid: u128) -> Result<u64> {
+    let earned = (balance as u128)
+        .checked_mul(reward_per_token.saturating_sub(reward_per_token_paid)).ok_or(ErrorCode::ArithmeticOverflow)?
+        / REWARD_PER_TOKEN_PRECISION;
+
+    to_u64(earned)
+}
+
+// Whether the fee implied by a quoted net amount is within max_slippage_bps of the
+// deposit from the fee actually charged, in either direction
+pub fn fee_within_quote_tolerance(
+    amount: u64,
+    fee_amount: u64,
+    quoted_net_amount: u64,
+    max_slippage_bps: u64,
+) -> Result<bool> {
+    let quoted_fee = amount.saturating_sub(quoted_net_amount);
+    let tolerance = compute_fee(amount, max_slippage_bps)?;
+
+    Ok(quoted_fee.abs_diff(fee_amount) <= tolerance)
+}
+
+// Fresh commitments a rolling position has entered by `now`. Each starts where the last
+// ended and is only entered once grace_seconds pass after that end without an exit.
+pub fn renewal_cycles(stake_timestamp: i64, commitment_seconds: i64, grace_seconds: i64, now: i64) -> u64 {
+    if commitment_seconds <= 0 {
+        return 0;
+    }
+    let elapsed = now.saturating_sub(stake_timestamp).saturating_sub(grace_seconds);
+    if elapsed < commitment_seconds {
+        return 0;
+    }
+
+    (elapsed / commitment_seconds) as u64
+}
+
+// Reward token base units paid for a lamport-denominated yield at tokens_per_sol base
+// units per SOL, rounded down so a claim never pays more than its yield is worth
+pub fn lamports_to_reward_tokens(lamports: u64, tokens_per_sol: u64) -> Result<u64> {
+    let tokens = (lamports as u128)
+        .checked_mul(tokens_per_sol as u128).ok_or(ErrorCode::ArithmeticOverflow)?
+        / LAMPORTS_PER_SOL;
+
+    to_u64(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DEFAULT_MAX_STAKE_AMOUNT, MAX_APY_BPS, MAX_COMMITMENT_DAYS};
+    use proptest::prelude::*;
+
+    const AMOUNT: u64 = 1_000_000_000;
+    const DAY: i64 = 86400;
+    const FULL_COMMITMENT: u64 = 10000;
+
+    #[test]
+    fn sliding_penalty_is_full_at_start_of_commitment() {
+        assert_eq!(compute_penalty(AMOUNT, 500, 0, 100, true, FULL_COMMITMENT).unwrap(), 50_000_000);
+    }
+
+    #[test]
+    fn sliding_penalty_is_halved_at_half_commitment() {
+        assert_eq!(compute_penalty(AMOUNT, 500, 50 * DAY, 100, true, FULL_COMMITMENT).unwrap(), 25_000_000);
+    }
+
+    #[test]
+    fn sliding_penalty_is_small_just_before_commitment_end() {
+        assert_eq!(compute_penalty(AMOUNT, 500, 99 * DAY, 100, true, FULL_COMMITMENT).unwrap(), 500_000);
+    }
+
+    #[test]
+    fn penalty_is_zero_once_commitment_is_met() {
+        assert_eq!(compute_penalty(AMOUNT, 500, 100 * DAY, 100, true, FULL_COMMITMENT).unwrap(), 0);
+        assert_eq!(compute_penalty(AMOUNT, 500, 100 * DAY, 100, false, FULL_COMMITMENT).unwrap(), 0);
+    }
+
+    #[test]
+    fn flat_penalty_ignores_elapsed_time() {
+        assert_eq!(compute_penalty(AMOUNT, 500, 0, 100, false, FULL_COMMITMENT).unwrap(), 50_000_000);
+        assert_eq!(compute_penalty(AMOUNT, 500, 99 * DAY, 100, false, FULL_COMMITMENT).unwrap(), 50_000_000);
+    }
+
+    #[test]
+    fn penalty_is_waived_once_the_grace_share_is_served() {
+        // 95% of a 100-day commitment is 95 days
+        assert_eq!(compute_penalty(AMOUNT, 500, 95 * DAY - 1, 100, false, 9500).unwrap(), 50_000_000);
+        assert_eq!(compute_penalty(AMOUNT, 500, 95 * DAY, 100, false, 9500).unwrap(), 0);
+        assert_eq!(compute_penalty(AMOUNT, 500, 95 * DAY, 100, false, FULL_COMMITMENT).unwrap(), 50_000_000);
+    }
+
+    #[test]
+    fn long_commitments_get_the_fee_discount_from_the_threshold() {
+        assert_eq!(apply_commitment_discount(50, 179, 180, 5000), 50);
+        assert_eq!(apply_commitment_discount(50, 180, 180, 5000), 25);
+        assert_eq!(apply_commitment_discount(50, 365, 180, 5000), 25);
+        assert_eq!(apply_commitment_discount(50, 365, 0, 5000), 50);
+        assert_eq!(apply_commitment_discount(50, 365, 180, 10000), 0);
+    }
+
+    #[test]
+    fn yields_accrue_by_the_second() {
+        // 10% on 365 SOL is 0.1 SOL a day, and half a day earns exactly half of it
+        assert_eq!(compute_yields(365 * AMOUNT, 1000, DAY).unwrap(), AMOUNT / 10);
+        assert_eq!(compute_yields(365 * AMOUNT, 1000, DAY / 2).unwrap(), AMOUNT / 20);
+        assert_eq!(compute_yields(365 * AMOUNT, 1000, 1).unwrap(), AMOUNT / 10 / DAY as u64);
+        assert_eq!(compute_yields(365 * AMOUNT, 1000, -1).unwrap(), 0);
+    }
+
+    #[test]
+    fn yields_overflow_is_an_error_not_a_panic() {
+        assert!(compute_yields(u64::MAX, 10000, 365 * DAY).is_ok());
+        assert!(compute_yields(u64::MAX, 10000, 366 * 365 * DAY).is_err());
+    }
+
+    #[test]
+    fn quote_tolerance_bounds_fee_changes_both_ways() {
+        // Quoted at a 0.5% fee with 1% tolerance
+        let quoted_net = AMOUNT - 5_000_000;
+        assert!(fee_within_quote_tolerance(AMOUNT, 5_000_000, quoted_net, 100).unwrap());
+        assert!(fee_within_quote_tolerance(AMOUNT, 15_000_000, quoted_net, 100).unwrap());
+        assert!(!fee_within_quote_tolerance(AMOUNT, 15_000_001, quoted_net, 100).unwrap());
+        assert!(fee_within_quote_tolerance(AMOUNT, 0, quoted_net, 100).unwrap());
+        assert!(!fee_within_quote_tolerance(AMOUNT, 5_000_000, 0, 100).unwrap());
+        assert!(!fee_within_quote_tolerance(AMOUNT, 0, quoted_net, 0).unwrap());
+    }
+
+    #[test]
+    fn gross_for_net_credits_exactly_the_net_at_awkward_rates() {
+        // 333 bps never divides evenly, so rounding the gross either way would be off by a lamport
+        for net in [1, 2, 3, 29, 30, 9_667, 9_668, 10 * AMOUNT, 10 * AMOUNT + 1, u64::MAX / 2] {
+            let gross = gross_for_net(net, 333).unwrap();
+            assert_eq!(gross - compute_fee(gross, 333).unwrap(), net);
+            assert!(gross - 1 - compute_fee(gross - 1, 333).unwrap() < net);
+        }
+        assert_eq!(gross_for_net(AMOUNT, 0).unwrap(), AMOUNT);
+        assert_eq!(gross_for_net(0, 333).unwrap(), 0);
+        assert!(gross_for_net(1, 10000).is_err());
+        assert!(gross_for_net(u64::MAX, 333).is_err());
+    }
+
+    #[test]
+    fn apy_boost_stops_at_max_apy() {
+        assert_eq!(apply_apy_boost(1000, 300, 2000), 1300);
+        assert_eq!(apply_apy_boost(1900, 300, 2000), 2000);
+        assert_eq!(apply_apy_boost(2500, 300, 2000), 2500);
+    }
+
+    #[test]
+    fn leverage_cap_allows_exactly_the_ratio_and_no_more() {
+        // 20% APY on 30 SOL promises 6 SOL a year; a 1 SOL reserve at 6x covers it
+        assert!(within_leverage_cap(30 * AMOUNT, 2000, AMOUNT, 6000));
+        assert!(!within_leverage_cap(30 * AMOUNT + 5, 2000, AMOUNT, 6000));
+        assert!(!within_leverage_cap(30 * AMOUNT, 2000, AMOUNT, 2000));
+        assert!(!within_leverage_cap(AMOUNT, 2000, 0, 6000));
+        assert!(within_leverage_cap(0, 2000, 0, 6000));
+    }
+
+    #[test]
+    fn runway_counts_days_of_yield_the_reserve_covers() {
+        // 10% on 365 SOL is 0.1 SOL a day, so 3 SOL lasts 30 days
+        assert_eq!(reserve_runway_days(3 * AMOUNT, 365 * AMOUNT, 1000), 30);
+        assert_eq!(reserve_runway_days(0, 365 * AMOUNT, 1000), 0);
+        assert_eq!(reserve_runway_days(AMOUNT, 0, 1000), u64::MAX);
+    }
+
+    #[test]
+    fn projected_apy_scales_with_runway_under_a_year() {
+        assert_eq!(projected_apy_bps(1000, 730), 1000);
+        assert_eq!(projected_apy_bps(1000, 365), 1000);
+        assert_eq!(projected_apy_bps(1000, 73), 200);
+        assert_eq!(projected_apy_bps(1000, 0), 0);
+    }
+
+    #[test]
+    fn recall_releases_principal_pro_rata_to_the_msol_burned() {
+        assert_eq!(recalled_principal(10 * AMOUNT, 9 * AMOUNT, 9 * AMOUNT), 10 * AMOUNT);
+        assert_eq!(recalled_principal(10 * AMOUNT, 3 * AMOUNT, 9 * AMOUNT), 3_333_333_333);
+        assert_eq!(recalled_principal(u64::MAX, u64::MAX - 1, u64::MAX), u64::MAX - 1);
+        assert_eq!(recalled_principal(AMOUNT, AMOUNT, 0), 0);
+    }
+
+    #[test]
+    fn msol_converts_at_marinade_price() {
+        let price = 1u64 << 32;
+        assert_eq!(msol_value(AMOUNT, price).unwrap(), AMOUNT);
+        assert_eq!(msol_value(AMOUNT, 5 * price / 4).unwrap(), 5 * AMOUNT / 4);
+        assert_eq!(msol_for_lamports(5 * AMOUNT / 4, 5 * price / 4).unwrap(), AMOUNT);
+        assert_eq!(msol_value(3, price - 1).unwrap(), 2);
+        assert!(msol_for_lamports(AMOUNT, 0).is_err());
+        assert_eq!(marinade_rate_floor(AMOUNT), 97 * AMOUNT / 100);
+        assert_eq!(marinade_rate_floor(0), 0);
+    }
+
+    #[test]
+    fn marinade_returns_split_into_principal_yield_and_loss() {
+        assert_eq!(split_marinade_return(105 * AMOUNT / 100, AMOUNT), (AMOUNT, 5 * AMOUNT / 100, 0));
+        assert_eq!(split_marinade_return(AMOUNT, AMOUNT), (AMOUNT, 0, 0));
+        assert_eq!(split_marinade_return(97 * AMOUNT / 100, AMOUNT), (97 * AMOUNT / 100, 0, 3 * AMOUNT / 100));
+        assert_eq!(split_marinade_return(0, AMOUNT), (0, 0, AMOUNT));
+    }
+
+    #[test]
+    fn distributions_split_pro_rata_to_within_a_lamport() {
+        // 1 SOL plus an odd lamport over stakes of 1 and 3 SOL
+        let increment = reward_per_token_increment(AMOUNT + 1, 4 * AMOUNT).unwrap();
+        let small = rewards_earned(AMOUNT, increment, 0).unwrap();
+        let large = rewards_earned(3 * AMOUNT, increment, 0).unwrap();
+        assert_eq!(small, AMOUNT / 4);
+        assert_eq!(large, 3 * AMOUNT / 4);
+        assert!(small + large <= AMOUNT + 1);
+
+        // Only the movement since the position's last reading counts
+        assert_eq!(rewards_earned(AMOUNT, 2 * increment, increment).unwrap(), small);
+        assert!(reward_per_token_increment(AMOUNT, 0).is_err());
+    }
+
+    #[test]
+    fn legal_parameters_hold_at_their_limits() {
+        let year = MAX_COMMITMENT_DAYS as i64 * DAY;
+
+        // 100% for a full year on the largest representable stake is exactly the principal
+        assert_eq!(compute_yields(u64::MAX, MAX_APY_BPS, year).unwrap(), u64::MAX);
+        assert_eq!(compute_yields(DEFAULT_MAX_STAKE_AMOUNT, MAX_APY_BPS, year).unwrap(), DEFAULT_MAX_STAKE_AMOUNT);
+        assert_eq!(compute_fee(u64::MAX, BPS_DENOMINATOR as u64).unwrap(), u64::MAX);
+        for sliding_scale in [false, true] {
+            let penalty = compute_penalty(u64::MAX, 10000, 0, MAX_COMMITMENT_DAYS, sliding_scale, FULL_COMMITMENT);
+            assert_eq!(penalty.unwrap(), u64::MAX);
+        }
+
+        // Only a result that does not fit in u64 is an error, never an intermediate
+        assert!(compute_yields(u64::MAX, MAX_APY_BPS, year + DAY).is_err());
+        assert!(compute_fee(u64::MAX, BPS_DENOMINATOR as u64 + 1).is_err());
+    }
+
+    #[test]
+    fn yield_capacity_allows_exactly_the_factor_and_no_more() {
+        // Twice the reserve at a factor of 2x
+        assert!(within_yield_capacity(2 * AMOUNT, AMOUNT, 20000));
+        assert!(!within_yield_capacity(2 * AMOUNT + 1, AMOUNT, 20000));
+        assert!(!within_yield_capacity(1, 0, 20000));
+        assert_eq!(yield_capacity_utilization_bps(2 * AMOUNT, AMOUNT, 20000), 10000);
+        assert_eq!(yield_capacity_utilization_bps(AMOUNT, AMOUNT, 20000), 5000);
+        assert_eq!(yield_capacity_utilization_bps(0, 0, 10000), 0);
+        assert_eq!(yield_capacity_utilization_bps(1, 0, 10000), u64::MAX);
+    }
+
+    #[test]
+    fn solvency_alerts_fire_only_when_a_level_is_crossed() {
+        assert!(crossed_solvency_alert(7999, 8000));
+        assert!(crossed_solvency_alert(9500, 10001));
+        assert!(crossed_solvency_alert(10000, 9999)); // Falling back below a level counts too
+        assert!(!crossed_solvency_alert(8000, 8999));
+        assert!(!crossed_solvency_alert(0, 7999));
+    }
+
+    #[test]
+    fn only_a_shortfall_past_the_tolerance_unbalances_the_books() {
+        assert_eq!(reconciliation_delta(1_000, 1_250), 250);
+        assert_eq!(reconciliation_delta(1_000, 900), -100);
+        assert_eq!(reconciliation_delta(u64::MAX, 0), i64::MIN); // Clamped, not wrapped
+
+        assert!(books_balance(250, 0)); // A donation to a vault
+        assert!(books_balance(-100, 100));
+        assert!(!books_balance(-101, 100));
+        assert!(!books_balance(i64::MIN, u64::MAX >> 1));
+        assert!(books_balance(i64::MIN, u64::MAX));
+    }
+
+    #[test]
+    fn commitments_renew_only_once_the_grace_window_has_passed() {
+        let commitment = 30 * DAY;
+        assert_eq!(renewal_cycles(0, commitment, DAY, commitment), 0); // Ended, in grace
+        assert_eq!(renewal_cycles(0, commitment, DAY, commitment + DAY - 1), 0);
+        assert_eq!(renewal_cycles(0, commitment, DAY, commitment + DAY), 1);
+        assert_eq!(renewal_cycles(0, commitment, DAY, 2 * commitment + DAY - 1), 1);
+        assert_eq!(renewal_cycles(0, commitment, DAY, 3 * commitment + DAY), 3);
+        assert_eq!(renewal_cycles(0, 0, DAY, 3 * commitment), 0);
+        assert_eq!(renewal_cycles(i64::MAX, commitment, DAY, i64::MIN), 0);
+    }
+
+    #[test]
+    fn reward_tokens_convert_at_the_set_price_rounding_down() {
+        // 1 SOL of yield at 250 six-decimal tokens per SOL
+        assert_eq!(lamports_to_reward_tokens(AMOUNT, 250_000_000).unwrap(), 250_000_000);
+        assert_eq!(lamports_to_reward_tokens(AMOUNT / 1000, 250_000_000).unwrap(), 250_000);
+        // 3 lamports at 1 base unit per SOL is worth nothing yet
+        assert_eq!(lamports_to_reward_tokens(3, 1).unwrap(), 0);
+        assert_eq!(lamports_to_reward_tokens(AMOUNT, 0).unwrap(), 0);
+        assert!(lamports_to_reward_tokens(u64::MAX, u64::MAX).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn reward_tokens_never_exceed_the_yields_value(
+            lamports in 0u64..=1 << 50,
+            tokens_per_sol in 0u64..=1 << 40,
+        ) {
+            let tokens = lamports_to_reward_tokens(lamports, tokens_per_sol).unwrap() as u128;
+            let value = lamports as u128 * tokens_per_sol as u128;
+            prop_assert!(tokens * LAMPORTS_PER_SOL <= value);
+            prop_assert!(value < (tokens + 1) * LAMPORTS_PER_SOL);
+        }
+
+        #[test]
+        fn renewal_leaves_the_position_short_of_its_next_grace_deadline(
+            stake_timestamp in 0i64..=1 << 40,
+            committed_days in 1i64..=MAX_COMMITMENT_DAYS as i64,
+            grace_seconds in 0i64..=30 * DAY,
+            elapsed in 0i64..=100 * 365 * DAY,
+        ) {
+            let commitment = committed_days * DAY;
+            let now = stake_timestamp + elapsed;
+            let cycles = renewal_cycles(stake_timestamp, commitment, grace_seconds, now) as i64;
+
+            // Every renewed commitment began at or before now, and the next would not have yet
+            let renewed_start = stake_timestamp + cycles * commitment;
+            prop_assert!(renewed_start <= now);
+            prop_assert!(now < renewed_start + commitment + grace_seconds);
+        }
+
+        #[test]
+        fn legal_parameters_never_error(
+            amount in 0u64..=DEFAULT_MAX_STAKE_AMOUNT,
+            apy_bps in 0u64..=MAX_APY_BPS,
+            elapsed in 0i64..=100 * 365 * DAY,
+            committed_days in 0u64..=MAX_COMMITMENT_DAYS,
+            penalty_bps in 0u64..=10000,
+            sliding_scale in any::<bool>(),
+        ) {
+            prop_assert!(compute_yields(amount, apy_bps, elapsed).is_ok());
+            prop_assert!(compute_fee(amount, penalty_bps).is_ok());
+            prop_assert!(compute_penalty(amount, penalty_bps, elapsed, committed_days, sliding_scale, FULL_COMMITMENT).is_ok());
+        }
+
+        #[test]
+        fn yields_never_decrease_with_more_time(
+            amount in any::<u64>(),
+            apy_bps in 0u64..=10000,
+            elapsed in 0i64..=3650 * DAY,
+            extra in 0i64..=365 * DAY,
+        ) {
+            if let (Ok(earlier), Ok(later)) = (
+                compute_yields(amount, apy_bps, elapsed),
+                compute_yields(amount, apy_bps, elapsed + extra),
+            ) {
+                prop_assert!(earlier <= later);
+            }
+        }
+
+        #[test]
+        fn yields_never_panic(amount in any::<u64>(), apy_bps in any::<u64>(), elapsed in any::<i64>()) {
+            let _ = compute_yields(amount, apy_bps, elapsed);
+        }
+
+        #[test]
+        fn fee_and_net_add_up_to_the_amount(amount in any::<u64>(), fee_bps in 0u64..=10000) {
+            let fee = compute_fee(amount, fee_bps).unwrap();
+            let net = amount.checked_sub(fee).unwrap();
+            prop_assert_eq!(fee + net, amount);
+        }
+
+        #[test]
+        fn gross_for_net_is_the_smallest_deposit_netting_exactly_the_amount(
+            net in 1u64..=u64::MAX / 2,
+            fee_bps in 0u64..=1000,
+        ) {
+            let gross = gross_for_net(net, fee_bps).unwrap();
+            prop_assert_eq!(gross - compute_fee(gross, fee_bps).unwrap(), net);
+            prop_assert!(gross - 1 - compute_fee(gross - 1, fee_bps).unwrap() < net);
+        }
+
+        #[test]
+        fn fee_never_panics(amount in any::<u64>(), fee_bps in any::<u64>()) {
+            let _ = compute_fee(amount, fee_bps);
+        }
+
+        #[test]
+        fn penalty_never_exceeds_principal(
+            amount in any::<u64>(),
+            penalty_bps in 0u64..=10000,
+            elapsed in any::<i64>(),
+            committed_days in 0u64..=3650,
+            sliding_scale in any::<bool>(),
+        ) {
+            let penalty = compute_penalty(amount, penalty_bps, elapsed, committed_days, sliding_scale, FULL_COMMITMENT).unwrap();
+            prop_assert!(penalty <= amount);
+        }
+
+        #[test]
+        fn commitment_discount_never_raises_the_fee(
+            amount in any::<u64>(),
+            fee_bps in 0u64..=10000,
+            committed_days in 0u64..=365,
+            threshold_days in 0u64..=365,
+            discount_bps in any::<u64>(),
+        ) {
+            let discounted_bps = apply_commitment_discount(fee_bps, committed_days, threshold_days, discount_bps);
+            prop_assert!(discounted_bps <= fee_bps);
+            prop_assert!(compute_fee(amount, discounted_bps).unwrap() <= compute_fee(amount, fee_bps).unwrap());
+        }
+
+        #[test]
+        fn grace_never_raises_the_penalty(
+            amount in any::<u64>(),
+            penalty_bps in 0u64..=10000,
+            elapsed in any::<i64>(),
+            committed_days in 0u64..=3650,
+            sliding_scale in any::<bool>(),
+            grace_bps in any::<u64>(),
+        ) {
+            let graced = compute_penalty(amount, penalty_bps, elapsed, committed_days, sliding_scale, grace_bps).unwrap();
+            let full = compute_penalty(amount, penalty_bps, elapsed, committed_days, sliding_scale, FULL_COMMITMENT).unwrap();
+            prop_assert!(graced <= full);
+        }
+
+        #[test]
+        fn apy_boost_never_exceeds_max_apy_or_lowers_the_locked_rate(
+            locked_apy_bps in 0u64..=10000,
+            boost_bps in 0u64..=1000,
+            max_apy_bps in 1u64..=10000,
+        ) {
+            let boosted = apply_apy_boost(locked_apy_bps, boost_bps, max_apy_bps);
+            prop_assert!(boosted >= locked_apy_bps);
+            prop_assert!(boosted <= locked_apy_bps.max(max_apy_bps));
+            prop_assert!(boosted <= locked_apy_bps + boost_bps);
+        }
+
+        #[test]
+        fn shares_of_a_distribution_never_exceed_it(
+            amount in any::<u64>(),
+            first in 1u64..=u64::MAX / 2,
+            second in 0u64..=u64::MAX / 2,
+        ) {
+            let increment = reward_per_token_increment(amount, first + second).unwrap();
+            let shares = rewards_earned(first, increment, 0).unwrap() as u128
+                + rewards_earned(second, increment, 0).unwrap() as u128;
+            prop_assert!(shares <= amount as u128);
+        }
+
+        #[test]
+        fn penalty_never_panics(
+            amount in any::<u64>(),
+            penalty_bps in any::<u64>(),
+            elapsed in any::<i64>(),
+            committed_days in any::<u64>(),
+            sliding_scale in any::<bool>(),
+            grace_bps in any::<u64>(),
+        ) {
+            let _ = compute_penalty(amount, penalty_bps, elapsed, committed_days, sliding_scale, grace_bps);
+        }
+    }
+}
@@ -2,8 +2,75 @@ import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { DefiTrustFund } from "../target/types/defi_trust_fund";
 import { PublicKey, Keypair, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
-import { TOKEN_PROGRAM_ID, ASSOCIATED_TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import {
+  TOKEN_PROGRAM_ID,
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  createMint,
+  createAccount,
+  mintTo,
+  getAccount,
+  getMint,
+  getAssociatedTokenAddressSync,
+  createWrappedNativeAccount,
+  NATIVE_MINT,
+} from "@solana/spl-token";
 import { expect } from "chai";
+import { createHash } from "crypto";
+
+const TOKEN_METADATA_PROGRAM_ID = new PublicKey("metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s");
+
+// Little-endian u64 seed used to derive each pool's PDAs
+const poolIdSeed = (poolId: number): Buffer => new anchor.BN(poolId).toArrayLike(Buffer, "le", 8);
+
+// Net deposit quoted at the default 0.5% fee, passed as the stake's min_expected_amount
+const netOfDepositFee = (amount: anchor.BN | number): anchor.BN => new anchor.BN(amount).muln(9950).divn(10000);
+
+// Sentinels for unstake and claimYields when no deadline or payout floor is wanted
+const NO_DEADLINE = new anchor.BN("9223372036854775807");
+const NO_MIN_PAYOUT = new anchor.BN(0);
+
+// Yield-capacity factor loose enough that only the leverage cap binds
+const LOOSE_SOLVENCY_FACTOR_BPS = 60000;
+
+// Compute budgets of the user hot paths. A change that pushes one more than 10% past
+// its budget fails the suite; raise the budget deliberately if the cost is justified.
+const STAKE_CU_BUDGET = 60_000;
+const CLAIM_CU_BUDGET = 50_000;
+const UNSTAKE_CU_BUDGET = 60_000;
+
+const expectWithinComputeBudget = async (
+  connection: anchor.web3.Connection,
+  signature: string,
+  budget: number
+) => {
+  const transaction = await connection.getTransaction(signature, {
+    commitment: "confirmed",
+    maxSupportedTransactionVersion: 0,
+  });
+  expect(transaction.meta.computeUnitsConsumed).to.be.at.most(budget * 1.1);
+};
+
+// Jupiter's address, where the localnet validator loads the mock swap router
+const SWAP_PROGRAM_ID = new PublicKey("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+// Marinade's address, where the localnet validator loads the mock Marinade program
+const MARINADE_PROGRAM_ID = new PublicKey("MarBmsSgKXdrN1egZf5sqe1TMai9K1rChYNDJgjq7aD");
+
+// Where the localnet validator loads the mock integrating protocol for the CPI view tests
+const CPI_CALLER_PROGRAM_ID = new PublicKey("5arcmDkgd4bTk8nBLJ1AMdp2whnrbGZqUito8QJ8UGEi");
+
+// Pool.pause_flags bits
+const PAUSE_DEPOSITS = 1;
+const PAUSE_CLAIMS = 2;
+const PAUSE_UNSTAKE = 4;
+const PAUSE_ALL = PAUSE_DEPOSITS | PAUSE_CLAIMS | PAUSE_UNSTAKE;
+
+// Reads a borsh string padded by Metaplex to a fixed width
+const readMetadataString = (data: Buffer, offset: number): [string, number] => {
+  const length = data.readUInt32LE(offset);
+  const value = data.slice(offset + 4, offset + 4 + length).toString("utf8").replace(/\0/g, "");
+  return [value, offset + 4 + length];
+};
 
 describe("defi-trust-fund", () => {
   const provider = anchor.AnchorProvider.env();
@@ -17,9 +84,19 @@ describe("defi-trust-fund", () => {
   const user2 = Keypair.generate();
   
   // Program accounts
+  let poolRegistry: PublicKey;
   let pool: PublicKey;
   let poolVault: PublicKey;
+  let rewardVault: PublicKey;
+  let feeVault: PublicKey;
+  let insuranceVault: PublicKey;
   let userStake: PublicKey;
+  let stakeMint: PublicKey;
+  let tokenVault: PublicKey;
+  let userTokenStake: PublicKey;
+  let user1TokenAccount: PublicKey;
+  let receiptMint: PublicKey;
+  let user1ReceiptAccount: PublicKey;
   
   before(async () => {
     // Airdrop SOL to test accounts
@@ -31,18 +108,59 @@ describe("defi-trust-fund", () => {
     await new Promise(resolve => setTimeout(resolve, 2000));
     
     // Derive PDAs
+    [poolRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("pool_registry")],
+      program.programId
+    );
+
     [pool] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pool")],
+      [Buffer.from("pool"), poolIdSeed(0)],
       program.programId
     );
     
     [poolVault] = PublicKey.findProgramAddressSync(
-      [Buffer.from("pool_vault")],
+      [Buffer.from("pool_vault"), poolIdSeed(0)],
+      program.programId
+    );
+    
+    [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("reward_vault"), poolIdSeed(0)],
+      program.programId
+    );
+    
+    [feeVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("fee_vault"), poolIdSeed(0)],
+      program.programId
+    );
+
+    [insuranceVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance_vault"), poolIdSeed(0)],
       program.programId
     );
     
     [userStake] = PublicKey.findProgramAddressSync(
-      [Buffer.from("user_stake"), user1.publicKey.toBuffer()],
+      [Buffer.from("user_stake"), poolIdSeed(0), user1.publicKey.toBuffer()],
+      program.programId
+    );
+
+    [receiptMint] = PublicKey.findProgramAddressSync(
+      [Buffer.from("receipt_mint"), poolIdSeed(0)],
+      program.programId
+    );
+    user1ReceiptAccount = getAssociatedTokenAddressSync(receiptMint, user1.publicKey);
+
+    // SPL stake mint and the user's token account
+    stakeMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    user1TokenAccount = await createAccount(provider.connection, user1, stakeMint, user1.publicKey);
+    await mintTo(provider.connection, admin, stakeMint, user1TokenAccount, admin, 1_000_000_000);
+
+    [tokenVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("token_vault"), poolIdSeed(0), stakeMint.toBuffer()],
+      program.programId
+    );
+
+    [userTokenStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_token_stake"), poolIdSeed(0), stakeMint.toBuffer(), user1.publicKey.toBuffer()],
       program.programId
     );
   });
@@ -51,13 +169,22 @@ describe("defi-trust-fund", () => {
     const maxApy = 5000; // 50% APY
     const minCommitmentDays = 1;
     const maxCommitmentDays = 365;
+    const apyTiers = [
+      { minDays: new anchor.BN(30), apyBps: new anchor.BN(2000) },
+      { minDays: new anchor.BN(90), apyBps: new anchor.BN(3500) },
+    ];
 
     await program.methods
-      .initializePool(maxApy, minCommitmentDays, maxCommitmentDays)
+      .initializePool(new anchor.BN(0), maxApy, minCommitmentDays, maxCommitmentDays, stakeMint, apyTiers, [])
       .accounts({
         admin: admin.publicKey,
+        poolRegistry: poolRegistry,
         pool: pool,
         poolVault: poolVault,
+        feeVault: feeVault,
+        rewardVault: rewardVault,
+        receiptMint: receiptMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
         rent: SYSVAR_RENT_PUBKEY,
       })
@@ -69,73 +196,689 @@ describe("defi-trust-fund", () => {
     expect(poolAccount.maxApy.toNumber()).to.equal(maxApy);
     expect(poolAccount.minCommitmentDays.toNumber()).to.equal(minCommitmentDays);
     expect(poolAccount.maxCommitmentDays.toNumber()).to.equal(maxCommitmentDays);
+    expect(poolAccount.stakeMint.toString()).to.equal(stakeMint.toString());
+    expect(poolAccount.apyTiers.length).to.equal(2);
+    expect(poolAccount.leverageRatio.toNumber()).to.equal(6000);
+    expect(poolAccount.reinvestmentRate.toNumber()).to.equal(1000);
+
+    // Stakes are capped at a multiple of the reward reserve, so seed it before anyone stakes
+    await program.methods
+      .fundRewards(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL))
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    await program.methods
+      .initializeInsuranceVault()
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        insuranceVault: insuranceVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    // New pools only accept yield the reserve covers; the suite stakes far more than the
+    // 2 SOL seed could pay, so loosen the check to the leverage cap until it is tested
+    expect(poolAccount.solvencyFactorBps.toNumber()).to.equal(10000);
+    await program.methods
+      .updateSolvencyFactor(new anchor.BN(LOOSE_SOLVENCY_FACTOR_BPS))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
   });
 
   it("Allows users to stake", async () => {
     const amount = new anchor.BN(1 * anchor.web3.LAMPORTS_PER_SOL); // 1 SOL
     const committedDays = 30;
 
-    await program.methods
-      .stake(amount, committedDays)
+    const signature = await program.methods
+      .stake(amount, committedDays, netOfDepositFee(amount), null)
       .accounts({
         user: user1.publicKey,
         pool: pool,
         poolVault: poolVault,
+        feeVault: feeVault,
         userStake: userStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: user1ReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
         rent: SYSVAR_RENT_PUBKEY,
       })
       .signers([user1])
-      .rpc();
+      .rpc({ commitment: "confirmed" });
+    await expectWithinComputeBudget(provider.connection, signature, STAKE_CU_BUDGET);
 
     const userStakeAccount = await program.account.userStake.fetch(userStake);
     expect(userStakeAccount.user.toString()).to.equal(user1.publicKey.toString());
     expect(userStakeAccount.amount.toNumber()).to.equal(amount.toNumber());
     expect(userStakeAccount.committedDays.toNumber()).to.equal(committedDays);
+    // Exactly at the 30-day threshold the first tier applies
+    expect(userStakeAccount.lockedApyBps.toNumber()).to.equal(2000);
+  });
+
+  it("Merges a top-up into an existing position without resetting the lock", async () => {
+    const [user2Stake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const stakeAccounts = {
+      user: user2.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: user2Stake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, user2.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(90), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts(stakeAccounts)
+      .signers([user2])
+      .rpc();
+
+    const first = await program.account.userStake.fetch(user2Stake);
+    const usersAfterFirst = (await program.account.pool.fetch(pool)).totalUsers.toNumber();
+
+    await program.methods
+      .stake(new anchor.BN(0.1 * anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(1), netOfDepositFee(0.1 * anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts(stakeAccounts)
+      .signers([user2])
+      .rpc();
+
+    const merged = await program.account.userStake.fetch(user2Stake);
+    const topUpNet = netOfDepositFee(0.1 * anchor.web3.LAMPORTS_PER_SOL);
+    expect(merged.amount.toNumber()).to.equal(first.amount.add(topUpNet).toNumber());
+
+    // A small top-up only nudges the commitment towards its own length
+    expect(merged.committedDays.toNumber()).to.be.greaterThan(80);
+    expect(merged.committedDays.toNumber()).to.be.lessThanOrEqual(90);
+    expect(merged.stakeTimestamp.toNumber()).to.be.greaterThanOrEqual(first.stakeTimestamp.toNumber());
+
+    // The same position is not counted as a second user
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalUsers.toNumber()).to.equal(usersAfterFirst);
+  });
+
+  it("Keeps existing stakers on their locked APY after a tier update", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+
+    await program.methods
+      .updateApyTiers([
+        { minDays: new anchor.BN(30), apyBps: new anchor.BN(4000) },
+        { minDays: new anchor.BN(90), apyBps: new anchor.BN(6000) },
+      ])
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+      })
+      .signers([admin])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.apyTiers[0].apyBps.toNumber()).to.equal(4000);
+
+    const after = await program.account.userStake.fetch(userStake);
+    expect(after.lockedApyBps.toNumber()).to.equal(before.lockedApyBps.toNumber());
+
+    try {
+      await program.methods
+        .updateApyTiers([
+          { minDays: new anchor.BN(90), apyBps: new anchor.BN(4000) },
+          { minDays: new anchor.BN(30), apyBps: new anchor.BN(6000) },
+        ])
+        .accounts({
+          admin: admin.publicKey,
+          pool: pool,
+        })
+        .signers([admin])
+        .rpc();
+
+      expect.fail("Should have thrown an error for unordered tiers");
+    } catch (error) {
+      expect(error.message).to.include("InvalidApyTiers");
+    }
+  });
+
+  it("Extends a commitment but never shortens it or passes the pool maximum", async () => {
+    const extend = (days: number) =>
+      program.methods
+        .extendCommitment(new anchor.BN(days))
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          userStake: userStake,
+        })
+        .signers([user1])
+        .rpc();
+
+    await extend(90);
+    let userStakeAccount = await program.account.userStake.fetch(userStake);
+    expect(userStakeAccount.committedDays.toNumber()).to.equal(90);
+    // Reaching the 90-day tier upgrades the locked rate going forward
+    expect(userStakeAccount.lockedApyBps.toNumber()).to.equal(6000);
+
+    await extend(120);
+    userStakeAccount = await program.account.userStake.fetch(userStake);
+    expect(userStakeAccount.committedDays.toNumber()).to.equal(120);
+
+    for (const days of [60, 366]) {
+      try {
+        await extend(days);
+        expect.fail("Should have thrown an error for invalid commitment days");
+      } catch (error) {
+        expect(error.message).to.include("InvalidCommitmentDays");
+      }
+    }
+  });
+
+  it("Funds the reward vault without touching principal", async () => {
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const poolVaultBefore = await provider.connection.getBalance(poolVault);
+    const rewardVaultBefore = await provider.connection.getBalance(rewardVault);
+    const reserveBefore = (await program.account.pool.fetch(pool)).rewardReserve;
+
+    await program.methods
+      .fundRewards(amount)
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.rewardReserve.toString()).to.equal(reserveBefore.add(amount).toString());
+    expect(await provider.connection.getBalance(rewardVault)).to.equal(rewardVaultBefore + amount.toNumber());
+    expect(await provider.connection.getBalance(poolVault)).to.equal(poolVaultBefore);
+
+    // Reserve measured against a year of yield at the pool's highest rate
+    const ratio = await program.methods.solvencyRatio().accounts({ pool: pool }).view();
+    const highestApy = Math.max(poolAccount.maxApy.toNumber(), ...poolAccount.apyTiers.map(tier => tier.apyBps.toNumber()));
+    const annualYield = poolAccount.totalStaked.muln(highestApy).divn(10000);
+    expect(ratio.toString()).to.equal(poolAccount.rewardReserve.muln(10000).div(annualYield).toString());
   });
 
   it("Allows users to claim yields", async () => {
     // Wait some time to accumulate yields
     await new Promise(resolve => setTimeout(resolve, 1000));
 
-    await program.methods
-      .claimYields()
+    const signature = await program.methods
+      .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
       .accounts({
         user: user1.publicKey,
         pool: pool,
-        poolVault: poolVault,
+        rewardVault: rewardVault,
         userStake: userStake,
         systemProgram: SystemProgram.programId,
       })
       .signers([user1])
-      .rpc();
+      .rpc({ commitment: "confirmed" });
+    await expectWithinComputeBudget(provider.connection, signature, CLAIM_CU_BUDGET);
 
     // Verify yields were claimed
     const userStakeAccount = await program.account.userStake.fetch(userStake);
     expect(userStakeAccount.lastClaimTimestamp.toNumber()).to.be.greaterThan(0);
   });
 
-  it("Allows users to unstake", async () => {
+  it("Does not pay the same period twice on back-to-back claims", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+    const claim = () =>
+      program.methods
+        .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          rewardVault: rewardVault,
+          userStake: userStake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1]);
+
+    // Both claims run at the same clock, so the second has no time left to pay for
+    try {
+      await claim()
+        .preInstructions([await claim().instruction()])
+        .rpc();
+
+      expect.fail("Should have thrown an error for no yield to claim");
+    } catch (error) {
+      expect(error.message).to.include("NoYieldToClaim");
+    }
+
+    const after = await program.account.userStake.fetch(userStake);
+    expect(after.totalClaimed.toNumber()).to.equal(before.totalClaimed.toNumber());
+    expect(after.lastClaimTimestamp.toNumber()).to.equal(before.lastClaimTimestamp.toNumber());
+  });
+
+  it("Refuses to compound yield that was just claimed", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+    const claim = await program.methods
+      .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        userStake: userStake,
+        systemProgram: SystemProgram.programId,
+      })
+      .instruction();
+
+    try {
+      await program.methods
+        .compoundYields()
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          rewardVault: rewardVault,
+          userStake: userStake,
+          receiptMint: receiptMint,
+          userReceiptAccount: user1ReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .preInstructions([claim])
+        .signers([user1])
+        .rpc();
+
+      expect.fail("Should have thrown an error for no yield to compound");
+    } catch (error) {
+      expect(error.message).to.include("NoYieldToClaim");
+    }
+
+    const after = await program.account.userStake.fetch(userStake);
+    expect(after.amount.toString()).to.equal(before.amount.toString());
+  });
+
+  it("Allows users to partially unstake", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+    const withdrawAmount = new anchor.BN(0.5 * anchor.web3.LAMPORTS_PER_SOL);
+
     await program.methods
-      .unstake()
+      .unstake(withdrawAmount, NO_DEADLINE, NO_MIN_PAYOUT)
       .accounts({
         user: user1.publicKey,
         pool: pool,
         poolVault: poolVault,
         userStake: userStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: user1ReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
         systemProgram: SystemProgram.programId,
       })
       .signers([user1])
       .rpc();
 
+    const userStakeAccount = await program.account.userStake.fetch(userStake);
+    expect(userStakeAccount.amount.toNumber()).to.equal(before.amount.sub(withdrawAmount).toNumber());
+    expect(userStakeAccount.committedDays.toNumber()).to.equal(before.committedDays.toNumber());
+    expect(userStakeAccount.stakeTimestamp.toNumber()).to.equal(before.stakeTimestamp.toNumber());
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalUsers.toNumber()).to.equal(2);
+  });
+
+  it("Rejects a partial unstake that leaves dust below the minimum", async () => {
+    const userStakeAccount = await program.account.userStake.fetch(userStake);
+    const withdrawAmount = userStakeAccount.amount.sub(new anchor.BN(1));
+
+    try {
+      await program.methods
+        .unstake(withdrawAmount, NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          userStake: userStake,
+          receiptMint: receiptMint,
+          userReceiptAccount: user1ReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+
+      expect.fail("Should have thrown an error for remainder too small");
+    } catch (error) {
+      expect(error.message).to.include("RemainderTooSmall");
+      // The program logs the limit so the caller can explain it without another fetch
+      const minStake = (await program.account.pool.fetch(pool)).minStakeAmount;
+      expect(error.logs.join("\n")).to.include(`min=${minStake.toString()} remaining=1`);
+    }
+  });
+
+  it("Allows users to unstake", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+    const userBalanceBefore = await provider.connection.getBalance(user1.publicKey);
+
+    const tx = await program.methods
+      .unstake(before.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        userStake: userStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: user1ReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user1])
+      .rpc({ commitment: "confirmed" });
+
+    await expectWithinComputeBudget(provider.connection, tx, UNSTAKE_CU_BUDGET);
+
+    // Decode the UnstakeEvent from the transaction logs
+    const txInfo = await provider.connection.getTransaction(tx, {
+      commitment: "confirmed",
+    });
+    const eventParser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    const events = [...eventParser.parseLogs(txInfo.meta.logMessages)];
+    const unstakeEvent = events.find((event) => event.name === "UnstakeEvent");
+    expect(unstakeEvent).to.not.be.undefined;
+    expect(unstakeEvent.data.version).to.equal(5);
+    expect(unstakeEvent.data.pool.toString()).to.equal(pool.toString());
+    expect(unstakeEvent.data.amount.toNumber()).to.equal(before.amount.toNumber());
+    expect(unstakeEvent.data.returnAmount.toNumber()).to.equal(
+      before.amount.sub(unstakeEvent.data.penalty).toNumber()
+    );
+    expect(unstakeEvent.data.remainingAmount.toNumber()).to.equal(0);
+
+    // The vault payout lands in the user's wallet (the provider pays the tx fee)
+    const userBalanceAfter = await provider.connection.getBalance(user1.publicKey, "confirmed");
+    expect(userBalanceAfter - userBalanceBefore).to.equal(unstakeEvent.data.returnAmount.toNumber());
+
     // Verify unstaking
     const userStakeAccount = await program.account.userStake.fetch(userStake);
     expect(userStakeAccount.amount.toNumber()).to.equal(0);
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalUsers.toNumber()).to.equal(1); // user2 still holds a position
+  });
+
+  it("Closes an empty stake account and refunds rent", async () => {
+    const stakeInfo = await provider.connection.getAccountInfo(userStake);
+    const userBalanceBefore = await provider.connection.getBalance(user1.publicKey);
+
+    await program.methods
+      .closeUserStake()
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        userStake: userStake,
+      })
+      .signers([user1])
+      .rpc({ commitment: "confirmed" });
+
+    expect(await provider.connection.getAccountInfo(userStake, "confirmed")).to.be.null;
+    const userBalanceAfter = await provider.connection.getBalance(user1.publicKey, "confirmed");
+    expect(userBalanceAfter - userBalanceBefore).to.equal(stakeInfo.lamports);
+
+    // Staking again re-initializes the PDA from scratch
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: userStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: user1ReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([user1])
+      .rpc();
+
+    const restaked = await program.account.userStake.fetch(userStake);
+    expect(restaked.totalClaimed.toNumber()).to.equal(0);
+    expect(restaked.totalStakedLifetime.toNumber()).to.equal(restaked.amount.toNumber());
+  });
+
+  it("Refuses to close a stake account that still holds a position", async () => {
+    try {
+      await program.methods
+        .closeUserStake()
+        .accounts({
+          user: user2.publicKey,
+          pool: pool,
+          userStake: PublicKey.findProgramAddressSync(
+            [Buffer.from("user_stake"), poolIdSeed(0), user2.publicKey.toBuffer()],
+            program.programId
+          )[0],
+        })
+        .signers([user2])
+        .rpc();
+
+      expect.fail("Should have thrown an error for a non-empty stake");
+    } catch (error) {
+      expect(error.message).to.include("StakeNotEmpty");
+    }
+  });
+
+  it("Lets a claim delegate trigger claims but nothing else", async () => {
+    const delegate = Keypair.generate();
+    const [user2Stake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const delegatedClaim = (recipient: PublicKey) =>
+      program.methods
+        .claimYieldsDelegated()
+        .accounts({
+          delegate: delegate.publicKey,
+          user: recipient,
+          pool: pool,
+          rewardVault: rewardVault,
+          userStake: user2Stake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([delegate])
+        .rpc();
+
+    await program.methods
+      .setClaimDelegate(delegate.publicKey)
+      .accounts({
+        user: user2.publicKey,
+        pool: pool,
+        userStake: user2Stake,
+      })
+      .signers([user2])
+      .rpc();
+
+    const position = await program.account.userStake.fetch(user2Stake);
+    expect(position.claimDelegate.toString()).to.equal(delegate.publicKey.toString());
+
+    // The delegate is authorized and the yield accrued so far goes to the owner
+    const ownerBalanceBefore = await provider.connection.getBalance(user2.publicKey);
+    await delegatedClaim(user2.publicKey);
+    expect(await provider.connection.getBalance(user2.publicKey)).to.be.greaterThan(ownerBalanceBefore);
+
+    // Payouts cannot be redirected to the delegate
+    try {
+      await delegatedClaim(delegate.publicKey);
+      expect.fail("Should have thrown an error for a redirected payout");
+    } catch (error) {
+      expect(error.message).to.include("ConstraintSeeds");
+    }
+
+    // The delegate can neither replace itself nor unstake
+    try {
+      await program.methods
+        .setClaimDelegate(delegate.publicKey)
+        .accounts({
+          user: delegate.publicKey,
+          pool: pool,
+          userStake: user2Stake,
+        })
+        .signers([delegate])
+        .rpc();
+      expect.fail("Should have thrown an error for a delegate changing the delegate");
+    } catch (error) {
+      expect(error.message).to.include("ConstraintSeeds");
+    }
+
+    try {
+      await program.methods
+        .unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: delegate.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          userStake: user2Stake,
+          receiptMint: receiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, user2.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([delegate])
+        .rpc();
+      expect.fail("Should have thrown an error for a delegate unstaking");
+    } catch (error) {
+      expect(error.message).to.include("ConstraintSeeds");
+    }
+
+    // Like the owner's own claim, a delegated one cannot land in the slot of a top-up
+    const topUp = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const topUpIx = await program.methods
+      .stake(topUp, new anchor.BN(30), netOfDepositFee(topUp), null)
+      .accounts({
+        user: user2.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: user2Stake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, user2.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .instruction();
+    const claimIx = await program.methods
+      .claimYieldsDelegated()
+      .accounts({
+        delegate: delegate.publicKey,
+        user: user2.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        userStake: user2Stake,
+        systemProgram: SystemProgram.programId,
+      })
+      .instruction();
+    try {
+      await provider.sendAndConfirm(new anchor.web3.Transaction().add(topUpIx, claimIx), [user2, delegate]);
+      expect.fail("Should have thrown an error for a delegated claim in the staking slot");
+    } catch (error) {
+      expect((error.logs ?? []).join("\n")).to.include("SameSlotOperation");
+    }
+
+    await program.methods
+      .revokeClaimDelegate()
+      .accounts({
+        user: user2.publicKey,
+        pool: pool,
+        userStake: user2Stake,
+      })
+      .signers([user2])
+      .rpc();
+
+    try {
+      await delegatedClaim(user2.publicKey);
+      expect.fail("Should have thrown an error for a revoked delegate");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+  });
+
+  it("Allows admin to withdraw fees to the treasury", async () => {
+    const poolAccount = await program.account.pool.fetch(pool);
+    const amount = poolAccount.totalFeesCollected;
+    expect(poolAccount.treasury.toString()).to.equal(admin.publicKey.toString());
+
+    const treasury = Keypair.generate();
+    await program.methods
+      .updateTreasury(treasury.publicKey)
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    const withdrawFees = (destination: PublicKey) =>
+      program.methods
+        .withdrawFees(amount)
+        .accounts({
+          admin: admin.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: feeVault,
+          treasury: destination,
+        })
+        .signers([admin]);
+
+    // The signer cannot redirect fees to itself or anywhere else
+    try {
+      await withdrawFees(admin.publicKey).rpc();
+      expect.fail("Should have thrown an error for a destination other than the treasury");
+    } catch (error) {
+      expect(error.message).to.include("InvalidTreasury");
+    }
+
+    const signature = await withdrawFees(treasury.publicKey).rpc({ commitment: "confirmed" });
+    expect(await provider.connection.getBalance(treasury.publicKey, "confirmed")).to.equal(amount.toNumber());
+
+    // The event reconciles with the pool's counters
+    const transaction = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const eventParser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    const withdrawn = [...eventParser.parseLogs(transaction.meta.logMessages)].find(
+      event => event.name === "FeesWithdrawnEvent"
+    );
+    const after = await program.account.pool.fetch(pool, "confirmed");
+    expect(withdrawn.data.treasury.toString()).to.equal(treasury.publicKey.toString());
+    expect(withdrawn.data.amount.toString()).to.equal(amount.toString());
+    expect(withdrawn.data.feesRemaining.toString()).to.equal(after.totalFeesCollected.toString());
+    expect(after.totalFeesCollected.toString()).to.equal(poolAccount.totalFeesCollected.sub(amount).toString());
+
+    // Later tests withdraw to the admin again
+    await program.methods
+      .updateTreasury(admin.publicKey)
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    // Fees come only out of the fee vault, which keeps its rent-exempt minimum
+    const vaultInfo = await provider.connection.getAccountInfo(feeVault, "confirmed");
+    const rentExemptMinimum = await provider.connection.getMinimumBalanceForRentExemption(vaultInfo.data.length);
+    expect(vaultInfo.lamports).to.equal(rentExemptMinimum);
+    expect(vaultInfo.owner.toString()).to.equal(program.programId.toString());
   });
 
   it("Allows admin to pause the pool", async () => {
     await program.methods
-      .emergencyPause("Testing pause functionality")
+      .emergencyPause("Testing pause functionality", PAUSE_ALL)
       .accounts({
         admin: admin.publicKey,
         pool: pool,
@@ -144,7 +887,33 @@ describe("defi-trust-fund", () => {
       .rpc();
 
     const poolAccount = await program.account.pool.fetch(pool);
-    expect(poolAccount.isPaused).to.be.true;
+    expect(poolAccount.pauseFlags).to.equal(PAUSE_ALL);
+  });
+
+  it("Rejects emergency withdrawal before the pause delay has elapsed", async () => {
+    try {
+      await program.methods
+        .emergencyWithdraw()
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          userStake: userStake,
+          receiptMint: receiptMint,
+          userReceiptAccount: user1ReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+
+      expect.fail("Should have thrown an error for emergency withdrawal before the delay");
+    } catch (error) {
+      expect(error.message).to.include("EmergencyWithdrawUnavailable");
+    }
+
+    const userStakeAccount = await program.account.userStake.fetch(userStake);
+    expect(userStakeAccount.amount.toNumber()).to.be.greaterThan(0);
   });
 
   it("Allows admin to unpause the pool", async () => {
@@ -158,7 +927,3927 @@ describe("defi-trust-fund", () => {
       .rpc();
 
     const poolAccount = await program.account.pool.fetch(pool);
-    expect(poolAccount.isPaused).to.be.false;
+    expect(poolAccount.pauseFlags).to.equal(0);
   });
-});
 
+  it("Lets the guardian pause instantly while only the admin can unpause", async () => {
+    const guardian = Keypair.generate();
+
+    await program.methods
+      .updateGuardian(guardian.publicKey, new anchor.BN(72 * 3600))
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+      })
+      .signers([admin])
+      .rpc();
+
+    // Only the appointed guardian may pause
+    try {
+      await program.methods
+        .guardianPause("Not the guardian")
+        .accounts({
+          guardian: user2.publicKey,
+          pool: pool,
+        })
+        .signers([user2])
+        .rpc();
+
+      expect.fail("Should have thrown an error for unauthorized guardian");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    await program.methods
+      .guardianPause("Suspicious activity")
+      .accounts({
+        guardian: guardian.publicKey,
+        pool: pool,
+      })
+      .signers([guardian])
+      .rpc();
+
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.pauseFlags).to.equal(PAUSE_ALL);
+    expect(poolAccount.pausedBy.toString()).to.equal(guardian.publicKey.toString());
+    expect(poolAccount.pauseExpiresAt.toNumber()).to.equal(poolAccount.pausedAt.toNumber() + 72 * 3600);
+
+    await program.methods
+      .emergencyUnpause()
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+      })
+      .signers([admin])
+      .rpc();
+
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.pauseFlags).to.equal(0);
+    expect(poolAccount.pauseExpiresAt.toNumber()).to.equal(0);
+  });
+
+  it("Allows admin to update the early-exit penalty within bounds", async () => {
+    await program.methods
+      .updateEarlyExitPenalty(new anchor.BN(1000), true)
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+      })
+      .signers([admin])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.earlyExitPenaltyBps.toNumber()).to.equal(1000);
+    expect(poolAccount.slidingPenaltyEnabled).to.be.true;
+
+    try {
+      await program.methods
+        .updateEarlyExitPenalty(new anchor.BN(2500), false)
+        .accounts({
+          admin: admin.publicKey,
+          pool: pool,
+        })
+        .signers([admin])
+        .rpc();
+
+      expect.fail("Should have thrown an error for invalid penalty");
+    } catch (error) {
+      expect(error.message).to.include("InvalidPenalty");
+    }
+  });
+
+  it("Keeps receipt supply equal to total staked across random operations", async () => {
+    const stakers = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+    for (const staker of stakers) {
+      await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    }
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const accountsFor = (staker: Keypair) => ({
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    });
+
+    const staked = new Set<number>();
+    const usersBefore = (await program.account.pool.fetch(pool)).totalUsers.toNumber();
+    let seed = 42;
+    const nextRandom = () => {
+      seed = (seed * 1103515245 + 12345) % 2147483648;
+      return seed;
+    };
+
+    for (let step = 0; step < 12; step++) {
+      const index = nextRandom() % stakers.length;
+      const staker = stakers[index];
+      const operation = nextRandom() % 3;
+
+      try {
+        if (!staked.has(index)) {
+          await program.methods
+            .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+            .accounts({
+              ...accountsFor(staker),
+              associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+              rent: SYSVAR_RENT_PUBKEY,
+            })
+            .signers([staker])
+            .rpc();
+          staked.add(index);
+        } else if (operation === 0) {
+          await program.methods
+            .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+            .accounts({
+              user: staker.publicKey,
+              pool: pool,
+              rewardVault: rewardVault,
+              userStake: accountsFor(staker).userStake,
+              systemProgram: SystemProgram.programId,
+            })
+            .signers([staker])
+            .rpc();
+        } else {
+          const position = await program.account.userStake.fetch(accountsFor(staker).userStake);
+          const withdrawAmount = operation === 1 ? new anchor.BN(0.2 * anchor.web3.LAMPORTS_PER_SOL) : position.amount;
+          await program.methods
+            .unstake(withdrawAmount, NO_DEADLINE, NO_MIN_PAYOUT)
+            .accounts(accountsFor(staker))
+            .signers([staker])
+            .rpc();
+        }
+      } catch (error) {
+        // Rejected operations (nothing to claim, empty position) must leave the invariant intact
+      }
+
+      const mint = await getMint(provider.connection, receiptMint);
+      const poolAccount = await program.account.pool.fetch(pool);
+      expect(mint.supply.toString()).to.equal(poolAccount.totalStaked.toString());
+
+      // total_users moves only with the number of live, counted positions
+      let livePositions = 0;
+      for (const other of stakers) {
+        const position = await program.account.userStake.fetchNullable(accountsFor(other).userStake);
+        if (position && position.amount.gtn(0)) {
+          expect(position.isCounted).to.be.true;
+          livePositions++;
+        }
+      }
+      expect(poolAccount.totalUsers.toNumber() - usersBefore).to.equal(livePositions);
+    }
+  });
+
+  it("Counts a user once across stake, unstake, stake, unstake", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const accounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const usersBefore = (await program.account.pool.fetch(pool)).totalUsers.toNumber();
+
+    for (let round = 0; round < 2; round++) {
+      await program.methods
+        .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+        .accounts({
+          ...accounts,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([staker])
+        .rpc();
+
+      let poolAccount = await program.account.pool.fetch(pool);
+      expect(poolAccount.totalUsers.toNumber()).to.equal(usersBefore + 1);
+
+      const position = await program.account.userStake.fetch(stakerStake);
+      await program.methods
+        .unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts(accounts)
+        .signers([staker])
+        .rpc();
+
+      poolAccount = await program.account.pool.fetch(pool);
+      expect(poolAccount.totalUsers.toNumber()).to.equal(usersBefore);
+      expect((await program.account.userStake.fetch(stakerStake)).isCounted).to.be.false;
+    }
+  });
+
+  it("Reconciles vault lamports with principal, fee and penalty counters", async () => {
+    const reconcile = async () => {
+      const poolAccount = await program.account.pool.fetch(pool);
+      const vaultInfo = await provider.connection.getAccountInfo(poolVault);
+      const feeVaultInfo = await provider.connection.getAccountInfo(feeVault);
+      const rentExemptMinimum = await provider.connection.getMinimumBalanceForRentExemption(vaultInfo.data.length);
+      const expected = poolAccount.totalStaked
+        .add(poolAccount.totalPenaltiesCollected)
+        .addn(rentExemptMinimum);
+      expect(vaultInfo.lamports.toString()).to.equal(expected.toString());
+      expect(feeVaultInfo.lamports.toString()).to.equal(poolAccount.totalFeesCollected.addn(rentExemptMinimum).toString());
+      return poolAccount;
+    };
+
+    // The random sequence above includes early exits, so penalties have accrued
+    const poolAccount = await reconcile();
+    expect(poolAccount.totalPenaltiesCollected.toNumber()).to.be.greaterThan(0);
+
+    // Half of the penalties are shared with the stakers still in the pool
+    const shared = poolAccount.totalPenaltiesCollected.divn(2);
+    await program.methods
+      .distributeToStakers(new anchor.BN(0), shared)
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        rewardVault: rewardVault,
+      })
+      .signers([admin])
+      .rpc();
+
+    const afterDistribution = await reconcile();
+    expect(afterDistribution.rewardPerTokenStored.gtn(0)).to.be.true;
+    expect(afterDistribution.rewardReserve.sub(poolAccount.rewardReserve).toString()).to.equal(shared.toString());
+
+    await program.methods
+      .withdrawPenalties(afterDistribution.totalPenaltiesCollected)
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        treasury: admin.publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    const afterWithdrawal = await reconcile();
+    expect(afterWithdrawal.totalPenaltiesCollected.toNumber()).to.equal(0);
+    expect(afterWithdrawal.totalFeesCollected.toString()).to.equal(poolAccount.totalFeesCollected.toString());
+  });
+
+  it("Allows users to stake and unstake SPL tokens", async () => {
+    await program.methods
+      .initializeTokenVault()
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        stakeMint: stakeMint,
+        tokenVault: tokenVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([admin])
+      .rpc();
+
+    const amount = new anchor.BN(100_000_000); // 100 tokens
+    await program.methods
+      .stakeSpl(amount, new anchor.BN(30), netOfDepositFee(amount))
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        stakeMint: stakeMint,
+        userTokenAccount: user1TokenAccount,
+        tokenVault: tokenVault,
+        userStake: userTokenStake,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([user1])
+      .rpc();
+
+    const expectedFee = amount.mul(new anchor.BN(50)).div(new anchor.BN(10000));
+    const userStakeAccount = await program.account.userStake.fetch(userTokenStake);
+    expect(userStakeAccount.amount.toNumber()).to.equal(amount.sub(expectedFee).toNumber());
+
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalTokenStaked.toNumber()).to.equal(amount.sub(expectedFee).toNumber());
+    expect(poolAccount.totalTokenFeesCollected.toNumber()).to.equal(expectedFee.toNumber());
+
+    const vault = await getAccount(provider.connection, tokenVault);
+    expect(Number(vault.amount)).to.equal(amount.toNumber());
+
+    await program.methods
+      .unstakeSpl(userStakeAccount.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: user1.publicKey,
+        pool: pool,
+        stakeMint: stakeMint,
+        userTokenAccount: user1TokenAccount,
+        tokenVault: tokenVault,
+        userStake: userTokenStake,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([user1])
+      .rpc();
+
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalTokenStaked.toNumber()).to.equal(0);
+  });
+
+  it("Rejects SPL stakes with a mismatched mint", async () => {
+    const otherMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    const otherTokenAccount = await createAccount(provider.connection, user2, otherMint, user2.publicKey);
+    await mintTo(provider.connection, admin, otherMint, otherTokenAccount, admin, 1_000_000_000);
+
+    try {
+      await program.methods
+        .stakeSpl(new anchor.BN(100_000_000), new anchor.BN(30), netOfDepositFee(100_000_000))
+        .accounts({
+          user: user2.publicKey,
+          pool: pool,
+          stakeMint: stakeMint,
+          userTokenAccount: otherTokenAccount,
+          tokenVault: tokenVault,
+          userStake: PublicKey.findProgramAddressSync(
+            [Buffer.from("user_token_stake"), poolIdSeed(0), stakeMint.toBuffer(), user2.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([user2])
+        .rpc();
+
+      expect.fail("Should have thrown an error for mismatched mint");
+    } catch (error) {
+      expect(error.message).to.include("InvalidMint");
+    }
+  });
+
+  it("Holds SPL stakes to token limits, quotes and exit floors without accruing SOL yield", async () => {
+    const splAccounts = {
+      user: user1.publicKey,
+      pool: pool,
+      stakeMint: stakeMint,
+      userTokenAccount: user1TokenAccount,
+      tokenVault: tokenVault,
+      userStake: userTokenStake,
+      tokenProgram: TOKEN_PROGRAM_ID,
+    };
+    const stakeAccounts = { ...splAccounts, systemProgram: SystemProgram.programId, rent: SYSVAR_RENT_PUBKEY };
+    const outcome = async (attempt: () => Promise<unknown>) => {
+      try {
+        await attempt();
+        return "ok";
+      } catch (error) {
+        return error.message as string;
+      }
+    };
+
+    await program.methods
+      .updateTokenStakeLimits(new anchor.BN(50_000_000), new anchor.BN(200_000_000))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    const small = new anchor.BN(10_000_000);
+    const tooSmall = await outcome(() =>
+      program.methods.stakeSpl(small, new anchor.BN(30), netOfDepositFee(small)).accounts(stakeAccounts).signers([user1]).rpc()
+    );
+    expect(tooSmall).to.include("AmountTooSmall");
+
+    const large = new anchor.BN(300_000_000);
+    const tooLarge = await outcome(() =>
+      program.methods.stakeSpl(large, new anchor.BN(30), netOfDepositFee(large)).accounts(stakeAccounts).signers([user1]).rpc()
+    );
+    expect(tooLarge).to.include("AmountTooLarge");
+
+    // A quote one token unit above what the fee leaves is refused
+    const amount = new anchor.BN(100_000_000);
+    const overQuoted = await outcome(() =>
+      program.methods
+        .stakeSpl(amount, new anchor.BN(30), netOfDepositFee(amount).addn(1))
+        .accounts(stakeAccounts)
+        .signers([user1])
+        .rpc()
+    );
+    expect(overQuoted).to.include("SlippageExceeded");
+
+    // Two deposits merge into one position that never accrues SOL yield
+    for (let i = 0; i < 2; i++) {
+      await program.methods
+        .stakeSpl(amount, new anchor.BN(30), netOfDepositFee(amount))
+        .accounts(stakeAccounts)
+        .signers([user1])
+        .rpc();
+    }
+    const position = await program.account.userStake.fetch(userTokenStake);
+    expect(position.amount.toString()).to.equal(netOfDepositFee(amount).muln(2).toString());
+    expect(position.lockedApyBps.toNumber()).to.equal(0);
+    expect(position.rewardsOwed.toNumber()).to.equal(0);
+
+    // Exits honour the remainder minimum, the payout floor and the deadline
+    const remainderTooSmall = await outcome(() =>
+      program.methods
+        .unstakeSpl(position.amount.subn(1), NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts(splAccounts)
+        .signers([user1])
+        .rpc()
+    );
+    expect(remainderTooSmall).to.include("RemainderTooSmall");
+
+    const floorMissed = await outcome(() =>
+      program.methods
+        .unstakeSpl(position.amount, NO_DEADLINE, position.amount.addn(1))
+        .accounts(splAccounts)
+        .signers([user1])
+        .rpc()
+    );
+    expect(floorMissed).to.include("SlippageExceeded");
+
+    const expired = await outcome(() =>
+      program.methods
+        .unstakeSpl(position.amount, new anchor.BN(Math.floor(Date.now() / 1000) - 3600), NO_MIN_PAYOUT)
+        .accounts(splAccounts)
+        .signers([user1])
+        .rpc()
+    );
+    expect(expired).to.include("TransactionExpired");
+
+    await program.methods
+      .unstakeSpl(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(splAccounts)
+      .signers([user1])
+      .rpc();
+    expect((await program.account.pool.fetch(pool)).totalTokenStaked.toNumber()).to.equal(0);
+
+    await program.methods
+      .updateTokenStakeLimits(new anchor.BN(1), new anchor.BN("18446744073709551615"))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Creates and updates receipt token metadata", async () => {
+    const [metadata] = PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), receiptMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+    const metadataAccounts = {
+      admin: admin.publicKey,
+      pool: pool,
+      receiptMint: receiptMint,
+      metadata: metadata,
+      tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+
+    await program.methods
+      .createReceiptMetadata("Trust Fund Stake", "tfSOL", "https://example.com/tfsol.json")
+      .accounts(metadataAccounts)
+      .signers([admin])
+      .rpc();
+
+    // Metadata layout: key (1) + update authority (32) + mint (32) + name + symbol + uri
+    let info = await provider.connection.getAccountInfo(metadata);
+    expect(new PublicKey(info.data.slice(1, 33)).toString()).to.equal(pool.toString());
+    expect(new PublicKey(info.data.slice(33, 65)).toString()).to.equal(receiptMint.toString());
+    let [name, offset] = readMetadataString(info.data, 65);
+    let [symbol, uriOffset] = readMetadataString(info.data, offset);
+    let [uri] = readMetadataString(info.data, uriOffset);
+    expect(name).to.equal("Trust Fund Stake");
+    expect(symbol).to.equal("tfSOL");
+    expect(uri).to.equal("https://example.com/tfsol.json");
+
+    try {
+      await program.methods
+        .createReceiptMetadata("Trust Fund Stake", "tfSOL", "https://example.com/tfsol.json")
+        .accounts(metadataAccounts)
+        .signers([admin])
+        .rpc();
+
+      expect.fail("Should have thrown an error for existing metadata");
+    } catch (error) {
+      expect(error.message).to.include("MetadataAlreadyExists");
+    }
+
+    await program.methods
+      .updateReceiptMetadata("Trust Fund Stake v2", "tfSOL", "https://example.com/tfsol-v2.json")
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+        receiptMint: receiptMint,
+        metadata: metadata,
+        tokenMetadataProgram: TOKEN_METADATA_PROGRAM_ID,
+      })
+      .signers([admin])
+      .rpc();
+
+    info = await provider.connection.getAccountInfo(metadata);
+    [name] = readMetadataString(info.data, 65);
+    expect(name).to.equal("Trust Fund Stake v2");
+  });
+  it("Keeps independent stakes in two pools with different APYs", async () => {
+    const [secondPool] = PublicKey.findProgramAddressSync([Buffer.from("pool"), poolIdSeed(1)], program.programId);
+    const [secondPoolVault] = PublicKey.findProgramAddressSync([Buffer.from("pool_vault"), poolIdSeed(1)], program.programId);
+    const [secondFeeVault] = PublicKey.findProgramAddressSync([Buffer.from("fee_vault"), poolIdSeed(1)], program.programId);
+    const [secondReceiptMint] = PublicKey.findProgramAddressSync([Buffer.from("receipt_mint"), poolIdSeed(1)], program.programId);
+    const [secondUserStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(1), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const initAccounts = {
+      admin: admin.publicKey,
+      poolRegistry: poolRegistry,
+      pool: secondPool,
+      poolVault: secondPoolVault,
+      rewardVault: PublicKey.findProgramAddressSync([Buffer.from("reward_vault"), poolIdSeed(1)], program.programId)[0],
+      feeVault: secondFeeVault,
+      receiptMint: secondReceiptMint,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+
+    // Ids are assigned in order, so a pool cannot skip ahead of the registry
+    try {
+      const [skippedPool] = PublicKey.findProgramAddressSync([Buffer.from("pool"), poolIdSeed(5)], program.programId);
+      await program.methods
+        .initializePool(new anchor.BN(5), 2000, 1, 365, null, [], [])
+        .accounts({
+          ...initAccounts,
+          pool: skippedPool,
+          poolVault: PublicKey.findProgramAddressSync([Buffer.from("pool_vault"), poolIdSeed(5)], program.programId)[0],
+          rewardVault: PublicKey.findProgramAddressSync([Buffer.from("reward_vault"), poolIdSeed(5)], program.programId)[0],
+          feeVault: PublicKey.findProgramAddressSync([Buffer.from("fee_vault"), poolIdSeed(5)], program.programId)[0],
+          receiptMint: PublicKey.findProgramAddressSync([Buffer.from("receipt_mint"), poolIdSeed(5)], program.programId)[0],
+        })
+        .signers([admin])
+        .rpc();
+
+      expect.fail("Should have thrown an error for an out-of-order pool id");
+    } catch (error) {
+      expect(error.message).to.include("InvalidPoolId");
+    }
+
+    await program.methods
+      .initializePool(new anchor.BN(1), 2000, 1, 365, null, [], [])
+      .accounts(initAccounts)
+      .signers([admin])
+      .rpc();
+
+    const registryAccount = await program.account.poolRegistry.fetch(poolRegistry);
+    expect(registryAccount.totalPools.toNumber()).to.equal(2);
+    expect(registryAccount.nextPoolId.toNumber()).to.equal(2);
+
+    await program.methods
+      .fundRewards(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 10))
+      .accounts({
+        admin: admin.publicKey,
+        pool: secondPool,
+        rewardVault: initAccounts.rewardVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    const firstStakeBefore = await program.account.userStake.fetch(userStake);
+
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        user: user1.publicKey,
+        pool: secondPool,
+        poolVault: secondPoolVault,
+        feeVault: secondFeeVault,
+        userStake: secondUserStake,
+        receiptMint: secondReceiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(secondReceiptMint, user1.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([user1])
+      .rpc();
+
+    // The second pool has no tiers, so its base APY is locked in
+    const secondStake = await program.account.userStake.fetch(secondUserStake);
+    expect(secondStake.lockedApyBps.toNumber()).to.equal(2000);
+
+    const firstStakeAfter = await program.account.userStake.fetch(userStake);
+    expect(firstStakeAfter.amount.toString()).to.equal(firstStakeBefore.amount.toString());
+    expect(firstStakeAfter.lockedApyBps.toString()).to.equal(firstStakeBefore.lockedApyBps.toString());
+
+    const secondPoolAccount = await program.account.pool.fetch(secondPool);
+    expect(secondPoolAccount.poolId.toNumber()).to.equal(1);
+    expect(secondPoolAccount.totalStaked.toString()).to.equal(secondStake.amount.toString());
+  });
+  it("Transfers a whole position to a new wallet", async () => {
+    const oldWallet = Keypair.generate();
+    const newWallet = Keypair.generate();
+    await provider.connection.requestAirdrop(oldWallet.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const stakeFor = (owner: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), owner.toBuffer()],
+        program.programId
+      )[0];
+    const transferAccounts = (newOwner: PublicKey) => ({
+      user: oldWallet.publicKey,
+      newOwner: newOwner,
+      pool: pool,
+      userStake: stakeFor(oldWallet.publicKey),
+      destinationStake: stakeFor(newOwner),
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, oldWallet.publicKey),
+      destinationReceiptAccount: getAssociatedTokenAddressSync(receiptMint, newOwner),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    });
+
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(60), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        user: oldWallet.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakeFor(oldWallet.publicKey),
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, oldWallet.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([oldWallet])
+      .rpc();
+
+    const source = await program.account.userStake.fetch(stakeFor(oldWallet.publicKey));
+    const usersBefore = (await program.account.pool.fetch(pool)).totalUsers.toNumber();
+
+    // Positions are never merged, so a wallet that already stakes cannot receive one
+    try {
+      await program.methods
+        .transferStake()
+        .accounts(transferAccounts(user1.publicKey))
+        .signers([oldWallet])
+        .rpc();
+      expect.fail("Should have thrown an error for an occupied destination");
+    } catch (error) {
+      expect(error.message).to.include("DestinationHasStake");
+    }
+
+    await program.methods
+      .transferStake()
+      .accounts(transferAccounts(newWallet.publicKey))
+      .signers([oldWallet])
+      .rpc({ commitment: "confirmed" });
+
+    const destination = await program.account.userStake.fetch(stakeFor(newWallet.publicKey), "confirmed");
+    expect(destination.user.toString()).to.equal(newWallet.publicKey.toString());
+    expect(destination.amount.toString()).to.equal(source.amount.toString());
+    expect(destination.committedDays.toNumber()).to.equal(60);
+    expect(destination.stakeTimestamp.toString()).to.equal(source.stakeTimestamp.toString());
+
+    expect(await provider.connection.getAccountInfo(stakeFor(oldWallet.publicKey), "confirmed")).to.be.null;
+    const receipts = await getAccount(
+      provider.connection,
+      getAssociatedTokenAddressSync(receiptMint, newWallet.publicKey),
+      "confirmed"
+    );
+    expect(receipts.amount.toString()).to.equal(source.amount.toString());
+
+    const poolAccount = await program.account.pool.fetch(pool, "confirmed");
+    expect(poolAccount.totalUsers.toNumber()).to.equal(usersBefore);
+  });
+  it("Credits referrers with a share of the deposit fee", async () => {
+    const referee = Keypair.generate();
+    await provider.connection.requestAirdrop(referee.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [referralAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("referral"), poolIdSeed(0), user2.publicKey.toBuffer()],
+      program.programId
+    );
+    const stakeAccounts = {
+      user: referee.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), referee.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, referee.publicKey),
+      referralAccount: referralAccount,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+    await program.methods
+      .registerReferrer()
+      .accounts({
+        referrer: user2.publicKey,
+        pool: pool,
+        referralAccount: referralAccount,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user2])
+      .rpc();
+
+    await program.methods
+      .updateReferralShare(new anchor.BN(5000))
+      .accounts({
+        admin: admin.publicKey,
+        pool: pool,
+      })
+      .signers([admin])
+      .rpc();
+
+    try {
+      await program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), referee.publicKey)
+        .accounts({ ...stakeAccounts, referralAccount: null })
+        .signers([referee])
+        .rpc();
+      expect.fail("Should have thrown an error for self-referral");
+    } catch (error) {
+      expect(error.message).to.include("SelfReferral");
+    }
+
+    await program.methods
+      .stake(amount, new anchor.BN(30), netOfDepositFee(amount), user2.publicKey)
+      .accounts(stakeAccounts)
+      .signers([referee])
+      .rpc();
+
+    const fee = amount.muln(50).divn(10000);
+    let referral = await program.account.referralAccount.fetch(referralAccount);
+    expect(referral.pendingRewards.toString()).to.equal(fee.divn(2).toString());
+    expect(referral.totalReferredVolume.toString()).to.equal(amount.toString());
+
+    // The referrer is fixed after the first stake
+    try {
+      await program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), user1.publicKey)
+        .accounts(stakeAccounts)
+        .signers([referee])
+        .rpc();
+      expect.fail("Should have thrown an error for changing the referrer");
+    } catch (error) {
+      expect(error.message).to.include("ReferrerAlreadySet");
+    }
+
+    const feesBefore = (await program.account.pool.fetch(pool)).totalFeesCollected;
+
+    await program.methods
+      .claimReferralRewards()
+      .accounts({
+        referrer: user2.publicKey,
+        pool: pool,
+        feeVault: feeVault,
+        referralAccount: referralAccount,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user2])
+      .rpc();
+
+    referral = await program.account.referralAccount.fetch(referralAccount);
+    expect(referral.pendingRewards.toNumber()).to.equal(0);
+    expect(referral.totalRewardsClaimed.toString()).to.equal(fee.divn(2).toString());
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.totalFeesCollected.toString()).to.equal(feesBefore.sub(fee.divn(2)).toString());
+    expect(poolAccount.totalReferralRewardsPending.toNumber()).to.equal(0);
+  });
+  it("Gates new stakes by whitelist or blacklist but never blocks exits", async () => {
+    const existing = Keypair.generate();
+    const newcomer = Keypair.generate();
+    for (const wallet of [existing, newcomer]) {
+      await provider.connection.requestAirdrop(wallet.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    }
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const accessEntryFor = (wallet: Keypair) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("access"), poolIdSeed(0), wallet.publicKey.toBuffer()],
+        program.programId
+      )[0];
+    const stakeAccountsFor = (wallet: Keypair, withEntry: boolean) => ({
+      user: wallet.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), wallet.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, wallet.publicKey),
+      accessEntry: withEntry ? accessEntryFor(wallet) : null,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    });
+    const stakeAs = (wallet: Keypair, withEntry: boolean) =>
+      program.methods
+        .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+        .accounts(stakeAccountsFor(wallet, withEntry))
+        .signers([wallet])
+        .rpc();
+    const setMode = (mode: number) =>
+      program.methods
+        .updateAccessMode(mode)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const setAccess = (wallet: Keypair, whitelisted: boolean, blacklisted: boolean) =>
+      program.methods
+        .setAccess(whitelisted, blacklisted)
+        .accounts({
+          admin: admin.publicKey,
+          user: wallet.publicKey,
+          pool: pool,
+          accessEntry: accessEntryFor(wallet),
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([admin])
+        .rpc();
+
+    // Staked while the pool was open
+    await stakeAs(existing, false);
+
+    await setMode(1);
+    try {
+      await stakeAs(newcomer, true); // The entry has not been created yet
+      expect.fail("Should have thrown an error for a non-whitelisted user");
+    } catch (error) {
+      expect(error.message).to.include("NotWhitelisted");
+    }
+    try {
+      await stakeAs(newcomer, false);
+      expect.fail("Should have thrown an error for a stake without its access entry");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    await setAccess(newcomer, true, false);
+    await stakeAs(newcomer, true);
+
+    await setMode(2);
+    await setAccess(existing, false, true);
+    try {
+      await stakeAs(existing, true);
+      expect.fail("Should have thrown an error for a blacklisted user");
+    } catch (error) {
+      expect(error.message).to.include("Blacklisted");
+    }
+    // Leaving the entry out does not get a blacklisted wallet past the check
+    try {
+      await stakeAs(existing, false);
+      expect.fail("Should have thrown an error for a blacklisted user omitting the entry");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    // A blacklisted staker can still leave
+    const { accessEntry, associatedTokenProgram, rent, ...unstakeAccounts } = stakeAccountsFor(existing, true);
+    const position = await program.account.userStake.fetch(unstakeAccounts.userStake);
+    await program.methods
+      .unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(unstakeAccounts)
+      .signers([existing])
+      .rpc();
+
+    expect((await program.account.userStake.fetch(unstakeAccounts.userStake)).amount.toNumber()).to.equal(0);
+
+    await setMode(0);
+  });
+
+  it("Queues withdrawals behind the cooldown and allows cancelling", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const stakerReceiptAccount = getAssociatedTokenAddressSync(receiptMint, staker.publicKey);
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: stakerReceiptAccount,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const manageAccounts = { user: staker.publicKey, pool: pool, userStake: stakerStake };
+    const setWithdrawalSettings = (cooldownSeconds: number) =>
+      program.methods
+        .updateWithdrawalSettings(new anchor.BN(cooldownSeconds), true, new anchor.BN(0))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    await program.methods
+      .stake(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(2 * anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        ...unstakeAccounts,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const requestAmount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2);
+    await program.methods
+      .requestUnstake(requestAmount)
+      .accounts(manageAccounts)
+      .signers([staker])
+      .rpc();
+
+    try {
+      await program.methods
+        .requestUnstake(requestAmount)
+        .accounts(manageAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for a second request");
+    } catch (error) {
+      expect(error.message).to.include("PendingWithdrawalExists");
+    }
+
+    try {
+      await program.methods
+        .executeUnstake()
+        .accounts(unstakeAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error before the cooldown elapsed");
+    } catch (error) {
+      expect(error.message).to.include("CooldownNotElapsed");
+    }
+
+    // With no cooldown the request is immediately executable, yet can still be cancelled
+    await setWithdrawalSettings(0);
+    await program.methods
+      .cancelUnstakeRequest()
+      .accounts(manageAccounts)
+      .signers([staker])
+      .rpc();
+    expect((await program.account.userStake.fetch(stakerStake)).pendingWithdrawal).to.be.null;
+
+    await program.methods
+      .requestUnstake(requestAmount)
+      .accounts(manageAccounts)
+      .signers([staker])
+      .rpc();
+    const before = await program.account.userStake.fetch(stakerStake);
+    await program.methods
+      .executeUnstake()
+      .accounts(unstakeAccounts)
+      .signers([staker])
+      .rpc();
+
+    const after = await program.account.userStake.fetch(stakerStake);
+    expect(after.amount.toString()).to.equal(before.amount.sub(requestAmount).toString());
+    expect(after.pendingWithdrawal).to.be.null;
+
+    await setWithdrawalSettings(86400);
+  });
+
+  it("Rejects payouts beyond the daily outflow cap", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const setOutflowCap = (bps: number) =>
+      program.methods
+        .updateMaxDailyOutflow(new anchor.BN(bps))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        ...unstakeAccounts,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    // 0.01% of TVL per day is far below a full position
+    await setOutflowCap(1);
+    const position = await program.account.userStake.fetch(stakerStake);
+    try {
+      await program.methods
+        .unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts(unstakeAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for exceeding the outflow cap");
+    } catch (error) {
+      expect(error.message).to.include("DailyOutflowExceeded");
+    }
+
+    await setOutflowCap(10000);
+    await program.methods
+      .unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(unstakeAccounts)
+      .signers([staker])
+      .rpc();
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toNumber()).to.equal(0);
+  });
+
+  it("Stamps layout versions and refuses to migrate current accounts", async () => {
+    expect((await program.account.pool.fetch(pool)).version).to.equal(32);
+    expect((await program.account.userStake.fetch(userStake)).version).to.equal(14);
+
+    try {
+      await program.methods
+        .migratePool()
+        .accounts({
+          payer: admin.publicKey,
+          legacyAccount: pool,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for an already migrated pool");
+    } catch (error) {
+      expect(error.message).to.include("AccountVersionMismatch");
+    }
+
+    // A pool is not a position, so the discriminator check rejects it as well
+    try {
+      await program.methods
+        .migrateUserStake(new anchor.BN(0), null)
+        .accounts({
+          payer: admin.publicKey,
+          legacyAccount: pool,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for the wrong account type");
+    } catch (error) {
+      expect(error.message).to.include("AccountVersionMismatch");
+    }
+  });
+
+  it("Reports pending yields without mutating the position", async () => {
+    const before = await program.account.userStake.fetch(userStake);
+    const pending = await program.methods
+      .getPendingYields()
+      .accounts({ pool: pool, userStake: userStake })
+      .view();
+
+    // Less than a day has passed since the last claim, so nothing is owed yet
+    expect(pending.yields.toNumber()).to.equal(0);
+    expect(pending.daysUntilCommitmentMet.toNumber()).to.be.at.most(before.committedDays.toNumber());
+
+    const after = await program.account.userStake.fetch(userStake);
+    expect(after.lastClaimTimestamp.toString()).to.equal(before.lastClaimTimestamp.toString());
+  });
+
+  it("Stakes on behalf of a beneficiary who does not sign", async () => {
+    const beneficiary = Keypair.generate();
+    const [beneficiaryStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), beneficiary.publicKey.toBuffer()],
+      program.programId
+    );
+    const beneficiaryReceiptAccount = getAssociatedTokenAddressSync(receiptMint, beneficiary.publicKey);
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+    await program.methods
+      .stakeFor(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts({
+        payer: user2.publicKey,
+        beneficiary: beneficiary.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: beneficiaryStake,
+        receiptMint: receiptMint,
+        beneficiaryReceiptAccount: beneficiaryReceiptAccount,
+        referralAccount: null,
+        accessEntry: null,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([user2])
+      .rpc();
+
+    const position = await program.account.userStake.fetch(beneficiaryStake);
+    expect(position.user.toString()).to.equal(beneficiary.publicKey.toString());
+    expect(position.fundedBy.toString()).to.equal(user2.publicKey.toString());
+    expect(position.amount.toNumber()).to.be.greaterThan(0);
+
+    const receipts = await getAccount(provider.connection, beneficiaryReceiptAccount);
+    expect(receipts.amount.toString()).to.equal(position.amount.toString());
+  });
+
+  it("Sets loyalty tiers and rejects boosts above the cap", async () => {
+    await program.methods
+      .updateLoyaltyTiers([
+        { minLifetimeStaked: new anchor.BN(100 * anchor.web3.LAMPORTS_PER_SOL), boostBps: new anchor.BN(100) },
+        { minLifetimeStaked: new anchor.BN(1000 * anchor.web3.LAMPORTS_PER_SOL), boostBps: new anchor.BN(300) },
+      ])
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.loyaltyTiers.length).to.equal(2);
+    expect(poolAccount.loyaltyTiers[1].boostBps.toNumber()).to.equal(300);
+
+    try {
+      await program.methods
+        .updateLoyaltyTiers([
+          { minLifetimeStaked: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), boostBps: new anchor.BN(301) },
+        ])
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for a boost above the cap");
+    } catch (error) {
+      expect(error.message).to.include("InvalidLoyaltyTiers");
+    }
+
+    await program.methods
+      .updateLoyaltyTiers([])
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Rejects NFT boost claims backed by forged metadata", async () => {
+    const collection = Keypair.generate().publicKey;
+    await program.methods
+      .updateNftBoost(collection, new anchor.BN(200))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    // An NFT the user really holds, but with no Metaplex metadata behind it
+    const nftMint = await createMint(provider.connection, admin, admin.publicKey, null, 0);
+    const nftAccount = await createAccount(provider.connection, user1, nftMint, user1.publicKey);
+    await mintTo(provider.connection, admin, nftMint, nftAccount, admin, 1);
+    const [metadataPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("metadata"), TOKEN_METADATA_PROGRAM_ID.toBuffer(), nftMint.toBuffer()],
+      TOKEN_METADATA_PROGRAM_ID
+    );
+
+    // A look-alike account the user controls, and the real PDA with nothing at it
+    const forgedMetadata = Keypair.generate();
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: user1.publicKey,
+          newAccountPubkey: forgedMetadata.publicKey,
+          lamports: await provider.connection.getMinimumBalanceForRentExemption(679),
+          space: 679,
+          programId: SystemProgram.programId,
+        })
+      ),
+      [user1, forgedMetadata]
+    );
+
+    for (const metadata of [forgedMetadata.publicKey, metadataPda]) {
+      try {
+        await program.methods
+          .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+          .accounts({
+            user: user1.publicKey,
+            pool: pool,
+            rewardVault: rewardVault,
+            userStake: userStake,
+            systemProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([
+            { pubkey: nftAccount, isWritable: false, isSigner: false },
+            { pubkey: metadata, isWritable: false, isSigner: false },
+          ])
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have thrown an error for unverifiable NFT metadata");
+      } catch (error) {
+        expect(error.message).to.include("NftBoostVerificationFailed");
+      }
+    }
+
+    await program.methods
+      .updateNftBoost(null, new anchor.BN(0))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Creates a proposal, counts stake-weighted votes once and locks voters in", async () => {
+    const voter = Keypair.generate();
+    await provider.connection.requestAirdrop(voter.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [voterStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), voter.publicKey.toBuffer()],
+      program.programId
+    );
+    const voterAccounts = {
+      user: voter.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: voterStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, voter.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    await program.methods
+      .stake(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(2 * anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        ...voterAccounts,
+        referralAccount: null,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([voter])
+      .rpc();
+
+    await program.methods
+      .updateGovernanceSettings(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(1000))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    const proposalId = (await program.account.pool.fetch(pool)).nextProposalId;
+    const [proposal] = PublicKey.findProgramAddressSync(
+      [Buffer.from("proposal"), poolIdSeed(0), proposalId.toArrayLike(Buffer, "le", 8)],
+      program.programId
+    );
+    const [voteRecord] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vote"), poolIdSeed(0), proposalId.toArrayLike(Buffer, "le", 8), voter.publicKey.toBuffer()],
+      program.programId
+    );
+    const votingEndsAt = new anchor.BN(Math.floor(Date.now() / 1000) + 2 * 86400);
+
+    // Outside the governance range of 100-1000 bps
+    try {
+      await program.methods
+        .createProposal(0, new anchor.BN(1500), votingEndsAt)
+        .accounts({ proposer: voter.publicKey, pool: pool, userStake: voterStake, proposal: proposal })
+        .signers([voter])
+        .rpc();
+      expect.fail("Should have thrown an error for an out-of-range value");
+    } catch (error) {
+      expect(error.message).to.include("InvalidProposal");
+    }
+
+    await program.methods
+      .createProposal(0, new anchor.BN(300), votingEndsAt)
+      .accounts({ proposer: voter.publicKey, pool: pool, userStake: voterStake, proposal: proposal })
+      .signers([voter])
+      .rpc();
+
+    const voteAccounts = {
+      voter: voter.publicKey,
+      pool: pool,
+      proposal: proposal,
+      userStake: voterStake,
+      voteRecord: voteRecord,
+      systemProgram: SystemProgram.programId,
+    };
+    await program.methods.vote(true).accounts(voteAccounts).signers([voter]).rpc();
+
+    const position = await program.account.userStake.fetch(voterStake);
+    const counted = await program.account.proposal.fetch(proposal);
+    expect(counted.votesFor.toString()).to.equal(position.amount.toString());
+    expect(position.voteLockedUntil.toString()).to.equal(votingEndsAt.toString());
+
+    try {
+      await program.methods.vote(false).accounts(voteAccounts).signers([voter]).rpc();
+      expect.fail("Should have thrown an error for a second vote");
+    } catch (error) {
+      expect(error.message).to.include("already in use");
+    }
+
+    try {
+      await program.methods.unstake(position.amount, NO_DEADLINE, NO_MIN_PAYOUT).accounts(voterAccounts).signers([voter]).rpc();
+      expect.fail("Should have thrown an error for unstaking during the vote");
+    } catch (error) {
+      expect(error.message).to.include("VotesLocked");
+    }
+
+    try {
+      await program.methods.finalizeProposal().accounts({ pool: pool, proposal: proposal }).rpc();
+      expect.fail("Should have thrown an error before the execution delay");
+    } catch (error) {
+      expect(error.message).to.include("ExecutionDelayNotElapsed");
+    }
+  });
+
+  it("Rebalances reinvested fees into the reserve and caps leverage on new stakes", async () => {
+    // The crank runs at most once per interval; shorten it so the test can call it now
+    await program.methods
+      .updateRebalanceSettings(new anchor.BN(1), new anchor.BN(30))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const before = await program.account.pool.fetch(pool);
+    const due = before.feesAwaitingRebalance.mul(before.reinvestmentRate).divn(10000);
+
+    await program.methods
+      .rebalance()
+      .accounts({ pool: pool, feeVault: feeVault, rewardVault: rewardVault, insuranceVault: insuranceVault })
+      .rpc();
+
+    const after = await program.account.pool.fetch(pool);
+    expect(after.rewardReserve.toString()).to.equal(before.rewardReserve.add(due).toString());
+    expect(after.totalFeesCollected.toString()).to.equal(before.totalFeesCollected.sub(due).toString());
+    expect(after.feesAwaitingRebalance.toNumber()).to.equal(0);
+
+    try {
+      await program.methods
+        .updateLeverageRatio(new anchor.BN(6001))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for leverage above 6x");
+    } catch (error) {
+      expect(error.message).to.include("InvalidProposal");
+    }
+
+    // At the tightest cap of 2x this pool's stake already promises more than it can back
+    await program.methods
+      .updateLeverageRatio(new anchor.BN(2000))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    const poolAccount = await program.account.pool.fetch(pool);
+    const highestApy = Math.max(poolAccount.maxApy.toNumber(), ...poolAccount.apyTiers.map(tier => tier.apyBps.toNumber()));
+    const capacity = poolAccount.rewardReserve.muln(2).muln(10000).divn(highestApy);
+    const amount = capacity.sub(poolAccount.totalStaked).addn(1);
+
+    if (amount.lte(poolAccount.maxStakeAmount)) {
+      const stakeAmount = amount.lt(poolAccount.minStakeAmount) ? poolAccount.minStakeAmount : amount;
+      try {
+        await program.methods
+          .stake(stakeAmount, new anchor.BN(30), netOfDepositFee(stakeAmount), null)
+          .accounts({
+            user: user2.publicKey,
+            pool: pool,
+            poolVault: poolVault,
+            feeVault: feeVault,
+            userStake: PublicKey.findProgramAddressSync(
+              [Buffer.from("user_stake"), poolIdSeed(0), user2.publicKey.toBuffer()],
+              program.programId
+            )[0],
+            receiptMint: receiptMint,
+            userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, user2.publicKey),
+            referralAccount: null,
+            accessEntry: null,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+            rent: SYSVAR_RENT_PUBKEY,
+          })
+          .signers([user2])
+          .rpc();
+        expect.fail("Should have thrown an error for exceeding the leverage cap");
+      } catch (error) {
+        expect(error.message).to.include("LeverageCapExceeded");
+      }
+    }
+
+    await program.methods
+      .updateLeverageRatio(new anchor.BN(6000))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Rate-limits the rebalance crank and pauses the pool when the reserve runway is short", async () => {
+    const rebalance = () =>
+      program.methods
+        .rebalance()
+        .accounts({ pool: pool, feeVault: feeVault, rewardVault: rewardVault, insuranceVault: insuranceVault })
+        .rpc();
+    const setRebalanceSettings = (interval: number, minRunwayDays: number) =>
+      program.methods
+        .updateRebalanceSettings(new anchor.BN(interval), new anchor.BN(minRunwayDays))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    // The previous test has just rebalanced, so an hour-long interval has not passed
+    await setRebalanceSettings(3600, 30);
+    try {
+      await rebalance();
+      expect.fail("Should have thrown an error for rebalancing too early");
+    } catch (error) {
+      expect(error.message).to.include("RebalanceTooEarly");
+    }
+
+    // After the interval the crank applies and records when it ran
+    await setRebalanceSettings(1, 30);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    const before = await program.account.pool.fetch(pool);
+    await rebalance();
+    const after = await program.account.pool.fetch(pool);
+    expect(after.lastRebalanceTimestamp.toNumber()).to.be.greaterThan(before.lastRebalanceTimestamp.toNumber());
+    expect(after.pauseFlags).to.equal(0);
+
+    const highestApy = Math.max(after.maxApy.toNumber(), ...after.apyTiers.map(tier => tier.apyBps.toNumber()));
+    expect(after.projectedApy.toNumber()).to.be.at.most(highestApy);
+
+    // Demanding ten years of runway trips the automatic pause
+    await setRebalanceSettings(1, 3650);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    await rebalance();
+    const paused = await program.account.pool.fetch(pool);
+    expect(paused.pauseFlags).to.equal(PAUSE_ALL);
+    expect(paused.pausedBy.toString()).to.equal(pool.toString());
+    expect(paused.pauseExpiresAt.toNumber()).to.equal(0);
+
+    await program.methods
+      .emergencyUnpause()
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    await setRebalanceSettings(86400, 30);
+  });
+
+  it("Takes rate-limited pool snapshots and keeps them for the retention window", async () => {
+    const poolAccount = await program.account.pool.fetch(pool);
+    const epochIndex = poolAccount.snapshotCount;
+    const [snapshot] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("snapshot"),
+        poolAccount.poolId.toArrayLike(Buffer, "le", 8),
+        epochIndex.toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+
+    await program.methods
+      .takeSnapshot()
+      .accounts({
+        payer: user.publicKey,
+        pool: pool,
+        snapshot: snapshot,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
+
+    const snapshotAccount = await program.account.poolSnapshot.fetch(snapshot);
+    expect(snapshotAccount.epochIndex.toNumber()).to.equal(epochIndex.toNumber());
+    expect(snapshotAccount.payer.toString()).to.equal(user.publicKey.toString());
+    expect(snapshotAccount.totalStaked.toString()).to.equal(poolAccount.totalStaked.toString());
+    expect(snapshotAccount.rewardReserve.toString()).to.equal(poolAccount.rewardReserve.toString());
+    expect((await program.account.pool.fetch(pool)).snapshotCount.toNumber()).to.equal(epochIndex.toNumber() + 1);
+
+    // A second snapshot within the daily interval is rejected
+    const [nextSnapshot] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("snapshot"),
+        poolAccount.poolId.toArrayLike(Buffer, "le", 8),
+        epochIndex.addn(1).toArrayLike(Buffer, "le", 8),
+      ],
+      program.programId
+    );
+    try {
+      await program.methods
+        .takeSnapshot()
+        .accounts({
+          payer: user.publicKey,
+          pool: pool,
+          snapshot: nextSnapshot,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+      expect.fail("Should have thrown an error for snapshotting too early");
+    } catch (error) {
+      expect(error.message).to.include("SnapshotTooEarly");
+    }
+
+    // The newest snapshot is inside the retention window and cannot be pruned
+    try {
+      await program.methods
+        .closeSnapshot()
+        .accounts({ pool: pool, snapshot: snapshot, payer: user.publicKey })
+        .rpc();
+      expect.fail("Should have thrown an error for pruning a retained snapshot");
+    } catch (error) {
+      expect(error.message).to.include("SnapshotRetained");
+    }
+  });
+
+  it("Delays stakes and unstakes once a slot's volume reaches the threshold", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const setMevSettings = (threshold: number, minBlockDelay: number) =>
+      program.methods
+        .updateMevSettings(new anchor.BN(threshold), new anchor.BN(minBlockDelay))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    // A 1 SOL stake fills a 0.5 SOL slot allowance and holds the next operations back
+    await setMevSettings(anchor.web3.LAMPORTS_PER_SOL / 2, 10);
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        ...unstakeAccounts,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const withdrawAmount = new anchor.BN(0.1 * anchor.web3.LAMPORTS_PER_SOL);
+    try {
+      await program.methods
+        .unstake(withdrawAmount, NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts(unstakeAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for unstaking inside the block delay");
+    } catch (error) {
+      expect(error.message).to.include("BlockDelayActive");
+    }
+
+    // A later slot past the delay goes through
+    await new Promise(resolve => setTimeout(resolve, 5000));
+    const before = await program.account.userStake.fetch(stakerStake);
+    await program.methods
+      .unstake(withdrawAmount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(unstakeAccounts)
+      .signers([staker])
+      .rpc();
+    const after = await program.account.userStake.fetch(stakerStake);
+    expect(after.amount.toString()).to.equal(before.amount.sub(withdrawAmount).toString());
+
+    await setMevSettings(10 * anchor.web3.LAMPORTS_PER_SOL, 2);
+  });
+
+  it("Reverts a stake when the deposit fee moves after the quote", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const quotedNet = netOfDepositFee(amount); // Quoted at the 0.5% fee
+    const stakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      accessEntry: null,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    };
+    const setDepositFee = (feeBps: number) =>
+      program.methods
+        .updateDepositFee(new anchor.BN(feeBps))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const expectStakeToFail = async (minExpected: anchor.BN, errorName: string) => {
+      try {
+        await program.methods
+          .stake(amount, new anchor.BN(30), minExpected, null)
+          .accounts(stakeAccounts)
+          .signers([staker])
+          .rpc();
+        expect.fail(`Should have thrown ${errorName}`);
+      } catch (error) {
+        expect(error.message).to.include(errorName);
+      }
+    };
+
+    // A small raise stays inside the 1% tolerance but lands below the quoted floor
+    await setDepositFee(100);
+    await expectStakeToFail(quotedNet, "SlippageExceeded");
+
+    // A large raise is outside the tolerance altogether
+    await setDepositFee(500);
+    await expectStakeToFail(quotedNet, "FeeChangedSinceQuote");
+
+    // A zero floor does not switch the check off
+    await setDepositFee(50);
+    await expectStakeToFail(new anchor.BN(0), "FeeChangedSinceQuote");
+
+    await program.methods
+      .stake(amount, new anchor.BN(30), quotedNet, null)
+      .accounts(stakeAccounts)
+      .signers([staker])
+      .rpc();
+    expect((await program.account.userStake.fetch(stakeAccounts.userStake)).amount.toString())
+      .to.equal(amount.sub(amount.muln(50).divn(10000)).toString());
+  });
+
+  it("Rejects unstakes and claims past their deadline or below their payout floor", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), netOfDepositFee(anchor.web3.LAMPORTS_PER_SOL), null)
+      .accounts({
+        ...unstakeAccounts,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const expired = new anchor.BN(Math.floor(Date.now() / 1000) - 60);
+    const withdrawAmount = new anchor.BN(0.1 * anchor.web3.LAMPORTS_PER_SOL);
+    try {
+      await program.methods
+        .unstake(withdrawAmount, expired, NO_MIN_PAYOUT)
+        .accounts(unstakeAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for an expired unstake");
+    } catch (error) {
+      expect(error.message).to.include("TransactionExpired");
+    }
+
+    try {
+      await program.methods
+        .claimYields(expired, NO_MIN_PAYOUT)
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          rewardVault: rewardVault,
+          userStake: stakerStake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for an expired claim");
+    } catch (error) {
+      expect(error.message).to.include("TransactionExpired");
+    }
+
+    // The early-exit penalty makes the payout smaller than the withdrawn principal
+    try {
+      await program.methods
+        .unstake(withdrawAmount, NO_DEADLINE, withdrawAmount)
+        .accounts(unstakeAccounts)
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for a payout below the floor");
+    } catch (error) {
+      expect(error.message).to.include("SlippageExceeded");
+    }
+
+    const before = await program.account.userStake.fetch(stakerStake);
+    const deadline = new anchor.BN(Math.floor(Date.now() / 1000) + 600);
+    await program.methods
+      .unstake(withdrawAmount, deadline, NO_MIN_PAYOUT)
+      .accounts(unstakeAccounts)
+      .signers([staker])
+      .rpc();
+    const after = await program.account.userStake.fetch(stakerStake);
+    expect(after.amount.toString()).to.equal(before.amount.sub(withdrawAmount).toString());
+  });
+
+  it("Rejects a stake and unstake composed into one transaction", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+    const stakeIx = await program.methods
+      .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts({
+        ...unstakeAccounts,
+        accessEntry: null,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .instruction();
+    const unstakeIx = await program.methods
+      .unstake(netOfDepositFee(amount), NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(unstakeAccounts)
+      .instruction();
+
+    try {
+      await provider.sendAndConfirm(new anchor.web3.Transaction().add(stakeIx, unstakeIx), [staker]);
+      expect.fail("Should have thrown an error for exiting in the staking slot");
+    } catch (error) {
+      expect((error.logs ?? []).join("\n")).to.include("SameSlotOperation");
+    }
+    expect(await provider.connection.getAccountInfo(stakerStake)).to.be.null;
+  });
+
+  it("Charges large deposits the fee of the tier they reach", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const setFeeTiers = (tiers: { minAmount: anchor.BN; feeBps: anchor.BN }[]) =>
+      program.methods
+        .updateFeeTiers(tiers)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    // Thresholds must increase
+    try {
+      await setFeeTiers([
+        { minAmount: new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL), feeBps: new anchor.BN(30) },
+        { minAmount: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), feeBps: new anchor.BN(20) },
+      ]);
+      expect.fail("Should have thrown an error for unordered fee tiers");
+    } catch (error) {
+      expect(error.message).to.include("InvalidFeeTiers");
+    }
+
+    // Deposits of 1 SOL or more pay 0.2% instead of the 0.5% base fee
+    await setFeeTiers([{ minAmount: new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), feeBps: new anchor.BN(20) }]);
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const expectedNet = amount.sub(amount.muln(20).divn(10000));
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(amount, new anchor.BN(30), expectedNet, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toString()).to.equal(expectedNet.toString());
+
+    await setFeeTiers([]);
+  });
+
+  it("Stakes from and unstakes to a wSOL account like native SOL", async () => {
+    const nativeStaker = Keypair.generate();
+    const wsolStaker = Keypair.generate();
+    for (const staker of [nativeStaker, wsolStaker]) {
+      await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    }
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const stakeAccounts = (staker: Keypair) => ({
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      accessEntry: null,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+      rent: SYSVAR_RENT_PUBKEY,
+    });
+
+    await program.methods
+      .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts(stakeAccounts(nativeStaker))
+      .signers([nativeStaker])
+      .rpc();
+
+    const wsolAccount = await createWrappedNativeAccount(
+      provider.connection,
+      wsolStaker,
+      wsolStaker.publicKey,
+      2 * anchor.web3.LAMPORTS_PER_SOL
+    );
+    const [unwrapAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("wsol_unwrap"), poolIdSeed(0), wsolStaker.publicKey.toBuffer()],
+      program.programId
+    );
+    const vaultBefore = await provider.connection.getBalance(poolVault);
+    await program.methods
+      .stakeWsol(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts({
+        stake: stakeAccounts(wsolStaker),
+        userWsolAccount: wsolAccount,
+        unwrapAccount: unwrapAccount,
+        nativeMint: NATIVE_MINT,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([wsolStaker])
+      .rpc();
+
+    // Same position as the native stake, the vault holds only the net amount and the
+    // temporary account is gone
+    const nativeStake = await program.account.userStake.fetch(stakeAccounts(nativeStaker).userStake);
+    const wsolStake = await program.account.userStake.fetch(stakeAccounts(wsolStaker).userStake);
+    expect(wsolStake.amount.toString()).to.equal(nativeStake.amount.toString());
+    expect(wsolStake.committedDays.toString()).to.equal(nativeStake.committedDays.toString());
+    expect(wsolStake.lockedApyBps.toString()).to.equal(nativeStake.lockedApyBps.toString());
+    expect(await provider.connection.getBalance(poolVault)).to.equal(vaultBefore + netOfDepositFee(amount).toNumber());
+    expect((await getAccount(provider.connection, wsolAccount)).amount.toString()).to.equal(
+      String(anchor.web3.LAMPORTS_PER_SOL)
+    );
+    expect(await provider.connection.getAccountInfo(unwrapAccount)).to.be.null;
+
+    // Paid back into the wSOL account as a wrapped balance
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    const { accessEntry, associatedTokenProgram, rent, ...unstakeAccounts } = stakeAccounts(wsolStaker);
+    await program.methods
+      .unstakeToWsol(netOfDepositFee(amount), NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({ unstake: unstakeAccounts, userWsolAccount: wsolAccount })
+      .signers([wsolStaker])
+      .rpc();
+
+    const wrapped = (await getAccount(provider.connection, wsolAccount)).amount;
+    expect(Number(wrapped)).to.be.greaterThan(anchor.web3.LAMPORTS_PER_SOL);
+  });
+
+  it("Stakes another token through the swap router and only the SOL it returns", async () => {
+    const swapper = Keypair.generate();
+    await provider.connection.requestAirdrop(swapper.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    // The mock router sells the input token into its reserve at a price fixed per route
+    const inputMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    const inputAccount = await createAccount(provider.connection, swapper, inputMint, swapper.publicKey);
+    await mintTo(provider.connection, admin, inputMint, inputAccount, admin, 1_000_000_000);
+    const [reserveAuthority] = PublicKey.findProgramAddressSync([Buffer.from("reserve")], SWAP_PROGRAM_ID);
+    const inputReserve = await createAccount(
+      provider.connection,
+      admin,
+      inputMint,
+      reserveAuthority,
+      Keypair.generate()
+    );
+    const wsolReserve = await createWrappedNativeAccount(
+      provider.connection,
+      admin,
+      reserveAuthority,
+      3 * anchor.web3.LAMPORTS_PER_SOL,
+      Keypair.generate()
+    );
+
+    const [swapOutputAccount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("swap_output"), poolIdSeed(0), swapper.publicKey.toBuffer()],
+      program.programId
+    );
+    const userStakeAddress = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), swapper.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const inputAmount = new anchor.BN(500_000_000);
+    const solOut = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const route = (amountIn: anchor.BN, amountOut: anchor.BN) =>
+      Buffer.concat([amountIn.toArrayLike(Buffer, "le", 8), amountOut.toArrayLike(Buffer, "le", 8)]);
+    const swapAndStake = (minSolOut: anchor.BN) =>
+      program.methods
+        .stakeWithSwap(inputAmount, minSolOut, route(inputAmount, solOut), new anchor.BN(30), netOfDepositFee(solOut), null)
+        .accounts({
+          stake: {
+            user: swapper.publicKey,
+            pool: pool,
+            poolVault: poolVault,
+            feeVault: feeVault,
+            userStake: userStakeAddress,
+            accessEntry: null,
+            receiptMint: receiptMint,
+            userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, swapper.publicKey),
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+            rent: SYSVAR_RENT_PUBKEY,
+          },
+          userInputAccount: inputAccount,
+          swapOutputAccount: swapOutputAccount,
+          nativeMint: NATIVE_MINT,
+          swapProgram: SWAP_PROGRAM_ID,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .remainingAccounts([
+          { pubkey: swapper.publicKey, isSigner: true, isWritable: true },
+          { pubkey: inputAccount, isSigner: false, isWritable: true },
+          { pubkey: inputReserve, isSigner: false, isWritable: true },
+          { pubkey: wsolReserve, isSigner: false, isWritable: true },
+          { pubkey: swapOutputAccount, isSigner: false, isWritable: true },
+          { pubkey: reserveAuthority, isSigner: false, isWritable: false },
+          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        ])
+        .signers([swapper]);
+
+    // A route paying less than the floor reverts the swap along with the stake
+    try {
+      await swapAndStake(solOut.addn(1)).rpc();
+      expect.fail("Should have thrown an error for a swap below min_sol_out");
+    } catch (error) {
+      expect(error.message).to.include("SwapSlippageExceeded");
+    }
+    expect((await getAccount(provider.connection, inputAccount)).amount.toString()).to.equal("1000000000");
+
+    const vaultBefore = await provider.connection.getBalance(poolVault);
+    const signature = await swapAndStake(solOut).rpc({ commitment: "confirmed" });
+
+    // The position and vault see only the SOL received, and the output account is gone
+    const position = await program.account.userStake.fetch(userStakeAddress);
+    expect(position.amount.toString()).to.equal(netOfDepositFee(solOut).toString());
+    expect(await provider.connection.getBalance(poolVault)).to.equal(vaultBefore + netOfDepositFee(solOut).toNumber());
+    expect(await provider.connection.getAccountInfo(swapOutputAccount)).to.be.null;
+    expect((await getAccount(provider.connection, inputAccount)).amount.toString()).to.equal("500000000");
+
+    const transaction = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    const eventParser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    const swapEvent = [...eventParser.parseLogs(transaction.meta.logMessages)].find(
+      event => event.name === "SwapAndStakeEvent"
+    );
+    expect(swapEvent.data.inputMint.toString()).to.equal(inputMint.toString());
+    expect(swapEvent.data.inputAmount.toString()).to.equal(inputAmount.toString());
+    expect(swapEvent.data.solReceived.toString()).to.equal(solOut.toString());
+  });
+
+  it("Credits yields to a full batch of positions within the compute budget", async () => {
+    const MAX_CREDIT_BATCH = 16;
+    const stakers = Array.from({ length: MAX_CREDIT_BATCH + 1 }, () => Keypair.generate());
+    await Promise.all(
+      stakers.map(staker => provider.connection.requestAirdrop(staker.publicKey, 2 * anchor.web3.LAMPORTS_PER_SOL))
+    );
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const positions = stakers.map(staker =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+        program.programId
+      )[0]
+    );
+    await Promise.all(
+      stakers.map((staker, index) =>
+        program.methods
+          .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+          .accounts({
+            user: staker.publicKey,
+            pool: pool,
+            poolVault: poolVault,
+            feeVault: feeVault,
+            userStake: positions[index],
+            accessEntry: null,
+            receiptMint: receiptMint,
+            userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+            tokenProgram: TOKEN_PROGRAM_ID,
+            associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+            rent: SYSVAR_RENT_PUBKEY,
+          })
+          .signers([staker])
+          .rpc()
+      )
+    );
+
+    const asRemaining = (keys: PublicKey[]) => keys.map(pubkey => ({ pubkey, isSigner: false, isWritable: true }));
+    const keeper = Keypair.generate();
+    await provider.connection.requestAirdrop(keeper.publicKey, anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    try {
+      await program.methods
+        .batchCreditYields()
+        .accounts({ keeper: keeper.publicKey, pool: pool })
+        .remainingAccounts(asRemaining(positions))
+        .signers([keeper])
+        .rpc();
+      expect.fail("Should have thrown an error for an oversized batch");
+    } catch (error) {
+      expect(error.message).to.include("BatchTooLarge");
+    }
+
+    // Non-position accounts in the batch are skipped rather than failing it
+    const signature = await program.methods
+      .batchCreditYields()
+      .accounts({ keeper: keeper.publicKey, pool: pool })
+      .remainingAccounts(asRemaining([...positions.slice(0, MAX_CREDIT_BATCH - 1), poolVault]))
+      .signers([keeper])
+      .rpc({ commitment: "confirmed" });
+    const transaction = await provider.connection.getTransaction(signature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    });
+    expect(transaction.meta.computeUnitsConsumed).to.be.lessThan(200_000);
+
+    // Even fresh positions are credited for the seconds since they were opened
+    const position = await program.account.userStake.fetch(positions[0]);
+    expect(position.pendingYields.toNumber()).to.be.greaterThan(0);
+    await program.methods
+      .withdrawCreditedYields()
+      .accounts({
+        user: stakers[0].publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        userStake: positions[0],
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([stakers[0]])
+      .rpc();
+    const withdrawn = await program.account.userStake.fetch(positions[0]);
+    expect(withdrawn.pendingYields.toNumber()).to.equal(0);
+  });
+
+  it("Sets aside a share of deposit fees for insurance and pays claims from it", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 10 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    await program.methods
+      .updateInsuranceShare(new anchor.BN(2000))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const before = await program.account.pool.fetch(pool);
+    const amounts = [1, 1, 2, 3].map(sol => new anchor.BN(sol * anchor.web3.LAMPORTS_PER_SOL).addn(sol * 7));
+    let expectedInsurance = new anchor.BN(0);
+    for (const amount of amounts) {
+      await program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: feeVault,
+          userStake: stakerStake,
+          accessEntry: null,
+          receiptMint: receiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([staker])
+        .rpc();
+      const fee = amount.muln(50).divn(10000);
+      expectedInsurance = expectedInsurance.add(fee.muln(2000).divn(10000));
+    }
+
+    // 20% of each fee, rounded per stake, waits in the fee vault and is not withdrawable
+    const afterStakes = await program.account.pool.fetch(pool);
+    expect(afterStakes.insurancePending.sub(before.insurancePending).toString()).to.equal(expectedInsurance.toString());
+
+    // Anyone may donate
+    await program.methods
+      .fundInsurance(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accounts({
+        funder: staker.publicKey,
+        pool: pool,
+        insuranceVault: insuranceVault,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([staker])
+      .rpc();
+
+    const recipient = Keypair.generate().publicKey;
+    const reasonHash = Array.from(Buffer.alloc(32, 7));
+    const payClaim = (amount: anchor.BN) =>
+      program.methods
+        .payInsuranceClaim(amount, reasonHash)
+        .accounts({ admin: admin.publicKey, pool: pool, insuranceVault: insuranceVault, recipient: recipient })
+        .signers([admin])
+        .rpc();
+
+    const funded = await program.account.pool.fetch(pool);
+    try {
+      await payClaim(funded.insuranceBalance.addn(1));
+      expect.fail("Should have thrown an error for a payout above the insurance balance");
+    } catch (error) {
+      expect(error.message).to.include("InsufficientFunds");
+    }
+
+    await payClaim(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2));
+    expect(await provider.connection.getBalance(recipient)).to.equal(anchor.web3.LAMPORTS_PER_SOL / 2);
+
+    // The counter always matches what the vault holds above its rent
+    const settled = await program.account.pool.fetch(pool);
+    const vaultInfo = await provider.connection.getAccountInfo(insuranceVault);
+    const rent = await provider.connection.getMinimumBalanceForRentExemption(vaultInfo.data.length);
+    expect(settled.insuranceBalance.toNumber()).to.equal(vaultInfo.lamports - rent);
+
+    await program.methods
+      .updateInsuranceShare(new anchor.BN(0))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Pauses deposits, claims and unstakes independently", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const unstakeAccounts = {
+      user: staker.publicKey,
+      pool: pool,
+      poolVault: poolVault,
+      feeVault: feeVault,
+      userStake: stakerStake,
+      receiptMint: receiptMint,
+      userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    const stake = (amount: anchor.BN) =>
+      program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+        .accounts({
+          ...unstakeAccounts,
+          accessEntry: null,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([staker])
+        .rpc();
+    await stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL));
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    // Error message of a call, or "" if it went through
+    const outcome = async (call: () => Promise<string>) => {
+      try {
+        await call();
+        return "";
+      } catch (error) {
+        return error.message as string;
+      }
+    };
+
+    for (let pauseFlags = 0; pauseFlags <= PAUSE_ALL; pauseFlags++) {
+      if (pauseFlags === 0) {
+        await program.methods.emergencyUnpause().accounts({ admin: admin.publicKey, pool: pool }).signers([admin]).rpc();
+      } else {
+        await program.methods
+          .emergencyPause("Partial pause", pauseFlags)
+          .accounts({ admin: admin.publicKey, pool: pool })
+          .signers([admin])
+          .rpc();
+      }
+      expect((await program.account.pool.fetch(pool)).pauseFlags).to.equal(pauseFlags);
+
+      const stakeError = await outcome(() => stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 10)));
+      const claimError = await outcome(() =>
+        program.methods
+          .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+          .accounts({
+            user: staker.publicKey,
+            pool: pool,
+            rewardVault: rewardVault,
+            userStake: stakerStake,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([staker])
+          .rpc()
+      );
+      await new Promise(resolve => setTimeout(resolve, 1000));
+      const unstakeError = await outcome(() =>
+        program.methods
+          .unstake(new anchor.BN(1_000_000), NO_DEADLINE, NO_MIN_PAYOUT)
+          .accounts(unstakeAccounts)
+          .signers([staker])
+          .rpc()
+      );
+
+      expect(stakeError.includes("PoolPaused")).to.equal((pauseFlags & PAUSE_DEPOSITS) !== 0);
+      expect(claimError.includes("PoolPaused")).to.equal((pauseFlags & PAUSE_CLAIMS) !== 0);
+      expect(unstakeError.includes("PoolPaused")).to.equal((pauseFlags & PAUSE_UNSTAKE) !== 0);
+    }
+
+    // Flags outside the three operations are rejected
+    const invalidError = await outcome(() =>
+      program.methods
+        .emergencyPause("Unknown flag", 8)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc()
+    );
+    expect(invalidError).to.include("InvalidPauseFlags");
+
+    await program.methods.emergencyUnpause().accounts({ admin: admin.publicKey, pool: pool }).signers([admin]).rpc();
+  });
+
+  it("Sunsets a pool, refuses new stakes and closes it once empty", async () => {
+    const poolId = (await program.account.poolRegistry.fetch(poolRegistry)).nextPoolId;
+    const seed = poolId.toArrayLike(Buffer, "le", 8);
+    const derive = (name: string) => PublicKey.findProgramAddressSync([Buffer.from(name), seed], program.programId)[0];
+    const sunsetPool = derive("pool");
+    const vaults = {
+      poolVault: derive("pool_vault"),
+      feeVault: derive("fee_vault"),
+      rewardVault: derive("reward_vault"),
+    };
+    const sunsetReceiptMint = derive("receipt_mint");
+
+    await program.methods
+      .initializePool(poolId, 2000, 1, 365, null, [], [])
+      .accounts({
+        admin: admin.publicKey,
+        poolRegistry: poolRegistry,
+        pool: sunsetPool,
+        ...vaults,
+        receiptMint: sunsetReceiptMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([admin])
+      .rpc();
+
+    // A pool that is still active cannot be closed
+    try {
+      await program.methods
+        .closePool()
+        .accounts({ admin: admin.publicKey, pool: sunsetPool, ...vaults, insuranceVault: null })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for closing an active pool");
+    } catch (error) {
+      expect(error.message).to.include("PoolActive");
+    }
+
+    const now = Math.floor(Date.now() / 1000);
+    await program.methods
+      .sunsetPool(new anchor.BN(now + 60))
+      .accounts({ admin: admin.publicKey, pool: sunsetPool })
+      .signers([admin])
+      .rpc();
+
+    const sunset = await program.account.pool.fetch(sunsetPool);
+    expect(sunset.isActive).to.equal(false);
+    expect(sunset.sunsetAt.toNumber()).to.equal(now + 60);
+
+    // Rates are frozen
+    try {
+      await program.methods
+        .updateApy(new anchor.BN(1000))
+        .accounts({ admin: admin.publicKey, pool: sunsetPool })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for changing the APY after sunset");
+    } catch (error) {
+      expect(error.message).to.include("PoolInactive");
+    }
+
+    // No new stakes
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    try {
+      await program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+        .accounts({
+          user: user1.publicKey,
+          pool: sunsetPool,
+          poolVault: vaults.poolVault,
+          feeVault: vaults.feeVault,
+          userStake: PublicKey.findProgramAddressSync(
+            [Buffer.from("user_stake"), seed, user1.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          accessEntry: null,
+          receiptMint: sunsetReceiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(sunsetReceiptMint, user1.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([user1])
+        .rpc();
+      expect.fail("Should have thrown an error for staking after sunset");
+    } catch (error) {
+      expect(error.message).to.include("PoolInactive");
+    }
+
+    // Nothing was ever staked, so the pool and its vaults can go
+    const adminBefore = await provider.connection.getBalance(admin.publicKey);
+    await program.methods
+      .closePool()
+      .accounts({ admin: admin.publicKey, pool: sunsetPool, ...vaults, insuranceVault: null })
+      .signers([admin])
+      .rpc();
+
+    expect(await provider.connection.getAccountInfo(sunsetPool)).to.be.null;
+    expect(await provider.connection.getAccountInfo(vaults.rewardVault)).to.be.null;
+    expect(await provider.connection.getBalance(admin.publicKey)).to.be.greaterThan(adminBefore);
+  });
+
+  it("Deploys idle principal to Marinade and recalls it when withdrawals need it", async () => {
+    const poolId = (await program.account.poolRegistry.fetch(poolRegistry)).nextPoolId;
+    const seed = poolId.toArrayLike(Buffer, "le", 8);
+    const derive = (name: string) => PublicKey.findProgramAddressSync([Buffer.from(name), seed], program.programId)[0];
+    const marinadePool = derive("pool");
+    const vaults = {
+      poolVault: derive("pool_vault"),
+      feeVault: derive("fee_vault"),
+      rewardVault: derive("reward_vault"),
+    };
+    const marinadeReceiptMint = derive("receipt_mint");
+    await program.methods
+      .initializePool(poolId, 2000, 1, 365, null, [], [])
+      .accounts({
+        admin: admin.publicKey,
+        poolRegistry: poolRegistry,
+        pool: marinadePool,
+        ...vaults,
+        receiptMint: marinadeReceiptMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([admin])
+      .rpc();
+
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    const amount = new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL);
+    const stakerStake = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), seed, staker.publicKey.toBuffer()],
+      program.programId
+    )[0];
+    const stakerReceiptAccount = getAssociatedTokenAddressSync(marinadeReceiptMint, staker.publicKey);
+    await program.methods
+      .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts({
+        user: staker.publicKey,
+        pool: marinadePool,
+        poolVault: vaults.poolVault,
+        feeVault: vaults.feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: marinadeReceiptMint,
+        userReceiptAccount: stakerReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    // The mock converts at the msol_price in its state account and pays SOL back out of
+    // its liquidity PDA; mSOL starts at 1 SOL and rises 5% before the recall
+    const [mintAuthority] = PublicKey.findProgramAddressSync([Buffer.from("mint_authority")], MARINADE_PROGRAM_ID);
+    const [liqPoolSolLeg] = PublicKey.findProgramAddressSync([Buffer.from("liq_sol")], MARINADE_PROGRAM_ID);
+    const [marinadeState] = PublicKey.findProgramAddressSync([Buffer.from("state")], MARINADE_PROGRAM_ID);
+    const setMsolPrice = (msolPrice: anchor.BN) =>
+      provider.sendAndConfirm(
+        new anchor.web3.Transaction().add(
+          new anchor.web3.TransactionInstruction({
+            programId: MARINADE_PROGRAM_ID,
+            keys: [
+              { pubkey: marinadeState, isSigner: false, isWritable: true },
+              { pubkey: admin.publicKey, isSigner: true, isWritable: true },
+              { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+            ],
+            data: Buffer.concat([Buffer.from("setprice"), msolPrice.toArrayLike(Buffer, "le", 8)]),
+          })
+        ),
+        [admin]
+      );
+    const PAR = new anchor.BN(2).pow(new anchor.BN(32));
+    const RISEN = PAR.muln(105).divn(100);
+    const msolMint = await createMint(provider.connection, admin, mintAuthority, null, 9);
+    await provider.connection.requestAirdrop(liqPoolSolLeg, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+    await setMsolPrice(PAR);
+    const marinadeAccounts = {
+      marinadeStaging: derive("marinade_staging"),
+      poolMsolAccount: derive("marinade_msol"),
+      msolMint: msolMint,
+      marinadeState: marinadeState,
+      liqPoolSolLegPda: liqPoolSolLeg,
+      liqPoolMsolLeg: Keypair.generate().publicKey,
+      marinadeProgram: MARINADE_PROGRAM_ID,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+
+    // Only staked principal can be deployed
+    const deployed = new anchor.BN(19).mul(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL)).divn(10);
+    const deploy = (lamports: anchor.BN) =>
+      program.methods
+        .deployToMarinade(lamports, lamports)
+        .accounts({
+          admin: admin.publicKey,
+          pool: marinadePool,
+          poolVault: vaults.poolVault,
+          ...marinadeAccounts,
+          liqPoolMsolLegAuthority: Keypair.generate().publicKey,
+          reservePda: Keypair.generate().publicKey,
+          msolMintAuthority: mintAuthority,
+        })
+        .signers([admin])
+        .rpc();
+    try {
+      await deploy(amount);
+      expect.fail("Should have thrown an error for deploying more than the staked principal");
+    } catch (error) {
+      expect(error.message).to.include("InvalidAmount");
+    }
+    await deploy(deployed);
+
+    expect((await program.account.pool.fetch(marinadePool)).deployedToMarinade.toString()).to.equal(deployed.toString());
+    expect((await getAccount(provider.connection, marinadeAccounts.poolMsolAccount)).amount.toString()).to.equal(
+      deployed.toString()
+    );
+
+    // With most of the principal in Marinade a full exit has to wait for a recall
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    const unstake = () =>
+      program.methods
+        .unstake(netOfDepositFee(amount), NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: staker.publicKey,
+          pool: marinadePool,
+          poolVault: vaults.poolVault,
+          userStake: stakerStake,
+          receiptMint: marinadeReceiptMint,
+          userReceiptAccount: stakerReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+    try {
+      await unstake();
+      expect.fail("Should have thrown an error while liquidity is deployed");
+    } catch (error) {
+      expect(error.message).to.include("LiquidityBeingRecalled");
+    }
+
+    // Half comes back through the liquidity pool: its principal returns to the pool vault
+    // and the 5% on top to the reward reserve
+    await setMsolPrice(RISEN);
+    const half = deployed.divn(2);
+    const halfValue = half.mul(RISEN).div(PAR);
+    const vaultBefore = await provider.connection.getBalance(vaults.poolVault);
+    await program.methods
+      .recallFromMarinade(half, halfValue)
+      .accounts({
+        admin: admin.publicKey,
+        pool: marinadePool,
+        poolVault: vaults.poolVault,
+        rewardVault: vaults.rewardVault,
+        ...marinadeAccounts,
+        treasuryMsolAccount: Keypair.generate().publicKey,
+      })
+      .signers([admin])
+      .rpc();
+
+    let recalled = await program.account.pool.fetch(marinadePool);
+    expect(recalled.deployedToMarinade.toString()).to.equal(deployed.sub(half).toString());
+    expect(recalled.rewardReserve.toString()).to.equal(halfValue.sub(half).toString());
+    expect(recalled.marinadeLoss.toNumber()).to.equal(0);
+    expect(await provider.connection.getBalance(vaults.poolVault)).to.equal(vaultBefore + half.toNumber());
+
+    // The rest waits out a delayed unstake, and stays out of the vault until its ticket is claimed
+    const rest = deployed.sub(half);
+    const restValue = rest.mul(RISEN).div(PAR);
+    const ticket = Keypair.generate();
+    const ticketRent = await provider.connection.getMinimumBalanceForRentExemption(88);
+    await program.methods
+      .orderMarinadeUnstake(rest)
+      .accounts({
+        admin: admin.publicKey,
+        pool: marinadePool,
+        marinadeStaging: marinadeAccounts.marinadeStaging,
+        poolMsolAccount: marinadeAccounts.poolMsolAccount,
+        msolMint: msolMint,
+        marinadeState: marinadeState,
+        newTicketAccount: ticket.publicKey,
+        marinadeProgram: MARINADE_PROGRAM_ID,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: SYSVAR_RENT_PUBKEY,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .preInstructions([
+        SystemProgram.createAccount({
+          fromPubkey: admin.publicKey,
+          newAccountPubkey: ticket.publicKey,
+          lamports: ticketRent,
+          space: 88,
+          programId: MARINADE_PROGRAM_ID,
+        }),
+      ])
+      .signers([admin, ticket])
+      .rpc();
+
+    const ordered = await program.account.pool.fetch(marinadePool);
+    expect(ordered.deployedToMarinade.toNumber()).to.equal(0);
+    expect(ordered.marinadeTicketPrincipal.toString()).to.equal(rest.toString());
+    expect(ordered.marinadeTicketLamports.toString()).to.equal(restValue.toString());
+    expect((await getAccount(provider.connection, marinadeAccounts.poolMsolAccount)).amount.toString()).to.equal("0");
+    try {
+      await unstake();
+      expect.fail("Should have thrown an error while a ticket is unclaimed");
+    } catch (error) {
+      expect(error.message).to.include("LiquidityBeingRecalled");
+    }
+
+    // Claiming settles the ticket like a recall and hands its rent back to the admin
+    const adminBefore = await provider.connection.getBalance(admin.publicKey);
+    await program.methods
+      .claimMarinadeTicket()
+      .accounts({
+        admin: admin.publicKey,
+        pool: marinadePool,
+        poolVault: vaults.poolVault,
+        rewardVault: vaults.rewardVault,
+        marinadeStaging: marinadeAccounts.marinadeStaging,
+        marinadeState: marinadeState,
+        reservePda: liqPoolSolLeg,
+        ticketAccount: ticket.publicKey,
+        marinadeProgram: MARINADE_PROGRAM_ID,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([admin])
+      .rpc();
+
+    recalled = await program.account.pool.fetch(marinadePool);
+    expect(recalled.marinadeTicketPrincipal.toNumber()).to.equal(0);
+    expect(recalled.marinadeTicketLamports.toNumber()).to.equal(0);
+    expect(recalled.rewardReserve.toString()).to.equal(halfValue.sub(half).add(restValue.sub(rest)).toString());
+    expect(await provider.connection.getBalance(vaults.poolVault)).to.equal(vaultBefore + deployed.toNumber());
+    expect(await provider.connection.getBalance(admin.publicKey)).to.be.above(adminBefore + ticketRent - 10000);
+
+    await unstake();
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toNumber()).to.equal(0);
+  });
+
+  it("Stores canonical bumps and rejects a vault derived with any other bump", async () => {
+    const poolAccount = await program.account.pool.fetch(pool);
+    const canonicalBump = (prefix: string) =>
+      PublicKey.findProgramAddressSync([Buffer.from(prefix), poolIdSeed(0)], program.programId)[1];
+    expect(poolAccount.bump).to.equal(canonicalBump("pool"));
+    expect(poolAccount.poolVaultBump).to.equal(canonicalBump("pool_vault"));
+    expect(poolAccount.feeVaultBump).to.equal(canonicalBump("fee_vault"));
+    expect(poolAccount.rewardVaultBump).to.equal(canonicalBump("reward_vault"));
+    expect(poolAccount.insuranceVaultBump).to.equal(canonicalBump("insurance_vault"));
+    const [, userStakeBump] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    expect((await program.account.userStake.fetch(userStake)).bump).to.equal(userStakeBump);
+
+    // The next lower bump that yields a valid off-curve address for the same seeds
+    let bump = poolAccount.rewardVaultBump - 1;
+    let nonCanonicalVault: PublicKey | null = null;
+    while (!nonCanonicalVault && bump >= 0) {
+      try {
+        nonCanonicalVault = PublicKey.createProgramAddressSync(
+          [Buffer.from("reward_vault"), poolIdSeed(0), Buffer.from([bump])],
+          program.programId
+        );
+      } catch (error) {
+        bump -= 1;
+      }
+    }
+    expect(nonCanonicalVault).to.not.be.null;
+
+    try {
+      await program.methods
+        .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          rewardVault: nonCanonicalVault,
+          userStake: userStake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+      expect.fail("Should have thrown an error for a non-canonical vault");
+    } catch (error) {
+      // Nothing can be created at a non-canonical address, so Anchor may reject it as
+      // uninitialised before the seeds check runs; either way the claim fails
+      expect(error.message).to.match(/ConstraintSeeds|AccountNotInitialized/);
+    }
+  });
+
+  it("Halves the deposit fee for long commitments and enforces the grace bounds", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 1000));
+
+    // New pools start with half the fee from 180 days and a 95% grace share
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.longCommitThresholdDays.toNumber()).to.equal(180);
+    expect(poolAccount.longCommitFeeDiscountBps.toNumber()).to.equal(5000);
+    expect(poolAccount.penaltyGraceBps.toNumber()).to.equal(9500);
+
+    const setIncentives = (thresholdDays: number, discountBps: number, graceBps: number) =>
+      program.methods
+        .updateCommitmentIncentives(new anchor.BN(thresholdDays), new anchor.BN(discountBps), new anchor.BN(graceBps))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    // Less than half the commitment can never count as served
+    try {
+      await setIncentives(180, 5000, 4999);
+      expect.fail("Should have thrown an error for a grace share below half");
+    } catch (error) {
+      expect(error.message).to.include("InvalidCommitmentIncentives");
+    }
+
+    await setIncentives(180, 5000, 9500);
+
+    // 0.5% base fee halved to 0.25% for a 180-day commitment
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const expectedNet = amount.sub(amount.muln(25).divn(10000));
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(amount, new anchor.BN(180), expectedNet, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toString()).to.equal(expectedNet.toString());
+  });
+
+  it("Sets the yield payout caps and only lets the admin change them", async () => {
+    // New pools pay out without caps
+    let poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.maxYieldPerClaim.toNumber()).to.equal(0);
+    expect(poolAccount.maxYieldPerUserPerYear.toNumber()).to.equal(0);
+
+    const perClaim = new anchor.BN(0.1 * anchor.web3.LAMPORTS_PER_SOL);
+    const perYear = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    try {
+      await program.methods
+        .updateYieldCaps(perClaim, perYear)
+        .accounts({ admin: user1.publicKey, pool: pool })
+        .signers([user1])
+        .rpc();
+      expect.fail("Should have thrown an error for a non-admin");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    await program.methods
+      .updateYieldCaps(perClaim, perYear)
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.maxYieldPerClaim.toString()).to.equal(perClaim.toString());
+    expect(poolAccount.maxYieldPerUserPerYear.toString()).to.equal(perYear.toString());
+
+    // Lift both caps again so later tests are unaffected
+    await program.methods
+      .updateYieldCaps(new anchor.BN(0), new anchor.BN(0))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+
+  it("Refuses the stake that would take projected yield past the reserve's capacity", async () => {
+    const stakers = [Keypair.generate(), Keypair.generate(), Keypair.generate()];
+    for (const staker of stakers) {
+      await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    }
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const stake = (staker: Keypair) => {
+      const [stakerStake] = PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+        program.programId
+      );
+      return program.methods
+        .stake(amount, new anchor.BN(30), NO_MIN_PAYOUT, null)
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: feeVault,
+          userStake: stakerStake,
+          accessEntry: null,
+          receiptMint: receiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([staker])
+        .rpc({ commitment: "confirmed" });
+    };
+    const setSolvencyFactor = (factorBps: anchor.BN) =>
+      program.methods
+        .updateSolvencyFactor(factorBps)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+
+    // One stake shows how much yield each identical stake projects
+    const before = await program.account.pool.fetch(pool);
+    await stake(stakers[0]);
+    const afterFirst = await program.account.pool.fetch(pool, "confirmed");
+    const projected = afterFirst.totalProjectedLiability.sub(before.totalProjectedLiability);
+    expect(projected.gtn(0)).to.be.true;
+
+    // Leave room for exactly one more: capacity is at least one stake's projection away
+    // and less than two, since a bps of the reserve is smaller than one projection
+    const reserve = afterFirst.rewardReserve;
+    const target = afterFirst.totalProjectedLiability.add(projected);
+    await setSolvencyFactor(target.muln(10000).add(reserve).subn(1).div(reserve));
+
+    await stake(stakers[1]);
+    const filled = await program.account.pool.fetch(pool, "confirmed");
+    expect(filled.totalProjectedLiability.toString()).to.equal(target.toString());
+
+    try {
+      await stake(stakers[2]);
+      expect.fail("Should have thrown an error for a stake past the yield capacity");
+    } catch (error) {
+      expect(error.message).to.include("InsufficientYieldCapacity");
+    }
+
+    await setSolvencyFactor(new anchor.BN(LOOSE_SOLVENCY_FACTOR_BPS));
+  });
+
+  it("Records stakes in an opt-in user history that can be cleared and closed", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [userHistory] = PublicKey.findProgramAddressSync(
+      [Buffer.from("history"), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initUserHistory()
+      .accounts({ user: staker.publicKey, userHistory, systemProgram: SystemProgram.programId })
+      .signers([staker])
+      .rpc();
+
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    await program.methods
+      .stake(amount, new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        userHistory,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc({ commitment: "confirmed" });
+
+    const history = await program.account.userHistory.fetch(userHistory, "confirmed");
+    const position = await program.account.userStake.fetch(stakerStake, "confirmed");
+    expect(history.totalEntries.toNumber()).to.equal(1);
+    expect(history.entries[0].opKind).to.equal(0); // Stake
+    expect(history.entries[0].amount.toString()).to.equal(position.amount.toString());
+
+    await program.methods
+      .clearUserHistory()
+      .accounts({ user: staker.publicKey, userHistory })
+      .signers([staker])
+      .rpc({ commitment: "confirmed" });
+    expect((await program.account.userHistory.fetch(userHistory, "confirmed")).totalEntries.toNumber()).to.equal(0);
+
+    await program.methods
+      .closeUserHistory()
+      .accounts({ user: staker.publicKey, userHistory })
+      .signers([staker])
+      .rpc({ commitment: "confirmed" });
+    expect(await provider.connection.getAccountInfo(userHistory, "confirmed")).to.be.null;
+  });
+
+  it("Reports lamports sent straight to a vault without pausing deposits", async () => {
+    const eventParser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+    const setMaxDelta = (maxDelta: anchor.BN) =>
+      program.methods
+        .updateMaxReconciliationDelta(maxDelta)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc({ commitment: "confirmed" });
+    const verify = async () => {
+      const signature = await program.methods
+        .verifySolvency()
+        .accounts({ pool, poolVault, feeVault, rewardVault })
+        .rpc({ commitment: "confirmed" });
+      const tx = await provider.connection.getTransaction(signature, { commitment: "confirmed" });
+      return [...eventParser.parseLogs(tx.meta.logMessages)].find((event) => event.name === "SolvencyCheckEvent").data;
+    };
+
+    // Whatever the earlier tests left behind must not pause the pool here
+    await setMaxDelta(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL));
+    const pauseFlagsBefore = (await program.account.pool.fetch(pool, "confirmed")).pauseFlags;
+    const before = await verify();
+
+    const injected = 12_345;
+    await provider.sendAndConfirm(
+      new anchor.web3.Transaction().add(
+        SystemProgram.transfer({ fromPubkey: admin.publicKey, toPubkey: poolVault, lamports: injected })
+      ),
+      [admin]
+    );
+
+    const after = await verify();
+    expect(after.expected.toString()).to.equal(before.expected.toString());
+    expect(after.actual.sub(before.actual).toNumber()).to.equal(injected);
+    expect(after.delta.sub(before.delta).toNumber()).to.equal(injected);
+    expect(after.ok).to.be.true;
+    expect(after.depositsPaused).to.be.false;
+    expect((await program.account.pool.fetch(pool, "confirmed")).pauseFlags).to.equal(pauseFlagsBefore);
+
+    await setMaxDelta(new anchor.BN(0));
+  });
+
+  it("Stakes exactly the requested net and refuses a deposit above the payment ceiling", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const netAmount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL + 333);
+    const stakeExactNet = (maxGross: anchor.BN) =>
+      program.methods
+        .stakeExactNet(netAmount, new anchor.BN(30), maxGross, NO_DEADLINE)
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: feeVault,
+          userStake: stakerStake,
+          accessEntry: null,
+          receiptMint: receiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([staker])
+        .rpc();
+
+    // Covering the fee takes more than the net itself
+    try {
+      await stakeExactNet(netAmount);
+      expect.fail("Should have thrown an error for a deposit above the ceiling");
+    } catch (error) {
+      expect(error.message).to.include("SlippageExceeded");
+    }
+
+    await stakeExactNet(new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL));
+    const position = await program.account.userStake.fetch(stakerStake);
+    expect(position.amount.toString()).to.equal(netAmount.toString());
+  });
+
+  it("Grandfathers positions when the minimum commitment rises and pays a bonus to migrate", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const setMinCommitment = (days: number) =>
+      program.methods
+        .updateMinCommitmentDays(new anchor.BN(days))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const setBonus = (bps: number) =>
+      program.methods
+        .updateMigrationBonusBps(new anchor.BN(bps))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const migrate = () =>
+      program.methods
+        .migrateCommitment()
+        .accounts({ user: staker.publicKey, pool: pool, userStake: stakerStake })
+        .signers([staker])
+        .rpc();
+
+    const policyBefore = (await program.account.pool.fetch(pool)).policyVersion.toNumber();
+    await setMinCommitment(60);
+    await setBonus(50);
+    const policy = (await program.account.pool.fetch(pool)).policyVersion.toNumber();
+    expect(policy).to.equal(policyBefore + 1);
+
+    // The 30-day position is grandfathered and claims as before
+    let position = await program.account.userStake.fetch(stakerStake);
+    expect(position.policyVersion.toNumber()).to.equal(policyBefore);
+    expect(position.committedDays.toNumber()).to.equal(30);
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    await program.methods
+      .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        userStake: stakerStake,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([staker])
+      .rpc();
+
+    // Migrating adopts the new minimum and owes 0.5% of principal once
+    await migrate();
+    position = await program.account.userStake.fetch(stakerStake);
+    expect(position.committedDays.toNumber()).to.equal(60);
+    expect(position.policyVersion.toNumber()).to.equal(policy);
+    expect(position.rewardsOwed.toNumber()).to.be.at.least(position.amount.toNumber() * 50 / 10000 - 1);
+
+    try {
+      await migrate();
+      expect.fail("Should have thrown an error for a position already on the current policy");
+    } catch (error) {
+      expect(error.message).to.include("NotGrandfathered");
+    }
+
+    await setBonus(0);
+    await setMinCommitment(1);
+  });
+
+  it("Answers get_position_value and get_pool_stats over CPI in the interface layout", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    // The mock caller decodes the view's return data with the interface crate and
+    // returns what it decoded
+    const callThroughMock = async (data: number, accounts: PublicKey[]): Promise<Buffer> => {
+      const transaction = new anchor.web3.Transaction().add(
+        new anchor.web3.TransactionInstruction({
+          programId: CPI_CALLER_PROGRAM_ID,
+          keys: [program.programId, ...accounts].map(pubkey => ({ pubkey, isSigner: false, isWritable: false })),
+          data: Buffer.from([data]),
+        })
+      );
+      const simulation = await provider.simulate(transaction);
+      expect(simulation.returnData.programId).to.equal(CPI_CALLER_PROGRAM_ID.toBase58());
+      return Buffer.from(simulation.returnData.data[0], "base64");
+    };
+
+    const position = await program.account.userStake.fetch(stakerStake);
+    const value = await callThroughMock(0, [pool, stakerStake]);
+    expect(value.length).to.equal(32);
+    expect(value.readBigUInt64LE(0).toString()).to.equal(position.amount.toString());
+    expect(value.readBigInt64LE(16).toString()).to.equal(
+      position.stakeTimestamp.add(position.committedDays.muln(86400)).toString()
+    );
+    expect(value.readBigUInt64LE(24).toString()).to.equal(position.accrualRateBps.toString());
+
+    // Called directly, the view answers with the same layout
+    const direct = await program.methods
+      .getPositionValue()
+      .accounts({ pool: pool, userStake: stakerStake })
+      .view();
+    expect(direct.amount.toString()).to.equal(position.amount.toString());
+
+    const poolAccount = await program.account.pool.fetch(pool);
+    const stats = await callThroughMock(1, [pool]);
+    expect(stats.length).to.equal(24);
+    expect(stats.readBigUInt64LE(0).toString()).to.equal(poolAccount.totalStaked.toString());
+    expect(stats.readBigUInt64LE(8).toString()).to.equal(poolAccount.totalUsers.toString());
+  });
+
+  it("Refuses claims below the pool minimum without counting them against the payout window", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const setMinClaim = (amount: anchor.BN) =>
+      program.methods
+        .updateMinClaimAmount(amount)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const claim = () =>
+      program.methods
+        .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          rewardVault: rewardVault,
+          userStake: stakerStake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+
+    try {
+      await setMinClaim(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL + 1));
+      expect.fail("Should have thrown an error for a minimum above 1 SOL");
+    } catch (error) {
+      expect(error.message).to.include("InvalidAmount");
+    }
+
+    // A few seconds of yield on 1 SOL is far below a 0.5 SOL minimum
+    await setMinClaim(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 2));
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    const before = await program.account.userStake.fetch(stakerStake);
+    try {
+      await claim();
+      expect.fail("Should have thrown an error for a claim below the minimum");
+    } catch (error) {
+      expect(error.message).to.include("YieldBelowMinimum");
+      expect(error.logs.join("\n")).to.include(`min=${anchor.web3.LAMPORTS_PER_SOL / 2}`);
+    }
+    const after = await program.account.userStake.fetch(stakerStake);
+    expect(after.yieldsClaimedInYear.toString()).to.equal(before.yieldsClaimedInYear.toString());
+    expect(after.yearWindowStart.toString()).to.equal(before.yearWindowStart.toString());
+
+    // With the minimum lifted the same yield claims as usual
+    await setMinClaim(new anchor.BN(0));
+    await claim();
+    expect((await program.account.userStake.fetch(stakerStake)).totalYieldsClaimed.toNumber()).to.be.above(0);
+  });
+
+  it("Lets owners opt out of rolling commitments and back in", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+
+    const setRenewalPolicy = (graceSeconds: number, idleApyBps: number) =>
+      program.methods
+        .updateRenewalPolicy(new anchor.BN(graceSeconds), new anchor.BN(idleApyBps))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+    const setAutoRenew = (autoRenew: boolean) =>
+      program.methods
+        .setAutoRenew(autoRenew)
+        .accounts({ user: staker.publicKey, pool: pool, userStake: stakerStake })
+        .signers([staker])
+        .rpc();
+
+    try {
+      await setRenewalPolicy(30 * 86400 + 1, 0);
+      expect.fail("Should have thrown an error for a grace window over 30 days");
+    } catch (error) {
+      expect(error.message).to.include("InvalidRenewalPolicy");
+    }
+    try {
+      await program.methods
+        .updateRenewalPolicy(new anchor.BN(86400), new anchor.BN(0))
+        .accounts({ admin: staker.publicKey, pool: pool })
+        .signers([staker])
+        .rpc();
+      expect.fail("Should have thrown an error for a non-admin");
+    } catch (error) {
+      expect(error.message).to.include("Unauthorized");
+    }
+
+    await setRenewalPolicy(86400, 200);
+    const poolAccount = await program.account.pool.fetch(pool);
+    expect(poolAccount.renewalGraceSeconds.toNumber()).to.equal(86400);
+    expect(poolAccount.idleApyBps.toNumber()).to.equal(200);
+
+    // Positions renew by default; opting out mid-commitment leaves the commitment as it is
+    const before = await program.account.userStake.fetch(stakerStake);
+    expect(before.autoRenewOptedOut).to.equal(false);
+    await setAutoRenew(false);
+    let position = await program.account.userStake.fetch(stakerStake);
+    expect(position.autoRenewOptedOut).to.equal(true);
+    expect(position.stakeTimestamp.toString()).to.equal(before.stakeTimestamp.toString());
+
+    // Opting back in before the commitment ends does not restart it either
+    await setAutoRenew(true);
+    position = await program.account.userStake.fetch(stakerStake);
+    expect(position.autoRenewOptedOut).to.equal(false);
+    expect(position.stakeTimestamp.toString()).to.equal(before.stakeTimestamp.toString());
+
+    await setRenewalPolicy(0, 0);
+  });
+
+  it("Logs pauses in the pool's incident log and reports the active one", async () => {
+    const [incidentLog] = PublicKey.findProgramAddressSync(
+      [Buffer.from("incidents"), pool.toBuffer()],
+      program.programId
+    );
+    await program.methods
+      .initIncidentLog()
+      .accounts({ admin: admin.publicKey, pool, incidentLog, systemProgram: SystemProgram.programId })
+      .signers([admin])
+      .rpc();
+
+    const reason = "Oracle outage";
+    await program.methods
+      .emergencyPause(reason, PAUSE_DEPOSITS)
+      .accounts({ admin: admin.publicKey, pool, incidentLog })
+      .signers([admin])
+      .rpc({ commitment: "confirmed" });
+
+    const active = await program.methods.getActiveIncident().accounts({ pool, incidentLog }).view();
+    expect(active.flags).to.equal(PAUSE_DEPOSITS);
+    expect(active.closedAt.toNumber()).to.equal(0);
+    expect(Buffer.from(active.reasonHash)).to.deep.equal(createHash("sha256").update(reason).digest());
+
+    await program.methods
+      .emergencyUnpause()
+      .accounts({ admin: admin.publicKey, pool, incidentLog })
+      .signers([admin])
+      .rpc({ commitment: "confirmed" });
+
+    const log = await program.account.incidentLog.fetch(incidentLog, "confirmed");
+    expect(log.totalIncidents.toNumber()).to.equal(1);
+    expect(log.incidents[0].closedAt.toNumber()).to.be.greaterThan(0);
+    expect(await program.methods.getActiveIncident().accounts({ pool, incidentLog }).view()).to.be.null;
+  });
+
+  it("Keeps recovery closed while the admin is active and bounds the inactivity timeout", async () => {
+    const recoveryAuthority = Keypair.generate();
+    const setRecoveryPolicy = (timeout: number) =>
+      program.methods
+        .updateRecoveryPolicy(recoveryAuthority.publicKey, new anchor.BN(timeout))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc({ commitment: "confirmed" });
+
+    try {
+      await setRecoveryPolicy(30 * 86400 - 1);
+      expect.fail("Should have thrown an error for a timeout under 30 days");
+    } catch (error) {
+      expect(error.message).to.include("InvalidRecoveryPolicy");
+    }
+
+    await setRecoveryPolicy(90 * 86400);
+    const poolAccount = await program.account.pool.fetch(pool, "confirmed");
+    expect(poolAccount.recoveryAuthority.toBase58()).to.equal(recoveryAuthority.publicKey.toBase58());
+    expect(poolAccount.adminInactivityTimeout.toNumber()).to.equal(90 * 86400);
+    expect(poolAccount.recoveryMode).to.equal(false);
+
+    // The admin just acted, so nobody can put the pool into recovery
+    try {
+      await program.methods
+        .activateRecoveryMode()
+        .accounts({ caller: recoveryAuthority.publicKey, pool: pool })
+        .signers([recoveryAuthority])
+        .rpc();
+      expect.fail("Should have thrown an error while the admin is active");
+    } catch (error) {
+      expect(error.message).to.include("AdminStillActive");
+    }
+
+    // Nor can the recovery authority take over a pool that is not in recovery
+    try {
+      await program.methods
+        .recoveryRotateAdmin(recoveryAuthority.publicKey)
+        .accounts({ caller: recoveryAuthority.publicKey, pool: pool })
+        .signers([recoveryAuthority])
+        .rpc();
+      expect.fail("Should have thrown an error outside recovery");
+    } catch (error) {
+      expect(error.message).to.include("NotInRecoveryMode");
+    }
+  });
+
+  it("Refuses to roll a position over before its commitment is met", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const userReceiptAccount = getAssociatedTokenAddressSync(receiptMint, staker.publicKey);
+    await program.methods
+      .stake(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL), new anchor.BN(30), NO_MIN_PAYOUT, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc({ commitment: "confirmed" });
+
+    for (const compound of [true, false]) {
+      try {
+        await program.methods
+          .rollover(new anchor.BN(90), compound)
+          .accounts({
+            user: staker.publicKey,
+            pool: pool,
+            poolVault: poolVault,
+            rewardVault: rewardVault,
+            feeVault: feeVault,
+            userStake: stakerStake,
+            receiptMint: receiptMint,
+            userReceiptAccount,
+            tokenProgram: TOKEN_PROGRAM_ID,
+            systemProgram: SystemProgram.programId,
+          })
+          .signers([staker])
+          .rpc();
+        expect.fail("Should have thrown an error mid-commitment");
+      } catch (error) {
+        expect(error.message).to.include("CommitmentNotMet");
+      }
+    }
+
+    // The fee a rollover may charge is capped like the deposit fee
+    try {
+      await program.methods
+        .updateRolloverFee(new anchor.BN(1001))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      expect.fail("Should have thrown an error for a fee over 10%");
+    } catch (error) {
+      expect(error.message).to.include("InvalidFee");
+    }
+  });
+
+  it("Pays the second pool's yields in its reward token and creates the owner's token account", async () => {
+    const [secondPool] = PublicKey.findProgramAddressSync([Buffer.from("pool"), poolIdSeed(1)], program.programId);
+    const [secondRewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("reward_vault"), poolIdSeed(1)],
+      program.programId
+    );
+    const [secondUserStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(1), user1.publicKey.toBuffer()],
+      program.programId
+    );
+    const rewardMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    const [rewardTokenVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("reward_token_vault"), poolIdSeed(1), rewardMint.toBuffer()],
+      program.programId
+    );
+
+    await program.methods
+      .initializeRewardTokenVault()
+      .accounts({
+        admin: admin.publicKey,
+        pool: secondPool,
+        rewardMint,
+        rewardTokenVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([admin])
+      .rpc();
+
+    // One token base unit per lamport: 1 SOL of yield pays 1,000 six-decimal tokens
+    await program.methods
+      .updateRewardTokenPrice(new anchor.BN(anchor.web3.LAMPORTS_PER_SOL))
+      .accounts({ admin: admin.publicKey, pool: secondPool })
+      .signers([admin])
+      .rpc();
+
+    const adminTokenAccount = await createAccount(provider.connection, admin, rewardMint, admin.publicKey);
+    await mintTo(provider.connection, admin, rewardMint, adminTokenAccount, admin, 1_000_000_000);
+    await program.methods
+      .fundTokenRewards(new anchor.BN(1_000_000_000))
+      .accounts({
+        admin: admin.publicKey,
+        pool: secondPool,
+        rewardMint,
+        rewardTokenVault,
+        adminTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .signers([admin])
+      .rpc();
+
+    // The SOL path is closed once the pool pays in its token
+    try {
+      await program.methods
+        .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts({
+          user: user1.publicKey,
+          pool: secondPool,
+          rewardVault: secondRewardVault,
+          userStake: secondUserStake,
+          userHistory: null,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+      expect.fail("Should have thrown an error for a SOL claim on a token pool");
+    } catch (error) {
+      expect(error.message).to.include("RewardsPaidInToken");
+    }
+
+    const tokenClaimAccounts = (mint: PublicKey) => ({
+      user: user1.publicKey,
+      pool: secondPool,
+      userStake: secondUserStake,
+      rewardMint: mint,
+      rewardTokenVault,
+      userRewardTokenAccount: getAssociatedTokenAddressSync(mint, user1.publicKey),
+      userHistory: null,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    });
+
+    const otherMint = await createMint(provider.connection, admin, admin.publicKey, null, 6);
+    try {
+      await program.methods
+        .claimTokenYields(NO_DEADLINE, NO_MIN_PAYOUT)
+        .accounts(tokenClaimAccounts(otherMint))
+        .signers([user1])
+        .rpc();
+      expect.fail("Should have thrown an error for a mint the pool does not pay in");
+    } catch (error) {
+      expect(error.message).to.include("InvalidMint");
+    }
+
+    const userRewardTokenAccount = getAssociatedTokenAddressSync(rewardMint, user1.publicKey);
+    expect(await provider.connection.getAccountInfo(userRewardTokenAccount)).to.be.null;
+    const stakeBefore = await program.account.userStake.fetch(secondUserStake);
+
+    await program.methods
+      .claimTokenYields(NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts(tokenClaimAccounts(rewardMint))
+      .signers([user1])
+      .rpc();
+
+    const stakeAfter = await program.account.userStake.fetch(secondUserStake);
+    const claimedLamports = stakeAfter.totalYieldsClaimed.sub(stakeBefore.totalYieldsClaimed);
+    const expectedTokens = claimedLamports; // At one token base unit per lamport
+
+    const received = await getAccount(provider.connection, userRewardTokenAccount);
+    expect(received.amount.toString()).to.equal(expectedTokens.toString());
+    const vaultAfter = await getAccount(provider.connection, rewardTokenVault);
+    expect(vaultAfter.amount.toString()).to.equal(new anchor.BN(1_000_000_000).sub(expectedTokens).toString());
+
+    // Principal is untouched and still held in SOL
+    expect(stakeAfter.amount.toString()).to.equal(stakeBefore.amount.toString());
+  });
+
+  it("Previews a stake and an instant unstake exactly as they execute", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+
+    // No position yet, so none is passed; a refused stake is reported, not thrown
+    const previewStake = (lamports: anchor.BN, days: number) =>
+      program.methods
+        .previewStake(lamports, new anchor.BN(days))
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          userStake: null,
+          referralAccount: null,
+          accessEntry: null,
+        })
+        .view();
+    const refused = await previewStake(amount, 0);
+    expect(refused.wouldSucceed).to.be.false;
+    expect(refused.failureCode).to.equal(program.idl.errors.find(error => error.name === "InvalidCommitmentDays").code);
+
+    const stakePreview = await previewStake(amount, 30);
+    expect(stakePreview.wouldSucceed).to.be.true;
+    expect(stakePreview.fee.add(stakePreview.net).toString()).to.equal(amount.toString());
+
+    // The previewed net is the quote the stake is signed with
+    await program.methods
+      .stake(amount, new anchor.BN(30), stakePreview.net, null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc({ commitment: "confirmed" });
+
+    const position = await program.account.userStake.fetch(stakerStake);
+    expect(position.amount.toString()).to.equal(stakePreview.net.toString());
+    expect(position.projectedLiability.toString()).to.equal(stakePreview.yields.toString());
+
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    const unstakePreview = await program.methods
+      .previewUnstake()
+      .accounts({ pool: pool, poolVault: poolVault, userStake: stakerStake })
+      .view();
+    expect(unstakePreview.wouldSucceed).to.be.true;
+    expect(unstakePreview.net.toString()).to.equal(position.amount.toString());
+    expect(unstakePreview.penalty.add(unstakePreview.returnAmount).toString()).to.equal(position.amount.toString());
+
+    // Previewing changed nothing on chain
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toString()).to.equal(position.amount.toString());
+
+    // The sliding penalty only shrinks with time, so the previewed payout is a safe floor
+    await program.methods
+      .unstake(position.amount, NO_DEADLINE, unstakePreview.returnAmount)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        userStake: stakerStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, staker.publicKey),
+        userHistory: null,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([staker])
+      .rpc();
+    expect((await program.account.userStake.fetch(stakerStake)).amount.toNumber()).to.equal(0);
+  });
+
+  it("Refuses to stake into another user's position and never resurrects a closed one", async () => {
+    const attacker = Keypair.generate();
+    const victim = Keypair.generate();
+    for (const wallet of [attacker, victim]) {
+      await provider.connection.requestAirdrop(wallet.publicKey, 3 * anchor.web3.LAMPORTS_PER_SOL);
+    }
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const positionOf = (owner: PublicKey) =>
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), poolIdSeed(0), owner.toBuffer()],
+        program.programId
+      )[0];
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    const stake = (wallet: Keypair, position: PublicKey) =>
+      program.methods
+        .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+        .accounts({
+          user: wallet.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: feeVault,
+          userStake: position,
+          accessEntry: null,
+          receiptMint: receiptMint,
+          userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, wallet.publicKey),
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([wallet])
+        .rpc({ commitment: "confirmed" });
+
+    // The victim's position, before and after it exists, only ever takes the victim's deposits
+    for (const victimHasStaked of [false, true]) {
+      if (victimHasStaked) {
+        await stake(victim, positionOf(victim.publicKey));
+      }
+      try {
+        await stake(attacker, positionOf(victim.publicKey));
+        expect.fail("Should have thrown an error for someone else's position");
+      } catch (error) {
+        expect(error.message).to.include("ConstraintSeeds");
+      }
+    }
+    const victimPosition = await program.account.userStake.fetch(positionOf(victim.publicKey));
+    expect(victimPosition.user.toBase58()).to.equal(victim.publicKey.toBase58());
+    expect(victimPosition.initialized).to.be.true;
+
+    // A position closed after a full exit comes back blank, with none of its old counters
+    const attackerPosition = positionOf(attacker.publicKey);
+    await stake(attacker, attackerPosition);
+    await new Promise(resolve => setTimeout(resolve, 1000));
+    const staked = await program.account.userStake.fetch(attackerPosition);
+    await program.methods
+      .unstake(staked.amount, NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: attacker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        userStake: attackerPosition,
+        receiptMint: receiptMint,
+        userReceiptAccount: getAssociatedTokenAddressSync(receiptMint, attacker.publicKey),
+        userHistory: null,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([attacker])
+      .rpc({ commitment: "confirmed" });
+    // Sweeping the yield settled on exit leaves a counter the closed account must not pass on
+    await program.methods
+      .claimYields(NO_DEADLINE, NO_MIN_PAYOUT)
+      .accounts({
+        user: attacker.publicKey,
+        pool: pool,
+        rewardVault: rewardVault,
+        userStake: attackerPosition,
+        userHistory: null,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([attacker])
+      .rpc({ commitment: "confirmed" });
+    expect((await program.account.userStake.fetch(attackerPosition, "confirmed")).totalYieldsClaimed.toNumber()).to.be.above(0);
+    await program.methods
+      .closeUserStake()
+      .accounts({ user: attacker.publicKey, pool: pool, userStake: attackerPosition })
+      .signers([attacker])
+      .rpc({ commitment: "confirmed" });
+    expect(await provider.connection.getAccountInfo(attackerPosition, "confirmed")).to.be.null;
+
+    await stake(attacker, attackerPosition);
+    const reopened = await program.account.userStake.fetch(attackerPosition);
+    expect(reopened.user.toBase58()).to.equal(attacker.publicKey.toBase58());
+    expect(reopened.initialized).to.be.true;
+    expect(reopened.totalStakedLifetime.toString()).to.equal(reopened.amount.toString());
+    expect(reopened.totalYieldsClaimed.toNumber()).to.equal(0);
+  });
+
+  it("Lets a capped position with credited yield still withdraw in an emergency", async () => {
+    const staker = Keypair.generate();
+    await provider.connection.requestAirdrop(staker.publicKey, 5 * anchor.web3.LAMPORTS_PER_SOL);
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    const [stakerStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), poolIdSeed(0), staker.publicKey.toBuffer()],
+      program.programId
+    );
+    const stakerReceiptAccount = getAssociatedTokenAddressSync(receiptMint, staker.publicKey);
+    const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+    await program.methods
+      .stake(amount, new anchor.BN(30), netOfDepositFee(amount), null)
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        feeVault: feeVault,
+        userStake: stakerStake,
+        accessEntry: null,
+        receiptMint: receiptMint,
+        userReceiptAccount: stakerReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
+      })
+      .signers([staker])
+      .rpc();
+    await new Promise(resolve => setTimeout(resolve, 2000));
+
+    // Anyone may credit the position, and a 1-lamport yearly cap keeps it from draining it
+    await program.methods
+      .updateYieldCaps(new anchor.BN(1), new anchor.BN(1))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    await program.methods
+      .batchCreditYields()
+      .accounts({ keeper: user1.publicKey, pool: pool })
+      .remainingAccounts([{ pubkey: stakerStake, isSigner: false, isWritable: true }])
+      .signers([user1])
+      .rpc();
+    const withdrawCredited = () =>
+      program.methods
+        .withdrawCreditedYields()
+        .accounts({
+          user: staker.publicKey,
+          pool: pool,
+          rewardVault: rewardVault,
+          userStake: stakerStake,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([staker])
+        .rpc();
+    await withdrawCredited();
+    try {
+      await withdrawCredited();
+      expect.fail("Should have thrown an error for a capped withdrawal");
+    } catch (error) {
+      expect(error.message).to.include("YieldCapReached");
+    }
+    const credited = (await program.account.userStake.fetch(stakerStake)).pendingYields;
+    expect(credited.toNumber()).to.be.greaterThan(0);
+
+    await program.methods
+      .updateEmergencyWithdrawDelay(new anchor.BN(0))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    await program.methods
+      .emergencyPause("Credited yield exit", PAUSE_ALL)
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    const before = await program.account.pool.fetch(pool);
+
+    await program.methods
+      .emergencyWithdraw()
+      .accounts({
+        user: staker.publicKey,
+        pool: pool,
+        poolVault: poolVault,
+        userStake: stakerStake,
+        receiptMint: receiptMint,
+        userReceiptAccount: stakerReceiptAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      })
+      .signers([staker])
+      .rpc();
+
+    // The credited yield left behind goes back to the reserve
+    expect(await provider.connection.getAccountInfo(stakerStake)).to.be.null;
+    const after = await program.account.pool.fetch(pool);
+    expect(after.rewardReserve.sub(before.rewardReserve).toString()).to.equal(credited.toString());
+    expect(before.totalYieldsCredited.sub(after.totalYieldsCredited).toString()).to.equal(credited.toString());
+
+    await program.methods.emergencyUnpause().accounts({ admin: admin.publicKey, pool: pool }).signers([admin]).rpc();
+    await program.methods
+      .updateEmergencyWithdrawDelay(new anchor.BN(7 * 86400))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+    await program.methods
+      .updateYieldCaps(new anchor.BN(0), new anchor.BN(0))
+      .accounts({ admin: admin.publicKey, pool: pool })
+      .signers([admin])
+      .rpc();
+  });
+});
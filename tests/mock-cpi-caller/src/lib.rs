@@ -0,0 +1,65 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use defi_trust_fund_interface::{
+    PoolStats, PositionValue, GET_POOL_STATS_DISCRIMINATOR, GET_POSITION_VALUE_DISCRIMINATOR, PROGRAM_ID,
+};
+use solana_program::account_info::AccountInfo;
+use solana_program::entrypoint;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::msg;
+use solana_program::program::{get_return_data, invoke, set_return_data};
+use solana_program::program_error::ProgramError;
+use solana_program::pubkey::Pubkey;
+
+// Stand-in for a lending protocol reading a position over CPI with nothing but the
+// interface crate. It calls get_position_value (data [0]) or get_pool_stats (data [1]),
+// decodes the answer with the published layout and hands it back as its own return
+// data, so the test can compare it with the accounts.
+//
+// Accounts: [trust fund program, pool] plus the user stake for get_position_value
+
+entrypoint!(process_instruction);
+
+fn process_instruction(_program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let trust_fund_id = Pubkey::new_from_array(PROGRAM_ID);
+    let (discriminator, view_accounts) = match (data, accounts) {
+        ([0], [program, pool, user_stake]) if *program.key == trust_fund_id => {
+            (GET_POSITION_VALUE_DISCRIMINATOR, vec![pool.clone(), user_stake.clone()])
+        }
+        ([1], [program, pool]) if *program.key == trust_fund_id => (GET_POOL_STATS_DISCRIMINATOR, vec![pool.clone()]),
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+
+    invoke(
+        &Instruction {
+            program_id: trust_fund_id,
+            accounts: view_accounts.iter().map(|account| AccountMeta::new_readonly(*account.key, false)).collect(),
+            data: discriminator.to_vec(),
+        },
+        &view_accounts,
+    )?;
+
+    // Only trust return data the trust fund itself set
+    let returned = match get_return_data() {
+        Some((program_id, returned)) if program_id == trust_fund_id => returned,
+        _ => return Err(ProgramError::InvalidAccountData),
+    };
+    let decoded = if data[0] == 0 {
+        let value = PositionValue::try_from_slice(&returned)?;
+        msg!(
+            "amount={} accrued_yields={} unlock_ts={} apy_bps={}",
+            value.amount, value.accrued_yields, value.unlock_ts, value.apy_bps
+        );
+        value.try_to_vec()?
+    } else {
+        let stats = PoolStats::try_from_slice(&returned)?;
+        msg!(
+            "total_staked={} total_users={} utilization_bps={}",
+            stats.total_staked, stats.total_users, stats.utilization_bps
+        );
+        stats.try_to_vec()?
+    };
+    set_return_data(&decoded);
+
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use solana_program::account_info::AccountInfo;
+use solana_program::clock::Clock;
+use solana_program::entrypoint;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_error::ProgramError;
+use solana_program::pubkey::Pubkey;
+use solana_program::rent::Rent;
+use solana_program::system_instruction;
+use solana_program::sysvar::Sysvar;
+
+// Stand-in for Marinade, loaded at its address by the localnet validator. It takes the
+// same accounts as Marinade's deposit, liquid_unstake, order_unstake and claim, in the
+// same order. Its only state is msol_price, kept where Marinade keeps it in the PDA
+// ["state"], which set_price creates and moves so a test can stand in for staking
+// yield accruing. Deposits and liquid unstakes convert at that price without a fee,
+// tickets are owed their value at the price they were ordered at, and claims pay at once.
+//
+// The mSOL mint's authority is the PDA ["mint_authority"], and liquid unstakes and
+// claims are paid from the PDA ["liq_sol"], which the test funds.
+
+const DEPOSIT: [u8; 8] = [242, 35, 198, 137, 82, 225, 242, 182];
+const LIQUID_UNSTAKE: [u8; 8] = [30, 30, 119, 240, 191, 227, 12, 16];
+const ORDER_UNSTAKE: [u8; 8] = [97, 167, 144, 107, 117, 190, 128, 36];
+const CLAIM: [u8; 8] = [62, 198, 214, 193, 213, 159, 108, 210];
+// Not a Marinade instruction: [state, payer (signer), system_program], price as data
+const SET_PRICE: [u8; 8] = *b"setprice";
+
+const STATE_DISCRIMINATOR: [u8; 8] = [216, 146, 107, 94, 104, 75, 182, 177];
+const TICKET_DISCRIMINATOR: [u8; 8] = [133, 77, 18, 98, 211, 1, 231, 3];
+const MSOL_PRICE_OFFSET: usize = 512;
+const PRICE_DENOMINATOR: u128 = 1 << 32;
+const TICKET_SPACE: usize = 88;
+
+entrypoint!(process_instruction);
+
+fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() < 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let amount = || -> Result<u64, ProgramError> {
+        let bytes = data.get(8..16).ok_or(ProgramError::InvalidInstructionData)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    match data[..8].try_into().unwrap() {
+        DEPOSIT => deposit(program_id, accounts, amount()?),
+        LIQUID_UNSTAKE => liquid_unstake(program_id, accounts, amount()?),
+        ORDER_UNSTAKE => order_unstake(program_id, accounts, amount()?),
+        CLAIM => claim(program_id, accounts),
+        SET_PRICE => set_price(program_id, accounts, amount()?),
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+// [state, payer (signer), system_program]
+fn set_price(program_id: &Pubkey, accounts: &[AccountInfo], msol_price: u64) -> ProgramResult {
+    let [state, payer, system_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    let (address, bump) = Pubkey::find_program_address(&[b"state"], program_id);
+    if address != *state.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if state.data_is_empty() {
+        let space = MSOL_PRICE_OFFSET + 8;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                state.key,
+                Rent::get()?.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[payer.clone(), state.clone(), system_program.clone()],
+            &[&[b"state", &[bump]]],
+        )?;
+    }
+
+    let mut data = state.try_borrow_mut_data()?;
+    data[..8].copy_from_slice(&STATE_DISCRIMINATOR);
+    data[MSOL_PRICE_OFFSET..].copy_from_slice(&msol_price.to_le_bytes());
+    Ok(())
+}
+
+fn msol_price(program_id: &Pubkey, state: &AccountInfo) -> Result<u128, ProgramError> {
+    if state.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let data = state.try_borrow_data()?;
+    Ok(u64::from_le_bytes(data[MSOL_PRICE_OFFSET..MSOL_PRICE_OFFSET + 8].try_into().unwrap()) as u128)
+}
+
+fn to_u64(value: u128) -> Result<u64, ProgramError> {
+    u64::try_from(value).map_err(|_| ProgramError::ArithmeticOverflow)
+}
+
+// Pay lamports out of the ["liq_sol"] PDA, which has to be the account given
+fn pay_from_liq_sol<'a>(
+    program_id: &Pubkey,
+    liq_sol: &AccountInfo<'a>,
+    recipient: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    lamports: u64,
+) -> ProgramResult {
+    let (address, bump) = Pubkey::find_program_address(&[b"liq_sol"], program_id);
+    if address != *liq_sol.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke_signed(
+        &system_instruction::transfer(&address, recipient.key, lamports),
+        &[liq_sol.clone(), recipient.clone(), system_program.clone()],
+        &[&[b"liq_sol", &[bump]]],
+    )
+}
+
+// [state, msol_mint, liq_pool_sol_leg_pda, liq_pool_msol_leg, liq_pool_msol_leg_authority,
+//  reserve_pda, transfer_from (signer), mint_to, msol_mint_authority, system_program, token_program]
+fn deposit(program_id: &Pubkey, accounts: &[AccountInfo], lamports: u64) -> ProgramResult {
+    let [state, msol_mint, _, _, _, reserve, transfer_from, mint_to, mint_authority, system_program, token_program] =
+        accounts
+    else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    let (authority, bump) = Pubkey::find_program_address(&[b"mint_authority"], program_id);
+    if authority != *mint_authority.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let msol_amount = to_u64(lamports as u128 * PRICE_DENOMINATOR / msol_price(program_id, state)?)?;
+
+    // transfer_from signed the outer call, so its signature carries through
+    invoke(
+        &system_instruction::transfer(transfer_from.key, reserve.key, lamports),
+        &[transfer_from.clone(), reserve.clone(), system_program.clone()],
+    )?;
+
+    invoke_signed(
+        &spl_token::instruction::mint_to(token_program.key, msol_mint.key, mint_to.key, &authority, &[], msol_amount)?,
+        &[msol_mint.clone(), mint_to.clone(), mint_authority.clone(), token_program.clone()],
+        &[&[b"mint_authority", &[bump]]],
+    )
+}
+
+// [state, msol_mint, liq_pool_sol_leg_pda, liq_pool_msol_leg, treasury_msol_account,
+//  get_msol_from, get_msol_from_authority (signer), transfer_sol_to, system_program, token_program]
+fn liquid_unstake(program_id: &Pubkey, accounts: &[AccountInfo], msol_amount: u64) -> ProgramResult {
+    let [state, msol_mint, sol_leg, _, _, msol_from, msol_authority, transfer_sol_to, system_program, token_program] =
+        accounts
+    else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    let lamports = to_u64(msol_amount as u128 * msol_price(program_id, state)? / PRICE_DENOMINATOR)?;
+
+    invoke(
+        &spl_token::instruction::burn(token_program.key, msol_from.key, msol_mint.key, msol_authority.key, &[], msol_amount)?,
+        &[msol_from.clone(), msol_mint.clone(), msol_authority.clone(), token_program.clone()],
+    )?;
+
+    pay_from_liq_sol(program_id, sol_leg, transfer_sol_to, system_program, lamports)
+}
+
+// [state, msol_mint, burn_msol_from, burn_msol_authority (signer), new_ticket_account,
+//  clock, rent, token_program]
+fn order_unstake(program_id: &Pubkey, accounts: &[AccountInfo], msol_amount: u64) -> ProgramResult {
+    let [state, msol_mint, msol_from, msol_authority, ticket, _, _, token_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    if ticket.owner != program_id || ticket.data_len() != TICKET_SPACE || ticket.data.borrow()[..8] != [0; 8] {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let lamports = to_u64(msol_amount as u128 * msol_price(program_id, state)? / PRICE_DENOMINATOR)?;
+
+    invoke(
+        &spl_token::instruction::burn(token_program.key, msol_from.key, msol_mint.key, msol_authority.key, &[], msol_amount)?,
+        &[msol_from.clone(), msol_mint.clone(), msol_authority.clone(), token_program.clone()],
+    )?;
+
+    let mut data = ticket.try_borrow_mut_data()?;
+    data[..8].copy_from_slice(&TICKET_DISCRIMINATOR);
+    data[8..40].copy_from_slice(state.key.as_ref());
+    data[40..72].copy_from_slice(msol_authority.key.as_ref());
+    data[72..80].copy_from_slice(&lamports.to_le_bytes());
+    data[80..88].copy_from_slice(&Clock::get()?.epoch.to_le_bytes());
+    Ok(())
+}
+
+// [state, reserve_pda, ticket_account, transfer_sol_to, clock, system_program]
+fn claim(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let [_, reserve, ticket, transfer_sol_to, _, system_program] = accounts else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    let lamports = {
+        let data = ticket.try_borrow_data()?;
+        if ticket.owner != program_id || data[..8] != TICKET_DISCRIMINATOR || data[40..72] != transfer_sol_to.key.to_bytes() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        u64::from_le_bytes(data[72..80].try_into().unwrap())
+    };
+
+    pay_from_liq_sol(program_id, reserve, transfer_sol_to, system_program, lamports)?;
+
+    // Closing the ticket refunds its rent to the beneficiary
+    **transfer_sol_to.try_borrow_mut_lamports()? += ticket.lamports();
+    **ticket.try_borrow_mut_lamports()? = 0;
+    ticket.try_borrow_mut_data()?.fill(0);
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use solana_program::account_info::AccountInfo;
+use solana_program::entrypoint;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_error::ProgramError;
+use solana_program::pubkey::Pubkey;
+
+// Stand-in for the Jupiter router, loaded at its address by the localnet validator.
+// A "route" sells amount_in of the input token into the mock's reserve and pays
+// amount_out wrapped SOL from the reserve to the destination at a fixed price set by
+// the test. Instruction data is amount_in then amount_out, both u64 little-endian.
+//
+// Accounts: [user (signer), user input account, input reserve, wSOL reserve,
+//            destination wSOL account, reserve authority PDA, token program]
+
+entrypoint!(process_instruction);
+
+fn process_instruction(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let [user, user_input, input_reserve, wsol_reserve, destination, reserve_authority, token_program] = accounts
+    else {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    };
+    if data.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let amount_in = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let amount_out = u64::from_le_bytes(data[8..].try_into().unwrap());
+
+    let (authority, bump) = Pubkey::find_program_address(&[b"reserve"], program_id);
+    if authority != *reserve_authority.key {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    invoke(
+        &spl_token::instruction::transfer(token_program.key, user_input.key, input_reserve.key, user.key, &[], amount_in)?,
+        &[user_input.clone(), input_reserve.clone(), user.clone(), token_program.clone()],
+    )?;
+
+    invoke_signed(
+        &spl_token::instruction::transfer(token_program.key, wsol_reserve.key, destination.key, &authority, &[], amount_out)?,
+        &[wsol_reserve.clone(), destination.clone(), reserve_authority.clone(), token_program.clone()],
+        &[&[b"reserve", &[bump]]],
+    )
+}
@@ -1,9 +1,17 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { DeFiTrustFund } from "../target/types/defi_trust_fund";
-import { PublicKey, Keypair, LAMPORTS_PER_SOL } from "@solana/web3.js";
+import { PublicKey, Keypair, LAMPORTS_PER_SOL, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
+import { TOKEN_PROGRAM_ID, ASSOCIATED_TOKEN_PROGRAM_ID, getAssociatedTokenAddressSync } from "@solana/spl-token";
 import { expect } from "chai";
 
+// Net deposit quoted at a 1% fee, which satisfies both the 0.5% default fee and the 1%
+// fee the admin tests set, within the pool's default quote tolerance
+const quotedNet = (amount: anchor.BN): anchor.BN => amount.muln(9900).divn(10000);
+
+// Pool.pause_flags with deposits, claims and unstakes all paused
+const PAUSE_ALL = 7;
+
 describe("Security Tests - Redesigned Contract", () => {
   const provider = anchor.AnchorProvider.env();
   anchor.setProvider(provider);
@@ -31,7 +39,7 @@ describe("Security Tests - Redesigned Contract", () => {
       const maxCommitmentDays = 365;
 
       await program.methods
-        .initializePool(maxApy, minCommitmentDays, maxCommitmentDays)
+        .initializePool(new anchor.BN(0), maxApy, minCommitmentDays, maxCommitmentDays, null, [], [])
         .accounts({
           admin: admin.publicKey,
           pool: poolKeypair.publicKey,
@@ -47,7 +55,7 @@ describe("Security Tests - Redesigned Contract", () => {
       expect(pool.minCommitmentDays.toNumber()).to.equal(minCommitmentDays);
       expect(pool.maxCommitmentDays.toNumber()).to.equal(maxCommitmentDays);
       expect(pool.isActive).to.be.true;
-      expect(pool.isPaused).to.be.false;
+      expect(pool.pauseFlags).to.equal(0);
     });
 
     it("should reject invalid APY during initialization", async () => {
@@ -55,7 +63,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       try {
         await program.methods
-          .initializePool(invalidMaxApy, 1, 365)
+          .initializePool(new anchor.BN(0), invalidMaxApy, 1, 365, null, [], [])
           .accounts({
             admin: admin.publicKey,
             pool: Keypair.generate().publicKey,
@@ -74,7 +82,7 @@ describe("Security Tests - Redesigned Contract", () => {
     it("should reject invalid commitment days during initialization", async () => {
       try {
         await program.methods
-          .initializePool(5000, 0, 365) // min days = 0
+          .initializePool(new anchor.BN(0), 5000, 0, 365, null, [], []) // min days = 0
           .accounts({
             admin: admin.publicKey,
             pool: Keypair.generate().publicKey,
@@ -98,7 +106,7 @@ describe("Security Tests - Redesigned Contract", () => {
       const vaultKey = Keypair.generate();
       
       await program.methods
-        .initializePool(5000, 1, 365)
+        .initializePool(new anchor.BN(0), 5000, 1, 365, null, [], [])
         .accounts({
           admin: admin.publicKey,
           pool: poolKey.publicKey,
@@ -114,7 +122,7 @@ describe("Security Tests - Redesigned Contract", () => {
       const committedDays = 30;
 
       await program.methods
-        .stake(amount, committedDays)
+        .stake(amount, committedDays, quotedNet(amount), null)
         .accounts({
           user: user1.publicKey,
           pool: poolKeypair.publicKey,
@@ -144,7 +152,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       try {
         await program.methods
-          .stake(smallAmount, 30)
+          .stake(smallAmount, 30, quotedNet(smallAmount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -169,7 +177,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       try {
         await program.methods
-          .stake(largeAmount, 30)
+          .stake(largeAmount, 30, quotedNet(largeAmount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -195,7 +203,7 @@ describe("Security Tests - Redesigned Contract", () => {
       // Test zero days
       try {
         await program.methods
-          .stake(amount, 0)
+          .stake(amount, 0, quotedNet(amount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -217,7 +225,7 @@ describe("Security Tests - Redesigned Contract", () => {
       // Test excessive days
       try {
         await program.methods
-          .stake(amount, 1000)
+          .stake(amount, 1000, quotedNet(amount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -242,7 +250,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       try {
         await program.methods
-          .stake(largeAmount, 30)
+          .stake(largeAmount, 30, quotedNet(largeAmount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -266,7 +274,7 @@ describe("Security Tests - Redesigned Contract", () => {
   describe("Emergency Controls", () => {
     it("should allow admin to pause pool", async () => {
       await program.methods
-        .emergencyPause("Security test pause")
+        .emergencyPause("Security test pause", PAUSE_ALL)
         .accounts({
           admin: admin.publicKey,
           pool: poolKeypair.publicKey,
@@ -275,14 +283,31 @@ describe("Security Tests - Redesigned Contract", () => {
         .rpc();
 
       const pool = await program.account.pool.fetch(poolKeypair.publicKey);
-      expect(pool.isPaused).to.be.true;
+      expect(pool.pauseFlags).to.equal(PAUSE_ALL);
       expect(pool.emergencyPauseReason).to.equal("Security test pause");
     });
 
+    it("should reject a pause reason over 64 bytes", async () => {
+      try {
+        await program.methods
+          .emergencyPause("x".repeat(500), PAUSE_ALL)
+          .accounts({
+            admin: admin.publicKey,
+            pool: poolKeypair.publicKey,
+          })
+          .signers([admin])
+          .rpc();
+
+        expect.fail("Should have thrown an error for an over-long reason");
+      } catch (error) {
+        expect(error.message).to.include("StringTooLong");
+      }
+    });
+
     it("should prevent non-admin from pausing pool", async () => {
       try {
         await program.methods
-          .emergencyPause("Unauthorized pause")
+          .emergencyPause("Unauthorized pause", PAUSE_ALL)
           .accounts({
             admin: attacker.publicKey,
             pool: poolKeypair.publicKey,
@@ -299,7 +324,7 @@ describe("Security Tests - Redesigned Contract", () => {
     it("should prevent staking when pool is paused", async () => {
       // First pause the pool
       await program.methods
-        .emergencyPause("Test pause")
+        .emergencyPause("Test pause", PAUSE_ALL)
         .accounts({
           admin: admin.publicKey,
           pool: poolKeypair.publicKey,
@@ -310,7 +335,7 @@ describe("Security Tests - Redesigned Contract", () => {
       // Try to stake
       try {
         await program.methods
-          .stake(new anchor.BN(LAMPORTS_PER_SOL), 30)
+          .stake(new anchor.BN(LAMPORTS_PER_SOL), 30, quotedNet(new anchor.BN(LAMPORTS_PER_SOL)), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -333,7 +358,7 @@ describe("Security Tests - Redesigned Contract", () => {
     it("should allow admin to unpause pool", async () => {
       // First pause
       await program.methods
-        .emergencyPause("Test pause")
+        .emergencyPause("Test pause", PAUSE_ALL)
         .accounts({
           admin: admin.publicKey,
           pool: poolKeypair.publicKey,
@@ -352,9 +377,104 @@ describe("Security Tests - Redesigned Contract", () => {
         .rpc();
 
       const pool = await program.account.pool.fetch(poolKeypair.publicKey);
-      expect(pool.isPaused).to.be.false;
+      expect(pool.pauseFlags).to.equal(0);
       expect(pool.emergencyPauseReason).to.equal("");
     });
+
+    it("should return principal through emergency withdraw once the delay has passed, even after sunset", async () => {
+      const [poolRegistry] = PublicKey.findProgramAddressSync([Buffer.from("pool_registry")], program.programId);
+      const poolId = (await program.account.poolRegistry.fetch(poolRegistry)).nextPoolId;
+      const seed = poolId.toArrayLike(Buffer, "le", 8);
+      const derive = (name: string) => PublicKey.findProgramAddressSync([Buffer.from(name), seed], program.programId)[0];
+      const pool = derive("pool");
+      const poolVault = derive("pool_vault");
+      const receiptMint = derive("receipt_mint");
+      const userStake = PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), seed, user1.publicKey.toBuffer()],
+        program.programId
+      )[0];
+      const userReceiptAccount = getAssociatedTokenAddressSync(receiptMint, user1.publicKey);
+
+      await program.methods
+        .initializePool(poolId, 2000, 1, 365, null, [], [])
+        .accounts({
+          admin: admin.publicKey,
+          poolRegistry: poolRegistry,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: derive("fee_vault"),
+          rewardVault: derive("reward_vault"),
+          receiptMint: receiptMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([admin])
+        .rpc();
+
+      const amount = new anchor.BN(LAMPORTS_PER_SOL);
+      await program.methods
+        .stake(amount, new anchor.BN(30), quotedNet(amount), null)
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          feeVault: derive("fee_vault"),
+          userStake: userStake,
+          accessEntry: null,
+          receiptMint: receiptMint,
+          userReceiptAccount: userReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: SYSVAR_RENT_PUBKEY,
+        })
+        .signers([user1])
+        .rpc();
+      const staked = (await program.account.userStake.fetch(userStake)).amount;
+
+      // No delay, so the pause unlocks emergency withdrawals at once; the sunset leaves
+      // the pool inactive, which must not strand the principal
+      await program.methods
+        .updateEmergencyWithdrawDelay(new anchor.BN(0))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      await program.methods
+        .sunsetPool(new anchor.BN(Math.floor(Date.now() / 1000) + 60))
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      await program.methods
+        .emergencyPause("Emergency withdraw test", PAUSE_ALL)
+        .accounts({ admin: admin.publicKey, pool: pool })
+        .signers([admin])
+        .rpc();
+      expect((await program.account.pool.fetch(pool)).isActive).to.equal(false);
+
+      const vaultBefore = await provider.connection.getBalance(poolVault);
+      await program.methods
+        .emergencyWithdraw()
+        .accounts({
+          user: user1.publicKey,
+          pool: pool,
+          poolVault: poolVault,
+          userStake: userStake,
+          receiptMint: receiptMint,
+          userReceiptAccount: userReceiptAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+
+      // The whole principal left the vault with no penalty and the position is closed
+      expect(vaultBefore - (await provider.connection.getBalance(poolVault))).to.equal(staked.toNumber());
+      expect(await provider.connection.getAccountInfo(userStake)).to.be.null;
+      const after = await program.account.pool.fetch(pool);
+      expect(after.totalStaked.toNumber()).to.equal(0);
+      expect(after.totalUsers.toNumber()).to.equal(0);
+    });
   });
 
   describe("Parameter Update Security", () => {
@@ -454,7 +574,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       try {
         await program.methods
-          .stake(new anchor.BN(maxU64), 1)
+          .stake(new anchor.BN(maxU64), 1, quotedNet(new anchor.BN(maxU64)), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -506,7 +626,7 @@ describe("Security Tests - Redesigned Contract", () => {
       try {
         // Attempt operation that will fail
         await program.methods
-          .stake(new anchor.BN(LAMPORTS_PER_SOL), 0) // Invalid commitment days
+          .stake(new anchor.BN(LAMPORTS_PER_SOL), 0, quotedNet(new anchor.BN(LAMPORTS_PER_SOL)), null) // Invalid commitment days
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -536,7 +656,7 @@ describe("Security Tests - Redesigned Contract", () => {
       const expectedFee = amount.mul(new anchor.BN(50)).div(new anchor.BN(10000)); // 0.5%
       
       await program.methods
-        .stake(amount, 30)
+        .stake(amount, 30, quotedNet(amount), null)
         .accounts({
           user: user1.publicKey,
           pool: poolKeypair.publicKey,
@@ -601,7 +721,7 @@ describe("Security Tests - Redesigned Contract", () => {
       const committedDays = 30;
       
       const tx = await program.methods
-        .stake(amount, committedDays)
+        .stake(amount, committedDays, quotedNet(amount), null)
         .accounts({
           user: user1.publicKey,
           pool: poolKeypair.publicKey,
@@ -631,7 +751,7 @@ describe("Security Tests - Redesigned Contract", () => {
       
       const promises = [
         program.methods
-          .stake(amount, committedDays)
+          .stake(amount, committedDays, quotedNet(amount), null)
           .accounts({
             user: user1.publicKey,
             pool: poolKeypair.publicKey,
@@ -645,7 +765,7 @@ describe("Security Tests - Redesigned Contract", () => {
           .signers([user1])
           .rpc(),
         program.methods
-          .stake(amount, committedDays)
+          .stake(amount, committedDays, quotedNet(amount), null)
           .accounts({
             user: user2.publicKey,
             pool: poolKeypair.publicKey,